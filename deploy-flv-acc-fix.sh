@@ -4,6 +4,11 @@ cargo build --release --example flv-info
 cargo build --release --example flv-config
 cargo build --release --example flv-split
 cargo build --release --example flv-aac-fix
+cargo build --release --example flv-salvage
+cargo build --release --example flv-annexb
+cargo build --release --example flv-mux-annexb
+cargo build --release --example flv-captions
+cargo build --release --example flv-keyframe-check
 cargo build --release --example timestamp-normalization
 
 cp ./target/release/examples/flv-* ./bin/
@@ -25,6 +25,7 @@ fn main() {
     let mut count = 0;
     let mut buf: Vec<u8> = Vec::new();
     let mut avcc_data: Option<Vec<u8>> = None;
+    let mut nal_length_size: usize = 4;
     
     loop {
         let nxt = parser.next();
@@ -36,15 +37,16 @@ fn main() {
             
             if tag.get_frame_type() == 1 && tag.get_avc_packet_type() == 0 {
                 let avcc = tag.get_avcc();
-                buf.write(&spliter);
-                buf.write(&avcc.sps);
-                buf.write(&spliter);
-                buf.write(&avcc.pps_array[0]);
+                for ps in avcc.sps_array.iter().chain(avcc.pps_array.iter()) {
+                    buf.write(&spliter);
+                    buf.write(ps);
+                }
+                nal_length_size = avcc.get_nal_length_size();
                 avcc_data = Some(tag.get_avcc_data().to_vec());
 
                 // println!("{:?}", avcc);
             } else {
-                let nalus = tag.get_nal_units();
+                let nalus = tag.get_nal_units_with_length_size(nal_length_size);
                 for nalu in nalus {
                     buf.write(&spliter);
                     buf.write(&nalu);
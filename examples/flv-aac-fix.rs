@@ -8,10 +8,9 @@ use std::fs::File;
 use std::io::{ Seek, SeekFrom, Read, Write };
 
 use self::byteorder::{BigEndian, WriteBytesExt};
-use rustc_serialize::json::Json;
 use getopts::Options;
 
-use flv_toolbox_rs::lib::{ FLVTagRead, FLVHeader, FLVTagType, FLVTag };
+use flv_toolbox_rs::lib::{ FLVTagRead, FLVTagWrite, FLVHeader, FLVTagType, FLVTag };
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} FILE [options]", program);
@@ -23,7 +22,7 @@ fn main() {
     let program = args[0].clone();
 
     let mut opts = Options::new();
-    opts.optflagopt("o", "output", "output flv file", "OUTPUT");
+    opts.optflagopt("o", "output", "output flv file, - for stdout", "OUTPUT");
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("t", "check-only", "test, check only");
 
@@ -81,7 +80,13 @@ fn main() {
         }
     };
 
-    let need_fix = detect_flv_acc(input_path);
+    let need_fix = match detect_flv_acc(input_path) {
+        Ok(need_fix) => need_fix,
+        Err(e) => {
+            eprintln!("detect err: {}", e);
+            std::process::exit(-1);
+        }
+    };
     if need_fix {
         match fix_flv_acc(input_path, &output, test) {
             Ok(_) => {
@@ -102,60 +107,66 @@ fn main() {
     }
 }
 
-fn next_tag_of_type<'a, R: Read>(parser: &mut FLVTagRead<'a, R>, tag_type: FLVTagType) -> Option<FLVTag> {
+fn next_tag_of_type<'a, R: Read>(parser: &mut FLVTagRead<'a, R>, tag_type: FLVTagType) -> Result<Option<FLVTag>, String> {
     loop {
-        if let Some(tag) = parser.next() {
+        let position = parser.get_position();
+        if let Some(tag) = parser.try_next().map_err(|e| format!("read tag err at {}: {}", position, e))? {
             if tag.get_tag_type() == tag_type {
-                break Some(tag);
+                break Ok(Some(tag));
             } else {
                 continue;
             }
         } else {
-            break None;
+            break Ok(None);
         }
     }
 }
 
-fn detect_flv_acc(flv_path: &Path) -> bool {
-    let mut file = std::fs::File::open(flv_path).unwrap();
-    let mut parser = FLVTagRead::new(&mut file);
+fn detect_flv_acc(flv_path: &Path) -> Result<bool, String> {
+    let mut file = std::fs::File::open(flv_path).map_err(|_| "cannot open input file.".to_owned())?;
+    let mut parser = FLVTagRead::try_new(&mut file).map_err(|e| format!("read flv header err: {}", e))?;
     {
         let header: &FLVHeader = &parser.header;
         if !header.hasAudioTags {
             eprintln!("no audio stream");
-            return false;
+            return Ok(false);
         }
     }
 
-    let audio_tag = next_tag_of_type(&mut parser, FLVTagType::TAG_TYPE_AUDIO);
+    let audio_tag = next_tag_of_type(&mut parser, FLVTagType::TAG_TYPE_AUDIO)?;
     if audio_tag.is_none() {
-        return false;
+        return Ok(false);
     }
     let audio_tag: FLVTag = audio_tag.unwrap();
     if !audio_tag.is_acc_sequence_header() {
         eprintln!("first audio tag is not acc_sequence_header, exit");
-        return false;
+        return Ok(false);
     }
     let data_size = audio_tag.get_data_size();
     // println!("{:?}", audio_tag.get_sound_audio_specific_config());
-    return data_size == 2;
+    return Ok(data_size == 2);
 }
 
 fn fix_flv_acc(flv_path: &Path, output_path: &str, test: bool) -> Result<(), String> {
     let new_tag = {
         let mut file = std::fs::File::open(flv_path).map_err(|_| "cannot open output file.".to_owned())?;
-        let mut parser = FLVTagRead::new(&mut file);
+        let mut parser = FLVTagRead::try_new(&mut file).map_err(|e| format!("read flv header err: {}", e))?;
         
-        let meta_tag = next_tag_of_type(&mut parser, FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT).ok_or("no meta tag".to_string())?;
-        let mut acc_tag = next_tag_of_type(&mut parser, FLVTagType::TAG_TYPE_AUDIO).ok_or::<String>("no acc_sequence_header".into())?;
+        // other script tags like onXMPData may come before onMetaData
+        let meta = loop {
+            let tag = next_tag_of_type(&mut parser, FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT)?.ok_or("no onMetaData tag".to_string())?;
+            if let Some(meta) = tag.try_get_metadata().map_err(|e| format!("read meta tag err: {}", e))? {
+                break meta;
+            }
+        };
+        let mut acc_tag = next_tag_of_type(&mut parser, FLVTagType::TAG_TYPE_AUDIO)?.ok_or::<String>("no acc_sequence_header".into())?;
         assert!(acc_tag.is_acc_sequence_header());
         assert_eq!(acc_tag.get_data_size(), 2);
-        let a_tag = next_tag_of_type(&mut parser, FLVTagType::TAG_TYPE_AUDIO).ok_or::<String>("only one acc_sequence_header".into())?;
-        let meta_obj = &meta_tag.get_objects()[1];
-        println!("{:?}", meta_obj);
-        let sample: i64 = meta_obj.find("audiosamplerate").ok_or("no audiosamplerate in meta, can't fix.".to_owned())?.as_f64().ok_or("audiosamplerate is not f64, can't fix.".to_string())? as _;
-        let stereo = meta_obj.find("stereo").ok_or("no stereo in meta, can't fix.".to_owned())?.as_boolean().ok_or("no stereo in meta or stereo is not boolean, can't fix.".to_owned())?;
-        if meta_obj.find("keyframes").is_some() {
+        let a_tag = next_tag_of_type(&mut parser, FLVTagType::TAG_TYPE_AUDIO)?.ok_or::<String>("only one acc_sequence_header".into())?;
+        eprintln!("{:?}", meta);
+        let sample: i64 = meta.audiosamplerate.ok_or("no numeric audiosamplerate in meta, can't fix.".to_owned())? as _;
+        let stereo = meta.stereo.ok_or("no boolean stereo in meta, can't fix.".to_owned())?;
+        if meta.keyframes.is_some() {
             eprintln!("warning: flv has keyframes table. filepositions should adjust, but not.");
         }
         let original_audio_object_type = 2;
@@ -176,7 +187,7 @@ fn fix_flv_acc(flv_path: &Path, output_path: &str, test: bool) -> Result<(), Str
         data.pop();
         data.write_u32::<BigEndian>(15).unwrap();
         eprintln!("{:?}", data);
-        let new_tag = FLVTag::read(&mut &*data).unwrap();
+        let new_tag = FLVTag::try_read(&mut &*data).map_err(|e| format!("build new acc_sequence_header err: {}", e))?.unwrap();
         eprintln!("{:?}", (new_tag.get_tag_type(), new_tag.get_data_size(), new_tag.get_sound_audio_specific_config()));
         new_tag
     };
@@ -186,14 +197,24 @@ fn fix_flv_acc(flv_path: &Path, output_path: &str, test: bool) -> Result<(), Str
     let mut new_tag = Some(new_tag);
     // reopen
     let mut file = std::fs::File::open(flv_path).map_err(|_| "cannot open output file.".to_owned())?;
-    let mut parser = FLVTagRead::new(&mut file);
-    let mut ofile = std::fs::File::create(output_path).map_err(|_| "cannot open output file.".to_owned())?;
-    parser.header.write(&mut ofile);
-    while let Some(mut tag) = parser.next() {
+    let mut parser = FLVTagRead::try_new(&mut file).map_err(|e| format!("read flv header err: {}", e))?;
+    let ofile: Box<Write> = if output_path == "-" {
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(std::fs::File::create(output_path).map_err(|_| "cannot open output file.".to_owned())?)
+    };
+    let mut tag_write = FLVTagWrite::new(ofile);
+    tag_write.write_header(&parser.header);
+    loop {
+        let position = parser.get_position();
+        let mut tag = match parser.try_next().map_err(|e| format!("read tag err at {}: {}", position, e))? {
+            Some(tag) => tag,
+            None => break,
+        };
         if new_tag.is_some() && tag.get_tag_type() == FLVTagType::TAG_TYPE_AUDIO && tag.is_acc_sequence_header() {
             tag = new_tag.take().unwrap();// only switch once
         }
-        tag.write(&mut ofile);
+        tag_write.write_tag(&tag);
     }
     Ok(())
 }
\ No newline at end of file
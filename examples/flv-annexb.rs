@@ -0,0 +1,116 @@
+extern crate getopts;
+extern crate flv_toolbox_rs;
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use getopts::Options;
+
+use flv_toolbox_rs::lib::{ FLVTagRead, FLVTagType, CODEC_ID_HEVC };
+use flv_toolbox_rs::annexb::AnnexBWriter;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optflagopt("o", "output", "output .h264/.h265 file", "OUTPUT");
+    opts.optflag("a", "aud", "insert access unit delimiters");
+    opts.optflag("h", "help", "print this help menu");
+
+    let usage_str = {
+        let brief = format!("Usage: {} FILE [options]", program);
+        format!("{}", opts.usage(&brief))
+    };
+
+    let exit_with_usage = || {
+        eprintln!("{}", usage_str);
+        std::process::exit(-1);
+    };
+
+    let matches: getopts::Matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("{}", f.to_string());
+            return exit_with_usage();
+        }
+    };
+
+    if matches.opt_present("h") {
+        return exit_with_usage();
+    }
+
+    let input: String = if !matches.free.is_empty() {
+        matches.free[0].clone()
+    } else {
+        eprintln!("no input file.");
+        return exit_with_usage();
+    };
+
+    let input_path: &Path = Path::new(&input);
+    if !input_path.exists() {
+        eprintln!("input file does not exist.");
+        return exit_with_usage();
+    }
+
+    let output = match matches.opt_default("o", "") {
+        Some(ref c) if !c.is_empty() => c.clone(),
+        _ => {
+            let ext = match first_video_codec(input_path) {
+                Ok(Some(CODEC_ID_HEVC)) => "h265",
+                Ok(_) => "h264",
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(-1);
+                }
+            };
+            let output = input_path.with_extension(ext);
+            eprintln!("no output file, use {}", output.to_str().unwrap());
+            output.to_string_lossy().to_string()
+        }
+    };
+
+    match export(input_path, &output, matches.opt_present("a")) {
+        Ok(frames) => {
+            eprintln!("{} frame(s) written.", frames);
+        }
+        Err(e) => {
+            eprintln!("export err: {}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+/// codec id of the first video tag, to name the output file
+fn first_video_codec(flv_path: &Path) -> Result<Option<u8>, String> {
+    let mut file = File::open(flv_path).map_err(|e| format!("cannot open input file: {}", e))?;
+    let mut parser = FLVTagRead::try_new(&mut file).map_err(|e| format!("read flv header err: {}", e))?;
+    for tag in parser.try_iter() {
+        let tag = tag.map_err(|e| format!("read tag err: {}", e))?;
+        if tag.get_tag_type() == FLVTagType::TAG_TYPE_VIDEO && tag.get_data_size() > 0 {
+            return Ok(Some(tag.get_codec_id()));
+        }
+    }
+    Ok(None)
+}
+
+fn export(flv_path: &Path, output_path: &str, aud: bool) -> Result<u64, String> {
+    let mut file = File::open(flv_path).map_err(|e| format!("cannot open input file: {}", e))?;
+    let mut parser = FLVTagRead::try_new(&mut file).map_err(|e| format!("read flv header err: {}", e))?;
+    let ofile = File::create(output_path).map_err(|e| format!("cannot open output file: {}", e))?;
+    let mut writer = AnnexBWriter::new(BufWriter::new(ofile)).with_aud(aud);
+    loop {
+        let position = parser.get_position();
+        match parser.try_next().map_err(|e| format!("read tag err at {}: {}", position, e))? {
+            Some(tag) => writer.write_tag(&tag).map_err(|e| format!("tag at {}: {}", position, e))?,
+            None => break,
+        }
+    }
+    if writer.get_codec_id().is_none() {
+        return Err("no avc/hevc sequence header.".to_string());
+    }
+    let frames = writer.get_frame_count();
+    writer.into_inner().flush().map_err(|e| format!("write err: {}", e))?;
+    Ok(frames)
+}
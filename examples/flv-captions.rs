@@ -0,0 +1,151 @@
+extern crate getopts;
+extern crate flv_toolbox_rs;
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use getopts::Options;
+
+use flv_toolbox_rs::lib::FLVTagRead;
+use flv_toolbox_rs::captions::{CaptionExtractor, CaptionCue, write_srt, write_webvtt};
+
+enum Channel {
+    Cea608(u8),
+    Cea708(u8),
+}
+
+fn parse_channel(s: &str) -> Option<Channel> {
+    match s {
+        "cc1" => Some(Channel::Cea608(1)),
+        "cc2" => Some(Channel::Cea608(2)),
+        s if s.starts_with("s") => match s[1..].parse::<u8>() {
+            Ok(n) if n >= 1 && n <= 63 => Some(Channel::Cea708(n)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optflagopt("o", "output", "output .srt/.vtt file", "OUTPUT");
+    opts.optflagopt("f", "format", "srt or vtt, default is the output extension, or srt", "FORMAT");
+    opts.optflagopt("c", "channel", "cc1, cc2 for CEA-608, s1 to s63 for CEA-708 services, default is cc1", "CHANNEL");
+    opts.optflag("h", "help", "print this help menu");
+
+    let usage_str = {
+        let brief = format!("Usage: {} FILE [options]", program);
+        format!("{}", opts.usage(&brief))
+    };
+
+    let exit_with_usage = || {
+        eprintln!("{}", usage_str);
+        std::process::exit(-1);
+    };
+
+    let matches: getopts::Matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("{}", f.to_string());
+            return exit_with_usage();
+        }
+    };
+
+    if matches.opt_present("h") {
+        return exit_with_usage();
+    }
+
+    let input: String = if !matches.free.is_empty() {
+        matches.free[0].clone()
+    } else {
+        eprintln!("no input file.");
+        return exit_with_usage();
+    };
+
+    let input_path: &Path = Path::new(&input);
+    if !input_path.exists() {
+        eprintln!("input file does not exist.");
+        return exit_with_usage();
+    }
+
+    let channel = match matches.opt_default("c", "") {
+        Some(ref c) if !c.is_empty() => match parse_channel(c) {
+            Some(channel) => channel,
+            None => {
+                eprintln!("bad channel {}.", c);
+                return exit_with_usage();
+            }
+        },
+        _ => Channel::Cea608(1),
+    };
+
+    let output = matches.opt_default("o", "").and_then(|o| if o.is_empty() { None } else { Some(o) });
+    let format = match matches.opt_default("f", "") {
+        Some(ref f) if !f.is_empty() => f.clone(),
+        _ => match output {
+            Some(ref o) if o.ends_with(".vtt") => "vtt".to_string(),
+            _ => "srt".to_string(),
+        },
+    };
+    if format != "srt" && format != "vtt" {
+        eprintln!("bad format {}.", format);
+        return exit_with_usage();
+    }
+    let output = match output {
+        Some(o) => o,
+        None => {
+            let output = input_path.with_extension(&format);
+            eprintln!("no output file, use {}", output.to_str().unwrap());
+            output.to_string_lossy().to_string()
+        }
+    };
+
+    let cues = match extract(input_path, &channel) {
+        Ok(cues) => cues,
+        Err(e) => {
+            eprintln!("extract err: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let r = File::create(&output).map_err(|e| format!("cannot open output file: {}", e)).and_then(|file| {
+        let mut w = BufWriter::new(file);
+        let r = if format == "vtt" { write_webvtt(&cues, &mut w) } else { write_srt(&cues, &mut w) };
+        r.and_then(|_| w.flush()).map_err(|e| format!("write err: {}", e))
+    });
+    match r {
+        Ok(_) => eprintln!("{} caption(s) written.", cues.len()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+fn extract(flv_path: &Path, channel: &Channel) -> Result<Vec<CaptionCue>, String> {
+    let mut file = File::open(flv_path).map_err(|e| format!("cannot open input file: {}", e))?;
+    let mut parser = FLVTagRead::try_new(&mut file).map_err(|e| format!("read flv header err: {}", e))?;
+    let mut extractor = CaptionExtractor::new();
+    loop {
+        let position = parser.get_position();
+        match parser.try_next().map_err(|e| format!("read tag err at {}: {}", position, e))? {
+            Some(tag) => {
+                if let Err(e) = extractor.push_tag(&tag) {
+                    eprintln!("warning: tag at {} ({}ms) skipped: {}", position, tag.get_timestamp(), e);
+                }
+            }
+            None => break,
+        }
+        for (timestamp, e) in extractor.take_warnings() {
+            eprintln!("warning: bad SEI in the tag at {}ms skipped: {}", timestamp, e);
+        }
+    }
+    Ok(match *channel {
+        Channel::Cea608(n) => extractor.decode_cea608(n),
+        Channel::Cea708(n) => extractor.decode_cea708(n),
+    })
+}
@@ -1,42 +1,36 @@
 extern crate rustc_serialize;
 extern crate getopts;
-extern crate byteorder;
 extern crate xml;
 extern crate flv_toolbox_rs;
 
 use std::io::Write;
-use flv_toolbox_rs::lib::{FLVTag, write_flv_config};
+use flv_toolbox_rs::lib::{FLVTagReverseRead, write_flv_config};
 
 use getopts::Options;
 
-fn flv_config(flvs: &Vec<String>, config_path: &String, url_prefix: &String, json: bool) {
+fn flv_config(flvs: &Vec<String>, config_path: &String, url_prefix: &String, json: bool) -> Result<(), String> {
     use std::fs::File;
-    use std::io::{Seek, SeekFrom};
-    use self::byteorder::{BigEndian, ReadBytesExt};
 
     let mut info_vec = Vec::<(u64, u64)>::new();
     let mut timelength = 0;
     for path in flvs.iter() {
-        let mut file = File::open(path).unwrap_or_else(|e| {
-            panic!(format!("try to open file {}, but {}", path, e))
-        });
-        file.seek(SeekFrom::End(-4)).expect("seek last tag size error");
-        let last_tag_size = file.read_u32::<BigEndian>().expect("read last u32 error");
-        let size = file.seek(SeekFrom::Current(0)).expect("get seek pos error");
-        file.seek(SeekFrom::End(-(last_tag_size as i64) - 4)).expect("seek last tag error");
-        let tag = FLVTag::read(&mut file).expect("last tag read error");
+        let mut file = File::open(path).map_err(|e| format!("try to open file {}, but {}", path, e))?;
+        let size = file.metadata().map_err(|e| format!("{}: get file size error: {}", path, e))?.len();
+        let tag = FLVTagReverseRead::try_new(&mut file)
+            .and_then(|mut parser| parser.try_next())
+            .map_err(|e| format!("{}: last tag read error: {}", path, e))?
+            .ok_or(format!("{}: last tag read error: no tag", path))?;
         timelength += tag.get_timestamp();
         info_vec.push((tag.get_timestamp(), size));
     }
 
-    let mut file = File::create(config_path).unwrap_or_else(|e| {
-        panic!(format!("try to create config file {}, but {}", config_path, e))
-    });
+    let mut file = File::create(config_path).map_err(|e| format!("try to create config file {}, but {}", config_path, e))?;
     if json {
         write_flv_config_json(&mut file, &info_vec, flvs, timelength, url_prefix);
     } else {
         write_flv_config(&mut file, &info_vec, flvs, timelength, url_prefix);
     }
+    Ok(())
 }
 
 fn print_usage(program: &str, opts: Options) {
@@ -83,7 +77,10 @@ fn main() {
             return;
         }
     };
-    flv_config(&matches.free, &config, &url_prefix, json);
+    if let Err(e) = flv_config(&matches.free, &config, &url_prefix, json) {
+        eprintln!("{}", e);
+        std::process::exit(-1);
+    }
 }
 
 pub fn write_flv_config_json<W: Write>(w: &mut W, info_vec: &Vec<(u64, u64)>, flvs: &Vec<String>, timelength: u64, url_prefix: &String) {
@@ -11,34 +11,67 @@ use getopts::Options;
 
 use flv_toolbox_rs::lib::*;
 use flv_toolbox_rs::crc32::Crc32;
+use flv_toolbox_rs::enhanced::FourCC;
+use flv_toolbox_rs::codec_config::{Av1C, VpcC, OpusHead, FlacConfig};
+use flv_toolbox_rs::timing::TimingAnalyzer;
 
-fn print_metatag(json: &Json) -> Result<(), Option<String>> {
-    let event_name = json.as_array().ok_or(Some("meta is not array".to_string()))?[0].as_string().ok_or(Some("arr[0] is not string.".to_string()))?;
-    println!("metadata: {}", event_name);
-
-    let obj = &json.as_array().ok_or(Some("arr[1] is not array".to_string()))?[1];
-    println!("{}", rustc_serialize::json::as_pretty_json(&obj));
+fn print_script_tag(tag: &FLVTag) -> Result<(), Option<String>> {
+    let script = tag.try_get_script_data().map_err(|e| Some(format!("{}", e)))?;
+    if script.name != script.event_name() {
+        println!("script data: {} {}", script.name, script.event_name());
+    } else {
+        println!("script data: {}", script.name);
+    }
+    for value in script.args().iter() {
+        println!("{}", rustc_serialize::json::as_pretty_json(&value.to_json()));
+    }
+    if !script.is_metadata() {
+        return Ok(());
+    }
 
-    let times = obj.find_path(&["keyframes", "times"]).ok_or(Some("no keyframes.times in meta".to_string()))?.as_array().ok_or(Some("keyframes.times is not an array".to_string()))?;
-    let times: Vec<f64> = times.iter().map(|val: &Json| {
-        val.as_f64().unwrap()
-    }).collect();
-    let filepositions = obj.find_path(&["keyframes", "filepositions"]).ok_or(Some("no keyframes.filepositions in meta".to_string()))?.as_array().ok_or(Some("keyframes.filepositions is not an array".to_string()))?;
-    let filepositions: Vec<u64> = filepositions.iter().map(|val: &Json| {
-        val.as_f64().unwrap() as u64
-    }).collect();
+    let meta = tag.try_get_metadata().map_err(|e| Some(format!("{}", e)))?.ok_or(Some("onMetaData value is not an object".to_string()))?;
+    let keyframes = meta.keyframes.ok_or(Some("no keyframes.times and keyframes.filepositions number arrays in meta".to_string()))?;
 
-    for (i, (t, p)) in (0u32..).zip(times.iter().zip(filepositions.iter())) {
+    for (i, (t, p)) in (0u32..).zip(keyframes.times.iter().zip(keyframes.filepositions.iter())) {
         println!("{:3} {} {:8}", i, format_seconds_ms((t * 1000f64) as u64), p);
     }
     Ok(())
 }
 
+/// NAL units of AVC and HEVC, the FourCC and track count of the other extended header codecs
+fn frame_info(tag: &FLVTag, nal_length_size: usize) -> String {
+    if tag.is_avc_or_hevc() {
+        return format!("{}", tag.get_nal_uints_info_with_length_size(nal_length_size));
+    }
+    match tag.get_ex_video_packet() {
+        Ok(ref packet) if tag.is_ex_video() && packet.tracks.len() > 0 => format!("[{} x{}]", packet.tracks[0].fourcc, packet.tracks.len()),
+        _ => String::new(),
+    }
+}
+
+fn print_ex_sequence_start(fourcc: FourCC, data: &[u8]) {
+    let info = match fourcc {
+        FourCC::AV01 => Av1C::parse(data).map(|c| format!("av1 {} level idx {} {} bit", c.get_profile_name(), c.seq_level_idx_0, c.get_bit_depth())),
+        FourCC::VP09 => VpcC::parse(data).map(|c| format!("vp9 profile {} level {:.1} {} bit", c.profile, c.get_level(), c.bit_depth)),
+        FourCC::OPUS => OpusHead::parse(data).map(|c| format!("opus {} channels {} Hz", c.channel_count, c.input_sample_rate)),
+        FourCC::FLAC => FlacConfig::parse(data).map(|c| match c.get_stream_info() {
+            Some(info) => format!("flac {} channels {} Hz {} bit", info.channels, info.sample_rate, info.bits_per_sample),
+            None => "flac without streaminfo".to_string(),
+        }),
+        _ => Ok(format!("{} sequence start, {} bytes", fourcc, data.len())),
+    };
+    match info {
+        Ok(info) => println!("{}", info),
+        Err(e) => println!("{}", format!("read {} config err: {}", fourcc, e).on_red()),
+    }
+}
+
 fn flv_info(path: &String, show_meta: bool, all_frame: bool, video_frame: bool, audio_frame: bool) {
     use std::fs::File;
     use std::path::Path;
     use std::fs;
     use std::io::{ Read, self };
+    use std::collections::BTreeMap;
     
     let mut source: Box<Read>;
     let mut parser: FLVTagRead<Box<Read>>;
@@ -46,7 +79,13 @@ fn flv_info(path: &String, show_meta: bool, all_frame: bool, video_frame: bool,
     if path == "-" { // stdin
         println!("show info for stdin");
         source = Box::new(io::stdin()) as Box<Read>;
-        parser = FLVTagRead::new(&mut source);
+        parser = match FLVTagRead::try_new(&mut source) {
+            Ok(p) => p.with_prev_tag_size_policy(PrevTagSizePolicy::Warn),
+            Err(e) => {
+                println!("read flv header err: {}", e);
+                return;
+            }
+        };
     } else {
         let path = Path::new(path);
         if fs::metadata(path).is_err() {
@@ -60,7 +99,13 @@ fn flv_info(path: &String, show_meta: bool, all_frame: bool, video_frame: bool,
         let file_size = file_meta.len();
         println!("file size: {}", file_size);
         source = Box::new(file) as Box<Read>;
-        parser = FLVTagRead::new(&mut source);//header has read
+        parser = match FLVTagRead::try_new(&mut source) {//header has read
+            Ok(p) => p.with_prev_tag_size_policy(PrevTagSizePolicy::Warn),
+            Err(e) => {
+                println!("read flv header err: {}", e);
+                return;
+            }
+        };
     }
 
     println!("\r\ntags: kf: key_frame cd: codec_id pt: packet_type", );
@@ -72,13 +117,21 @@ fn flv_info(path: &String, show_meta: bool, all_frame: bool, video_frame: bool,
     let mut last_v_tag: Option<FLVTag> = None;
     let mut last_a_tag: Option<FLVTag> = None;
     let mut asc: Option<AudioSpecificConfig> = None;
+    let mut nal_length_size: usize = 4;
+    let mut unknown_tags: BTreeMap<u8, u64> = BTreeMap::new();
     loop {
         let position = parser.get_position();
-        let tag = parser.next();
-        if tag.is_none() {
-            break;
+        let tag = match parser.try_next() {
+            Ok(Some(tag)) => tag,
+            Ok(None) => break,
+            Err(e) => {
+                println!("read tag err at {}: {}", position, e);
+                break;
+            }
+        };
+        for (p, w) in parser.take_warnings() {
+            println!("{}", format!("warning at {}: {}", p, w).on_red());
         }
-        let tag = tag.unwrap();
         match tag.get_tag_type() {
             FLVTagType::TAG_TYPE_VIDEO => {
                 let dts_delta: i64 = if last_v_tag.is_some() {
@@ -86,18 +139,51 @@ fn flv_info(path: &String, show_meta: bool, all_frame: bool, video_frame: bool,
                 } else {
                     0
                 };
-                if tag.get_frame_type() == 1 && video_frame{// FRAME_TYPE_KEYFRAME
-                    if tag.get_avc_packet_type() == 0 { // AVC_PACKET_TYPE_SEQUENCE_HEADER
-                        println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6}"     , i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type() as usize, tag.get_frame_type(), tag.get_codec_id(), tag.get_avc_packet_type(), 0, 0, 0, dts_delta).on_red());
-                        // println!("{:?}", tag.get_avcc());
+                if tag.is_video_sequence_header() && tag.is_avc_or_hevc() {
+                    if let Ok(size) = tag.get_nal_length_size() {
+                        nal_length_size = size;
+                    }
+                }
+                // AV1, VP9 and the legacy codecs have no composition time
+                let cts: i32 = if tag.is_avc_or_hevc() { tag.get_avc_composition_time_offset() } else { 0 };
+                if tag.is_legacy_video() {
+                    if video_frame && (tag.get_frame_type() == 1 || all_frame) {
+                        let info = match tag.get_legacy_video_header() {
+                            Ok(Some(ref header)) if header.is_mislabeled(tag.get_frame_type()) => format!("{}, should be kf {}", header, header.get_frame_type()),
+                            Ok(Some(header)) => format!("{}", header),
+                            Ok(None) => String::new(),
+                            Err(e) => format!("err: {}", e),
+                        };
+                        let line = format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | {}", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type().as_u8(), tag.get_frame_type(), tag.get_codec_id(), "", "", tag.get_timestamp(), tag.get_timestamp(), dts_delta, info);
+                        println!("{}", if tag.get_frame_type() == 1 { line.on_blue() } else { line.on_magenta() });
+                        i += 1;
+                    }
+                } else if tag.get_frame_type() == 1 && video_frame{// FRAME_TYPE_KEYFRAME
+                    if tag.is_video_sequence_header() {
+                        println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6}"     , i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type().as_u8(), tag.get_frame_type(), tag.get_codec_id(), tag.get_avc_packet_type(), 0, 0, 0, dts_delta).on_red());
+                        if tag.get_codec_id() == CODEC_ID_AVC {
+                            match tag.try_get_avcc().and_then(|avcc| avcc.parse_sps().map(|sps| (avcc, sps))) {
+                                Ok((avcc, sps)) => println!("avc {} level {:.1} {}x{} {} bit{}, {} byte nal length, sps {} pps {}", sps.get_profile_name(), sps.level_idc as f64 / 10.0, sps.get_width(), sps.get_height(), sps.bit_depth_luma,
+                                    sps.get_frame_rate().map_or(String::new(), |fps| format!(" {:.3} fps", fps)), avcc.get_nal_length_size(), avcc.sps_array.len(), avcc.pps_array.len()),
+                                Err(e) => println!("{}", format!("read sps err: {}", e).on_red()),
+                            }
+                        } else if tag.get_codec_id() == CODEC_ID_HEVC {
+                            match tag.get_hvcc() {
+                                Ok(hvcc) => println!("hevc {} tier {} level {:.1} {} bit, {} byte nal length, vps {} sps {} pps {}", hvcc.get_profile_name(), hvcc.get_tier_name(), hvcc.get_level(), hvcc.bit_depth_luma,
+                                    hvcc.length_size_minus_one + 1, hvcc.get_vps().len(), hvcc.get_sps().len(), hvcc.get_pps().len()),
+                                Err(e) => println!("{}", format!("read hvcc err: {}", e).on_red()),
+                            }
+                        } else if let Some(fourcc) = tag.get_video_fourcc() {
+                            print_ex_sequence_start(fourcc, tag.get_avcc_data());
+                        }
                         i += 1;
                     } else { // AVC_PACKET_TYPE_NALU
-                        println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | {}", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type() as usize, tag.get_frame_type(), tag.get_codec_id(), tag.get_avc_packet_type(), tag.get_avc_composition_time_offset(), tag.get_timestamp(), (tag.get_timestamp() as i64) + (tag.get_avc_composition_time_offset() as i64), dts_delta, tag.get_nal_uints_info()).on_blue());
+                        println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | {}", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type().as_u8(), tag.get_frame_type(), tag.get_codec_id(), tag.get_avc_packet_type(), cts, tag.get_timestamp(), (tag.get_timestamp() as i64) + (cts as i64), dts_delta, frame_info(&tag, nal_length_size)).on_blue());
                         // println!("{:?}", tag.get_nal_units());
                         i += 1;
                     }
                 } else if all_frame && video_frame {
-                    println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | {}", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type() as usize, tag.get_frame_type(), tag.get_codec_id(), tag.get_avc_packet_type(), tag.get_avc_composition_time_offset(), tag.get_timestamp(), (tag.get_timestamp() as i64) + (tag.get_avc_composition_time_offset() as i64), dts_delta, tag.get_nal_uints_info()).on_magenta());
+                    println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | {}", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type().as_u8(), tag.get_frame_type(), tag.get_codec_id(), tag.get_avc_packet_type(), cts, tag.get_timestamp(), (tag.get_timestamp() as i64) + (cts as i64), dts_delta, frame_info(&tag, nal_length_size)).on_magenta());
                     // println!("{:?}", tag.get_nal_units());
                     i += 1;
                 }
@@ -109,15 +195,34 @@ fn flv_info(path: &String, show_meta: bool, all_frame: bool, video_frame: bool,
                 } else {
                     0
                 };
-                if tag.is_acc_sequence_header() && audio_frame {
+                if tag.is_ex_audio() {
+                    let packet = tag.get_ex_audio_packet();
+                    let sequence_start = packet.as_ref().map_or(false, |p| p.packet_type == 0); // AUDIO_PACKET_TYPE_SEQUENCE_START
+                    if audio_frame && (sequence_start || all_frame) {
+                        let info = match packet {
+                            Ok(ref p) if p.tracks.len() > 0 => format!("[{} x{} {}]", p.tracks[0].fourcc, p.tracks.len(), p.packet_type),
+                            Ok(_) => String::new(),
+                            Err(ref e) => format!("err: {}", e),
+                        };
+                        let line = format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | {}", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type().as_u8(), "", tag.get_sound_format(), "", "", tag.get_timestamp(), "", dts_delta, info);
+                        println!("{}", if sequence_start { line.on_cyan() } else { line.on_yellow() });
+                        if let (true, Ok(ref p)) = (sequence_start, packet.as_ref()) {
+                            for track in p.tracks.iter() {
+                                print_ex_sequence_start(track.fourcc, track.data);
+                            }
+                        }
+                        i += 1;
+                    }
+                }
+                else if tag.is_acc_sequence_header() && audio_frame {
                     asc = Some(tag.get_sound_audio_specific_config());
-                    println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | [{:>5} {:>5} {}]", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type() as usize, "", tag.get_sound_format(), tag.get_sound_channels(), "", tag.get_timestamp(), "", dts_delta, tag.get_sound_frame_duration(asc.as_ref().unwrap()), asc.as_ref().unwrap().get_sample_rate(), tag.get_sound_size()).on_cyan());
+                    println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | [{:>5} {:>5} {}]", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type().as_u8(), "", tag.get_sound_format(), tag.get_sound_channels(), "", tag.get_timestamp(), "", dts_delta, tag.get_sound_frame_duration(asc.as_ref().unwrap()), asc.as_ref().unwrap().get_sample_rate(), tag.get_sound_size()).on_cyan());
                     // println!("{:?}", asc.as_ref().unwrap());
                     // println!("{:?}", FLVTag::get_sound_adts_header_data(asc.as_ref().unwrap(), 9 + 7));
                     i += 1;
                 }
                 else if all_frame && audio_frame {
-                    println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | [{:>5} {:>5} {}]", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type() as usize, "", tag.get_sound_format(), tag.get_sound_channels(), "", tag.get_timestamp(), "", dts_delta, tag.get_sound_frame_duration(asc.as_ref().unwrap()), asc.as_ref().unwrap().get_sample_rate(), tag.get_sound_size()).on_yellow());
+                    println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | {:>4} | {:>6} | {:>6} | {:>6} | [{:>5} {:>5} {}]", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type().as_u8(), "", tag.get_sound_format(), tag.get_sound_channels(), "", tag.get_timestamp(), "", dts_delta, tag.get_sound_frame_duration(asc.as_ref().unwrap()), asc.as_ref().unwrap().get_sample_rate(), tag.get_sound_size()).on_yellow());
                     // println!("{:?}", FLVTag::get_sound_adts_header_data(asc.as_ref().unwrap(), tag.get_sound_data_size() + 7));
                     // println!("{:?}", tag.get_sound_data());
                     i += 1;
@@ -125,19 +230,27 @@ fn flv_info(path: &String, show_meta: bool, all_frame: bool, video_frame: bool,
                 last_a_tag = Some(tag);
             },
             FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT => {
-                println!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2}", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type() as usize, "", "", "");
+                println!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2}", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), tag.get_tag_type().as_u8(), "", "", "");
                 if show_meta {
-                    match print_metatag(&Json::Array(tag.get_objects())) {
+                    match print_script_tag(&tag) {
                         Ok(_) => {}
                         Err(s) => {
-                            println!("print meta err: {:?}", s);
+                            println!("print script data err: {:?}", s);
                         }
                     }
                 }
                 i += 1;
             }
+            FLVTagType::Unknown(t) => {
+                println!("{}", format!("{:>6} | {:>10} | {:>10} | {:>6} | {:>4} | {:>2} | {:>2} | {:>2} | unknown tag, filter: {}, reserved: {}", i, format_seconds_ms(tag.get_timestamp()), position, tag.get_tag_size(), t, "", "", "", tag.get_filter(), tag.get_reserved()).on_white());
+                *unknown_tags.entry(t).or_insert(0) += 1;
+                i += 1;
+            }
         };
     }
+    for (t, count) in unknown_tags.iter() {
+        println!("unknown tag type {}: {} tag(s)", t, count);
+    }
 }
 
 fn print_usage(program: &str, opts: Options) {
@@ -150,12 +263,13 @@ fn main() {
     let program = args[0].clone();
 
     let mut opts = Options::new();
-    opts.optflag("m", "meta", "show metadata");
+    opts.optflag("m", "meta", "show metadata and the other script data tags");
     opts.optflag("a", "all", "print all frames");
     opts.optflag("v", "video", "print video frames");
     opts.optflag("d", "audio", "print audio frames");
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("c", "crc32", "calculate crc32 of tags");
+    opts.optflag("t", "timing", "analyze cts, presentation order and GOP structure of the video");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -180,6 +294,8 @@ fn main() {
     let audio_frame = matches.opt_present("d");
     if crc32_file {
         flv_crc32(&input);
+    } else if matches.opt_present("t") {
+        flv_timing(&input);
     } else {
         flv_info(&input, show_meta, all_frame, video_frame, audio_frame);
     }
@@ -190,7 +306,13 @@ fn flv_crc32(path: &String) {
     use std::io::Write;
 
     let mut file = File::open(&path).unwrap();
-    let mut parser = FLVTagRead::new(&mut file);
+    let mut parser = match FLVTagRead::try_new(&mut file) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("read flv header err: {}", e);
+            return;
+        }
+    };
 
     let mut i = 0;
     let mut key_time: f64 = 0f64;
@@ -199,12 +321,15 @@ fn flv_crc32(path: &String) {
     let mut ret: Vec<Json> = Vec::new();
     loop {
         let position = parser.get_position();
-        let tag = parser.next();
-        if tag.is_none() {
-            break;
-        }
+        let tag = match parser.try_next() {
+            Ok(Some(tag)) => tag,
+            Ok(None) => break,
+            Err(e) => {
+                println!("read tag err at {}: {}", position, e);
+                break;
+            }
+        };
 
-        let tag = tag.unwrap();
         let mut bytes: Vec<u8> = Vec::with_capacity(tag.get_tag_size() as usize);
         tag.write(&mut bytes);
         let mut hash = Crc32::new();
@@ -229,6 +354,55 @@ fn flv_crc32(path: &String) {
     write!(output_file, "{}", rustc_serialize::json::as_pretty_json(&Json::Array(ret)));
 }
 
+fn flv_timing(path: &String) {
+    use std::fs::File;
+
+    let mut file = File::open(&path).unwrap();
+    let mut parser = match FLVTagRead::try_new(&mut file) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("read flv header err: {}", e);
+            return;
+        }
+    };
+
+    let mut analyzer = TimingAnalyzer::new();
+    loop {
+        let position = parser.get_position();
+        let tag = match parser.try_next() {
+            Ok(Some(tag)) => tag,
+            Ok(None) => break,
+            Err(e) => {
+                println!("read tag err at {}: {}", position, e);
+                break;
+            }
+        };
+        if let Err(e) = analyzer.push_tag(&tag) {
+            println!("{}", format!("tag at {}: {}", position, e).on_red());
+        }
+    }
+    let report = analyzer.finish();
+
+    println!("{:>6} | {:>10} | {:>6} | {:>8} | {:>6} | {:>5} | {}", "gop", "time", "frames", "duration", "open", "depth", "pattern");
+    for (i, gop) in report.gops.iter().enumerate() {
+        println!("{:>6} | {:>10} | {:>6} | {:>8} | {:>6} | {:>5} | {}", i, format_seconds_ms(gop.dts.max(0) as u64), gop.frame_count, gop.duration,
+            if gop.open { "open" } else { "closed" }, gop.max_reorder_depth, gop.pattern);
+    }
+    for issue in report.issues.iter() {
+        println!("{}", format!("{}", issue).on_red());
+    }
+    let lengths: Vec<usize> = report.gops.iter().map(|gop| gop.frame_count).collect();
+    if lengths.is_empty() {
+        println!("no avc/hevc frames.");
+        return;
+    }
+    println!("{} frame(s), {} gop(s) of {} to {} frames, {} open", report.frames.len(), lengths.len(), lengths.iter().min().unwrap(), lengths.iter().max().unwrap(),
+        report.gops.iter().filter(|gop| gop.open).count());
+    println!("max reorder depth {}, sps max_num_reorder_frames {}", report.max_reorder_depth,
+        report.max_num_reorder_frames.map_or("unknown".to_string(), |n| n.to_string()));
+    println!("{} issue(s)", report.issues.len());
+}
+
 fn output_info(key_pos: u64, key_time: f64, crc_list: &mut Vec<u32>) -> Json {
     use std::collections::BTreeMap;
     let mut arr: Vec<Json> = 
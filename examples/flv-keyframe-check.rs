@@ -0,0 +1,169 @@
+extern crate getopts;
+extern crate flv_toolbox_rs;
+
+use std::path::Path;
+use std::fs::{File, OpenOptions};
+
+use getopts::Options;
+
+use flv_toolbox_rs::lib::{FLVTag, FLVTagRead, FLVTagWrite, FLVTagType};
+use flv_toolbox_rs::metadata::FlvKeyframes;
+use flv_toolbox_rs::frame_check::FrameChecker;
+use flv_toolbox_rs::legacy_video::LegacyVideoHeader;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optflagopt("o", "output", "output flv file with the frame types fixed", "OUTPUT");
+    opts.optflag("h", "help", "print this help menu");
+
+    let usage_str = {
+        let brief = format!("Usage: {} FILE [options]", program);
+        format!("{}", opts.usage(&brief))
+    };
+
+    let exit_with_usage = || {
+        eprintln!("{}", usage_str);
+        std::process::exit(-1);
+    };
+
+    let matches: getopts::Matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("{}", f.to_string());
+            return exit_with_usage();
+        }
+    };
+
+    if matches.opt_present("h") {
+        return exit_with_usage();
+    }
+
+    let input: String = if !matches.free.is_empty() {
+        matches.free[0].clone()
+    } else {
+        eprintln!("no input file.");
+        return exit_with_usage();
+    };
+
+    let input_path: &Path = Path::new(&input);
+    if !input_path.exists() {
+        eprintln!("input file does not exist.");
+        return exit_with_usage();
+    }
+
+    let output = matches.opt_default("o", "").and_then(|o| if o.is_empty() { None } else { Some(o) });
+
+    match check(input_path, output.as_ref().map(|o| o.as_str())) {
+        Ok((frames, mislabeled)) => {
+            println!("{} frame(s) checked, {} mislabeled{}.", frames, mislabeled, if output.is_some() { " and fixed" } else { "" });
+        }
+        Err(e) => {
+            eprintln!("check err: {}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+/// returns how many frames were checked and how many were mislabeled
+fn check(flv_path: &Path, output_path: Option<&str>) -> Result<(u64, u64), String> {
+    let mut file = File::open(flv_path).map_err(|e| format!("cannot open input file: {}", e))?;
+    let mut parser = FLVTagRead::try_new(&mut file).map_err(|e| format!("read flv header err: {}", e))?;
+    let mut tag_write: Option<FLVTagWrite<File>> = match output_path {
+        Some(output) => {
+            // read back too, for moving tags when the metadata grows
+            let output_file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(output).map_err(|e| format!("creat output file err: {}", e))?;
+            let mut tag_write = FLVTagWrite::new(output_file);
+            tag_write.write_header(&parser.header);
+            Some(tag_write)
+        }
+        None => None,
+    };
+
+    let mut checker = FrameChecker::new();
+    let mut metatag: Option<FLVTag> = None;
+    let mut keyframes = FlvKeyframes::default();
+    // the first Sorenson H.263, Screen Video or VP6 picture with a size
+    let mut video_header: Option<LegacyVideoHeader> = None;
+    let (mut frames, mut mislabeled) = (0, 0);
+    let mut i = 0;
+    loop {
+        let position = parser.get_position();
+        let mut tag = match parser.try_next().map_err(|e| format!("read tag err at {}: {}", position, e))? {
+            Some(tag) => tag,
+            None => break,
+        };
+        match tag.get_tag_type() {
+            FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT if i == 0 && tag.try_get_metadata().map(|m| m.is_some()).unwrap_or(false) => {
+                if let Some(ref mut tag_write) = tag_write {
+                    tag_write.write_meta_tag(&tag).map_err(|e| format!("write metatag err: {}", e))?;
+                }
+                metatag = Some(tag);
+                i += 1;
+                continue;
+            }
+            FLVTagType::TAG_TYPE_VIDEO if tag.is_legacy_video() => match tag.get_legacy_video_header() {
+                Ok(Some(header)) => {
+                    frames += 1;
+                    if header.is_mislabeled(tag.get_frame_type()) {
+                        mislabeled += 1;
+                        println!("{:>6} | {:>10} | {:>10} | kf {} | {}", i, tag.get_timestamp(), position, tag.get_frame_type(), header);
+                        tag.set_frame_type(header.get_frame_type());
+                    }
+                    if video_header.is_none() && header.get_width().is_some() {
+                        video_header = Some(header);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("tag {} at {}: {}", i, position, e),
+            },
+            FLVTagType::TAG_TYPE_VIDEO => match checker.push_tag(&tag) {
+                Ok(Some(info)) => {
+                    frames += 1;
+                    if info.is_mislabeled(tag.get_frame_type()) {
+                        mislabeled += 1;
+                        println!("{:>6} | {:>10} | {:>10} | kf {} | {}", i, tag.get_timestamp(), position, tag.get_frame_type(), info);
+                        tag.set_frame_type(info.get_frame_type());
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("tag {} at {}: {}", i, position, e),
+            },
+            _ => {}
+        }
+        if let Some(ref mut tag_write) = tag_write {
+            if tag.get_tag_type() == FLVTagType::TAG_TYPE_VIDEO && tag.get_frame_type() == 1 && !tag.is_video_sequence_header() {
+                keyframes.times.push(tag.get_timestamp() as f64 / 1000.0);
+                keyframes.filepositions.push(tag_write.get_position());
+            }
+            tag_write.write_tag(&tag);
+        }
+        i += 1;
+    }
+
+    // the keyframes index follows the fixed frame types, legacy codecs also get the missing size
+    if let (Some(tag_write), Some(metatag)) = (tag_write.as_mut(), metatag.as_ref()) {
+        let mut meta = metatag.try_get_metadata().map_err(|e| format!("read metatag err: {}", e))?.unwrap();
+        let size = (meta.width, meta.height);
+        if let Some(ref header) = video_header {
+            meta.fill_from_video_header(header);
+        }
+        if (meta.keyframes.is_some() && mislabeled > 0) || size != (meta.width, meta.height) {
+            tag_write.write_meta_tag_relocating(|shift| {
+                // the tags move when the metadata grows
+                if meta.keyframes.is_some() {
+                    meta.keyframes = Some(FlvKeyframes {
+                        times: keyframes.times.clone(),
+                        filepositions: keyframes.filepositions.iter().map(|&p| p + shift).collect(),
+                    });
+                }
+                let mut tag = metatag.clone();
+                tag.set_metadata(&meta);
+                tag
+            }).map_err(|e| format!("write metatag err: {}", e))?;
+        }
+    }
+    Ok((frames, mislabeled))
+}
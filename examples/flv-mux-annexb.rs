@@ -0,0 +1,97 @@
+extern crate getopts;
+extern crate memmap;
+extern crate flv_toolbox_rs;
+
+use std::path::Path;
+use std::fs::{File, OpenOptions};
+
+use getopts::Options;
+
+use flv_toolbox_rs::lib::FLVTagWrite;
+use flv_toolbox_rs::annexb::import_annexb;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optflagopt("o", "output", "output flv file", "OUTPUT");
+    opts.optflagopt("r", "framerate", "frames per second, default is the one in the sps", "FPS");
+    opts.optflag("h", "help", "print this help menu");
+
+    let usage_str = {
+        let brief = format!("Usage: {} FILE [options]", program);
+        format!("{}", opts.usage(&brief))
+    };
+
+    let exit_with_usage = || {
+        eprintln!("{}", usage_str);
+        std::process::exit(-1);
+    };
+
+    let matches: getopts::Matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("{}", f.to_string());
+            return exit_with_usage();
+        }
+    };
+
+    if matches.opt_present("h") {
+        return exit_with_usage();
+    }
+
+    let input: String = if !matches.free.is_empty() {
+        matches.free[0].clone()
+    } else {
+        eprintln!("no input file.");
+        return exit_with_usage();
+    };
+
+    let input_path: &Path = Path::new(&input);
+    if !input_path.exists() {
+        eprintln!("input file does not exist.");
+        return exit_with_usage();
+    }
+
+    let frame_rate: Option<f64> = match matches.opt_default("r", "") {
+        Some(ref r) if !r.is_empty() => match r.parse::<f64>() {
+            Ok(r) if r > 0.0 => Some(r),
+            _ => {
+                eprintln!("bad framerate {}.", r);
+                return exit_with_usage();
+            }
+        },
+        _ => None,
+    };
+
+    let output = match matches.opt_default("o", "") {
+        Some(ref c) if !c.is_empty() => c.clone(),
+        _ => {
+            let output = input_path.with_extension("flv");
+            eprintln!("no output file, use {}", output.to_str().unwrap());
+            output.to_string_lossy().to_string()
+        }
+    };
+
+    match mux(input_path, &output, frame_rate) {
+        Ok((frames, fps)) => {
+            eprintln!("{} frame(s) written at {} fps.", frames, fps);
+        }
+        Err(e) => {
+            eprintln!("mux err: {}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+fn mux(input_path: &Path, output_path: &str, frame_rate: Option<f64>) -> Result<(u64, f64), String> {
+    let file = File::open(input_path).map_err(|e| format!("cannot open input file: {}", e))?;
+    let mmap = unsafe { memmap::Mmap::map(&file) }.map_err(|e| format!("map input file err: {}", e))?;
+    // read back too, for moving tags when the metadata grows
+    let output_file: File = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(output_path).map_err(|e| format!("creat output file err: {}", e))?;
+    let mut tag_write: FLVTagWrite<File> = FLVTagWrite::new(output_file).with_meta_padding(1024);
+    let meta = import_annexb(&mmap, &mut tag_write, frame_rate).map_err(|e| format!("{}", e))?;
+    let fps = meta.framerate.unwrap_or(0.0);
+    Ok(((meta.duration.unwrap_or(0.0) * fps).round() as u64, fps))
+}
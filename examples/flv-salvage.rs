@@ -0,0 +1,120 @@
+extern crate getopts;
+extern crate flv_toolbox_rs;
+
+use std::path::Path;
+use std::fs::File;
+
+use getopts::Options;
+
+use flv_toolbox_rs::lib::{ FLVTagWrite, format_seconds_ms };
+use flv_toolbox_rs::resync::{ FLVTagResyncRead, ResyncEvent };
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optflagopt("o", "output", "output flv file", "OUTPUT");
+    opts.optflag("t", "check-only", "test, only report corrupted regions");
+    opts.optflag("h", "help", "print this help menu");
+
+    let usage_str = {
+        let brief = format!("Usage: {} FILE [options]", program);
+        format!("{}", opts.usage(&brief))
+    };
+
+    let exit_with_usage = || {
+        eprintln!("{}", usage_str);
+        std::process::exit(-1);
+    };
+
+    let matches: getopts::Matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("{}", f.to_string());
+            return exit_with_usage();
+        }
+    };
+
+    if matches.opt_present("h") {
+        return exit_with_usage();
+    }
+
+    let test = matches.opt_present("t");
+
+    let input: String = if !matches.free.is_empty() {
+        matches.free[0].clone()
+    } else {
+        eprintln!("no input file.");
+        return exit_with_usage();
+    };
+
+    let input_path: &Path = Path::new(&input);
+    if !input_path.exists() {
+        eprintln!("input file does not exist.");
+        return exit_with_usage();
+    }
+
+    let output = match matches.opt_default("o", "") {
+        Some(c) => c,
+        _ => {
+            let file = input_path.file_stem().unwrap().to_string_lossy().to_string();
+            let mut output = input_path.with_file_name(format!("{}-salvaged.flv", &file));
+            let mut i: i32 = 0;
+            while output.exists() {
+                i += 1;
+                output = input_path.with_file_name(format!("{}-salvaged({}).flv", &file, i));
+            }
+            if !test {
+                eprintln!("no output file, use {}", output.to_str().unwrap());
+            }
+            output.to_string_lossy().to_string()
+        }
+    };
+
+    match salvage(input_path, &output, test) {
+        Ok(0) => {
+            eprintln!("no corrupted region.");
+        }
+        Ok(n) => {
+            eprintln!("{} corrupted region(s) skipped.", n);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("salvage err: {}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+fn salvage(flv_path: &Path, output_path: &str, test: bool) -> Result<u64, String> {
+    let mut file = File::open(flv_path).map_err(|e| format!("cannot open input file: {}", e))?;
+    let mut parser = FLVTagResyncRead::try_new(&mut file).map_err(|e| format!("read flv header err: {}", e))?;
+
+    let mut tag_write: Option<FLVTagWrite<File>> = None;
+    if !test {
+        let ofile = File::create(output_path).map_err(|e| format!("cannot open output file: {}", e))?;
+        let mut w = FLVTagWrite::new(ofile);
+        w.write_header(&parser.header);
+        tag_write = Some(w);
+    }
+
+    let mut last_timestamp: u64 = 0;
+    let mut skipped: u64 = 0;
+    loop {
+        match parser.next_event().map_err(|e| format!("read err at {}: {}", parser.get_position(), e))? {
+            Some(ResyncEvent::Tag(_, tag)) => {
+                last_timestamp = tag.get_timestamp();
+                if let Some(w) = tag_write.as_mut() {
+                    w.write_tag(&tag);
+                }
+            }
+            Some(ResyncEvent::Skipped { start, end, error }) => {
+                eprintln!("skip {:>10} - {:>10} ({:>8} bytes) after {}: {}", start, end, end - start, format_seconds_ms(last_timestamp), error);
+                skipped += 1;
+            }
+            None => break,
+        }
+    }
+    Ok(skipped)
+}
@@ -6,10 +6,11 @@ extern crate flv_toolbox_rs;
 
 use std::fs::File;
 
-use rustc_serialize::json::{Json, ToJson};
 use getopts::Options;
 
 use flv_toolbox_rs::lib::*;
+use flv_toolbox_rs::amf0::Amf0Value;
+use flv_toolbox_rs::metadata::FlvKeyframes;
 
 const PROGRAM_SIGN: &'static str = "modified by flv-split, 2015";
 
@@ -23,35 +24,35 @@ fn delta(v1: u64, v2: u64) -> u64 {
 }
 
 //扫描关键点的视频音频位置信息
-fn flv_scan(file: &mut File, verbose: bool, min: u64, win: u64) -> Vec<(u64, u64, u64, u64)> {//video offset, next audio offset, position
-    use std::io::SeekFrom;
-    use std::io::Seek;
-
-    let header = FLVHeader::read(file);
-    let mut metatag = FLVTag::read(file).expect("read meta tag err");
-    assert_eq!(metatag.get_tag_type(), FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT);
-
-    let v = metatag.get_objects();
-    let _event_name = v[0].as_string().unwrap().to_string();
-    let filepositions = v[1].find_path(&["keyframes", "filepositions"]).unwrap().as_array().unwrap();
-    let filepositions = filepositions.iter().map(|val: &Json| {
-        val.as_f64().unwrap() as u64
-    }).collect::<Vec<u64>>();
+fn flv_scan(file: &mut File, verbose: bool, min: u64, win: u64) -> Result<Vec<(u64, u64, u64, u64)>, String> {//video offset, next audio offset, position
+    let header = FLVHeader::try_read(file).map_err(|e| format!("read flv header err: {}", e))?;
+    // the first onMetaData, other script tags like onXMPData may come before it
+    let meta = loop {
+        let tag = FLVTag::try_read(file).map_err(|e| format!("read meta tag err: {}", e))?.ok_or("read meta tag err: no onMetaData tag".to_string())?;
+        if tag.get_tag_type() != FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT {
+            continue;
+        }
+        if let Some(meta) = tag.try_get_metadata().map_err(|e| format!("read meta objects err: {}", e))? {
+            break meta;
+        }
+    };
+    let filepositions = meta.keyframes.ok_or("no keyframes in meta".to_string())?.filepositions;
 
     let mut info_vec: Vec<(u64, u64, u64)> = Vec::with_capacity(filepositions.len());
+    let mut scan = FLVTagScan::try_new(file).map_err(|e| format!("read flv header err: {}", e))?;
     for (i, pos) in filepositions.iter().enumerate() {
-        file.seek(SeekFrom::Start(*pos)).expect("seek flv file err");
-        let ktag = FLVTag::read(file).expect("read video keyframe err");
-        let t1 = ktag.get_timestamp();
+        scan.seek(*pos).map_err(|e| format!("seek flv file err: {}", e))?;
+        let ktag = scan.try_next().map_err(|e| format!("read video keyframe err at {}: {}", pos, e))?.ok_or(format!("read video keyframe err at {}: no tag", pos))?;
+        let t1 = ktag.timestamp;
         let t2 = {
-            let mut tag = FLVTag::read(file);
-            while tag.is_some() && tag.as_ref().unwrap().get_tag_type() != FLVTagType::TAG_TYPE_AUDIO {
-                tag = FLVTag::read(file);
+            let mut tag = scan.try_next().map_err(|e| format!("read tag after keyframe {} err: {}", pos, e))?;
+            while tag.is_some() && tag.as_ref().unwrap().tag_type != FLVTagType::TAG_TYPE_AUDIO {
+                tag = scan.try_next().map_err(|e| format!("read tag after keyframe {} err: {}", pos, e))?;
             }
             if tag.is_none() {
                 t1 + 100
             } else {
-                tag.as_ref().unwrap().get_timestamp()
+                tag.as_ref().unwrap().timestamp
             }
         };
         let dt = delta(t1, t2);
@@ -66,12 +67,12 @@ fn flv_scan(file: &mut File, verbose: bool, min: u64, win: u64) -> Vec<(u64, u64
         println!("{:?}", vec.iter().map(|&(t, p, n, dt)| (format_seconds_ms(t), p, n, dt)).collect::<Vec<(String, u64, u64, u64)>>());
     }
 
-    file.seek(SeekFrom::Start(0)).expect("flv seek err");
-    vec
+    scan.seek(0).map_err(|e| format!("flv seek err: {}", e))?;
+    Ok(vec)
 }
 
-fn flv_split(path: &String, min: u64, win: u64, prefix: &String, verbose: bool, config_path: &String, url_prefix: &String) {
-    use std::fs::File;
+fn flv_split(path: &String, min: u64, win: u64, prefix: &String, verbose: bool, config_path: &String, url_prefix: &String) -> Result<(), String> {
+    use std::fs::{File, OpenOptions};
     use std::fs;
     use std::path::Path;
 
@@ -87,19 +88,33 @@ fn flv_split(path: &String, min: u64, win: u64, prefix: &String, verbose: bool,
     let file_size = file_meta.len();
     println!("file size: {}", file_size);
     //split config
-    let vec = flv_scan(&mut file, verbose, min, win);
-
-    let mut parser = FLVTagRead::new(&mut file);//header has read
-    let mut metatag = parser.next().unwrap();
-    assert_eq!(metatag.get_tag_type(), FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT);
-
-    let video_metatag = parser.next().unwrap();
-    assert_eq!(video_metatag.get_tag_type(), FLVTagType::TAG_TYPE_VIDEO);
-    assert_eq!(video_metatag.get_avc_packet_type(), 0);//avc tag
+    let vec = flv_scan(&mut file, verbose, min, win)?;
 
-    let audio_metatag = parser.next().unwrap();
-    assert_eq!(audio_metatag.get_tag_type(), FLVTagType::TAG_TYPE_AUDIO);
-    assert!(audio_metatag.is_acc_sequence_header());
+    let mut parser = FLVTagRead::try_new(&mut file).map_err(|e| format!("read flv header err: {}", e))?;//header has read
+    // tags before the first keyframe: the metadata, other script data like onXMPData, and the sequence headers
+    let mut metatag: Option<FLVTag> = None;
+    let mut script_tags: Vec<FLVTag> = Vec::new();
+    let mut video_metatag: Option<FLVTag> = None;
+    let mut audio_metatag: Option<FLVTag> = None;
+    while parser.get_position() < vec[0].1 {
+        let position = parser.get_position();
+        let tag = parser.try_next().map_err(|e| format!("read tag err at {}: {}", position, e))?.ok_or("no first keyframe".to_string())?;
+        match tag.get_tag_type() {
+            FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT => {
+                if metatag.is_none() && tag.try_get_metadata().map_err(|e| format!("read meta tag err: {}", e))?.is_some() {
+                    metatag = Some(tag);
+                } else {
+                    script_tags.push(tag);
+                }
+            }
+            FLVTagType::TAG_TYPE_VIDEO if tag.is_video_sequence_header() => video_metatag = Some(tag),
+            FLVTagType::TAG_TYPE_AUDIO if tag.is_acc_sequence_header() => audio_metatag = Some(tag),
+            _ => return Err(format!("unexpected tag before the first keyframe at {}", position)),
+        }
+    }
+    let mut metatag = metatag.ok_or("no meta tag".to_string())?;
+    let video_metatag = video_metatag.ok_or("no avc sequence header".to_string())?;
+    let audio_metatag = audio_metatag.ok_or("no aac sequence header".to_string())?;
 
     //init
     assert_eq!(vec[0].1, parser.get_position());//begin first real frames
@@ -112,40 +127,48 @@ fn flv_split(path: &String, min: u64, win: u64, prefix: &String, verbose: bool,
     let mut filepositions: Option<Vec<u64>> = None;
     let mut duration_filesize: Vec<(u64, u64)> = Vec::new();
 
-    fn write_back_meta_tag(duration: u64, metatag: &mut FLVTag, times: &Vec<u64>, filepositions: &Vec<u64>, tag_write: &mut FLVTagWrite<File>) {
-        let mut metas = metatag.get_objects();
-        {
-            metas[1].as_object_mut().unwrap().insert("duration".to_string(), Json::F64(duration as f64 / 1000.0));
-            metas[1].as_object_mut().unwrap().insert("metadatacreator".to_string(), PROGRAM_SIGN.to_string().to_json());
-            let key_times = Json::Array(times.iter().map(|&t| Json::F64(t as f64 / 1000.0)).collect::<Vec<Json>>());
-            let key_positions = Json::Array(filepositions.iter().map(|&p| Json::F64(p as f64)).collect::<Vec<Json>>());
-            let keyframes = metas[1].as_object_mut().unwrap().get_mut("keyframes").unwrap().as_object_mut().unwrap();
-            keyframes.insert("times".to_string(), key_times);
-            keyframes.insert("filepositions".to_string(), key_positions);
-        }
-        metatag.set_objects(&metas);
-        tag_write.write_meta_tag(&metatag);
+    fn write_back_meta_tag(duration: u64, metatag: &mut FLVTag, times: &Vec<u64>, filepositions: &Vec<u64>, tag_write: &mut FLVTagWrite<File>) -> Result<(), String> {
+        // set_metadata edits the object in place, the other entries keep their order and bytes
+        let mut meta = metatag.try_get_metadata().map_err(|e| format!("read meta values err: {}", e))?.ok_or("first tag is not onMetaData".to_string())?;
+        meta.duration = Some(duration as f64 / 1000.0);
+        meta.extra.insert("metadatacreator", Amf0Value::String(PROGRAM_SIGN.to_string()));
+        let key_times: Vec<f64> = times.iter().map(|&t| t as f64 / 1000.0).collect();
+        // the tags are moved back when the metadata outgrows its room, filepositions move with them
+        tag_write.write_meta_tag_relocating(|shift| {
+            meta.keyframes = Some(FlvKeyframes {
+                times: key_times.clone(),
+                filepositions: filepositions.iter().map(|&p| p + shift).collect(),
+            });
+            metatag.set_metadata(&meta);
+            metatag.clone()
+        }).map_err(|e| format!("write meta tag err: {}", e))?;
+        Ok(())
     }
 
     loop {
         let position = parser.get_position();
-        let tag = parser.next();
+        let tag = parser.try_next().map_err(|e| format!("read tag err at {}: {}", position, e))?;
         if seg_index < vec.len() as i64 - 1 && vec[(seg_index + 1) as usize].1 == position {
             //fillback metatag
             if tag_write.is_some() {
-                write_back_meta_tag(tag.as_ref().unwrap().get_timestamp() - time_offset, &mut metatag, times.as_ref().unwrap(), filepositions.as_ref().unwrap(), tag_write.as_mut().unwrap());
+                write_back_meta_tag(tag.as_ref().unwrap().get_timestamp() - time_offset, &mut metatag, times.as_ref().unwrap(), filepositions.as_ref().unwrap(), tag_write.as_mut().unwrap())?;
                 duration_filesize.push((tag.as_ref().unwrap().get_timestamp() - time_offset, tag_write.as_ref().unwrap().get_position()));
             }
             seg_index += 1;
             let file_name = format!("{}{}.flv", prefix, seg_index + 1);
-            tag_write = Some(FLVTagWrite::new(File::create(file_name).unwrap()));
+            // read back too, for moving tags when the metadata grows
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&file_name).map_err(|e| format!("create {} err: {}", file_name, e))?;
+            tag_write = Some(FLVTagWrite::new(file));
             let tag_write = tag_write.as_mut().unwrap();
             let key_tag_len = (vec[seg_index as usize].2 + 1) as usize;
             times = Some(vec![0; key_tag_len]);
             filepositions = Some(vec![0; key_tag_len]);
             tag_write.write_header(&parser.header);
             //modify metatag
-            write_back_meta_tag(0, &mut metatag, times.as_ref().unwrap(), filepositions.as_ref().unwrap(), tag_write);
+            write_back_meta_tag(0, &mut metatag, times.as_ref().unwrap(), filepositions.as_ref().unwrap(), tag_write)?;
+            for tag in script_tags.iter() {
+                tag_write.write_tag(tag);
+            }
             filepositions.as_mut().unwrap()[0] = tag_write.get_position();
             tag_write.write_tag(&video_metatag);
             tag_write.write_tag(&audio_metatag);
@@ -154,7 +177,7 @@ fn flv_split(path: &String, min: u64, win: u64, prefix: &String, verbose: bool,
         }
 
         if tag.is_none() {
-            write_back_meta_tag(timestamp - time_offset, &mut metatag, times.as_ref().unwrap(), filepositions.as_ref().unwrap(), tag_write.as_mut().unwrap());
+            write_back_meta_tag(timestamp - time_offset, &mut metatag, times.as_ref().unwrap(), filepositions.as_ref().unwrap(), tag_write.as_mut().unwrap())?;
             duration_filesize.push((timestamp - time_offset, tag_write.as_ref().unwrap().get_position()));
             break;
         }
@@ -172,10 +195,9 @@ fn flv_split(path: &String, min: u64, win: u64, prefix: &String, verbose: bool,
     }
 
     //output partial config
-    let mut file = File::create(config_path).unwrap_or_else(|e| {
-        panic!(format!("try to create config file {}, but {}", config_path, e))
-    });
+    let mut file = File::create(config_path).map_err(|e| format!("try to create config file {}, but {}", config_path, e))?;
     write_flv_config(&mut file, &duration_filesize, &(0..vec.len()).map(|i| format!("{}{}.flv", prefix, i + 1)).collect(), timestamp, url_prefix);
+    Ok(())
 }
 
 fn print_usage(program: &str, opts: Options) {
@@ -242,5 +264,8 @@ fn main() {
         }
     };
     let verbose = matches.opt_present("v");
-    flv_split(&input, min, win, &prefix, verbose, &config, &url_prefix);
+    if let Err(e) = flv_split(&input, min, win, &prefix, verbose, &config, &url_prefix) {
+        eprintln!("{}", e);
+        std::process::exit(-1);
+    }
 }
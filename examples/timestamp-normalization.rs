@@ -2,18 +2,23 @@ extern crate rustc_serialize;
 extern crate getopts;
 extern crate flv_toolbox_rs;
 extern crate libc;
+extern crate memmap;
 
-use flv_toolbox_rs::lib::{ FLVTagRead, FLVHeader, FLVTag, FLVTagType, FLVTagWrite, format_seconds_ms, AudioSpecificConfig };
+use flv_toolbox_rs::lib::{ FLVSliceRead, FLVHeader, MIN_FILE_HEADER_BYTE_COUNT, FLVTag, FLVTagRef, FLVTagType, FLVTagWrite, format_seconds_ms, AudioSpecificConfig, CODEC_ID_AVC, CODEC_ID_HEVC };
 
-use rustc_serialize::json::{Json};
+use flv_toolbox_rs::amf0::Amf0Value;
+use flv_toolbox_rs::metadata::FlvKeyframes;
+use flv_toolbox_rs::h264::Sps;
+use flv_toolbox_rs::legacy_video::LegacyVideoHeader;
 use rustc_serialize::{ Encodable, Encoder };
 use getopts::Options;
 
 use std::str::FromStr;
 use std::path::Path;
-use std::fs::File;
+use std::fs::{ File, OpenOptions };
 use std::io::{ Write, Seek, SeekFrom };
 use std::collections::BTreeMap;
+use std::sync::atomic::{ AtomicBool, Ordering };
 
 const PROGRAM_SIGN: &'static str = "audio gap fixed by timestamp-normalization, 2017";
 
@@ -63,13 +68,14 @@ impl TagProfile {
         }
     }
 
-    pub fn new_meta(id: u64, timestamp_us: i64, position: u64) -> Self {
+    /// `metadata` marks the onMetaData tag, it is kept in `sequence_header`
+    pub fn new_meta(id: u64, timestamp_us: i64, position: u64, metadata: bool) -> Self {
         TagProfile {
             id,
             tag_type: FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT,
             timestamp_us,
             position,
-            sequence_header: false,
+            sequence_header: metadata,
             keyframe: false,
             decode_duration_us: 0,
             offset_us: 0,
@@ -77,12 +83,14 @@ impl TagProfile {
         }
     }
 
-    pub fn tag(&self, file: &mut File) -> FLVTag {
+    pub fn tag(&self, file: &mut File) -> Result<FLVTag, String> {
         if self.id == MAX_ID { // generate mut audio tag
-            TagProfile::new_mute_tag(self.timestamp_us, self.position as u8)
+            Ok(TagProfile::new_mute_tag(self.timestamp_us, self.position as u8))
         } else {
-            file.seek(SeekFrom::Start(self.position)).unwrap();
-            FLVTag::read(file).unwrap()
+            file.seek(SeekFrom::Start(self.position)).map_err(|e| format!("seek input file err: {}", e))?;
+            FLVTag::try_read(file)
+                .map_err(|e| format!("read tag {} at {} err: {}", self.id, self.position, e))?
+                .ok_or(format!("read tag {} at {} err: no tag", self.id, self.position))
         }
     }
 
@@ -130,10 +138,11 @@ impl TagProfile {
         // audio data
         tag_data.extend_from_slice(data);
         // prev size
-        tag_data.push(0);
-        tag_data.push(0);
-        tag_data.push(0);
-        tag_data.push(0);
+        let tag_size: usize = data_size + 11;
+        tag_data.push(((tag_size >> 24) & 0xff) as u8);
+        tag_data.push(((tag_size >> 16) & 0xff) as u8);
+        tag_data.push(((tag_size >>  8) & 0xff) as u8);
+        tag_data.push(((tag_size      ) & 0xff) as u8);
 
         let tag = FLVTag::read(&mut &*tag_data).unwrap();
         tag
@@ -142,10 +151,11 @@ impl TagProfile {
 
 fn get_info(path: &str) -> Result<(FLVInfo, u32, u8), String> {
     
-    let mut file = File::open(path).unwrap();
-    let file_info = file.metadata().unwrap();
-    let file_len = file_info.len();
-    let mut parser = FLVTagRead::new(&mut file);
+    let file = File::open(path).map_err(|e| format!("open input file err: {}", e))?;
+    // tags are borrowed from the map, no copy per tag
+    let mmap = unsafe { memmap::Mmap::map(&file) }.map_err(|e| format!("map input file err: {}", e))?;
+    let file_len = mmap.len();
+    let mut parser = FLVSliceRead::try_new(&mmap).map_err(|e| format!("read flv header err: {}", e))?;
     
     // 只有一路av流，不存在音画不同步
     if !(parser.header.hasAudioTags && parser.header.hasVideoTags) {
@@ -158,22 +168,32 @@ fn get_info(path: &str) -> Result<(FLVInfo, u32, u8), String> {
 
     // asc
     let mut asc: Option<AudioSpecificConfig> = None;
+    // from the last avc/hevc sequence header
+    let mut nal_length_size: usize = 4;
 
     loop {
         let position = parser.get_position();
         write!(std::io::stderr(), "scan progress: {: >3.0}%\r", position as f64 / file_len as f64 * 100.).unwrap();
-        let nxt: Option<FLVTag> = parser.next();
+        let nxt: Option<FLVTagRef> = parser.try_next().map_err(|e| format!("read tag err at {}: {}", position, e))?;
         if nxt.is_none() {
             break;
         }
-        let tag: FLVTag = nxt.unwrap();
+        let tag: FLVTagRef = nxt.unwrap();
         
         match tag.get_tag_type() {
             FLVTagType::TAG_TYPE_VIDEO => {
                 let timestamp = tag.get_timestamp() as i64;
-                let sequence_header = tag.get_frame_type() == 1 && tag.get_avc_packet_type() == 0;
-                let keyframe = tag.get_frame_type() == 1;
-                let cts = if sequence_header { 0 } else { tag.get_avc_composition_time_offset() as i64 };
+                let sequence_header = tag.get_frame_type() == 1 && tag.is_video_sequence_header();
+                if sequence_header && tag.is_avc_or_hevc() {
+                    match tag.get_nal_length_size() {
+                        Ok(size) => nal_length_size = size,
+                        Err(e) => eprintln!("read sequence header err at {}, but fix is proceeding: {}", position, e),
+                    }
+                }
+                // some hevc muxers mark CRA pictures as inter frames
+                let keyframe = tag.get_frame_type() == 1 || (tag.get_codec_id() == CODEC_ID_HEVC && tag.has_random_access_nal_unit(nal_length_size));
+                // only AVC/HEVC NALU packets have a composition time, VP6, AV1 and the like have none
+                let cts = if sequence_header || !tag.is_avc_or_hevc() { 0 } else { tag.get_avc_composition_time_offset() as i64 };
                 let pts = cts + timestamp;
                 info.push(TagProfile::new_video(id, timestamp * 1000, position, sequence_header, keyframe, 
                     pts));
@@ -191,7 +211,16 @@ fn get_info(path: &str) -> Result<(FLVInfo, u32, u8), String> {
                 }
             },
             FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT => {
-                info.push(TagProfile::new_meta(id, tag.get_timestamp() as i64 * 1000, position));
+                // onCuePoint and the like are written back in place, only onMetaData is rewritten
+                let metadata = match tag.try_get_script_data() {
+                    Ok(script) => script.is_metadata(),
+                    Err(_) => false,
+                };
+                info.push(TagProfile::new_meta(id, tag.get_timestamp() as i64 * 1000, position, metadata));
+            }
+            FLVTagType::Unknown(..) => {
+                // unknown tags are not written back
+                continue;
             }
         };
         id += 1;
@@ -631,7 +660,7 @@ fn fix_file(input: &str, output: &str, info: FLVInfo, update_duration: bool, inj
         if v_sh_len != 1 {
             return Err(format!("video sequence header tag count is not 1 but {}.", v_sh_len));
         }
-        let m_len = info.iter().filter(|&&TagProfile { ref tag_type, .. }| *tag_type == FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT).count();
+        let m_len = info.iter().filter(|&&TagProfile { ref tag_type, sequence_header: ref metadata, .. }| *tag_type == FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT && *metadata).count();
         if m_len > 1 {
             return Err(format!("metadata tag count is not 0 or 1 but {}.", m_len));
         }
@@ -641,44 +670,40 @@ fn fix_file(input: &str, output: &str, info: FLVInfo, update_duration: bool, inj
     // let file_info = file.metadata().unwrap();
     // let file_len = file_info.len();
     
-    let output_file: File = File::create(output).map_err(|e| format!("creat output file err: {}", e))?;
-    let mut tag_write: FLVTagWrite<File> = FLVTagWrite::new(output_file);
-
-    let header = FLVHeader::read(&mut file);
-    tag_write.write_header(&header);
+    let header = FLVHeader::try_read(&mut file).map_err(|e| format!("read flv header err: {}", e))?;
 
     // function from flv-split
-    fn write_back_meta_tag<T: Write + Seek>(duration: u64, metatag: &mut FLVTag, times: &Vec<u64>, filepositions: &Vec<u64>, tag_write: &mut FLVTagWrite<T>, update_duration: bool, inject_keyframes: bool) -> Result<(), String> {
-        let mut metas = metatag.get_objects();
-        {
-            // if the updating of metadata was failed, then would write back the original metadata
-            let r: Result<(), String> = (|| {
-                let root: &mut BTreeMap<String, Json> = metas[1].as_object_mut().ok_or("meta[1] is not object.".to_string())?;
-                if update_duration {
-                    root.insert("duration".to_string(), Json::F64(duration as f64 / 1000.0));
-                }
-                root.insert("gapfixedby".to_string(), Json::String(PROGRAM_SIGN.to_string()));
-                let key_times = Json::Array(times.iter().map(|&t| Json::F64(t as f64 / 1000.0)).collect::<Vec<Json>>());
-                let key_positions = Json::Array(filepositions.iter().map(|&p| Json::F64(p as f64)).collect::<Vec<Json>>());
-                let keyframes = {
-                    if inject_keyframes {
-                        root.entry("keyframes".to_string()).or_insert(Json::Object(BTreeMap::new()));
-                    }
-                    root.get_mut("keyframes").ok_or("meta[1].keyframes dose not exits.".to_string())?.as_object_mut().ok_or("meta[1].keyframes is not object.".to_string())?
-                };
-                keyframes.insert("times".to_string(), key_times);
-                keyframes.insert("filepositions".to_string(), key_positions);
-                Ok(())
-            })();
-            match r {
-                Ok(_) => {},
-                Err(err) => {
-                    eprintln!("update metadata error: {}", err);
-                }
+    fn update_meta_tag(duration: u64, metatag: &mut FLVTag, times: &Vec<u64>, filepositions: &Vec<u64>, update_duration: bool, inject_keyframes: bool, sps: Option<&Sps>, video_header: Option<&LegacyVideoHeader>) -> Result<(), String> {
+        // set_metadata edits the object in place, the untouched entries keep their order and bytes
+        let mut meta = match metatag.try_get_metadata().map_err(|e| format!("read metadata err: {}", e))? {
+            Some(meta) => meta,
+            None => {
+                // the original metadata is written back
+                eprintln!("update metadata error: meta[0] is not onMetaData or meta[1] is not object.");
+                return Ok(());
+            }
+        };
+        if update_duration {
+            meta.duration = Some(duration as f64 / 1000.0);
+        }
+        meta.extra.insert("gapfixedby", Amf0Value::String(PROGRAM_SIGN.to_string()));
+        if inject_keyframes {
+            if let Some(sps) = sps {
+                meta.fill_from_sps(sps);
+            }
+            if let Some(header) = video_header {
+                meta.fill_from_video_header(header);
             }
         }
-        metatag.set_objects(&metas);
-        tag_write.write_meta_tag(&metatag);
+        if meta.keyframes.is_some() || inject_keyframes {
+            meta.keyframes = Some(FlvKeyframes {
+                times: times.iter().map(|&t| t as f64 / 1000.0).collect(),
+                filepositions: filepositions.clone(),
+            });
+        } else {
+            eprintln!("update metadata error: meta[1].keyframes dose not exits.");
+        }
+        metatag.set_metadata(&meta);
         Ok(())
     }
 
@@ -687,62 +712,136 @@ fn fix_file(input: &str, output: &str, info: FLVInfo, update_duration: bool, inj
         .filter(|&&TagProfile { ref tag_type, ref keyframe, .. }| *tag_type == FLVTagType::TAG_TYPE_VIDEO && *keyframe )
         .map(|&TagProfile { timestamp_us: ref t, .. }| *t as u64 / 1000).collect::<Vec<u64>>();
     let mut positions: Vec<u64> = vec![0u64; times.len()];
-    let mut metatag = info.iter().find(|&&TagProfile { ref tag_type, .. }| *tag_type == FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT).map(|item| item.tag(&mut file));
+    let mut metatag = match info.iter().find(|&&TagProfile { ref tag_type, sequence_header: ref metadata, .. }| *tag_type == FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT && *metadata) {
+        Some(item) => Some(item.tag(&mut file)?),
+        None => None,
+    };
+    // width, height and framerate for the injected metadata
+    let sps = match info.iter().find(|&&TagProfile { ref tag_type, sequence_header: ref sh, .. }| *tag_type == FLVTagType::TAG_TYPE_VIDEO && *sh) {
+        Some(item) => {
+            let tag = item.tag(&mut file)?;
+            if tag.get_codec_id() == CODEC_ID_AVC { tag.try_get_avcc().and_then(|avcc| avcc.parse_sps()).ok() } else { None }
+        }
+        None => None,
+    };
+    // the size of the legacy codecs is in their keyframes
+    let video_header = match info.iter().find(|&&TagProfile { ref tag_type, ref keyframe, .. }| *tag_type == FLVTagType::TAG_TYPE_VIDEO && *keyframe) {
+        Some(item) => item.tag(&mut file)?.get_legacy_video_header().unwrap_or(None),
+        None => None,
+    };
     let new_duration = {
         let item = info.iter().filter(|&&TagProfile { ref tag_type, .. }| *tag_type == FLVTagType::TAG_TYPE_AUDIO).last().ok_or("no any audio tags.".to_string())?;
         (item.timestamp_us + item.decode_duration_us) as u64 / 1000
     };
 
     if metatag.is_some() {
-        // write metatag
-        match write_back_meta_tag(new_duration, metatag.as_mut().unwrap(), &times, &positions, &mut tag_write, update_duration, inject_keyframes) {
+        match update_meta_tag(new_duration, metatag.as_mut().unwrap(), &times, &positions, update_duration, inject_keyframes, sps.as_ref(), video_header.as_ref()) {
             Ok(_) => {},
             Err(msg) => {
                 eprintln!("write metatag err, but fix is proceeding: {}", msg);
+                metatag = None;
             }
         };
     } else {
         eprintln!("can't find metatag, but fix is proceeding.");
     }
 
-    let mut frame_index: usize = 0;
+    if output == "-" {
+        // stdout can not seek back, so the keyframe positions are computed before anything is written.
+        // amf0 numbers have a fixed size, the metatag keeps its size when the positions are filled in.
+        let mut position = MIN_FILE_HEADER_BYTE_COUNT as u64 + 4;
+        if let Some(ref metatag) = metatag {
+            position += metatag.get_tag_size() as u64 + 4;
+        }
+        let mut frame_index: usize = 0;
+        for item in info.iter() {
+            match item.tag_type {
+                FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT if item.sequence_header => continue,
+                FLVTagType::TAG_TYPE_VIDEO if item.keyframe => {
+                    positions[frame_index] = position;
+                    frame_index += 1;
+                }
+                _ => {}
+            }
+            position += item.tag(&mut file)?.get_tag_size() as u64 + 4;
+        }
+        if metatag.is_some() {
+            match update_meta_tag(new_duration, metatag.as_mut().unwrap(), &times, &positions, update_duration, inject_keyframes, sps.as_ref(), video_header.as_ref()) {
+                Ok(_) => {},
+                Err(msg) => {
+                    eprintln!("write metatag err, but fix is proceeding: {}", msg);
+                }
+            };
+        }
+
+        let stdout = std::io::stdout();
+        let mut tag_write = FLVTagWrite::new(stdout.lock());
+        tag_write.write_header(&header);
+        if let Some(ref metatag) = metatag {
+            tag_write.write_tag(metatag);
+        }
+        write_tags(&info, &mut file, &mut tag_write)?;
+        return Ok(());
+    }
+
+    // read back too, for moving tags when the metadata grows
+    let output_file: File = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(output).map_err(|e| format!("creat output file err: {}", e))?;
+    let mut tag_write: FLVTagWrite<File> = FLVTagWrite::new(output_file);
+    tag_write.write_header(&header);
+
+    if let Some(ref metatag) = metatag {
+        tag_write.write_meta_tag(metatag).map_err(|e| format!("write metatag err: {}", e))?;
+    }
+
+    let positions = write_tags(&info, &mut file, &mut tag_write)?;
+
+    if let Some(ref metatag) = metatag {
+        // write metatag, the tags are moved back when it outgrows its room
+        let r = tag_write.write_meta_tag_relocating(|shift| {
+            let positions = positions.iter().map(|&p| p + shift).collect::<Vec<u64>>();
+            let mut metatag = metatag.clone();
+            match update_meta_tag(new_duration, &mut metatag, &times, &positions, update_duration, inject_keyframes, sps.as_ref(), video_header.as_ref()) {
+                Ok(_) => {},
+                Err(msg) => {
+                    eprintln!("write metatag err, but fix is proceeding: {}", msg);
+                }
+            };
+            metatag
+        });
+        r.map_err(|e| format!("write metatag err: {}", e))?;
+    }
+    Ok(())
+}
+
+/// writes all tags except the metatag, returns the positions of the video keyframes
+fn write_tags<W: Write>(info: &FLVInfo, file: &mut File, tag_write: &mut FLVTagWrite<W>) -> Result<Vec<u64>, String> {
+    let mut positions: Vec<u64> = Vec::new();
 
     for item in info.iter() {
         let &TagProfile {
             ref tag_type,
+            ref sequence_header,
             ref keyframe,
             ref timestamp_us,
             ref offset_us,
             ..
         } = item;
-        match *tag_type { // skip
-            FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT => {
+        match *tag_type { // skip the metatag
+            FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT if *sequence_header => {
                 continue;
             }
             FLVTagType::TAG_TYPE_VIDEO => {
                 if *keyframe {
-                    let p = tag_write.get_position();
-                    positions[frame_index] = p;
-                    frame_index += 1;
+                    positions.push(tag_write.get_position());
                 }
             }
             _ => {}
         }
-        let mut tag = item.tag(&mut file);
+        let mut tag = item.tag(file)?;
         tag.set_timestamp((*timestamp_us + *offset_us) as u64 / 1000);
         tag_write.write_tag(&tag);
     }
-
-    if metatag.is_some() {
-        // write metatag
-        match write_back_meta_tag(new_duration, metatag.as_mut().unwrap(), &times, &positions, &mut tag_write, update_duration, inject_keyframes) {
-            Ok(_) => {},
-            Err(msg) => {
-                eprintln!("write metatag err, but fix is proceeding: {}", msg);
-            }
-        };
-    }
-    Ok(())
+    Ok(positions)
 }
 
 fn print_usage(program: &str, opts: Options) {
@@ -750,9 +849,16 @@ fn print_usage(program: &str, opts: Options) {
     write!(std::io::stderr(), "{}", opts.usage(&brief)).unwrap();
 }
 
+// set when the fixed flv goes to stdout, the result json goes to stderr then
+static FLV_TO_STDOUT: AtomicBool = AtomicBool::new(false);
+
 fn return_code(code: i32, output: bool, msg: Option<&str>, data: Option<(Vec<OffsetInfo>, i64, i64, i64, i64)>, need_fix: Option<bool>) -> i32 {
 
-    let mut out = std::io::stdout();
+    let mut out: Box<Write> = if FLV_TO_STDOUT.load(Ordering::SeqCst) {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
     write!(out, "{{\"code\": {}", code).unwrap();
     write!(out, ", \"output\": {}", output).unwrap();
     if msg.is_some() {
@@ -792,7 +898,7 @@ fn app() -> i32 {
     let program = args[0].clone();
 
     let mut opts = Options::new();
-    opts.optflagopt("o", "output", "output flv file", "OUTPUT");
+    opts.optflagopt("o", "output", "output flv file, - for stdout", "OUTPUT");
     opts.optflagopt("t", "threshold", "when in fix mode, only max_offset > threshold would be fixed, in microseconds, default 0", "THRESHOLD");
     opts.optflagopt("r", "offset_rate_threshold", "when in fix mode, only offset_rate < offset_rate_threshold would be fixed, in 0-1, double number, default 0.01", "OFFSET_RATE");
     opts.optflag("d", "drop-video", "fix audio gap by drop video frames");
@@ -845,6 +951,7 @@ fn app() -> i32 {
             output.to_string_lossy().to_string()
         }
     };
+    FLV_TO_STDOUT.store(output == "-", Ordering::SeqCst);
 
     let _verbose    = matches.opt_present("v");
     let drop_mode   = matches.opt_present("d");
@@ -928,6 +1035,10 @@ fn app() -> i32 {
                 get_fix_info2(info, TagProfile::new_mute(0, sample_rate, channels), offset_mode)
             };
             match fix_file(&input, &output, new_info, update_duration, inject_keyframes) {
+                Ok(_) if output == "-" => {
+                    eprintln!("flv fix complete.");
+                    return return_code(1, true, None, Some((offset_infos, max_offset, sum_offset, offset_tag_count, audio_tag_count)), need_fix);
+                }
                 Ok(_) => {
                     eprintln!("flv fix complete.\nplease use `ffmpeg -i \"{}\" -acodec copy -vcodec copy \"{}\"` to get mp4 file.", &output, Path::new(&output).with_extension("mp4").to_str().unwrap());
                     return return_code(1, true, None, Some((offset_infos, max_offset, sum_offset, offset_tag_count, audio_tag_count)), need_fix);
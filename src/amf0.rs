@@ -0,0 +1,668 @@
+use std::io::{Read, Write};
+use std::collections::BTreeMap;
+use std::cmp;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rustc_serialize::json::Json;
+
+use lib::{FlvError, AMF0_DATE_KEY, AMF0_TIMEZONE_KEY, AMF0_XML_KEY, AMF0_CLASS_KEY, check_amf_depth};
+use amf3::{Amf3Value, Amf3Decoder, WriteAMF3Ext};
+
+/// Object members in the order they were read or inserted.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Amf0Object(pub Vec<(String, Amf0Value)>);
+
+impl Amf0Object {
+    pub fn new() -> Amf0Object {
+        Amf0Object(Vec::new())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Amf0Value> {
+        self.0.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Amf0Value> {
+        self.0.iter_mut().find(|&&mut (ref k, _)| k == key).map(|&mut (_, ref mut v)| v)
+    }
+
+    /// replaces the value in place when the key exists, appends it otherwise
+    pub fn insert(&mut self, key: &str, value: Amf0Value) -> Option<Amf0Value> {
+        if let Some(v) = self.get_mut(key) {
+            return Some(::std::mem::replace(v, value));
+        }
+        self.0.push((key.to_string(), value));
+        None
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Amf0Value> {
+        match self.0.iter().position(|&(ref k, _)| k == key) {
+            Some(i) => Some(self.0.remove(i).1),
+            None => None,
+        }
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<'_, (String, Amf0Value)> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// One AMF0 value, kept as written so `write` gives back the bytes `try_read` got,
+/// except for an ECMA array count that disagrees with its members.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Amf0Value {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Object(Amf0Object),
+    MovieClip,
+    Null,
+    Undefined,
+    /// index into the complex values read before, in the order they start
+    Reference(u16),
+    /// `count` is only kept when it disagrees with the members, it is not written: the members may have been edited
+    EcmaArray { count: Option<u32>, entries: Amf0Object },
+    /// object end marker out of an object
+    ObjectEnd,
+    StrictArray(Vec<Amf0Value>),
+    Date { ms: f64, timezone: i16 },
+    LongString(String),
+    /// string that is not valid UTF-8, like GBK metadata, kept as read and written back as is
+    RawString(Vec<u8>),
+    RawLongString(Vec<u8>),
+    Unsupported,
+    XmlDocument(String),
+    TypedObject { class: String, members: Amf0Object },
+    /// AMF3 value after the avmplus marker, byte exact unless it used AMF3 object references
+    Avmplus(Amf3Value),
+}
+
+impl Amf0Value {
+    /// values nested deeper than `AMF_MAX_DEPTH` are `FlvError::AmfTooDeep`
+    pub fn try_read<R: Read + ?Sized>(r: &mut R) -> Result<Amf0Value, FlvError> {
+        Amf0Value::try_read_at(r, 0)
+    }
+
+    fn try_read_at<R: Read + ?Sized>(r: &mut R, depth: usize) -> Result<Amf0Value, FlvError> {
+        let marker = r.read_u8()?;
+        Amf0Value::try_read_with_marker(r, marker, depth)
+    }
+
+    fn try_read_with_marker<R: Read + ?Sized>(r: &mut R, marker: u8, depth: usize) -> Result<Amf0Value, FlvError> {
+        check_amf_depth(depth)?;
+        Ok(match marker {
+            0x00 => Amf0Value::Number(r.read_f64::<BigEndian>()?),
+            0x01 => Amf0Value::Boolean(r.read_u8()? != 0),
+            0x02 => {
+                let len = r.read_u16::<BigEndian>()?;
+                match String::from_utf8(read_bytes(r, len as usize)?) {
+                    Ok(s) => Amf0Value::String(s),
+                    Err(e) => Amf0Value::RawString(e.into_bytes()),
+                }
+            }
+            0x03 => Amf0Value::Object(read_members(r, depth)?),
+            0x04 => Amf0Value::MovieClip,
+            0x05 => Amf0Value::Null,
+            0x06 => Amf0Value::Undefined,
+            0x07 => Amf0Value::Reference(r.read_u16::<BigEndian>()?),
+            0x08 => {
+                let count = r.read_u32::<BigEndian>()?;
+                let entries = read_members(r, depth)?;
+                Amf0Value::EcmaArray {
+                    count: if count as usize == entries.len() { None } else { Some(count) },
+                    entries: entries,
+                }
+            }
+            0x09 => Amf0Value::ObjectEnd,
+            0x0A => {
+                let count = r.read_u32::<BigEndian>()?;
+                // count comes from the stream, don't trust it for the allocation
+                let mut v: Vec<Amf0Value> = Vec::with_capacity(cmp::min(count, 1024) as usize);
+                for _ in 0..count {
+                    v.push(Amf0Value::try_read_at(r, depth + 1)?);
+                }
+                Amf0Value::StrictArray(v)
+            }
+            0x0B => {
+                let ms = r.read_f64::<BigEndian>()?;
+                Amf0Value::Date { ms: ms, timezone: r.read_i16::<BigEndian>()? }
+            }
+            0x0C => {
+                let len = r.read_u32::<BigEndian>()?;
+                match String::from_utf8(read_bytes(r, len as usize)?) {
+                    Ok(s) => Amf0Value::LongString(s),
+                    Err(e) => Amf0Value::RawLongString(e.into_bytes()),
+                }
+            }
+            0x0D => Amf0Value::Unsupported,
+            0x0F => {
+                let len = r.read_u32::<BigEndian>()?;
+                Amf0Value::XmlDocument(read_utf8(r, len as usize)?)
+            }
+            0x10 => {
+                let len = r.read_u16::<BigEndian>()?;
+                let class = read_utf8(r, len as usize)?;
+                Amf0Value::TypedObject { class: class, members: read_members(r, depth)? }
+            }
+            0x11 => Amf0Value::Avmplus(Amf3Decoder::new().read_value(r)?),
+            n => return Err(FlvError::BadAmfMarker(n)),
+        })
+    }
+
+    /// reads values until the end of the stream
+    pub fn try_read_all<R: Read + ?Sized>(r: &mut R) -> Result<Vec<Amf0Value>, FlvError> {
+        let mut v: Vec<Amf0Value> = Vec::new();
+        loop {
+            let marker = match r.read_u8() {
+                Ok(n) => n,
+                Err(::byteorder::Error::UnexpectedEOF) => break,
+                Err(e) => return Err(FlvError::from(e)),
+            };
+            v.push(Amf0Value::try_read_with_marker(r, marker, 0)?);
+        }
+        Ok(v)
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) {
+        self.try_write(w).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// keys and class names over 65535 bytes have no long form and are `FlvError::BadAmfString`
+    pub fn try_write<W: Write + ?Sized>(&self, w: &mut W) -> Result<(), FlvError> {
+        match *self {
+            Amf0Value::Number(f) => {
+                w.write_u8(0x00)?;
+                w.write_f64::<BigEndian>(f)?;
+            }
+            Amf0Value::Boolean(b) => {
+                w.write_u8(0x01)?;
+                w.write_u8(if b { 1 } else { 0 })?;
+            }
+            Amf0Value::String(ref s) if s.len() > 0xffff => Amf0Value::LongString(s.clone()).try_write(w)?,
+            Amf0Value::String(ref s) => {
+                w.write_u8(0x02)?;
+                write_utf8(w, s.as_bytes())?;
+            }
+            Amf0Value::Object(ref o) => {
+                w.write_u8(0x03)?;
+                write_members(w, o)?;
+            }
+            Amf0Value::MovieClip => w.write_u8(0x04)?,
+            Amf0Value::Null => w.write_u8(0x05)?,
+            Amf0Value::Undefined => w.write_u8(0x06)?,
+            Amf0Value::Reference(index) => {
+                w.write_u8(0x07)?;
+                w.write_u16::<BigEndian>(index)?;
+            }
+            Amf0Value::EcmaArray { ref entries, .. } => {
+                w.write_u8(0x08)?;
+                w.write_u32::<BigEndian>(entries.len() as u32)?;
+                write_members(w, entries)?;
+            }
+            Amf0Value::ObjectEnd => w.write_u8(0x09)?,
+            Amf0Value::StrictArray(ref v) => {
+                w.write_u8(0x0A)?;
+                w.write_u32::<BigEndian>(v.len() as u32)?;
+                for item in v.iter() {
+                    item.try_write(w)?;
+                }
+            }
+            Amf0Value::Date { ms, timezone } => {
+                w.write_u8(0x0B)?;
+                w.write_f64::<BigEndian>(ms)?;
+                w.write_i16::<BigEndian>(timezone)?;
+            }
+            Amf0Value::LongString(ref s) => {
+                w.write_u8(0x0C)?;
+                w.write_u32::<BigEndian>(s.len() as u32)?;
+                w.write_all(s.as_bytes())?;
+            }
+            Amf0Value::RawString(ref s) if s.len() > 0xffff => Amf0Value::RawLongString(s.clone()).try_write(w)?,
+            Amf0Value::RawString(ref s) => {
+                w.write_u8(0x02)?;
+                write_utf8(w, s)?;
+            }
+            Amf0Value::RawLongString(ref s) => {
+                w.write_u8(0x0C)?;
+                w.write_u32::<BigEndian>(s.len() as u32)?;
+                w.write_all(s)?;
+            }
+            Amf0Value::Unsupported => w.write_u8(0x0D)?,
+            Amf0Value::XmlDocument(ref s) => {
+                w.write_u8(0x0F)?;
+                w.write_u32::<BigEndian>(s.len() as u32)?;
+                w.write_all(s.as_bytes())?;
+            }
+            Amf0Value::TypedObject { ref class, ref members } => {
+                w.write_u8(0x10)?;
+                write_utf8(w, class.as_bytes())?;
+                write_members(w, members)?;
+            }
+            Amf0Value::Avmplus(ref v) => {
+                w.write_u8(0x11)?;
+                w.write_amf3_value(v);
+            }
+        }
+        Ok(())
+    }
+
+    /// members of an object, ECMA array or typed object
+    pub fn as_object(&self) -> Option<&Amf0Object> {
+        match *self {
+            Amf0Value::Object(ref o) => Some(o),
+            Amf0Value::EcmaArray { ref entries, .. } => Some(entries),
+            Amf0Value::TypedObject { ref members, .. } => Some(members),
+            _ => None,
+        }
+    }
+
+    pub fn as_object_mut(&mut self) -> Option<&mut Amf0Object> {
+        match *self {
+            Amf0Value::Object(ref mut o) => Some(o),
+            Amf0Value::EcmaArray { ref mut entries, .. } => Some(entries),
+            Amf0Value::TypedObject { ref mut members, .. } => Some(members),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Amf0Value::Number(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Amf0Value::String(ref s) | Amf0Value::LongString(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// follows `keys` through nested objects
+    pub fn find_path(&self, keys: &[&str]) -> Option<&Amf0Value> {
+        let mut v = self;
+        for key in keys.iter() {
+            v = match v.as_object().and_then(|o| o.get(key)) {
+                Some(v) => v,
+                None => return None,
+            };
+        }
+        Some(v)
+    }
+
+    /// same mapping as `ReadAMF0Ext`, a reference resolves only inside this value
+    pub fn to_json(&self) -> Json {
+        self.to_json_with(&mut Vec::new())
+    }
+
+    fn to_json_with(&self, refs: &mut Vec<Json>) -> Json {
+        match *self {
+            Amf0Value::Number(f) => Json::F64(f),
+            Amf0Value::Boolean(b) => Json::Boolean(b),
+            Amf0Value::String(ref s) | Amf0Value::LongString(ref s) => Json::String(s.clone()),
+            // json strings are UTF-8, the raw bytes don't make it there
+            Amf0Value::RawString(ref s) | Amf0Value::RawLongString(ref s) => Json::String(String::from_utf8_lossy(s).into_owned()),
+            Amf0Value::MovieClip | Amf0Value::Null | Amf0Value::Undefined | Amf0Value::ObjectEnd | Amf0Value::Unsupported => Json::Null,
+            Amf0Value::Reference(index) => refs.get(index as usize).cloned().unwrap_or(Json::Null),
+            Amf0Value::Object(ref o) | Amf0Value::EcmaArray { entries: ref o, .. } => members_to_json(o, None, refs),
+            Amf0Value::TypedObject { ref class, ref members } => members_to_json(members, Some(class), refs),
+            Amf0Value::StrictArray(ref v) => {
+                let index = refs.len();
+                refs.push(Json::Null);
+                let a = Json::Array(v.iter().map(|item| item.to_json_with(refs)).collect());
+                refs[index] = a.clone();
+                a
+            }
+            Amf0Value::Date { ms, timezone } => {
+                let mut obj = BTreeMap::new();
+                obj.insert(AMF0_DATE_KEY.to_string(), Json::F64(ms));
+                obj.insert(AMF0_TIMEZONE_KEY.to_string(), Json::I64(timezone as i64));
+                Json::Object(obj)
+            }
+            Amf0Value::XmlDocument(ref s) => {
+                let mut obj = BTreeMap::new();
+                obj.insert(AMF0_XML_KEY.to_string(), Json::String(s.clone()));
+                Json::Object(obj)
+            }
+            Amf0Value::Avmplus(ref v) => v.to_json(),
+        }
+    }
+
+    /// the reverse of `to_json`, json objects become ECMA arrays like in `onMetaData`;
+    /// lossy for objects that look like a date or an XML document, see `AMF0_DATE_KEY`
+    pub fn from_json(json: &Json) -> Amf0Value {
+        match *json {
+            Json::I64(i) => Amf0Value::Number(i as f64),
+            Json::U64(u) => Amf0Value::Number(u as f64),
+            Json::F64(f) => Amf0Value::Number(f),
+            Json::String(ref s) if s.len() > 0xffff => Amf0Value::LongString(s.clone()),
+            Json::String(ref s) => Amf0Value::String(s.clone()),
+            Json::Boolean(b) => Amf0Value::Boolean(b),
+            Json::Array(ref a) => Amf0Value::StrictArray(a.iter().map(Amf0Value::from_json).collect()),
+            Json::Null => Amf0Value::Null,
+            Json::Object(ref o) => from_json_members(Amf0Object(o.iter().map(|(k, v)| (k.clone(), Amf0Value::from_json(v))).collect())),
+        }
+    }
+}
+
+/// the value of a json object, the serde mapping reads objects back the same way
+fn from_json_members(mut members: Amf0Object) -> Amf0Value {
+    match (members.get(AMF0_DATE_KEY), members.get(AMF0_TIMEZONE_KEY), members.len()) {
+        (Some(&Amf0Value::Number(ms)), Some(&Amf0Value::Number(timezone)), 2) => {
+            return Amf0Value::Date { ms: ms, timezone: timezone as i16 };
+        }
+        _ => {}
+    }
+    match (members.get(AMF0_XML_KEY), members.len()) {
+        (Some(&Amf0Value::String(ref xml)), 1) | (Some(&Amf0Value::LongString(ref xml)), 1) => {
+            return Amf0Value::XmlDocument(xml.clone());
+        }
+        _ => {}
+    }
+    match members.remove(AMF0_CLASS_KEY) {
+        Some(Amf0Value::String(class)) | Some(Amf0Value::LongString(class)) => Amf0Value::TypedObject { class: class, members: members },
+        _ => Amf0Value::EcmaArray { count: None, entries: members },
+    }
+}
+
+fn members_to_json(o: &Amf0Object, class: Option<&String>, refs: &mut Vec<Json>) -> Json {
+    let index = refs.len();
+    refs.push(Json::Null);
+    let mut obj = BTreeMap::new();
+    if let Some(class) = class {
+        obj.insert(AMF0_CLASS_KEY.to_string(), Json::String(class.clone()));
+    }
+    for &(ref k, ref v) in o.iter() {
+        let v = v.to_json_with(refs);
+        obj.insert(k.clone(), v);
+    }
+    let obj = Json::Object(obj);
+    refs[index] = obj.clone();
+    obj
+}
+
+fn read_members<R: Read + ?Sized>(r: &mut R, depth: usize) -> Result<Amf0Object, FlvError> {
+    let mut members: Vec<(String, Amf0Value)> = Vec::new();
+    loop {
+        let len = r.read_u16::<BigEndian>()? as usize;
+        if len == 0 {
+            let end_mark = r.read_u8()?;
+            if end_mark != 0x09 {
+                return Err(FlvError::BadAmfMarker(end_mark));
+            }
+            break;
+        }
+        let key = read_utf8(r, len)?;
+        let v = Amf0Value::try_read_at(r, depth + 1)?;
+        members.push((key, v));
+    }
+    Ok(Amf0Object(members))
+}
+
+fn write_members<W: Write + ?Sized>(w: &mut W, o: &Amf0Object) -> Result<(), FlvError> {
+    for &(ref k, ref v) in o.iter() {
+        write_utf8(w, k.as_bytes())?;
+        v.try_write(w)?;
+    }
+    w.write_u16::<BigEndian>(0)?;
+    w.write_u8(0x09)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read + ?Sized>(r: &mut R, len: usize) -> Result<Vec<u8>, FlvError> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(cmp::min(len, 64 * 1024));
+    if r.take(len as u64).read_to_end(&mut buffer)? != len {
+        return Err(FlvError::UnexpectedEof);
+    }
+    Ok(buffer)
+}
+
+// keys, class names and XML documents have no raw form, strings are `RawString` instead
+fn read_utf8<R: Read + ?Sized>(r: &mut R, len: usize) -> Result<String, FlvError> {
+    String::from_utf8(read_bytes(r, len)?).map_err(|_| FlvError::BadAmfString("not valid UTF-8"))
+}
+
+// strings have the long string marker to fall back to, keys and class names don't
+fn write_utf8<W: Write + ?Sized>(w: &mut W, s: &[u8]) -> Result<(), FlvError> {
+    if s.len() > 0xffff {
+        return Err(FlvError::BadAmfString("longer than 65535 bytes"));
+    }
+    w.write_u16::<BigEndian>(s.len() as u16)?;
+    w.write_all(s)?;
+    Ok(())
+}
+
+/// JSON export keeps the member order, values map like `to_json`
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use std::fmt;
+    use serde::ser::{Serialize, Serializer, SerializeMap, SerializeSeq};
+    use serde::de::{Deserialize, Deserializer, Visitor, MapAccess, SeqAccess};
+    use rustc_serialize::json::Json;
+    use super::{Amf0Value, Amf0Object, from_json_members};
+
+    struct JsonRef<'a>(&'a Json);
+
+    impl<'a> Serialize for JsonRef<'a> {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            match *self.0 {
+                Json::I64(i) => s.serialize_i64(i),
+                Json::U64(u) => s.serialize_u64(u),
+                Json::F64(f) => s.serialize_f64(f),
+                Json::String(ref v) => s.serialize_str(v),
+                Json::Boolean(b) => s.serialize_bool(b),
+                Json::Null => s.serialize_unit(),
+                Json::Array(ref a) => {
+                    let mut seq = s.serialize_seq(Some(a.len()))?;
+                    for item in a.iter() {
+                        seq.serialize_element(&JsonRef(item))?;
+                    }
+                    seq.end()
+                }
+                Json::Object(ref o) => {
+                    let mut map = s.serialize_map(Some(o.len()))?;
+                    for (k, v) in o.iter() {
+                        map.serialize_entry(k, &JsonRef(v))?;
+                    }
+                    map.end()
+                }
+            }
+        }
+    }
+
+    impl Serialize for Amf0Value {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            match *self {
+                Amf0Value::Object(ref o) | Amf0Value::EcmaArray { entries: ref o, .. } => o.serialize(s),
+                Amf0Value::StrictArray(ref v) => v.serialize(s),
+                _ => JsonRef(&self.to_json()).serialize(s),
+            }
+        }
+    }
+
+    impl Serialize for Amf0Object {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            let mut map = s.serialize_map(Some(self.len()))?;
+            for &(ref k, ref v) in self.iter() {
+                map.serialize_entry(k, v)?;
+            }
+            map.end()
+        }
+    }
+
+    struct Amf0ValueVisitor;
+
+    impl<'de> Visitor<'de> for Amf0ValueVisitor {
+        type Value = Amf0Value;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a json value")
+        }
+
+        fn visit_bool<E>(self, v: bool) -> Result<Amf0Value, E> {
+            Ok(Amf0Value::Boolean(v))
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Amf0Value, E> {
+            Ok(Amf0Value::Number(v as f64))
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Amf0Value, E> {
+            Ok(Amf0Value::Number(v as f64))
+        }
+
+        fn visit_f64<E>(self, v: f64) -> Result<Amf0Value, E> {
+            Ok(Amf0Value::Number(v))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Amf0Value, E> {
+            Ok(Amf0Value::from_json(&Json::String(v.to_string())))
+        }
+
+        fn visit_unit<E>(self) -> Result<Amf0Value, E> {
+            Ok(Amf0Value::Null)
+        }
+
+        fn visit_none<E>(self) -> Result<Amf0Value, E> {
+            Ok(Amf0Value::Null)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Amf0Value, A::Error> {
+            let mut v: Vec<Amf0Value> = Vec::new();
+            while let Some(item) = seq.next_element()? {
+                v.push(item);
+            }
+            Ok(Amf0Value::StrictArray(v))
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Amf0Value, A::Error> {
+            Ok(from_json_members(Amf0ObjectVisitor.visit_map(map)?))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Amf0Value {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Amf0Value, D::Error> {
+            d.deserialize_any(Amf0ValueVisitor)
+        }
+    }
+
+    struct Amf0ObjectVisitor;
+
+    impl<'de> Visitor<'de> for Amf0ObjectVisitor {
+        type Value = Amf0Object;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a json object")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Amf0Object, A::Error> {
+            let mut o = Amf0Object::new();
+            while let Some((k, v)) = map.next_entry::<String, Amf0Value>()? {
+                o.insert(&k, v);
+            }
+            Ok(o)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Amf0Object {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Amf0Object, D::Error> {
+            d.deserialize_map(Amf0ObjectVisitor)
+        }
+    }
+}
+
+#[test]
+fn test_amf0_value() {
+    // "onMetaData", ECMA array with a wrong count: zeta, alpha, keyframes (undefined), ref to the array
+    let mut data: Vec<u8> = vec![0x02, 0, 10];
+    data.extend_from_slice(b"onMetaData");
+    data.extend_from_slice(&[0x08, 0, 0, 0, 0]);
+    data.extend_from_slice(&[0, 4, b'z', b'e', b't', b'a', 0x00, 0x40, 0, 0, 0, 0, 0, 0, 0]);
+    data.extend_from_slice(&[0, 5, b'a', b'l', b'p', b'h', b'a', 0x0B, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    data.extend_from_slice(&[0, 1, b'k', 0x06]);
+    data.extend_from_slice(&[0, 0, 0x09]);
+    data.extend_from_slice(&[0x07, 0, 0]);
+    let values = Amf0Value::try_read_all(&mut &data[..]).unwrap();
+    assert_eq!(values.len(), 3);
+    match values[1] {
+        Amf0Value::EcmaArray { count: Some(0), ref entries } => {
+            let keys: Vec<&str> = entries.iter().map(|&(ref k, _)| k.as_str()).collect();
+            assert_eq!(keys, vec!["zeta", "alpha", "k"]);
+            assert_eq!(entries.get("k"), Some(&Amf0Value::Undefined));
+        }
+        ref v => panic!("unexpected {:?}", v),
+    }
+    let mut out: Vec<u8> = Vec::new();
+    for v in values.iter() {
+        v.write(&mut out);
+    }
+    // the count is written from the members
+    data[17] = 3;
+    assert_eq!(out, data);
+
+    // a string too long for its marker goes out as a long string
+    let mut out: Vec<u8> = Vec::new();
+    Amf0Value::String("a".repeat(0x10000)).write(&mut out);
+    assert_eq!(&out[..5], &[0x0C, 0, 1, 0, 0]);
+    assert_eq!(Amf0Value::try_read(&mut &out[..]).unwrap(), Amf0Value::LongString("a".repeat(0x10000)));
+
+    // a GBK title is kept byte for byte, a key has no room for more than 65535 bytes
+    let data: Vec<u8> = vec![0x02, 0, 4, 0xd6, 0xd0, 0xce, 0xc4];
+    let v = Amf0Value::try_read(&mut &data[..]).unwrap();
+    assert_eq!(v, Amf0Value::RawString(vec![0xd6, 0xd0, 0xce, 0xc4]));
+    let mut out: Vec<u8> = Vec::new();
+    v.try_write(&mut out).unwrap();
+    assert_eq!(out, data);
+    match Amf0Value::try_read(&mut &[0x03, 0, 1, 0xd6, 0x05, 0, 0, 0x09][..]) {
+        Err(FlvError::BadAmfString(..)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    let mut o = Amf0Object::new();
+    o.insert(&"k".repeat(0x10000), Amf0Value::Null);
+    match Amf0Value::Object(o).try_write(&mut Vec::new()) {
+        Err(FlvError::BadAmfString(..)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+
+    let json = values[1].to_json();
+    assert_eq!(json.find("zeta"), Some(&Json::F64(2.0)));
+    assert_eq!(json.find("alpha").and_then(|d| d.find(AMF0_DATE_KEY)), Some(&Json::F64(0.0)));
+    match Amf0Value::from_json(&json) {
+        Amf0Value::EcmaArray { count: None, ref entries } => {
+            assert_eq!(entries.get("alpha"), Some(&Amf0Value::Date { ms: 0.0, timezone: 0 }));
+            assert_eq!(entries.get("k"), Some(&Amf0Value::Null));
+        }
+        ref v => panic!("unexpected {:?}", v),
+    }
+
+    // the json mapping is lossy, an ECMA array that looks like a date comes back as one
+    let mut entries = Amf0Object::new();
+    entries.insert(AMF0_DATE_KEY, Amf0Value::Number(1.0));
+    entries.insert(AMF0_TIMEZONE_KEY, Amf0Value::Number(0.0));
+    let array = Amf0Value::EcmaArray { count: None, entries: entries };
+    assert_eq!(Amf0Value::from_json(&array.to_json()), Amf0Value::Date { ms: 1.0, timezone: 0 });
+
+    // 100 nested strict arrays
+    let mut data: Vec<u8> = Vec::new();
+    for _ in 0..100 {
+        data.extend_from_slice(&[0x0A, 0, 0, 0, 1]);
+    }
+    data.extend_from_slice(&[0x05]);
+    match Amf0Value::try_read(&mut &data[..]) {
+        Err(FlvError::AmfTooDeep) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    match ::lib::ReadAMF0Ext::try_read_amf0_value(&mut &data[..]) {
+        Err(FlvError::AmfTooDeep) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    assert!(Amf0Value::try_read(&mut &data[(100 - 10) * 5..]).is_ok());
+}
@@ -0,0 +1,654 @@
+use std::io::{Read, Write};
+use std::collections::BTreeMap;
+use std::cmp;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rustc_serialize::json::Json;
+
+use lib::{FlvError, AMF0_DATE_KEY, AMF0_TIMEZONE_KEY, AMF0_XML_KEY, AMF0_CLASS_KEY, check_amf_depth};
+
+/// Externalizable classes whose body is a single AMF3 value.
+const EXTERNALIZABLE_WRAPPERS: [&'static str; 3] = [
+    "flex.messaging.io.ArrayCollection",
+    "flex.messaging.io.ObjectProxy",
+    "mx.collections.ArrayCollection",
+];
+
+const INTEGER_MIN: i32 = -(1 << 28);
+const INTEGER_MAX: i32 = (1 << 28) - 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Amf3Traits {
+    pub class_name: String, // empty for anonymous objects
+    pub dynamic: bool,
+    pub externalizable: bool,
+    pub sealed_names: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Amf3Value {
+    Undefined,
+    Null,
+    Boolean(bool),
+    Integer(i32), // 29 bits
+    Double(f64),
+    String(String),
+    XmlDocument(String),
+    /// milliseconds since the epoch, UTC
+    Date(f64),
+    Array { assoc: Vec<(String, Amf3Value)>, dense: Vec<Amf3Value> },
+    /// `sealed` has one value per `traits.sealed_names`,
+    /// `external` is the body of an externalizable object
+    Object { traits: Amf3Traits, sealed: Vec<Amf3Value>, dynamic: Vec<(String, Amf3Value)>, external: Option<Box<Amf3Value>> },
+    Xml(String),
+    ByteArray(Vec<u8>),
+    VectorInt { fixed: bool, items: Vec<i32> },
+    VectorUint { fixed: bool, items: Vec<u32> },
+    VectorDouble { fixed: bool, items: Vec<f64> },
+    VectorObject { fixed: bool, type_name: String, items: Vec<Amf3Value> },
+    Dictionary { weak_keys: bool, entries: Vec<(Amf3Value, Amf3Value)> },
+}
+
+impl Amf3Value {
+    /// same mapping as the AMF0 reader, byte arrays become arrays of numbers
+    /// and dictionary keys are turned into strings
+    pub fn to_json(&self) -> Json {
+        match *self {
+            Amf3Value::Undefined | Amf3Value::Null => Json::Null,
+            Amf3Value::Boolean(b) => Json::Boolean(b),
+            Amf3Value::Integer(i) => Json::I64(i as i64),
+            Amf3Value::Double(f) => Json::F64(f),
+            Amf3Value::String(ref s) => Json::String(s.clone()),
+            Amf3Value::XmlDocument(ref s) | Amf3Value::Xml(ref s) => {
+                let mut obj = BTreeMap::new();
+                obj.insert(AMF0_XML_KEY.to_string(), Json::String(s.clone()));
+                Json::Object(obj)
+            }
+            Amf3Value::Date(ms) => {
+                let mut obj = BTreeMap::new();
+                obj.insert(AMF0_DATE_KEY.to_string(), Json::F64(ms));
+                obj.insert(AMF0_TIMEZONE_KEY.to_string(), Json::I64(0));
+                Json::Object(obj)
+            }
+            Amf3Value::Array { ref assoc, ref dense } => {
+                if assoc.is_empty() {
+                    return Json::Array(dense.iter().map(|v| v.to_json()).collect());
+                }
+                let mut obj = BTreeMap::new();
+                for (i, v) in dense.iter().enumerate() {
+                    obj.insert(i.to_string(), v.to_json());
+                }
+                for &(ref k, ref v) in assoc.iter() {
+                    obj.insert(k.clone(), v.to_json());
+                }
+                Json::Object(obj)
+            }
+            Amf3Value::Object { ref traits, ref sealed, ref dynamic, ref external } => {
+                if let Some(ref external) = *external {
+                    return external.to_json();
+                }
+                let mut obj = BTreeMap::new();
+                if !traits.class_name.is_empty() {
+                    obj.insert(AMF0_CLASS_KEY.to_string(), Json::String(traits.class_name.clone()));
+                }
+                for (k, v) in traits.sealed_names.iter().zip(sealed.iter()) {
+                    obj.insert(k.clone(), v.to_json());
+                }
+                for &(ref k, ref v) in dynamic.iter() {
+                    obj.insert(k.clone(), v.to_json());
+                }
+                Json::Object(obj)
+            }
+            Amf3Value::ByteArray(ref bytes) => Json::Array(bytes.iter().map(|&b| Json::U64(b as u64)).collect()),
+            Amf3Value::VectorInt { ref items, .. } => Json::Array(items.iter().map(|&i| Json::I64(i as i64)).collect()),
+            Amf3Value::VectorUint { ref items, .. } => Json::Array(items.iter().map(|&u| Json::U64(u as u64)).collect()),
+            Amf3Value::VectorDouble { ref items, .. } => Json::Array(items.iter().map(|&f| Json::F64(f)).collect()),
+            Amf3Value::VectorObject { ref items, .. } => Json::Array(items.iter().map(|v| v.to_json()).collect()),
+            Amf3Value::Dictionary { ref entries, .. } => {
+                let mut obj = BTreeMap::new();
+                for &(ref k, ref v) in entries.iter() {
+                    let key = match *k {
+                        Amf3Value::String(ref s) => s.clone(),
+                        ref k => k.to_json().to_string(),
+                    };
+                    obj.insert(key, v.to_json());
+                }
+                Json::Object(obj)
+            }
+        }
+    }
+}
+
+/// Reads AMF3 values, keeping the string, object and traits reference tables across values.
+#[derive(Default)]
+pub struct Amf3Decoder {
+    strings: Vec<String>,
+    objects: Vec<Option<Amf3Value>>, // None until the value is complete
+    traits: Vec<Amf3Traits>,
+    depth: usize, // of the value being read
+}
+
+impl Amf3Decoder {
+    pub fn new() -> Amf3Decoder {
+        Amf3Decoder::default()
+    }
+
+    /// values nested deeper than `AMF_MAX_DEPTH` are `FlvError::AmfTooDeep`
+    pub fn read_value<R: Read + ?Sized>(&mut self, r: &mut R) -> Result<Amf3Value, FlvError> {
+        check_amf_depth(self.depth)?;
+        self.depth += 1;
+        let v = self.read_nested_value(r);
+        self.depth -= 1;
+        v
+    }
+
+    fn read_nested_value<R: Read + ?Sized>(&mut self, r: &mut R) -> Result<Amf3Value, FlvError> {
+        let marker = r.read_u8()?;
+        match marker {
+            0x00 => Ok(Amf3Value::Undefined),
+            0x01 => Ok(Amf3Value::Null),
+            0x02 => Ok(Amf3Value::Boolean(false)),
+            0x03 => Ok(Amf3Value::Boolean(true)),
+            0x04 => {
+                let u = read_u29(r)?;
+                // sign extend the 29 bits
+                Ok(Amf3Value::Integer(((u << 3) as i32) >> 3))
+            }
+            0x05 => Ok(Amf3Value::Double(r.read_f64::<BigEndian>()?)),
+            0x06 => Ok(Amf3Value::String(self.read_string(r)?)),
+            0x07 | 0x0B => {
+                let u = read_u29(r)?;
+                if u & 1 == 0 {
+                    return self.object_reference(u >> 1);
+                }
+                let s = read_utf8(r, (u >> 1) as usize)?;
+                let v = if marker == 0x07 { Amf3Value::XmlDocument(s) } else { Amf3Value::Xml(s) };
+                self.objects.push(Some(v.clone()));
+                Ok(v)
+            }
+            0x08 => {
+                let u = read_u29(r)?;
+                if u & 1 == 0 {
+                    return self.object_reference(u >> 1);
+                }
+                let v = Amf3Value::Date(r.read_f64::<BigEndian>()?);
+                self.objects.push(Some(v.clone()));
+                Ok(v)
+            }
+            0x09 => {
+                let u = read_u29(r)?;
+                if u & 1 == 0 {
+                    return self.object_reference(u >> 1);
+                }
+                let index = self.start_object();
+                let mut assoc: Vec<(String, Amf3Value)> = Vec::new();
+                loop {
+                    let key = self.read_string(r)?;
+                    if key.is_empty() {
+                        break;
+                    }
+                    let v = self.read_value(r)?;
+                    assoc.push((key, v));
+                }
+                let count = u >> 1;
+                // counts come from the stream, don't trust them for the allocation
+                let mut dense: Vec<Amf3Value> = Vec::with_capacity(cmp::min(count, 1024) as usize);
+                for _ in 0..count {
+                    dense.push(self.read_value(r)?);
+                }
+                Ok(self.end_object(index, Amf3Value::Array { assoc: assoc, dense: dense }))
+            }
+            0x0A => self.read_object(r),
+            0x0C => {
+                let u = read_u29(r)?;
+                if u & 1 == 0 {
+                    return self.object_reference(u >> 1);
+                }
+                let len = (u >> 1) as usize;
+                let mut bytes: Vec<u8> = Vec::with_capacity(cmp::min(len, 64 * 1024));
+                if r.take(len as u64).read_to_end(&mut bytes)? != len {
+                    return Err(FlvError::UnexpectedEof);
+                }
+                let v = Amf3Value::ByteArray(bytes);
+                self.objects.push(Some(v.clone()));
+                Ok(v)
+            }
+            0x0D | 0x0E | 0x0F | 0x10 => {
+                let u = read_u29(r)?;
+                if u & 1 == 0 {
+                    return self.object_reference(u >> 1);
+                }
+                let count = u >> 1;
+                let capacity = cmp::min(count, 1024) as usize;
+                let fixed = r.read_u8()? != 0;
+                let index = self.start_object();
+                let v = match marker {
+                    0x0D => {
+                        let mut items: Vec<i32> = Vec::with_capacity(capacity);
+                        for _ in 0..count {
+                            items.push(r.read_i32::<BigEndian>()?);
+                        }
+                        Amf3Value::VectorInt { fixed: fixed, items: items }
+                    }
+                    0x0E => {
+                        let mut items: Vec<u32> = Vec::with_capacity(capacity);
+                        for _ in 0..count {
+                            items.push(r.read_u32::<BigEndian>()?);
+                        }
+                        Amf3Value::VectorUint { fixed: fixed, items: items }
+                    }
+                    0x0F => {
+                        let mut items: Vec<f64> = Vec::with_capacity(capacity);
+                        for _ in 0..count {
+                            items.push(r.read_f64::<BigEndian>()?);
+                        }
+                        Amf3Value::VectorDouble { fixed: fixed, items: items }
+                    }
+                    _ => {
+                        let type_name = self.read_string(r)?;
+                        let mut items: Vec<Amf3Value> = Vec::with_capacity(capacity);
+                        for _ in 0..count {
+                            items.push(self.read_value(r)?);
+                        }
+                        Amf3Value::VectorObject { fixed: fixed, type_name: type_name, items: items }
+                    }
+                };
+                Ok(self.end_object(index, v))
+            }
+            0x11 => {
+                let u = read_u29(r)?;
+                if u & 1 == 0 {
+                    return self.object_reference(u >> 1);
+                }
+                let weak_keys = r.read_u8()? != 0;
+                let index = self.start_object();
+                let mut entries: Vec<(Amf3Value, Amf3Value)> = Vec::new();
+                for _ in 0..(u >> 1) {
+                    let k = self.read_value(r)?;
+                    let v = self.read_value(r)?;
+                    entries.push((k, v));
+                }
+                Ok(self.end_object(index, Amf3Value::Dictionary { weak_keys: weak_keys, entries: entries }))
+            }
+            n => Err(FlvError::BadAmfMarker(n)),
+        }
+    }
+
+    fn read_object<R: Read + ?Sized>(&mut self, r: &mut R) -> Result<Amf3Value, FlvError> {
+        let u = read_u29(r)?;
+        if u & 1 == 0 {
+            return self.object_reference(u >> 1);
+        }
+        let traits = if u & 2 == 0 {
+            match self.traits.get((u >> 2) as usize) {
+                Some(traits) => traits.clone(),
+                None => return Err(FlvError::BadAmf3("traits reference out of range")),
+            }
+        } else {
+            let class_name = self.read_string(r)?;
+            let mut sealed_names: Vec<String> = Vec::new();
+            for _ in 0..(u >> 4) {
+                sealed_names.push(self.read_string(r)?);
+            }
+            let traits = Amf3Traits {
+                class_name: class_name,
+                dynamic: u & 8 != 0,
+                externalizable: u & 4 != 0,
+                sealed_names: sealed_names,
+            };
+            self.traits.push(traits.clone());
+            traits
+        };
+        let index = self.start_object();
+        let mut sealed: Vec<Amf3Value> = Vec::new();
+        let mut dynamic: Vec<(String, Amf3Value)> = Vec::new();
+        let mut external: Option<Box<Amf3Value>> = None;
+        if traits.externalizable {
+            // only the class knows how long its body is, nothing after it can be read
+            if !EXTERNALIZABLE_WRAPPERS.contains(&traits.class_name.as_str()) {
+                return Err(FlvError::BadAmf3("unknown externalizable class"));
+            }
+            external = Some(Box::new(self.read_value(r)?));
+        } else {
+            for _ in 0..traits.sealed_names.len() {
+                sealed.push(self.read_value(r)?);
+            }
+            if traits.dynamic {
+                loop {
+                    let key = self.read_string(r)?;
+                    if key.is_empty() {
+                        break;
+                    }
+                    let v = self.read_value(r)?;
+                    dynamic.push((key, v));
+                }
+            }
+        }
+        Ok(self.end_object(index, Amf3Value::Object { traits: traits, sealed: sealed, dynamic: dynamic, external: external }))
+    }
+
+    fn read_string<R: Read + ?Sized>(&mut self, r: &mut R) -> Result<String, FlvError> {
+        let u = read_u29(r)?;
+        if u & 1 == 0 {
+            return match self.strings.get((u >> 1) as usize) {
+                Some(s) => Ok(s.clone()),
+                None => Err(FlvError::BadAmf3("string reference out of range")),
+            };
+        }
+        let s = read_utf8(r, (u >> 1) as usize)?;
+        if !s.is_empty() {
+            self.strings.push(s.clone());
+        }
+        Ok(s)
+    }
+
+    fn object_reference(&self, index: u32) -> Result<Amf3Value, FlvError> {
+        match self.objects.get(index as usize) {
+            Some(&Some(ref v)) => Ok(v.clone()),
+            _ => Err(FlvError::BadAmf3("object reference out of range")),
+        }
+    }
+
+    fn start_object(&mut self) -> usize {
+        self.objects.push(None);
+        self.objects.len() - 1
+    }
+
+    fn end_object(&mut self, index: usize, v: Amf3Value) -> Amf3Value {
+        self.objects[index] = Some(v.clone());
+        v
+    }
+}
+
+/// Writes AMF3 values, repeated strings and traits are written as references.
+/// Complex values are always written inline.
+#[derive(Default)]
+pub struct Amf3Encoder {
+    strings: Vec<String>,
+    traits: Vec<Amf3Traits>,
+}
+
+impl Amf3Encoder {
+    pub fn new() -> Amf3Encoder {
+        Amf3Encoder::default()
+    }
+
+    pub fn write_value<W: Write + ?Sized>(&mut self, w: &mut W, v: &Amf3Value) {
+        match *v {
+            Amf3Value::Undefined => w.write_u8(0x00).unwrap(),
+            Amf3Value::Null => w.write_u8(0x01).unwrap(),
+            Amf3Value::Boolean(false) => w.write_u8(0x02).unwrap(),
+            Amf3Value::Boolean(true) => w.write_u8(0x03).unwrap(),
+            Amf3Value::Integer(i) if i >= INTEGER_MIN && i <= INTEGER_MAX => {
+                w.write_u8(0x04).unwrap();
+                write_u29(w, (i as u32) & 0x1fffffff);
+            }
+            Amf3Value::Integer(i) => {
+                w.write_u8(0x05).unwrap();
+                w.write_f64::<BigEndian>(i as f64).unwrap();
+            }
+            Amf3Value::Double(f) => {
+                w.write_u8(0x05).unwrap();
+                w.write_f64::<BigEndian>(f).unwrap();
+            }
+            Amf3Value::String(ref s) => {
+                w.write_u8(0x06).unwrap();
+                self.write_string(w, s);
+            }
+            Amf3Value::XmlDocument(ref s) | Amf3Value::Xml(ref s) => {
+                w.write_u8(if let Amf3Value::Xml(..) = *v { 0x0B } else { 0x07 }).unwrap();
+                write_u29(w, ((s.len() as u32) << 1) | 1);
+                w.write_all(s.as_bytes()).unwrap();
+            }
+            Amf3Value::Date(ms) => {
+                w.write_u8(0x08).unwrap();
+                write_u29(w, 1);
+                w.write_f64::<BigEndian>(ms).unwrap();
+            }
+            Amf3Value::Array { ref assoc, ref dense } => {
+                w.write_u8(0x09).unwrap();
+                write_u29(w, ((dense.len() as u32) << 1) | 1);
+                for &(ref k, ref v) in assoc.iter() {
+                    self.write_string(w, k);
+                    self.write_value(w, v);
+                }
+                self.write_string(w, "");
+                for v in dense.iter() {
+                    self.write_value(w, v);
+                }
+            }
+            Amf3Value::Object { ref traits, ref sealed, ref dynamic, ref external } => {
+                self.write_object_head(w, traits);
+                if let Some(ref external) = *external {
+                    self.write_value(w, external);
+                } else {
+                    for v in sealed.iter() {
+                        self.write_value(w, v);
+                    }
+                    if traits.dynamic {
+                        for &(ref k, ref v) in dynamic.iter() {
+                            self.write_string(w, k);
+                            self.write_value(w, v);
+                        }
+                        self.write_string(w, "");
+                    }
+                }
+            }
+            Amf3Value::ByteArray(ref bytes) => {
+                w.write_u8(0x0C).unwrap();
+                write_u29(w, ((bytes.len() as u32) << 1) | 1);
+                w.write_all(bytes).unwrap();
+            }
+            Amf3Value::VectorInt { fixed, ref items } => {
+                self.write_vector_head(w, 0x0D, items.len(), fixed);
+                for &i in items.iter() {
+                    w.write_i32::<BigEndian>(i).unwrap();
+                }
+            }
+            Amf3Value::VectorUint { fixed, ref items } => {
+                self.write_vector_head(w, 0x0E, items.len(), fixed);
+                for &u in items.iter() {
+                    w.write_u32::<BigEndian>(u).unwrap();
+                }
+            }
+            Amf3Value::VectorDouble { fixed, ref items } => {
+                self.write_vector_head(w, 0x0F, items.len(), fixed);
+                for &f in items.iter() {
+                    w.write_f64::<BigEndian>(f).unwrap();
+                }
+            }
+            Amf3Value::VectorObject { fixed, ref type_name, ref items } => {
+                self.write_vector_head(w, 0x10, items.len(), fixed);
+                self.write_string(w, type_name);
+                for v in items.iter() {
+                    self.write_value(w, v);
+                }
+            }
+            Amf3Value::Dictionary { weak_keys, ref entries } => {
+                w.write_u8(0x11).unwrap();
+                write_u29(w, ((entries.len() as u32) << 1) | 1);
+                w.write_u8(if weak_keys { 1 } else { 0 }).unwrap();
+                for &(ref k, ref v) in entries.iter() {
+                    self.write_value(w, k);
+                    self.write_value(w, v);
+                }
+            }
+        }
+    }
+
+    fn write_object_head<W: Write + ?Sized>(&mut self, w: &mut W, traits: &Amf3Traits) {
+        w.write_u8(0x0A).unwrap();
+        match self.traits.iter().position(|t| t == traits) {
+            Some(index) => write_u29(w, ((index as u32) << 2) | 0b01),
+            None => {
+                let mut u = ((traits.sealed_names.len() as u32) << 4) | 0b011;
+                if traits.externalizable {
+                    u |= 4;
+                }
+                if traits.dynamic {
+                    u |= 8;
+                }
+                write_u29(w, u);
+                self.write_string(w, &traits.class_name);
+                for name in traits.sealed_names.iter() {
+                    self.write_string(w, name);
+                }
+                self.traits.push(traits.clone());
+            }
+        }
+    }
+
+    fn write_vector_head<W: Write + ?Sized>(&mut self, w: &mut W, marker: u8, len: usize, fixed: bool) {
+        w.write_u8(marker).unwrap();
+        write_u29(w, ((len as u32) << 1) | 1);
+        w.write_u8(if fixed { 1 } else { 0 }).unwrap();
+    }
+
+    fn write_string<W: Write + ?Sized>(&mut self, w: &mut W, s: &str) {
+        if !s.is_empty() {
+            if let Some(index) = self.strings.iter().position(|t| t == s) {
+                write_u29(w, (index as u32) << 1);
+                return;
+            }
+            self.strings.push(s.to_string());
+        }
+        write_u29(w, ((s.len() as u32) << 1) | 1);
+        w.write_all(s.as_bytes()).unwrap();
+    }
+}
+
+pub trait ReadAMF3Ext : ReadBytesExt {
+    /// reads one value with fresh reference tables, use `Amf3Decoder` to share them across values
+    fn try_read_amf3_value(&mut self) -> Result<Amf3Value, FlvError> {
+        Amf3Decoder::new().read_value(self)
+    }
+}
+
+impl<R: Read + ?Sized> ReadAMF3Ext for R {}
+
+pub trait WriteAMF3Ext : WriteBytesExt {
+    fn write_amf3_value(&mut self, v: &Amf3Value) {
+        Amf3Encoder::new().write_value(self, v)
+    }
+}
+
+impl<W: Write + ?Sized> WriteAMF3Ext for W {}
+
+/// variable length unsigned 29 bit integer
+fn read_u29<R: Read + ?Sized>(r: &mut R) -> Result<u32, FlvError> {
+    let mut n: u32 = 0;
+    for _ in 0..3 {
+        let b = r.read_u8()? as u32;
+        n = (n << 7) | (b & 0x7f);
+        if b & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Ok((n << 8) | r.read_u8()? as u32)
+}
+
+fn write_u29<W: Write + ?Sized>(w: &mut W, n: u32) {
+    let n = n & 0x1fffffff;
+    if n < 0x80 {
+        w.write_u8(n as u8).unwrap();
+    } else if n < 0x4000 {
+        w.write_u8((n >> 7 | 0x80) as u8).unwrap();
+        w.write_u8((n & 0x7f) as u8).unwrap();
+    } else if n < 0x200000 {
+        w.write_u8((n >> 14 | 0x80) as u8).unwrap();
+        w.write_u8((n >> 7 & 0x7f | 0x80) as u8).unwrap();
+        w.write_u8((n & 0x7f) as u8).unwrap();
+    } else {
+        w.write_u8((n >> 22 | 0x80) as u8).unwrap();
+        w.write_u8((n >> 15 & 0x7f | 0x80) as u8).unwrap();
+        w.write_u8((n >> 8 & 0x7f | 0x80) as u8).unwrap();
+        w.write_u8((n & 0xff) as u8).unwrap();
+    }
+}
+
+fn read_utf8<R: Read + ?Sized>(r: &mut R, len: usize) -> Result<String, FlvError> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(cmp::min(len, 64 * 1024));
+    if r.take(len as u64).read_to_end(&mut buffer)? != len {
+        return Err(FlvError::UnexpectedEof);
+    }
+    String::from_utf8(buffer).map_err(|_| FlvError::BadAmfString("not valid UTF-8"))
+}
+
+#[test]
+fn test_amf3() {
+    for &n in [0u32, 0x7f, 0x80, 0x3fff, 0x4000, 0x1fffff, 0x200000, 0x1fffffff].iter() {
+        let mut buf: Vec<u8> = Vec::new();
+        write_u29(&mut buf, n);
+        assert_eq!(read_u29(&mut &buf[..]).unwrap(), n);
+    }
+
+    let point = Amf3Traits {
+        class_name: "Point".to_string(),
+        dynamic: true,
+        externalizable: false,
+        sealed_names: vec!["x".to_string(), "y".to_string()],
+    };
+    let v = Amf3Value::Array {
+        assoc: vec![("name".to_string(), Amf3Value::String("name".to_string()))],
+        dense: vec![
+            Amf3Value::Integer(-1),
+            Amf3Value::Integer(1 << 28), // too big, goes out as a double
+            Amf3Value::Date(1.5e12),
+            Amf3Value::Object { traits: point.clone(), sealed: vec![Amf3Value::Integer(1), Amf3Value::Null], dynamic: vec![("z".to_string(), Amf3Value::Boolean(true))], external: None },
+            Amf3Value::Object { traits: point.clone(), sealed: vec![Amf3Value::Integer(2), Amf3Value::Undefined], dynamic: vec![], external: None },
+            Amf3Value::ByteArray(vec![1, 2, 3]),
+            Amf3Value::VectorInt { fixed: false, items: vec![-5, 7] },
+            Amf3Value::VectorObject { fixed: true, type_name: "Point".to_string(), items: vec![Amf3Value::Xml("<a/>".to_string())] },
+            Amf3Value::Dictionary { weak_keys: false, entries: vec![(Amf3Value::Integer(3), Amf3Value::Double(0.5))] },
+        ],
+    };
+    let mut buf: Vec<u8> = Vec::new();
+    buf.write_amf3_value(&v);
+    let v2 = (&mut &buf[..]).try_read_amf3_value().unwrap();
+    match v2 {
+        Amf3Value::Array { ref dense, .. } => assert_eq!(dense[1], Amf3Value::Double((1 << 28) as f64)),
+        _ => panic!("not an array"),
+    }
+    let mut buf2: Vec<u8> = Vec::new();
+    buf2.write_amf3_value(&v2);
+    assert_eq!(buf, buf2);
+
+    // object reference, the second element is the first object again
+    let data = [0x09, 0x05, 0x01, 0x0A, 0x0B, 0x01, 0x03, 0x61, 0x04, 0x01, 0x01, 0x0A, 0x02];
+    let v = (&mut &data[..]).try_read_amf3_value().unwrap();
+    match v {
+        Amf3Value::Array { ref dense, .. } => {
+            assert_eq!(dense[0], dense[1]);
+            assert_eq!(dense[0].to_json().find("a"), Some(&Json::I64(1)));
+        }
+        _ => panic!("not an array"),
+    }
+
+    // AMF0 switching to AMF3
+    use lib::ReadAMF0Ext;
+    assert_eq!((&mut &[0x11, 0x04, 0x05][..]).try_read_amf0_value().unwrap(), Json::I64(5));
+
+    // 100 nested arrays of one element
+    let mut data: Vec<u8> = Vec::new();
+    for _ in 0..100 {
+        data.extend_from_slice(&[0x09, 0x03, 0x01]);
+    }
+    data.push(0x01);
+    match Amf3Decoder::new().read_value(&mut &data[..]) {
+        Err(FlvError::AmfTooDeep) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    assert!(Amf3Decoder::new().read_value(&mut &data[(100 - 10) * 3..]).is_ok());
+
+    // externalizable "Foo" with a body of its own format, its length is unknown
+    let data = [0x0A, 0x07, 0x07, 0x46, 0x6F, 0x6F, 0xDE, 0xAD];
+    match (&mut &data[..]).try_read_amf3_value() {
+        Err(FlvError::BadAmf3("unknown externalizable class")) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    // a string that is not UTF-8
+    match (&mut &[0x06, 0x05, 0xd6, 0xd0][..]).try_read_amf3_value() {
+        Err(FlvError::BadAmfString(..)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
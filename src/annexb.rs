@@ -0,0 +1,448 @@
+use std::io::{Read, Write, Seek};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use lib::{FLVTag, FLVTagRead, FLVTagWrite, FLVTagType, FLVHeader, FlvError, AvcC, CODEC_ID_AVC, CODEC_ID_HEVC};
+use metadata::{FlvMetadata, FlvKeyframes};
+use h264::{self, Sps, Pps, SliceHeader, PicOrderCnt};
+use hevc;
+
+pub const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// access unit delimiters that allow any slice type
+const H264_AUD: [u8; 2] = [0x09, 0xf0];
+const HEVC_AUD: [u8; 3] = [0x46, 0x01, 0x50];
+
+/// Writes the AVC or HEVC video tags of a stream as an Annex-B elementary stream.
+///
+/// The parameter sets of the last sequence header go in front of every IDR (IRAP for HEVC) picture
+/// that does not carry its own, so the output can be cut at any keyframe. A new sequence header in
+/// the middle of the stream replaces them. NALU packets before the first sequence header are dropped.
+pub struct AnnexBWriter<W: Write> {
+    stream: W,
+    codec_id: Option<u8>,
+    parameter_sets: Vec<Vec<u8>>, // VPS, SPS, PPS in decoding order
+    nal_length_size: usize,
+    aud: bool,
+    frame_count: u64,
+}
+
+impl<W: Write> AnnexBWriter<W> {
+    pub fn new(w: W) -> AnnexBWriter<W> {
+        AnnexBWriter::<W> {
+            stream: w,
+            codec_id: None,
+            parameter_sets: Vec::new(),
+            nal_length_size: 4,
+            aud: false,
+            frame_count: 0,
+        }
+    }
+
+    /// start every access unit that has none with an access unit delimiter
+    pub fn with_aud(mut self, aud: bool) -> AnnexBWriter<W> {
+        self.aud = aud;
+        self
+    }
+
+    /// codec of the first sequence header, `None` before it
+    pub fn get_codec_id(&self) -> Option<u8> {
+        self.codec_id
+    }
+
+    /// access units written so far
+    pub fn get_frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Skips audio, script data and the video tags of other codecs.
+    pub fn write_tag<D: AsRef<[u8]>>(&mut self, tag: &FLVTag<D>) -> Result<(), FlvError> {
+        if tag.get_tag_type() != FLVTagType::TAG_TYPE_VIDEO || tag.get_data_size() < 5 || !tag.is_avc_or_hevc() {
+            return Ok(());
+        }
+        let codec_id = tag.get_codec_id();
+        if self.codec_id.map_or(false, |id| id != codec_id) {
+            return Err(FlvError::BadBitstream("video codec changes in the middle of the stream"));
+        }
+        match tag.get_avc_packet_type() {
+            0 => { // AVC_PACKET_TYPE_SEQUENCE_HEADER
+                if codec_id == CODEC_ID_HEVC {
+                    let hvcc = tag.get_hvcc()?;
+                    self.parameter_sets = hvcc.get_vps().into_iter()
+                        .chain(hvcc.get_sps().into_iter())
+                        .chain(hvcc.get_pps().into_iter())
+                        .map(|ps| ps.to_vec())
+                        .collect();
+                } else {
+                    let avcc = tag.try_get_avcc()?;
+                    self.parameter_sets = avcc.sps_array.iter().chain(avcc.pps_array.iter()).cloned().collect();
+                }
+                self.nal_length_size = tag.get_nal_length_size()?;
+                self.codec_id = Some(codec_id);
+                Ok(())
+            }
+            1 if self.codec_id.is_some() => self.write_access_unit(tag), // AVC_PACKET_TYPE_NALU
+            _ => Ok(()),
+        }
+    }
+
+    fn write_access_unit<D: AsRef<[u8]>>(&mut self, tag: &FLVTag<D>) -> Result<(), FlvError> {
+        let hevc = self.codec_id == Some(CODEC_ID_HEVC);
+        let nal_type = |nalu: &[u8]| if hevc { hevc::get_nal_unit_type(nalu[0]) } else { nalu[0] & 0x1f };
+        let nalus: Vec<&[u8]> = tag.nal_units_with_length_size(self.nal_length_size).filter(|nalu| !nalu.is_empty()).collect();
+        if nalus.is_empty() {
+            return Ok(());
+        }
+        let (aud_type, sps_type) = if hevc {
+            (hevc::NAL_UNIT_TYPE_AUD, hevc::NAL_UNIT_TYPE_SPS)
+        } else {
+            (h264::NAL_UNIT_TYPE_AUD, h264::NAL_UNIT_TYPE_SPS)
+        };
+        if self.aud && nal_type(nalus[0]) != aud_type {
+            self.stream.write_all(&START_CODE)?;
+            self.stream.write_all(if hevc { &HEVC_AUD[..] } else { &H264_AUD[..] })?;
+        }
+        let mut parameter_sets_written = nalus.iter().any(|nalu| nal_type(nalu) == sps_type);
+        for nalu in nalus.iter() {
+            let t = nal_type(nalu);
+            let random_access = if hevc { hevc::is_irap(t) } else { t == h264::NAL_UNIT_TYPE_IDR };
+            if random_access && !parameter_sets_written {
+                for ps in self.parameter_sets.iter() {
+                    self.stream.write_all(&START_CODE)?;
+                    self.stream.write_all(ps)?;
+                }
+                parameter_sets_written = true;
+            }
+            self.stream.write_all(&START_CODE)?;
+            self.stream.write_all(nalu)?;
+        }
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.stream
+    }
+}
+
+/// Writes the video of an FLV stream to `w` as Annex-B, returns the writer to read the codec and frame count from.
+pub fn export_annexb<R: Read, W: Write>(r: &mut R, w: W, aud: bool) -> Result<AnnexBWriter<W>, FlvError> {
+    let mut parser = FLVTagRead::try_new(r)?;
+    let mut writer = AnnexBWriter::new(w).with_aud(aud);
+    for tag in parser.try_iter() {
+        writer.write_tag(&tag?)?;
+    }
+    Ok(writer)
+}
+
+/// NAL units of an Annex-B stream, without the start codes and the zero bytes before them
+pub struct AnnexBNalUnits<'a> {
+    data: &'a [u8],
+}
+
+fn find_start_code(data: &[u8]) -> Option<usize> {
+    data.windows(3).position(|w| w == [0, 0, 1])
+}
+
+/// anything before the first start code is skipped
+pub fn annexb_nal_units(data: &[u8]) -> AnnexBNalUnits<'_> {
+    let start = find_start_code(data).map_or(data.len(), |i| i + 3);
+    AnnexBNalUnits { data: &data[start..] }
+}
+
+impl<'a> Iterator for AnnexBNalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        while !self.data.is_empty() {
+            let (mut nalu, rest) = match find_start_code(self.data) {
+                Some(i) => (&self.data[..i], &self.data[(i + 3)..]),
+                None => (self.data, &[][..]),
+            };
+            self.data = rest;
+            while nalu.last() == Some(&0) {
+                nalu = &nalu[..(nalu.len() - 1)];
+            }
+            if !nalu.is_empty() {
+                return Some(nalu);
+            }
+        }
+        None
+    }
+}
+
+/// H.264 access units, by the first NAL unit of a primary coded picture rules (7.4.1.2.3).
+/// Arbitrary slice order is not supported, a picture starts at the slice with first_mb_in_slice 0.
+pub struct AccessUnits<'a> {
+    nalus: ::std::iter::Peekable<AnnexBNalUnits<'a>>,
+}
+
+pub fn annexb_access_units(data: &[u8]) -> AccessUnits<'_> {
+    AccessUnits { nalus: annexb_nal_units(data).peekable() }
+}
+
+impl<'a> Iterator for AccessUnits<'a> {
+    type Item = Vec<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Vec<&'a [u8]>> {
+        let mut au: Vec<&'a [u8]> = Vec::new();
+        let mut has_vcl = false;
+        loop {
+            let starts_next = match self.nalus.peek() {
+                None => break,
+                Some(nalu) => has_vcl && match nalu[0] & 0x1f {
+                    // ue(v) first_mb_in_slice is 0 when its first bit is set
+                    1 | 5 => nalu.len() > 1 && nalu[1] & 0x80 != 0,
+                    6..=9 | 14..=18 => true,
+                    _ => false,
+                },
+            };
+            if starts_next {
+                break;
+            }
+            let nalu = self.nalus.next().unwrap();
+            has_vcl = has_vcl || nalu[0] & 0x1f == h264::NAL_UNIT_TYPE_SLICE || nalu[0] & 0x1f == h264::NAL_UNIT_TYPE_IDR;
+            au.push(nalu);
+        }
+        if au.is_empty() { None } else { Some(au) }
+    }
+}
+
+/// a frame of `import_annexb`, kept until the picture order counts of the stream are known
+struct ImportFrame<'a> {
+    nalus: Vec<&'a [u8]>,
+    keyframe: bool,
+    /// avcC record of the parameter sets that changed before the frame
+    sequence_header: Option<Vec<u8>>,
+    pic_order_cnt: i64,
+}
+
+fn parse_parameter_sets(sps_array: &[Vec<u8>], pps_array: &[Vec<u8>]) -> Result<(Vec<Sps>, Vec<Pps>), FlvError> {
+    let mut sps: Vec<Sps> = Vec::new();
+    for nalu in sps_array.iter() {
+        sps.push(Sps::parse(nalu)?);
+    }
+    let mut pps: Vec<Pps> = Vec::new();
+    for nalu in pps_array.iter() {
+        // the sps only sizes the scaling lists, parse again when the pps refers to another one
+        let mut p = Pps::parse(nalu, &sps[0])?;
+        if let Some(s) = sps.iter().find(|s| s.seq_parameter_set_id == p.seq_parameter_set_id) {
+            p = Pps::parse(nalu, s)?;
+        }
+        pps.push(p);
+    }
+    Ok((sps, pps))
+}
+
+/// Position of each frame in presentation order. The picture order counts start over at every IDR
+/// picture, so the frames are sorted one group of pictures at a time.
+fn presentation_order(frames: &[ImportFrame<'_>]) -> Vec<u64> {
+    let mut order: Vec<u64> = vec![0; frames.len()];
+    let mut start = 0;
+    while start < frames.len() {
+        let mut end = start + 1;
+        while end < frames.len() && !frames[end].keyframe {
+            end += 1;
+        }
+        let mut group: Vec<usize> = (start..end).collect();
+        group.sort_by_key(|&i| (frames[i].pic_order_cnt, i));
+        for (rank, &i) in group.iter().enumerate() {
+            order[i] = (start + rank) as u64;
+        }
+        start = end;
+    }
+    order
+}
+
+fn avc_video_tag(timestamp: u64, keyframe: bool, packet_type: u8, composition_time: u32, payload: &[u8]) -> FLVTag {
+    let mut body: Vec<u8> = Vec::with_capacity(5 + payload.len());
+    body.write_u8(if keyframe { 0x10 } else { 0x20 } | CODEC_ID_AVC).unwrap();
+    body.write_u8(packet_type).unwrap();
+    body.write_all(&[(composition_time >> 16) as u8, (composition_time >> 8) as u8, composition_time as u8]).unwrap();
+    body.write_all(payload).unwrap();
+    FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, timestamp, &body)
+}
+
+/// Muxes an H.264 Annex-B stream into a video only FLV written to a fresh `tag_write`.
+///
+/// The sequence header is built from the in-band SPS and PPS, and written again when they change.
+/// Frames are `frame_rate` apart, or as far as the SPS timing says when it is `None`. Parameter sets
+/// and delimiters are taken out of the frames. Composition times come from the picture order counts,
+/// with the timestamps held back by the deepest reordering of the stream so that none is negative.
+/// Returns the metadata written, with the keyframes table.
+pub fn import_annexb<W: Read + Write + Seek>(data: &[u8], tag_write: &mut FLVTagWrite<W>, frame_rate: Option<f64>) -> Result<FlvMetadata, FlvError> {
+    let sps = match annexb_nal_units(data).find(|nalu| nalu[0] & 0x1f == h264::NAL_UNIT_TYPE_SPS) {
+        Some(nalu) => Sps::parse(nalu)?,
+        None => return Err(FlvError::BadBitstream("no sps in the stream")),
+    };
+    let frame_rate = match frame_rate.or(sps.get_frame_rate()) {
+        Some(fps) if fps > 0.0 => fps,
+        _ => return Err(FlvError::BadBitstream("no frame rate in the sps")),
+    };
+
+    let mut sps_array: Vec<Vec<u8>> = Vec::new();
+    let mut pps_array: Vec<Vec<u8>> = Vec::new();
+    let mut parsed: (Vec<Sps>, Vec<Pps>) = (Vec::new(), Vec::new());
+    let mut parameter_sets_changed = false;
+    let mut pic_order_cnt = PicOrderCnt::new();
+    let mut frames: Vec<ImportFrame<'_>> = Vec::new();
+    for au in annexb_access_units(data) {
+        let in_band = |t: u8| au.iter().filter(|nalu| nalu[0] & 0x1f == t).map(|nalu| nalu.to_vec()).collect::<Vec<Vec<u8>>>();
+        let (sps_in_band, pps_in_band) = (in_band(h264::NAL_UNIT_TYPE_SPS), in_band(h264::NAL_UNIT_TYPE_PPS));
+        if !sps_in_band.is_empty() && sps_in_band != sps_array {
+            sps_array = sps_in_band;
+            if pps_in_band.is_empty() {
+                pps_array.clear();
+            }
+            parameter_sets_changed = true;
+        }
+        if !pps_in_band.is_empty() && pps_in_band != pps_array {
+            pps_array = pps_in_band;
+            parameter_sets_changed = true;
+        }
+
+        let nalus: Vec<&[u8]> = au.into_iter().filter(|nalu| match nalu[0] & 0x1f {
+            h264::NAL_UNIT_TYPE_SPS | h264::NAL_UNIT_TYPE_PPS | h264::NAL_UNIT_TYPE_AUD => false,
+            _ => true,
+        }).collect();
+        let slice = match nalus.iter().find(|nalu| nalu[0] & 0x1f == h264::NAL_UNIT_TYPE_SLICE || nalu[0] & 0x1f == h264::NAL_UNIT_TYPE_IDR) {
+            Some(&nalu) => nalu,
+            None => continue,
+        };
+        if sps_array.is_empty() || pps_array.is_empty() {
+            continue; // not decodable before the first parameter sets
+        }
+
+        let mut sequence_header = None;
+        if parameter_sets_changed {
+            parsed = parse_parameter_sets(&sps_array, &pps_array)?;
+            let mut record: Vec<u8> = Vec::new();
+            AvcC::from_parameter_sets(sps_array.clone(), pps_array.clone())?.write(&mut record);
+            sequence_header = Some(record);
+            parameter_sets_changed = false;
+        }
+        let header = SliceHeader::parse(slice, &parsed.0, &parsed.1)?;
+        let slice_sps = parsed.1.iter().rev().find(|p| p.pic_parameter_set_id == header.pic_parameter_set_id)
+            .and_then(|p| parsed.0.iter().rev().find(|s| s.seq_parameter_set_id == p.seq_parameter_set_id))
+            .unwrap(); // the slice header was parsed with them
+        frames.push(ImportFrame {
+            keyframe: header.is_idr(),
+            pic_order_cnt: pic_order_cnt.next(slice_sps, &header),
+            nalus,
+            sequence_header,
+        });
+    }
+
+    let order = presentation_order(&frames);
+    let delay = order.iter().enumerate().map(|(i, &o)| (i as u64).saturating_sub(o)).max().unwrap_or(0);
+    let mut meta = FlvMetadata::default();
+    meta.duration = Some(frames.len() as f64 / frame_rate);
+    meta.width = Some(sps.get_width() as f64);
+    meta.height = Some(sps.get_height() as f64);
+    meta.framerate = Some(frame_rate);
+    meta.videocodecid = Some(CODEC_ID_AVC.into());
+    tag_write.write_header(&FLVHeader { hasAudioTags: false, hasVideoTags: true });
+    tag_write.write_meta_tag(&FLVTag::new_metadata_tag(&meta))?;
+
+    let mut times: Vec<f64> = Vec::new();
+    let mut filepositions: Vec<u64> = Vec::new();
+    let mut timestamp: u64 = 0;
+    for (i, frame) in frames.iter().enumerate() {
+        timestamp = (i as f64 * 1000.0 / frame_rate).round() as u64;
+        let presentation = ((order[i] + delay) as f64 * 1000.0 / frame_rate).round() as u64;
+        if let Some(ref record) = frame.sequence_header {
+            tag_write.write_tag(&avc_video_tag(timestamp, true, 0, 0, record)); // AVC_PACKET_TYPE_SEQUENCE_HEADER
+        }
+        if frame.keyframe {
+            times.push(timestamp as f64 / 1000.0);
+            filepositions.push(tag_write.get_position());
+        }
+        let mut payload: Vec<u8> = Vec::new();
+        for nalu in frame.nalus.iter() {
+            payload.write_u32::<BigEndian>(nalu.len() as u32).unwrap();
+            payload.write_all(nalu).unwrap();
+        }
+        tag_write.write_tag(&avc_video_tag(timestamp, frame.keyframe, 1, (presentation - timestamp) as u32, &payload)); // AVC_PACKET_TYPE_NALU
+    }
+    if !frames.is_empty() {
+        tag_write.write_tag(&avc_video_tag(timestamp, true, 2, 0, &[])); // AVC_PACKET_TYPE_END_OF_SEQUENCE
+    }
+
+    tag_write.write_meta_tag_relocating(|shift| {
+        meta.keyframes = Some(FlvKeyframes {
+            times: times.clone(),
+            filepositions: filepositions.iter().map(|&p| p + shift).collect(),
+        });
+        FLVTag::new_metadata_tag(&meta)
+    })?;
+    Ok(meta)
+}
+
+#[test]
+fn test_annexb_export() {
+    use std::io::Cursor;
+
+    let mut data: Vec<u8> = b"FLV\x01\x01\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+    // avcC with one SPS and one PPS, 2 byte NAL lengths
+    data.extend_from_slice(&[9, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0x17, 0, 0, 0, 0,
+                             1, 66, 0, 30, 0xfd, 0xe1, 0, 2, 0x67, 0x42, 1, 0, 2, 0x68, 0xce, 0, 0, 0, 31]);
+    // IDR with an SEI in front, then a P frame
+    data.extend_from_slice(&[9, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 0x17, 1, 0, 0, 0, 0, 1, 0x06, 0, 2, 0x65, 0x88, 0, 0, 0, 23]);
+    data.extend_from_slice(&[9, 0, 0, 9, 0, 0, 40, 0, 0, 0, 0, 0x27, 1, 0, 0, 0, 0, 2, 0x41, 0x9a, 0, 0, 0, 20]);
+
+    let writer = export_annexb(&mut Cursor::new(data), Vec::new(), true).unwrap();
+    assert_eq!(writer.get_frame_count(), 2);
+    let expected: Vec<u8> = vec![0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1, 0x06, 0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 1, 0x68, 0xce,
+                                 0, 0, 0, 1, 0x65, 0x88, 0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1, 0x41, 0x9a];
+    assert_eq!(writer.into_inner(), expected);
+}
+
+#[test]
+fn test_annexb_import() {
+    use std::io::Cursor;
+
+    let sps: Vec<u8> = vec![0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00,
+                            0x04, 0x00, 0x00, 0x03, 0x00, 0xf2, 0x3c, 0x22, 0x11, 0x65, 0x80];
+    let mut data: Vec<u8> = vec![0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1];
+    data.extend_from_slice(&sps);
+    // IDR with poc lsb 0, then a P with poc lsb 4 and a non-reference B with poc lsb 2 shown before it
+    data.extend_from_slice(&[0, 0, 1, 0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0, 0, 0, 1, 0x65, 0x88, 0x84, 0x08,
+                             0, 0, 0, 1, 0x09, 0x30, 0, 0, 1, 0x41, 0x9a, 0x22, 0x40, 0, 0, 0, 1, 0x01, 0x9e, 0x41, 0x40]);
+    let nalus: Vec<&[u8]> = annexb_nal_units(&data).collect();
+    assert_eq!(nalus.len(), 7);
+    assert_eq!(annexb_access_units(&data).count(), 3);
+
+    let mut tag_write = FLVTagWrite::new(Cursor::new(Vec::new()));
+    let meta = import_annexb(&data, &mut tag_write, None).unwrap();
+    assert_eq!((meta.width, meta.height, meta.framerate), (Some(1920.0), Some(1080.0), Some(30.0)));
+    assert_eq!(meta.duration, Some(0.1));
+
+    let mut c = tag_write.into_inner();
+    c.set_position(0);
+    let tags: Vec<FLVTag> = FLVTagRead::try_new(&mut c).unwrap().try_iter().map(|t| t.unwrap()).collect();
+    assert_eq!(tags.len(), 6);
+    let keyframes = tags[0].try_get_metadata().unwrap().unwrap().keyframes.unwrap();
+    assert_eq!(keyframes.times, vec![0.0]);
+    assert!(tags[1].is_video_sequence_header());
+    assert_eq!(tags[1].try_get_avcc().unwrap().sps_array, vec![sps.clone()]);
+    assert_eq!(c.get_ref()[keyframes.filepositions[0] as usize + 11], 0x17);
+    // presented a frame late so that the B frame has a composition time of 0
+    assert_eq!(tags[2].get_body(), &[0x17, 1, 0, 0, 33, 0, 0, 0, 4, 0x65, 0x88, 0x84, 0x08][..]);
+    assert_eq!((tags[3].get_frame_type(), tags[3].get_timestamp(), tags[3].get_avc_composition_time_offset()), (2, 33, 67));
+    assert_eq!((tags[4].get_timestamp(), tags[4].get_avc_composition_time_offset()), (67, 0));
+    assert_eq!(tags[5].get_body(), &[0x17, 2, 0, 0, 0][..]);
+
+    // the same parameter sets again make no new sequence header
+    let mut more = data.clone();
+    more.extend_from_slice(&[0, 0, 1]);
+    more.extend_from_slice(&sps);
+    more.extend_from_slice(&[0, 0, 1, 0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0, 0, 0, 1, 0x65, 0x88, 0x84, 0x08]);
+    let mut tag_write = FLVTagWrite::new(Cursor::new(Vec::new()));
+    import_annexb(&more, &mut tag_write, None).unwrap();
+    let mut c = tag_write.into_inner();
+    c.set_position(0);
+    let tags: Vec<FLVTag> = FLVTagRead::try_new(&mut c).unwrap().try_iter().map(|t| t.unwrap()).collect();
+    assert_eq!(tags[1..].iter().filter(|t| t.is_video_sequence_header()).count(), 1);
+    // the second IDR starts a new group of pictures, its poc 0 comes after the B frame
+    assert_eq!((tags[5].get_frame_type(), tags[5].get_timestamp(), tags[5].get_avc_composition_time_offset()), (1, 100, 33));
+}
@@ -0,0 +1,502 @@
+use std::io::{self, SeekFrom};
+use std::cmp;
+use std::pin::Pin;
+use std::future::Future;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncSeek, ReadBuf};
+use futures_core::Stream;
+
+use lib::{FLVHeader, FLVTag, FlvError, PrevTagSizePolicy, MIN_FILE_HEADER_BYTE_COUNT, PREV_TAG_BYTE_COUNT, DEFAULT_META_PADDING, meta_room_tag};
+use demux::{FlvDemuxer, DemuxEvent};
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// `Stream` of the tags of an `AsyncRead`, the async counterpart of `FLVTagRead::try_iter`.
+///
+/// The header is available from `get_header` once the first tag has been yielded.
+pub struct FLVTagAsyncRead<R: AsyncRead + Unpin> {
+    source: R,
+    demuxer: FlvDemuxer,
+    header: Option<FLVHeader>,
+    position: u64,
+    buffer: Vec<u8>,
+    eof: bool,
+    finished: bool,
+}
+
+impl<R: AsyncRead + Unpin> FLVTagAsyncRead<R> {
+    pub fn new(r: R) -> FLVTagAsyncRead<R> {
+        FLVTagAsyncRead {
+            source: r,
+            demuxer: FlvDemuxer::new(),
+            header: None,
+            position: 0,
+            buffer: vec![0; READ_CHUNK_SIZE],
+            eof: false,
+            finished: false,
+        }
+    }
+
+    /// see `FLVTagRead::with_prev_tag_size_policy`
+    pub fn with_prev_tag_size_policy(mut self, policy: PrevTagSizePolicy) -> FLVTagAsyncRead<R> {
+        self.demuxer = self.demuxer.with_prev_tag_size_policy(policy);
+        self
+    }
+
+    /// warnings collected so far under `PrevTagSizePolicy::Warn`, with the offset of the tag
+    pub fn take_warnings(&mut self) -> Vec<(u64, FlvError)> {
+        self.demuxer.take_warnings()
+    }
+
+    pub fn get_header(&self) -> Option<&FLVHeader> {
+        self.header.as_ref()
+    }
+
+    /// offset of the tag yielded last
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for FLVTagAsyncRead<R> {
+    type Item = Result<FLVTag, FlvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<FLVTag, FlvError>>> {
+        let this = self.get_mut();
+        loop {
+            if this.finished {
+                return Poll::Ready(None);
+            }
+            match this.demuxer.next_event() {
+                Ok(Some(DemuxEvent::Header(header))) => {
+                    this.header = Some(header);
+                    continue;
+                }
+                Ok(Some(DemuxEvent::Tag(position, tag))) => {
+                    this.position = position;
+                    return Poll::Ready(Some(Ok(tag)));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    this.finished = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+            if this.eof {
+                this.finished = true;
+                return match this.demuxer.finish() {
+                    Ok(()) if this.header.is_none() => Poll::Ready(Some(Err(FlvError::UnexpectedEof))),
+                    Ok(()) => Poll::Ready(None),
+                    Err(e) => Poll::Ready(Some(Err(e))),
+                };
+            }
+            let read_len = {
+                let mut buf = ReadBuf::new(&mut this.buffer);
+                match Pin::new(&mut this.source).poll_read(cx, &mut buf) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => {
+                        this.finished = true;
+                        return Poll::Ready(Some(Err(FlvError::from(e))));
+                    }
+                    Poll::Ready(Ok(())) => buf.filled().len(),
+                }
+            };
+            if read_len == 0 {
+                this.eof = true;
+            } else {
+                this.demuxer.push(&this.buffer[..read_len]);
+            }
+        }
+    }
+}
+
+/// Async counterpart of `FLVTagWrite`, each method returns a future that writes one piece.
+pub struct FLVTagAsyncWrite<W: AsyncWrite + AsyncSeek + Unpin> {
+    stream: W,
+    position: u64,
+    meta_padding: u32,
+    meta_reserved: Option<u32>, // tag size of the room after the file header
+}
+
+impl<W: AsyncWrite + AsyncSeek + Unpin> FLVTagAsyncWrite<W> {
+    pub fn new(w: W) -> FLVTagAsyncWrite<W> {
+        FLVTagAsyncWrite {
+            stream: w,
+            position: 0,
+            meta_padding: DEFAULT_META_PADDING,
+            meta_reserved: None,
+        }
+    }
+
+    /// see `FLVTagWrite::with_meta_padding`
+    pub fn with_meta_padding(mut self, padding: u32) -> FLVTagAsyncWrite<W> {
+        self.meta_padding = padding;
+        self
+    }
+
+    pub fn write_header(&mut self, header: &FLVHeader) -> WriteFuture<W> {
+        let mut buf: Vec<u8> = Vec::new();
+        header.write(&mut buf);
+        WriteFuture::new(self, buf, false)
+    }
+
+    pub fn write_tag<D: AsRef<[u8]>>(&mut self, tag: &FLVTag<D>) -> WriteFuture<W> {
+        let mut buf: Vec<u8> = Vec::new();
+        tag.write(&mut buf);
+        WriteFuture::new(self, buf, false)
+    }
+
+    /// Writes the metadata tag right after the file header, in the room reserved by the first call
+    /// like `FLVTagWrite::write_meta_tag`. A tag that does not fit is `FlvError::MetaTagOverflow`
+    /// before anything is written.
+    pub fn write_meta_tag<D: AsRef<[u8]>>(&mut self, tag: &FLVTag<D>) -> Result<WriteFuture<W>, FlvError> {
+        let tag = meta_room_tag(tag, self.meta_reserved, self.meta_padding, self.position)?;
+        let mut buf: Vec<u8> = Vec::new();
+        tag.write(&mut buf);
+        Ok(WriteFuture::new(self, buf, true))
+    }
+
+    /// Writes the metadata tag like `FLVTagWrite::write_meta_tag_relocating`, moving the tags after
+    /// the room when it does not fit. The future gives the total move.
+    pub fn write_meta_tag_relocating<F>(&mut self, build: F) -> RelocateFuture<W, F>
+        where W: AsyncRead, F: FnMut(u64) -> FLVTag {
+        RelocateFuture {
+            writer: Some(self),
+            build: build,
+            shift: 0,
+            grow: 0,
+            start: 0,
+            end: 0,
+            len: 0,
+            buf: Vec::new(),
+            state: RelocateState::Build,
+            meta: None,
+        }
+    }
+
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> W {
+        self.stream
+    }
+}
+
+enum WriteState {
+    StartSeek(u64),
+    Seeking,
+    Writing,
+    StartSeekBack,
+    SeekingBack,
+    Done,
+}
+
+pub struct WriteFuture<'b, W: AsyncWrite + AsyncSeek + Unpin + 'b> {
+    writer: &'b mut FLVTagAsyncWrite<W>,
+    buf: Vec<u8>,
+    written: usize,
+    meta: bool,
+    end: u64,
+    state: WriteState,
+}
+
+impl<'b, W: AsyncWrite + AsyncSeek + Unpin + 'b> WriteFuture<'b, W> {
+    fn new(writer: &'b mut FLVTagAsyncWrite<W>, buf: Vec<u8>, meta: bool) -> WriteFuture<'b, W> {
+        let state = if meta {
+            WriteState::StartSeek(MIN_FILE_HEADER_BYTE_COUNT as u64 + PREV_TAG_BYTE_COUNT as u64)
+        } else {
+            WriteState::Writing
+        };
+        let end = writer.position;
+        WriteFuture {
+            writer: writer,
+            buf: buf,
+            written: 0,
+            meta: meta,
+            end: end,
+            state: state,
+        }
+    }
+}
+
+impl<'b, W: AsyncWrite + AsyncSeek + Unpin + 'b> Future for WriteFuture<'b, W> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.state {
+                WriteState::StartSeek(position) => {
+                    Pin::new(&mut this.writer.stream).start_seek(SeekFrom::Start(position))?;
+                    this.end = position;
+                    this.state = WriteState::Seeking;
+                }
+                WriteState::Seeking => {
+                    match Pin::new(&mut this.writer.stream).poll_complete(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(r) => { r?; }
+                    }
+                    this.state = WriteState::Writing;
+                }
+                WriteState::Writing => {
+                    if this.written == this.buf.len() {
+                        this.end += this.buf.len() as u64;
+                        if this.meta {
+                            this.writer.meta_reserved = Some(this.buf.len() as u32 - PREV_TAG_BYTE_COUNT);
+                        }
+                        // position fix, like FLVTagWrite::write_meta_tag
+                        if this.meta && this.writer.position > this.end {
+                            this.state = WriteState::StartSeekBack;
+                        } else {
+                            this.writer.position = this.end;
+                            this.state = WriteState::Done;
+                        }
+                        continue;
+                    }
+                    match Pin::new(&mut this.writer.stream).poll_write(cx, &this.buf[this.written..]) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write tag"))),
+                        Poll::Ready(Ok(n)) => this.written += n,
+                    }
+                }
+                WriteState::StartSeekBack => {
+                    let position = this.writer.position;
+                    Pin::new(&mut this.writer.stream).start_seek(SeekFrom::Start(position))?;
+                    this.state = WriteState::SeekingBack;
+                }
+                WriteState::SeekingBack => {
+                    match Pin::new(&mut this.writer.stream).poll_complete(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(r) => { r?; }
+                    }
+                    this.state = WriteState::Done;
+                }
+                WriteState::Done => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+enum RelocateState {
+    Build,
+    NextChunk,
+    SeekingRead,
+    Reading(usize),
+    SeekingWrite,
+    Writing(usize),
+}
+
+/// Future of `FLVTagAsyncWrite::write_meta_tag_relocating`. The tags after the room are copied from
+/// the end, a chunk at a time, like `FLVTagWrite::grow_meta_room`.
+pub struct RelocateFuture<'b, W: AsyncRead + AsyncWrite + AsyncSeek + Unpin + 'b, F> {
+    writer: Option<&'b mut FLVTagAsyncWrite<W>>,
+    build: F,
+    shift: u64,
+    grow: u32,
+    start: u64,
+    end: u64,
+    len: usize,
+    buf: Vec<u8>,
+    state: RelocateState,
+    meta: Option<WriteFuture<'b, W>>, // the final write, once the tag fits
+}
+
+// never pinned in place, `build` is only called
+impl<'b, W: AsyncRead + AsyncWrite + AsyncSeek + Unpin + 'b, F> Unpin for RelocateFuture<'b, W, F> {}
+
+impl<'b, W, F> Future for RelocateFuture<'b, W, F>
+    where W: AsyncRead + AsyncWrite + AsyncSeek + Unpin + 'b, F: FnMut(u64) -> FLVTag {
+    type Output = Result<u64, FlvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<u64, FlvError>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(ref mut meta) = this.meta {
+                return match Pin::new(meta).poll(cx) {
+                    Poll::Pending => Poll::Pending,
+                    Poll::Ready(r) => Poll::Ready(r.map(|_| this.shift).map_err(FlvError::from)),
+                };
+            }
+            let writer = match this.writer {
+                Some(ref mut writer) => writer,
+                None => return Poll::Ready(Err(FlvError::Io(io::Error::new(io::ErrorKind::Other, "polled after completion")))),
+            };
+            match this.state {
+                RelocateState::Build => {
+                    let tag = (this.build)(this.shift);
+                    let has_room = writer.meta_reserved.is_some();
+                    match meta_room_tag(&tag, writer.meta_reserved, writer.meta_padding, writer.position) {
+                        Ok(tag) => {
+                            let mut buf: Vec<u8> = Vec::new();
+                            tag.write(&mut buf);
+                            let writer = this.writer.take().unwrap();
+                            this.meta = Some(WriteFuture::new(writer, buf, true));
+                        }
+                        Err(FlvError::MetaTagOverflow { reserved, needed }) if has_room && needed > reserved => {
+                            this.grow = needed - reserved;
+                            this.start = (MIN_FILE_HEADER_BYTE_COUNT + PREV_TAG_BYTE_COUNT + reserved + PREV_TAG_BYTE_COUNT) as u64;
+                            this.end = writer.position;
+                            this.state = RelocateState::NextChunk;
+                        }
+                        Err(e) => return Poll::Ready(Err(e)),
+                    }
+                }
+                RelocateState::NextChunk => {
+                    if this.end > this.start {
+                        if this.buf.is_empty() {
+                            this.buf = vec![0; READ_CHUNK_SIZE];
+                        }
+                        // copy from the end, the ranges overlap
+                        this.len = cmp::min(this.buf.len() as u64, this.end - this.start) as usize;
+                        Pin::new(&mut writer.stream).start_seek(SeekFrom::Start(this.end - this.len as u64))?;
+                        this.state = RelocateState::SeekingRead;
+                    } else {
+                        // the room is filled by the tag written at the end
+                        writer.position += this.grow as u64;
+                        writer.meta_reserved = writer.meta_reserved.map(|reserved| reserved + this.grow);
+                        this.shift += this.grow as u64;
+                        this.state = RelocateState::Build;
+                    }
+                }
+                RelocateState::SeekingRead => {
+                    match Pin::new(&mut writer.stream).poll_complete(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(r) => { r?; }
+                    }
+                    this.state = RelocateState::Reading(0);
+                }
+                RelocateState::Reading(filled) => {
+                    if filled == this.len {
+                        Pin::new(&mut writer.stream).start_seek(SeekFrom::Start(this.end - this.len as u64 + this.grow as u64))?;
+                        this.state = RelocateState::SeekingWrite;
+                        continue;
+                    }
+                    let n = {
+                        let mut buf = ReadBuf::new(&mut this.buf[filled..this.len]);
+                        match Pin::new(&mut writer.stream).poll_read(cx, &mut buf) {
+                            Poll::Pending => return Poll::Pending,
+                            Poll::Ready(r) => { r?; }
+                        }
+                        buf.filled().len()
+                    };
+                    if n == 0 {
+                        return Poll::Ready(Err(FlvError::UnexpectedEof));
+                    }
+                    this.state = RelocateState::Reading(filled + n);
+                }
+                RelocateState::SeekingWrite => {
+                    match Pin::new(&mut writer.stream).poll_complete(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(r) => { r?; }
+                    }
+                    this.state = RelocateState::Writing(0);
+                }
+                RelocateState::Writing(written) => {
+                    if written == this.len {
+                        this.end -= this.len as u64;
+                        this.state = RelocateState::NextChunk;
+                        continue;
+                    }
+                    match Pin::new(&mut writer.stream).poll_write(cx, &this.buf[written..this.len]) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(FlvError::from(e))),
+                        Poll::Ready(Ok(0)) => return Poll::Ready(Err(FlvError::Io(io::Error::new(io::ErrorKind::WriteZero, "failed to move tags")))),
+                        Poll::Ready(Ok(n)) => this.state = RelocateState::Writing(written + n),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_async_read_write() {
+    use std::io::Cursor;
+    use std::future::poll_fn;
+    use lib::{FLVTagRead, FLVTagType};
+
+    let mut data: Vec<u8> = b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+    data.extend_from_slice(&[18, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 12]);
+    for &timestamp in [0u8, 40].iter() {
+        data.extend_from_slice(&[8, 0, 0, 2, 0, 0, timestamp, 0, 0, 0, 0, 0xaf, 0x01, 0, 0, 0, 13]);
+    }
+    let rt = ::tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    let mut stream = FLVTagAsyncRead::new(Cursor::new(data.clone()));
+    let mut tags: Vec<FLVTag> = Vec::new();
+    while let Some(tag) = rt.block_on(poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))) {
+        tags.push(tag.unwrap());
+    }
+    assert_eq!(tags.len(), 3);
+    assert_eq!(stream.get_position(), 46);
+    let header = stream.get_header().unwrap().clone();
+
+    let mut writer = FLVTagAsyncWrite::new(Cursor::new(Vec::new())).with_meta_padding(0);
+    rt.block_on(writer.write_header(&header)).unwrap();
+    rt.block_on(writer.write_meta_tag(&tags[0]).unwrap()).unwrap();
+    for tag in tags[1..].iter() {
+        rt.block_on(writer.write_tag(tag)).unwrap();
+    }
+    rt.block_on(writer.write_meta_tag(&tags[0]).unwrap()).unwrap();
+    assert_eq!(writer.get_position(), data.len() as u64);
+    // no room for a bigger one, the audio tags after it are kept
+    let bigger = FLVTag::new(FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT, 0, &[5, 5]);
+    match writer.write_meta_tag(&bigger) {
+        Err(FlvError::MetaTagOverflow { reserved: 12, needed: 13 }) => {},
+        Err(e) => panic!("unexpected {:?}", e),
+        Ok(_) => panic!("bigger metadata written over the tags after it"),
+    }
+    let mut out = writer.into_inner();
+    assert_eq!(out.get_ref(), &data);
+
+    out.set_position(0);
+    assert_eq!(FLVTagRead::try_new(&mut out).unwrap().try_iter().count(), 3);
+
+    // relocating moves the audio tags to make room for it
+    let mut writer = FLVTagAsyncWrite::new(Cursor::new(Vec::new())).with_meta_padding(0);
+    rt.block_on(writer.write_header(&header)).unwrap();
+    rt.block_on(writer.write_meta_tag(&tags[0]).unwrap()).unwrap();
+    for tag in tags[1..].iter() {
+        rt.block_on(writer.write_tag(tag)).unwrap();
+    }
+    let shift = rt.block_on(writer.write_meta_tag_relocating(|_| bigger.clone())).unwrap();
+    assert_eq!((shift, writer.get_position()), (1, data.len() as u64 + 1));
+    let mut out = writer.into_inner();
+    out.set_position(0);
+    let moved: Vec<FLVTag> = FLVTagRead::try_new(&mut out).unwrap().try_iter().map(|t| t.unwrap()).collect();
+    assert_eq!(moved[0].get_body(), bigger.get_body());
+    assert_eq!((moved[1].get_timestamp(), moved[2].get_timestamp()), (0, 40));
+
+    // the default padding leaves room for it
+    let mut writer = FLVTagAsyncWrite::new(Cursor::new(Vec::new()));
+    rt.block_on(writer.write_header(&header)).unwrap();
+    rt.block_on(writer.write_meta_tag(&tags[0]).unwrap()).unwrap();
+    for tag in tags[1..].iter() {
+        rt.block_on(writer.write_tag(tag)).unwrap();
+    }
+    let position = writer.get_position();
+    rt.block_on(writer.write_meta_tag(&bigger).unwrap()).unwrap();
+    assert_eq!(writer.get_position(), position);
+    let mut out = writer.into_inner();
+    out.set_position(0);
+    let tags: Vec<FLVTag> = FLVTagRead::try_new(&mut out).unwrap().try_iter().map(|t| t.unwrap()).collect();
+    assert_eq!(tags[0].get_data_size(), 1 + DEFAULT_META_PADDING);
+    assert_eq!(tags[2].get_timestamp(), 40);
+
+    // the PreviousTagSize of the metadata tag is wrong
+    let mut bad = data.clone();
+    bad[28] = 11;
+    let mut stream = FLVTagAsyncRead::new(Cursor::new(bad)).with_prev_tag_size_policy(PrevTagSizePolicy::Error);
+    match rt.block_on(poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))) {
+        Some(Err(FlvError::PrevTagSizeMismatch { expected: 12, actual: 11 })) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    assert!(rt.block_on(poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))).is_none());
+}
@@ -0,0 +1,752 @@
+use std::io::{self, Write};
+use std::mem;
+
+use lib::{FLVTag, FLVTagType, FlvError};
+use sei::{self, SeiMessage, CcData, CC_TYPE_NTSC_FIELD_1, CC_TYPE_DTVCC_PACKET_DATA, CC_TYPE_DTVCC_PACKET_START};
+
+/// caption text on screen from `start` to `end`, in milliseconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptionCue {
+    pub start: u64,
+    pub end: u64,
+    pub text: String,
+}
+
+/// turns screen changes into cues
+struct CueBuilder {
+    cues: Vec<CaptionCue>,
+    text: String,
+    start: u64,
+}
+
+impl CueBuilder {
+    fn new() -> CueBuilder {
+        CueBuilder { cues: Vec::new(), text: String::new(), start: 0 }
+    }
+
+    fn update(&mut self, time: u64, text: String) {
+        if text == self.text {
+            return;
+        }
+        let old = mem::replace(&mut self.text, text);
+        if !old.is_empty() && time > self.start {
+            self.cues.push(CaptionCue { start: self.start, end: time, text: old });
+        }
+        self.start = time;
+    }
+
+    fn finish(mut self, time: u64) -> Vec<CaptionCue> {
+        self.update(time, String::new());
+        self.cues
+    }
+}
+
+const CEA608_ROWS: usize = 15;
+const CEA608_COLUMNS: usize = 32;
+
+// 0x11 0x30-0x3f, 0x39 is the transparent space
+const CEA608_SPECIAL_CHARS: &'static str = "®°½¿™¢£♪à èâêîôû";
+// 0x12 and 0x13 0x20-0x3f, each replaces the char before it
+const CEA608_EXTENDED_CHARS_12: &'static str = "ÁÉÓÚÜü‘¡*'—©℠•“”ÀÂÇÈÊËëÎÏïÔÙùÛ«»";
+const CEA608_EXTENDED_CHARS_13: &'static str = "ÃãÍÌìÒòÕõ{}\\^_|~ÄäÖöß¥¤¦ÅåØø┌┐└┘";
+
+fn cea608_basic_char(b: u8) -> char {
+    match b {
+        0x2a => 'á',
+        0x5c => 'é',
+        0x5e => 'í',
+        0x5f => 'ó',
+        0x60 => 'ú',
+        0x7b => 'ç',
+        0x7c => '÷',
+        0x7d => 'Ñ',
+        0x7e => 'ñ',
+        0x7f => '█',
+        b => b as char,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cea608Mode {
+    PopOn,
+    RollUp(usize),
+    PaintOn,
+    Text,
+}
+
+#[derive(Clone)]
+struct Cea608Memory {
+    rows: Vec<Vec<char>>,
+}
+
+impl Cea608Memory {
+    fn new() -> Cea608Memory {
+        Cea608Memory { rows: vec![vec![' '; CEA608_COLUMNS]; CEA608_ROWS] }
+    }
+
+    fn clear(&mut self) {
+        *self = Cea608Memory::new();
+    }
+
+    fn get_text(&self) -> String {
+        let rows: Vec<String> = self.rows.iter()
+            .map(|row| row.iter().collect::<String>().trim().to_string())
+            .filter(|row| !row.is_empty())
+            .collect();
+        rows.join("\n")
+    }
+}
+
+/// CEA-608 decoder of one data channel of field 1, CC1 or CC2
+pub struct Cea608Decoder {
+    channel: u8,
+    current_channel: u8,
+    mode: Cea608Mode,
+    displayed: Cea608Memory,
+    non_displayed: Cea608Memory,
+    row: usize,
+    column: usize,
+    last_control: Option<(u8, u8)>,
+    cues: CueBuilder,
+}
+
+impl Cea608Decoder {
+    /// `channel` is 1 or 2
+    pub fn new(channel: u8) -> Cea608Decoder {
+        Cea608Decoder {
+            channel: channel,
+            current_channel: 1,
+            mode: Cea608Mode::PopOn,
+            displayed: Cea608Memory::new(),
+            non_displayed: Cea608Memory::new(),
+            row: CEA608_ROWS - 1,
+            column: 0,
+            last_control: None,
+            cues: CueBuilder::new(),
+        }
+    }
+
+    fn memory(&mut self) -> &mut Cea608Memory {
+        match self.mode {
+            Cea608Mode::PopOn => &mut self.non_displayed,
+            _ => &mut self.displayed,
+        }
+    }
+
+    fn write_char(&mut self, c: char) {
+        if self.mode == Cea608Mode::Text {
+            return;
+        }
+        let (row, column) = (self.row, self.column);
+        self.memory().rows[row][column] = c;
+        self.column = if column + 1 < CEA608_COLUMNS { column + 1 } else { column };
+    }
+
+    fn backspace(&mut self) {
+        if self.column > 0 {
+            self.column -= 1;
+            let (row, column) = (self.row, self.column);
+            self.memory().rows[row][column] = ' ';
+        }
+    }
+
+    fn roll_up(&mut self) {
+        let depth = match self.mode {
+            Cea608Mode::RollUp(depth) => depth,
+            _ => return,
+        };
+        let top = (self.row + 1).saturating_sub(depth);
+        for row in 0..CEA608_ROWS {
+            if row < top || row > self.row {
+                self.displayed.rows[row] = vec![' '; CEA608_COLUMNS];
+            }
+        }
+        for row in top..self.row {
+            self.displayed.rows[row] = self.displayed.rows[row + 1].clone();
+        }
+        self.displayed.rows[self.row] = vec![' '; CEA608_COLUMNS];
+        self.column = 0;
+    }
+
+    fn misc_control(&mut self, code: u8) {
+        match code {
+            0x20 => self.mode = Cea608Mode::PopOn, // RCL
+            0x21 => self.backspace(), // BS
+            0x24 => { // DER
+                let (row, column) = (self.row, self.column);
+                for c in self.memory().rows[row][column..].iter_mut() {
+                    *c = ' ';
+                }
+            }
+            0x25..=0x27 => { // RU2, RU3, RU4
+                match self.mode {
+                    Cea608Mode::RollUp(_) => {},
+                    _ => {
+                        self.displayed.clear();
+                        self.non_displayed.clear();
+                        self.row = CEA608_ROWS - 1;
+                    }
+                }
+                self.mode = Cea608Mode::RollUp((code - 0x23) as usize);
+                self.column = 0;
+            }
+            0x29 => self.mode = Cea608Mode::PaintOn, // RDC
+            0x2a | 0x2b => self.mode = Cea608Mode::Text, // TR, RTD
+            0x2c => self.displayed.clear(), // EDM
+            0x2d => self.roll_up(), // CR
+            0x2e => self.non_displayed.clear(), // ENM
+            0x2f => { // EOC
+                mem::swap(&mut self.displayed, &mut self.non_displayed);
+                self.mode = Cea608Mode::PopOn;
+            }
+            _ => {},
+        }
+    }
+
+    /// preamble address code, the row and the indent
+    fn preamble(&mut self, b1: u8, b2: u8) {
+        let row = match b1 {
+            0x11 => 1,
+            0x12 => 3,
+            0x15 => 5,
+            0x16 => 7,
+            0x17 => 9,
+            0x10 => 11,
+            0x13 => 12,
+            _ => 14,
+        } + if b1 != 0x10 && b2 & 0x20 != 0 { 1 } else { 0 };
+        let row = row - 1;
+        if let Cea608Mode::RollUp(_) = self.mode {
+            // the rows move along with the base row
+            if row != self.row {
+                let rows = self.displayed.rows.clone();
+                self.displayed.clear();
+                for i in 0..CEA608_ROWS {
+                    if i + row >= self.row && i + row < CEA608_ROWS + self.row {
+                        self.displayed.rows[i + row - self.row] = rows[i].clone();
+                    }
+                }
+            }
+        }
+        self.row = row;
+        self.column = if b2 & 0x10 != 0 { ((b2 & 0x0e) as usize >> 1) * 4 } else { 0 };
+    }
+
+    /// a byte pair of field 1 shown at `time`
+    pub fn push(&mut self, time: u64, data: [u8; 2]) {
+        let (b1, b2) = (data[0] & 0x7f, data[1] & 0x7f);
+        if b1 >= 0x10 && b1 <= 0x1f {
+            // control codes are sent twice
+            if self.last_control == Some((b1, b2)) {
+                self.last_control = None;
+                return;
+            }
+            self.last_control = Some((b1, b2));
+            self.current_channel = if b1 & 0x08 != 0 { 2 } else { 1 };
+            if self.current_channel != self.channel {
+                return;
+            }
+            let c = b1 & 0x77;
+            match (c, b2) {
+                (0x11, 0x20..=0x2f) => self.write_char(' '), // mid-row code
+                (0x11, 0x30..=0x3f) => {
+                    let special = CEA608_SPECIAL_CHARS.chars().nth((b2 - 0x30) as usize).unwrap();
+                    self.write_char(special);
+                }
+                (0x12, 0x20..=0x3f) | (0x13, 0x20..=0x3f) => {
+                    let chars = if c == 0x12 { CEA608_EXTENDED_CHARS_12 } else { CEA608_EXTENDED_CHARS_13 };
+                    self.backspace();
+                    self.write_char(chars.chars().nth((b2 - 0x20) as usize).unwrap());
+                }
+                (0x14, 0x20..=0x2f) => self.misc_control(b2),
+                (0x17, 0x21..=0x23) => { // tab offsets
+                    for _ in 0..(b2 - 0x20) {
+                        self.write_char(' ');
+                    }
+                }
+                (_, 0x40..=0x7f) => self.preamble(c, b2),
+                _ => {},
+            }
+        } else {
+            self.last_control = None;
+            if b1 < 0x20 || self.current_channel != self.channel {
+                return; // padding or XDS
+            }
+            self.write_char(cea608_basic_char(b1));
+            if b2 >= 0x20 {
+                self.write_char(cea608_basic_char(b2));
+            }
+        }
+        let text = self.displayed.get_text();
+        self.cues.update(time, text);
+    }
+
+    pub fn finish(self, time: u64) -> Vec<CaptionCue> {
+        self.cues.finish(time)
+    }
+}
+
+#[derive(Clone)]
+struct Cea708Window {
+    defined: bool,
+    visible: bool,
+    row_count: usize,
+    rows: Vec<String>,
+    row: usize,
+}
+
+impl Cea708Window {
+    fn new() -> Cea708Window {
+        Cea708Window { defined: false, visible: false, row_count: 15, rows: vec![String::new()], row: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.rows = vec![String::new()];
+        self.row = 0;
+    }
+
+    fn write_char(&mut self, c: char) {
+        self.rows[self.row].push(c);
+    }
+
+    fn backspace(&mut self) {
+        self.rows[self.row].pop();
+    }
+
+    fn set_row(&mut self, row: usize) {
+        while self.rows.len() <= row {
+            self.rows.push(String::new());
+        }
+        self.row = row;
+    }
+
+    fn clear_row(&mut self) {
+        self.rows[self.row].clear();
+    }
+
+    fn carriage_return(&mut self) {
+        if self.row + 1 >= self.row_count {
+            self.rows.remove(0);
+            self.rows.push(String::new());
+        } else {
+            let row = self.row + 1;
+            self.set_row(row);
+        }
+    }
+
+    fn get_text(&self) -> String {
+        let rows: Vec<&str> = self.rows.iter().map(|row| row.trim()).filter(|row| !row.is_empty()).collect();
+        rows.join("\n")
+    }
+}
+
+fn cea708_g2_char(b: u8) -> Option<char> {
+    Some(match b {
+        0x20 | 0x21 => ' ',
+        0x25 => '…',
+        0x2a => 'Š',
+        0x2c => 'Œ',
+        0x30 => '█',
+        0x31 => '‘',
+        0x32 => '’',
+        0x33 => '“',
+        0x34 => '”',
+        0x35 => '•',
+        0x39 => '™',
+        0x3a => 'š',
+        0x3c => 'œ',
+        0x3d => '℠',
+        0x3f => 'Ÿ',
+        0x76 => '⅛',
+        0x77 => '⅜',
+        0x78 => '⅝',
+        0x79 => '⅞',
+        0x7a => '│',
+        0x7b => '┐',
+        0x7c => '└',
+        0x7d => '─',
+        0x7e => '┘',
+        0x7f => '┌',
+        _ => return None,
+    })
+}
+
+/// CEA-708 decoder of one caption service, text of the visible windows only, no pen or window styles
+pub struct Cea708Decoder {
+    service: u8,
+    packet: Vec<u8>,
+    windows: Vec<Cea708Window>,
+    current_window: usize,
+    cues: CueBuilder,
+}
+
+impl Cea708Decoder {
+    /// `service` is 1 to 63, 1 is the primary caption service
+    pub fn new(service: u8) -> Cea708Decoder {
+        Cea708Decoder {
+            service: service,
+            packet: Vec::new(),
+            windows: vec![Cea708Window::new(); 8],
+            current_window: 0,
+            cues: CueBuilder::new(),
+        }
+    }
+
+    /// DTVCC cc_data shown at `time`, other cc_types are ignored
+    pub fn push(&mut self, time: u64, cc: &CcData) {
+        if !cc.cc_valid {
+            return;
+        }
+        match cc.cc_type {
+            CC_TYPE_DTVCC_PACKET_START => {
+                self.packet.clear();
+                self.packet.extend_from_slice(&cc.data);
+            }
+            CC_TYPE_DTVCC_PACKET_DATA if !self.packet.is_empty() => self.packet.extend_from_slice(&cc.data),
+            _ => return,
+        }
+        let packet_size = match self.packet[0] & 0x3f {
+            0 => 128,
+            code => code as usize * 2,
+        };
+        if self.packet.len() >= packet_size {
+            let packet = mem::take(&mut self.packet);
+            self.decode_packet(&packet[1..packet_size]);
+            let text = self.get_text();
+            self.cues.update(time, text);
+        }
+    }
+
+    fn decode_packet(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let mut service = data[0] >> 5;
+            let block_size = (data[0] & 0x1f) as usize;
+            let mut header_size = 1;
+            if service == 0 {
+                return; // null block, padding to the end
+            }
+            if service == 7 {
+                match data.get(1) {
+                    Some(&b) => service = b & 0x3f,
+                    None => return,
+                }
+                header_size = 2;
+            }
+            if data.len() < header_size + block_size {
+                return;
+            }
+            if service == self.service {
+                self.decode_block(&data[header_size..(header_size + block_size)]);
+            }
+            data = &data[(header_size + block_size)..];
+        }
+    }
+
+    fn for_windows<F: FnMut(&mut Cea708Window)>(&mut self, bitmap: u8, mut f: F) {
+        for (i, window) in self.windows.iter_mut().enumerate() {
+            if bitmap & (1 << i) != 0 {
+                f(window);
+            }
+        }
+    }
+
+    fn decode_block(&mut self, data: &[u8]) {
+        let mut i = 0;
+        while i < data.len() {
+            let c = data[i];
+            let params = |n: usize| if i + n < data.len() { Some(&data[(i + 1)..(i + 1 + n)]) } else { None };
+            let window = self.current_window;
+            let size = match c {
+                0x08 => { self.windows[window].backspace(); 1 } // BS
+                0x0c => { self.windows[window].clear(); 1 } // FF
+                0x0d => { self.windows[window].carriage_return(); 1 } // CR
+                0x0e => { self.windows[window].clear_row(); 1 } // HCR
+                0x10 => { // EXT1, the extended code sets
+                    let e = match params(1) {
+                        Some(p) => p[0],
+                        None => return,
+                    };
+                    match e {
+                        0x00..=0x07 => 2,
+                        0x08..=0x0f => 3,
+                        0x10..=0x17 => 4,
+                        0x18..=0x1f => 5,
+                        0x20..=0x7f => {
+                            if let Some(c) = cea708_g2_char(e) {
+                                self.windows[window].write_char(c);
+                            }
+                            2
+                        }
+                        0x80..=0x87 => 6,
+                        0x88..=0x8f => 7,
+                        0x90..=0x9f => return, // variable length, not used by the caption services
+                        _ => 2, // G3, the [CC] icon
+                    }
+                }
+                0x11..=0x17 => 2,
+                0x18..=0x1f => 3,
+                0x00..=0x0f => 1, // NUL, ETX and the reserved ones
+                0x20..=0x7f => {
+                    self.windows[window].write_char(if c == 0x7f { '♪' } else { c as char });
+                    1
+                }
+                0x80..=0x87 => { self.current_window = (c & 0x07) as usize; 1 } // CWx
+                0x88..=0x8c => { // CLW, DSW, HDW, TGW, DLW
+                    let bitmap = match params(1) {
+                        Some(p) => p[0],
+                        None => return,
+                    };
+                    match c {
+                        0x88 => self.for_windows(bitmap, |w| w.clear()),
+                        0x89 => self.for_windows(bitmap, |w| w.visible = true),
+                        0x8a => self.for_windows(bitmap, |w| w.visible = false),
+                        0x8b => self.for_windows(bitmap, |w| w.visible = !w.visible),
+                        _ => self.for_windows(bitmap, |w| *w = Cea708Window::new()),
+                    }
+                    2
+                }
+                0x8d => 2, // DLY
+                0x8e => 1, // DLC
+                0x8f => { // RST
+                    self.windows = vec![Cea708Window::new(); 8];
+                    1
+                }
+                0x90 => 3, // SPA
+                0x91 => 4, // SPC
+                0x92 => { // SPL
+                    match params(2) {
+                        Some(p) => self.windows[window].set_row((p[0] & 0x0f) as usize),
+                        None => return,
+                    }
+                    3
+                }
+                0x97 => 5, // SWA
+                0x98..=0x9f => { // DFx
+                    let p = match params(6) {
+                        Some(p) => p,
+                        None => return,
+                    };
+                    let window = &mut self.windows[(c & 0x07) as usize];
+                    if !window.defined {
+                        window.clear();
+                    }
+                    window.defined = true;
+                    window.visible = p[0] & 0x20 != 0;
+                    window.row_count = (p[3] & 0x0f) as usize + 1;
+                    self.current_window = (c & 0x07) as usize;
+                    7
+                }
+                0x93..=0x96 => 1,
+                _ => { // G1, Latin-1
+                    self.windows[window].write_char(c as char);
+                    1
+                }
+            };
+            i += size;
+        }
+    }
+
+    fn get_text(&self) -> String {
+        let texts: Vec<String> = self.windows.iter()
+            .filter(|w| w.defined && w.visible)
+            .map(|w| w.get_text())
+            .filter(|text| !text.is_empty())
+            .collect();
+        texts.join("\n")
+    }
+
+    pub fn finish(self, time: u64) -> Vec<CaptionCue> {
+        self.cues.finish(time)
+    }
+}
+
+/// Collects the A/53 caption data of AVC/HEVC video tags. Captions are sent in decoding order
+/// and shown in presentation order, so the frames are sorted by timestamp + cts before decoding.
+pub struct CaptionExtractor {
+    length_size: usize,
+    frames: Vec<(i64, Vec<CcData>)>,
+    end: i64,
+    warnings: Vec<(u64, FlvError)>,
+}
+
+impl CaptionExtractor {
+    pub fn new() -> CaptionExtractor {
+        CaptionExtractor { length_size: 4, frames: Vec::new(), end: 0, warnings: Vec::new() }
+    }
+
+    /// SEI NAL units that could not be parsed and were skipped, with the timestamp of their tag
+    pub fn take_warnings(&mut self) -> Vec<(u64, FlvError)> {
+        mem::take(&mut self.warnings)
+    }
+
+    pub fn push_tag<D: AsRef<[u8]>>(&mut self, tag: &FLVTag<D>) -> Result<(), FlvError> {
+        if tag.get_tag_type() != FLVTagType::TAG_TYPE_VIDEO || tag.get_data_size() == 0 || !tag.is_avc_or_hevc() {
+            return Ok(());
+        }
+        if tag.is_video_sequence_header() {
+            self.length_size = tag.get_nal_length_size()?;
+            return Ok(());
+        }
+        if tag.get_avc_packet_type() != 1 {
+            return Ok(());
+        }
+        let pts = tag.get_timestamp() as i64 + tag.get_avc_composition_time_offset() as i64;
+        if pts > self.end {
+            self.end = pts;
+        }
+        let codec_id = tag.get_codec_id();
+        let mut cc_data: Vec<CcData> = Vec::new();
+        for nalu in tag.nal_units_with_length_size(self.length_size) {
+            if nalu.is_empty() || !sei::is_sei_nal_unit(nalu[0], codec_id) {
+                continue;
+            }
+            match SeiMessage::parse_nal_unit(nalu, codec_id) {
+                Ok(messages) => cc_data.extend(messages.iter().filter_map(|m| m.get_cc_data()).flatten()),
+                Err(e) => self.warnings.push((tag.get_timestamp(), e)),
+            }
+        }
+        if !cc_data.is_empty() {
+            self.frames.push((pts, cc_data));
+        }
+        Ok(())
+    }
+
+    fn sorted_frames(&self) -> Vec<&(i64, Vec<CcData>)> {
+        let mut frames: Vec<&(i64, Vec<CcData>)> = self.frames.iter().collect();
+        frames.sort_by_key(|f| f.0);
+        frames
+    }
+
+    /// CEA-608 captions of CC1 or CC2
+    pub fn decode_cea608(&self, channel: u8) -> Vec<CaptionCue> {
+        let mut decoder = Cea608Decoder::new(channel);
+        for &&(pts, ref cc_data) in self.sorted_frames().iter() {
+            for cc in cc_data.iter().filter(|cc| cc.cc_valid && cc.cc_type == CC_TYPE_NTSC_FIELD_1) {
+                decoder.push(pts.max(0) as u64, cc.data);
+            }
+        }
+        decoder.finish(self.end.max(0) as u64)
+    }
+
+    /// CEA-708 captions of a service
+    pub fn decode_cea708(&self, service: u8) -> Vec<CaptionCue> {
+        let mut decoder = Cea708Decoder::new(service);
+        for &&(pts, ref cc_data) in self.sorted_frames().iter() {
+            for cc in cc_data.iter() {
+                decoder.push(pts.max(0) as u64, cc);
+            }
+        }
+        decoder.finish(self.end.max(0) as u64)
+    }
+}
+
+impl Default for CaptionExtractor {
+    fn default() -> CaptionExtractor {
+        CaptionExtractor::new()
+    }
+}
+
+fn format_time(ms: u64, separator: char) -> String {
+    format!("{:02}:{:02}:{:02}{}{:03}", ms / 3600000, ms / 60000 % 60, ms / 1000 % 60, separator, ms % 1000)
+}
+
+pub fn write_srt<W: Write>(cues: &[CaptionCue], w: &mut W) -> io::Result<()> {
+    for (i, cue) in cues.iter().enumerate() {
+        write!(w, "{}\n{} --> {}\n{}\n\n", i + 1, format_time(cue.start, ','), format_time(cue.end, ','), cue.text)?;
+    }
+    Ok(())
+}
+
+pub fn write_webvtt<W: Write>(cues: &[CaptionCue], w: &mut W) -> io::Result<()> {
+    write!(w, "WEBVTT\n\n")?;
+    for cue in cues.iter() {
+        write!(w, "{} --> {}\n{}\n\n", format_time(cue.start, '.'), format_time(cue.end, '.'), cue.text)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_cea608() {
+    // with odd parity like on the wire
+    fn parity(b: u8) -> u8 {
+        if b.count_ones() % 2 == 0 { b | 0x80 } else { b }
+    }
+    let pairs: Vec<(u64, [u8; 2])> = vec![
+        (0, [0x14, 0x20]), (0, [0x14, 0x20]), // RCL twice
+        (33, [0x14, 0x70]), // PAC row 15
+        (67, [b'H', b'i']), (100, [0x11, 0x37]), // "Hi♪"
+        (133, [0x14, 0x2f]), (133, [0x14, 0x2f]), // EOC
+        (1000, [0x14, 0x2c]), // EDM
+        (1500, [0x14, 0x25]), // RU2
+        (1533, [b'a', 0]), (1567, [0x14, 0x2d]), (1600, [b'b', 0]),
+        (2000, [0x1c, 0x2c]), // EDM of CC2
+    ];
+    let mut decoder = Cea608Decoder::new(1);
+    for &(time, data) in pairs.iter() {
+        decoder.push(time, [parity(data[0]), parity(data[1])]);
+    }
+    let cues = decoder.finish(3000);
+    assert_eq!(cues, vec![
+        CaptionCue { start: 133, end: 1000, text: "Hi♪".to_string() },
+        CaptionCue { start: 1533, end: 1600, text: "a".to_string() },
+        CaptionCue { start: 1600, end: 3000, text: "a\nb".to_string() },
+    ]);
+
+    let mut srt: Vec<u8> = Vec::new();
+    write_srt(&cues[..1], &mut srt).unwrap();
+    assert_eq!(String::from_utf8(srt).unwrap(), "1\n00:00:00,133 --> 00:00:01,000\nHi♪\n\n");
+    let mut vtt: Vec<u8> = Vec::new();
+    write_webvtt(&cues[..1], &mut vtt).unwrap();
+    assert_eq!(String::from_utf8(vtt).unwrap(), "WEBVTT\n\n00:00:00.133 --> 00:00:01.000\nHi♪\n\n");
+}
+
+#[test]
+fn test_extractor_bad_sei() {
+    // an SEI with a payload past its end, then A/53 captions in the next one
+    let bad: Vec<u8> = vec![0x06, 0x04, 0x20, 0x00];
+    let good: Vec<u8> = vec![0x06, 0x04, 0x11, 0xb5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03, 0x42, 0xff,
+                             0xfc, 0x94, 0x2c, 0xff, 0x02, 0x21, 0xff, 0x80];
+    let mut data: Vec<u8> = vec![0x27, 1, 0, 0, 0];
+    for nalu in [&bad, &good].iter() {
+        data.extend_from_slice(&[0, 0, 0, nalu.len() as u8]);
+        data.extend_from_slice(nalu);
+    }
+    let mut extractor = CaptionExtractor::new();
+    extractor.push_tag(&FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 40, &data)).unwrap();
+    assert_eq!(extractor.frames.len(), 1);
+    assert_eq!(extractor.frames[0].1.len(), 2);
+    assert_eq!(extractor.take_warnings().iter().map(|&(t, _)| t).collect::<Vec<u64>>(), vec![40]);
+}
+
+#[test]
+fn test_cea708() {
+    // DF0 visible with 2 rows, "Hi", CR, "there", then HDW
+    let block: Vec<u8> = vec![0x98, 0x38, 0, 0, 0x01, 0, 0, b'H', b'i', 0x0d, b't', b'h', b'e', b'r', b'e'];
+    let mut packet: Vec<u8> = vec![0x00, (1 << 5) | block.len() as u8];
+    packet.extend_from_slice(&block);
+    packet.push(0);
+    packet[0] = (packet.len() / 2) as u8;
+    let mut decoder = Cea708Decoder::new(1);
+    for (i, pair) in packet.chunks(2).enumerate() {
+        let cc_type = if i == 0 { CC_TYPE_DTVCC_PACKET_START } else { CC_TYPE_DTVCC_PACKET_DATA };
+        decoder.push(100, &CcData { cc_valid: true, cc_type: cc_type, data: [pair[0], pair[1]] });
+    }
+    let hide: Vec<u8> = vec![0x02, (1 << 5) | 2, 0x8a, 0x01];
+    for (i, pair) in hide.chunks(2).enumerate() {
+        let cc_type = if i == 0 { CC_TYPE_DTVCC_PACKET_START } else { CC_TYPE_DTVCC_PACKET_DATA };
+        decoder.push(900, &CcData { cc_valid: true, cc_type: cc_type, data: [pair[0], pair[1]] });
+    }
+    assert_eq!(decoder.finish(1000), vec![CaptionCue { start: 100, end: 900, text: "Hi\nthere".to_string() }]);
+
+    // DLY takes a parameter, DLC takes none and the text right after it is kept
+    let block: Vec<u8> = vec![0x98, 0x38, 0, 0, 0x01, 0, 0, 0x8d, 0x05, 0x8e, b'H', b'i'];
+    let mut packet: Vec<u8> = vec![0x00, (1 << 5) | block.len() as u8];
+    packet.extend_from_slice(&block);
+    packet[0] = (packet.len() / 2) as u8;
+    let mut decoder = Cea708Decoder::new(1);
+    for (i, pair) in packet.chunks(2).enumerate() {
+        let cc_type = if i == 0 { CC_TYPE_DTVCC_PACKET_START } else { CC_TYPE_DTVCC_PACKET_DATA };
+        decoder.push(100, &CcData { cc_valid: true, cc_type: cc_type, data: [pair[0], pair[1]] });
+    }
+    assert_eq!(decoder.finish(1000), vec![CaptionCue { start: 100, end: 1000, text: "Hi".to_string() }]);
+}
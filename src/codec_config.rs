@@ -0,0 +1,385 @@
+use std::io::Write;
+
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use lib::FlvError;
+
+fn read_vec(r: &mut &[u8], len: usize) -> Result<Vec<u8>, FlvError> {
+    if r.len() < len {
+        return Err(FlvError::UnexpectedEof);
+    }
+    let v = r[..len].to_vec();
+    *r = &r[len..];
+    Ok(v)
+}
+
+/// AV1CodecConfigurationRecord, the SequenceStart of `av01`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Av1C {
+    pub version: u8,
+    pub seq_profile: u8,
+    pub seq_level_idx_0: u8,
+    pub seq_tier_0: bool,
+    pub high_bitdepth: bool,
+    pub twelve_bit: bool,
+    pub monochrome: bool,
+    pub chroma_subsampling_x: bool,
+    pub chroma_subsampling_y: bool,
+    pub chroma_sample_position: u8,
+    pub initial_presentation_delay_minus_one: Option<u8>,
+    /// sequence header and metadata OBUs
+    pub config_obus: Vec<u8>,
+}
+
+impl Av1C {
+    pub fn parse(mut data: &[u8]) -> Result<Av1C, FlvError> {
+        let r = &mut data;
+        let b0 = r.read_u8()?;
+        if b0 & 0x80 == 0 {
+            return Err(FlvError::BadBitstream("av1C marker bit is not set"));
+        }
+        let b1 = r.read_u8()?;
+        let b2 = r.read_u8()?;
+        let b3 = r.read_u8()?;
+        Ok(Av1C {
+            version: b0 & 0x7f,
+            seq_profile: b1 >> 5,
+            seq_level_idx_0: b1 & 0x1f,
+            seq_tier_0: b2 & 0x80 != 0,
+            high_bitdepth: b2 & 0x40 != 0,
+            twelve_bit: b2 & 0x20 != 0,
+            monochrome: b2 & 0x10 != 0,
+            chroma_subsampling_x: b2 & 0x08 != 0,
+            chroma_subsampling_y: b2 & 0x04 != 0,
+            chroma_sample_position: b2 & 0x03,
+            initial_presentation_delay_minus_one: if b3 & 0x10 != 0 { Some(b3 & 0x0f) } else { None },
+            config_obus: r.to_vec(),
+        })
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) {
+        w.write_u8(0x80 | self.version).unwrap();
+        w.write_u8(self.seq_profile << 5 | self.seq_level_idx_0).unwrap();
+        w.write_u8((self.seq_tier_0 as u8) << 7 | (self.high_bitdepth as u8) << 6 | (self.twelve_bit as u8) << 5
+            | (self.monochrome as u8) << 4 | (self.chroma_subsampling_x as u8) << 3 | (self.chroma_subsampling_y as u8) << 2
+            | self.chroma_sample_position).unwrap();
+        w.write_u8(self.initial_presentation_delay_minus_one.map_or(0, |d| 0x10 | d)).unwrap();
+        w.write_all(&self.config_obus).unwrap();
+    }
+
+    pub fn get_bit_depth(&self) -> u8 {
+        match (self.high_bitdepth, self.twelve_bit) {
+            (true, true) => 12,
+            (true, false) => 10,
+            _ => 8,
+        }
+    }
+
+    pub fn get_profile_name(&self) -> &'static str {
+        match self.seq_profile {
+            0 => "Main",
+            1 => "High",
+            2 => "Professional",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// VPCodecConfigurationRecord behind the version and flags of the `vpcC` box, the SequenceStart of `vp09`
+#[derive(Debug, Clone, PartialEq)]
+pub struct VpcC {
+    pub version: u8,
+    pub flags: u32,
+    pub profile: u8,
+    pub level: u8,
+    pub bit_depth: u8,
+    pub chroma_subsampling: u8,
+    pub video_full_range_flag: bool,
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub codec_initialization_data: Vec<u8>,
+}
+
+impl VpcC {
+    pub fn parse(mut data: &[u8]) -> Result<VpcC, FlvError> {
+        let r = &mut data;
+        let version = r.read_u8()?;
+        let flags = (r.read_u8()? as u32) << 16 | r.read_u16::<BigEndian>()? as u32;
+        let profile = r.read_u8()?;
+        let level = r.read_u8()?;
+        let b = r.read_u8()?;
+        let colour_primaries = r.read_u8()?;
+        let transfer_characteristics = r.read_u8()?;
+        let matrix_coefficients = r.read_u8()?;
+        let size = r.read_u16::<BigEndian>()? as usize;
+        let codec_initialization_data = read_vec(r, size)?;
+        Ok(VpcC {
+            version,
+            flags,
+            profile,
+            level,
+            bit_depth: b >> 4,
+            chroma_subsampling: (b >> 1) & 0x07,
+            video_full_range_flag: b & 1 != 0,
+            colour_primaries,
+            transfer_characteristics,
+            matrix_coefficients,
+            codec_initialization_data,
+        })
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) {
+        w.write_u8(self.version).unwrap();
+        w.write_u8((self.flags >> 16) as u8).unwrap();
+        w.write_u16::<BigEndian>(self.flags as u16).unwrap();
+        w.write_u8(self.profile).unwrap();
+        w.write_u8(self.level).unwrap();
+        w.write_u8(self.bit_depth << 4 | self.chroma_subsampling << 1 | self.video_full_range_flag as u8).unwrap();
+        w.write_u8(self.colour_primaries).unwrap();
+        w.write_u8(self.transfer_characteristics).unwrap();
+        w.write_u8(self.matrix_coefficients).unwrap();
+        w.write_u16::<BigEndian>(self.codec_initialization_data.len() as u16).unwrap();
+        w.write_all(&self.codec_initialization_data).unwrap();
+    }
+
+    /// level is 10 times the level number
+    pub fn get_level(&self) -> f64 {
+        self.level as f64 / 10.0
+    }
+}
+
+const OPUS_HEAD_MAGIC: &'static [u8; 8] = b"OpusHead";
+
+/// Opus identification header, the SequenceStart of `Opus`. Multi-byte fields are little endian.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpusHead {
+    pub version: u8,
+    pub channel_count: u8,
+    pub pre_skip: u16,
+    pub input_sample_rate: u32,
+    pub output_gain: i16,
+    pub channel_mapping_family: u8,
+    /// the channel mapping table, only for a family other than 0
+    pub stream_count: u8,
+    pub coupled_count: u8,
+    pub channel_mapping: Vec<u8>,
+}
+
+impl OpusHead {
+    pub fn parse(mut data: &[u8]) -> Result<OpusHead, FlvError> {
+        let r = &mut data;
+        if read_vec(r, 8)? != &OPUS_HEAD_MAGIC[..] {
+            return Err(FlvError::BadBitstream("no OpusHead signature"));
+        }
+        let version = r.read_u8()?;
+        let channel_count = r.read_u8()?;
+        let pre_skip = r.read_u16::<LittleEndian>()?;
+        let input_sample_rate = r.read_u32::<LittleEndian>()?;
+        let output_gain = r.read_i16::<LittleEndian>()?;
+        let channel_mapping_family = r.read_u8()?;
+        let (mut stream_count, mut coupled_count, mut channel_mapping) = (0, 0, Vec::new());
+        if channel_mapping_family != 0 {
+            stream_count = r.read_u8()?;
+            coupled_count = r.read_u8()?;
+            channel_mapping = read_vec(r, channel_count as usize)?;
+        }
+        Ok(OpusHead {
+            version,
+            channel_count,
+            pre_skip,
+            input_sample_rate,
+            output_gain,
+            channel_mapping_family,
+            stream_count,
+            coupled_count,
+            channel_mapping,
+        })
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) {
+        w.write_all(OPUS_HEAD_MAGIC).unwrap();
+        w.write_u8(self.version).unwrap();
+        w.write_u8(self.channel_count).unwrap();
+        w.write_u16::<LittleEndian>(self.pre_skip).unwrap();
+        w.write_u32::<LittleEndian>(self.input_sample_rate).unwrap();
+        w.write_i16::<LittleEndian>(self.output_gain).unwrap();
+        w.write_u8(self.channel_mapping_family).unwrap();
+        if self.channel_mapping_family != 0 {
+            w.write_u8(self.stream_count).unwrap();
+            w.write_u8(self.coupled_count).unwrap();
+            w.write_all(&self.channel_mapping).unwrap();
+        }
+    }
+}
+
+pub const FLAC_METADATA_STREAMINFO: u8 = 0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlacMetadataBlock {
+    pub block_type: u8,
+    pub data: Vec<u8>,
+}
+
+/// FLAC metadata blocks, the SequenceStart of `fLaC`, with or without the `fLaC` stream marker
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlacConfig {
+    pub has_marker: bool,
+    pub blocks: Vec<FlacMetadataBlock>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlacStreamInfo {
+    pub min_block_size: u16,
+    pub max_block_size: u16,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    pub total_samples: u64,
+}
+
+impl FlacConfig {
+    pub fn parse(mut data: &[u8]) -> Result<FlacConfig, FlvError> {
+        let r = &mut data;
+        let has_marker = r.starts_with(b"fLaC");
+        if has_marker {
+            *r = &r[4..];
+        }
+        let mut blocks: Vec<FlacMetadataBlock> = Vec::new();
+        loop {
+            let b = r.read_u8()?;
+            let len = (r.read_u8()? as usize) << 16 | r.read_u16::<BigEndian>()? as usize;
+            blocks.push(FlacMetadataBlock { block_type: b & 0x7f, data: read_vec(r, len)? });
+            if b & 0x80 != 0 { // last-metadata-block flag
+                break;
+            }
+        }
+        Ok(FlacConfig { has_marker, blocks })
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) {
+        if self.has_marker {
+            w.write_all(b"fLaC").unwrap();
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            let last = if i + 1 == self.blocks.len() { 0x80 } else { 0 };
+            w.write_u8(last | block.block_type).unwrap();
+            w.write_u8((block.data.len() >> 16) as u8).unwrap();
+            w.write_u16::<BigEndian>(block.data.len() as u16).unwrap();
+            w.write_all(&block.data).unwrap();
+        }
+    }
+
+    pub fn get_stream_info(&self) -> Option<FlacStreamInfo> {
+        let block = self.blocks.iter().find(|b| b.block_type == FLAC_METADATA_STREAMINFO)?;
+        let d = &block.data;
+        if d.len() < 18 {
+            return None;
+        }
+        // 20 bits rate, 3 bits channels - 1, 5 bits bits per sample - 1, 36 bits samples
+        let packed = d[10..18].iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+        Some(FlacStreamInfo {
+            min_block_size: (d[0] as u16) << 8 | d[1] as u16,
+            max_block_size: (d[2] as u16) << 8 | d[3] as u16,
+            sample_rate: (packed >> 44) as u32,
+            channels: ((packed >> 41) & 0x07) as u8 + 1,
+            bits_per_sample: ((packed >> 36) & 0x1f) as u8 + 1,
+            total_samples: packed & 0xf_ffff_ffff,
+        })
+    }
+}
+
+/// AC3SpecificBox payload, the SequenceStart of `ac-3`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ac3Config {
+    pub fscod: u8,
+    pub bsid: u8,
+    pub bsmod: u8,
+    pub acmod: u8,
+    pub lfeon: bool,
+    pub bit_rate_code: u8,
+}
+
+impl Ac3Config {
+    pub fn parse(mut data: &[u8]) -> Result<Ac3Config, FlvError> {
+        let r = &mut data;
+        let v = (r.read_u8()? as u32) << 16 | r.read_u16::<BigEndian>()? as u32;
+        Ok(Ac3Config {
+            fscod: (v >> 22) as u8 & 0x03,
+            bsid: (v >> 17) as u8 & 0x1f,
+            bsmod: (v >> 14) as u8 & 0x07,
+            acmod: (v >> 11) as u8 & 0x07,
+            lfeon: (v >> 10) & 1 != 0,
+            bit_rate_code: (v >> 5) as u8 & 0x1f,
+        })
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) {
+        let v = (self.fscod as u32) << 22 | (self.bsid as u32) << 17 | (self.bsmod as u32) << 14
+            | (self.acmod as u32) << 11 | (self.lfeon as u32) << 10 | (self.bit_rate_code as u32) << 5;
+        w.write_u8((v >> 16) as u8).unwrap();
+        w.write_u16::<BigEndian>(v as u16).unwrap();
+    }
+
+    pub fn get_sample_rate(&self) -> Option<u32> {
+        match self.fscod {
+            0 => Some(48000),
+            1 => Some(44100),
+            2 => Some(32000),
+            _ => None,
+        }
+    }
+
+    /// full range channels and the LFE channel
+    pub fn get_channels(&self) -> u8 {
+        [2, 1, 2, 3, 3, 4, 4, 5][self.acmod as usize] + self.lfeon as u8
+    }
+}
+
+#[test]
+fn test_codec_config() {
+    // Main profile level 4.0 (idx 8), 4:2:0 8 bit, no OBUs
+    let data = [0x81, 0x08, 0x0c, 0x00];
+    let av1c = Av1C::parse(&data).unwrap();
+    assert_eq!(av1c.seq_level_idx_0, 8);
+    assert_eq!(av1c.get_bit_depth(), 8);
+    assert!(av1c.chroma_subsampling_x && av1c.chroma_subsampling_y);
+    let mut out: Vec<u8> = Vec::new();
+    av1c.write(&mut out);
+    assert_eq!(&out[..], &data[..]);
+
+    let data = [1, 0, 0, 0, 0, 31, 0x82, 1, 1, 1, 0, 0];
+    let vpcc = VpcC::parse(&data).unwrap();
+    assert_eq!((vpcc.profile, vpcc.bit_depth, vpcc.chroma_subsampling), (0, 8, 1));
+    assert!((vpcc.get_level() - 3.1).abs() < 1e-9);
+    let mut out: Vec<u8> = Vec::new();
+    vpcc.write(&mut out);
+    assert_eq!(&out[..], &data[..]);
+
+    let data = [b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', 1, 2, 0x38, 0x01, 0x80, 0xbb, 0, 0, 0, 0, 0];
+    let head = OpusHead::parse(&data).unwrap();
+    assert_eq!((head.channel_count, head.pre_skip, head.input_sample_rate), (2, 312, 48000));
+    let mut out: Vec<u8> = Vec::new();
+    head.write(&mut out);
+    assert_eq!(&out[..], &data[..]);
+
+    // STREAMINFO of 44100 Hz stereo 16 bit, 1000 samples
+    let mut data: Vec<u8> = b"fLaC".to_vec();
+    data.extend_from_slice(&[0x80, 0, 0, 34, 0x10, 0, 0x10, 0, 0, 0, 0, 0, 0, 0,
+                             0x0a, 0xc4, 0x42, 0xf0, 0, 0, 0x03, 0xe8]);
+    data.extend_from_slice(&[0; 16]);
+    let flac = FlacConfig::parse(&data).unwrap();
+    let info = flac.get_stream_info().unwrap();
+    assert_eq!((info.sample_rate, info.channels, info.bits_per_sample, info.total_samples), (44100, 2, 16, 1000));
+    let mut out: Vec<u8> = Vec::new();
+    flac.write(&mut out);
+    assert_eq!(out, data);
+
+    // 48 kHz 3/2 with LFE, 384 kbps
+    let data = [0x10, 0x3d, 0xc0];
+    let ac3 = Ac3Config::parse(&data).unwrap();
+    assert_eq!((ac3.get_sample_rate(), ac3.get_channels(), ac3.bit_rate_code), (Some(48000), 6, 14));
+    let mut out: Vec<u8> = Vec::new();
+    ac3.write(&mut out);
+    assert_eq!(&out[..], &data[..]);
+}
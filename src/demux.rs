@@ -0,0 +1,190 @@
+use lib::{FLVHeader, FLVTag, FlvError, PrevTagSizePolicy, TAG_HEADER_BYTE_COUNT, PREV_TAG_BYTE_COUNT, MIN_FILE_HEADER_BYTE_COUNT};
+
+#[derive(Debug)]
+pub enum DemuxEvent {
+    Header(FLVHeader),
+    /// a tag and its offset in the stream
+    Tag(u64, FLVTag),
+}
+
+/// Push based demuxer, does no I/O of its own.
+///
+/// Feed it bytes in chunks of any size with `push`, then call `next_event`
+/// until it returns `Ok(None)`, which means more bytes are needed.
+/// After an error the demuxer keeps returning `Ok(None)`.
+pub struct FlvDemuxer {
+    buffer: Vec<u8>,
+    start: usize, // buffer[start] is at stream offset `position`
+    position: u64,
+    header_read: bool,
+    failed: bool,
+    prev_tag_size_policy: PrevTagSizePolicy,
+    warnings: Vec<(u64, FlvError)>,
+}
+
+impl FlvDemuxer {
+    pub fn new() -> FlvDemuxer {
+        FlvDemuxer {
+            buffer: Vec::new(),
+            start: 0,
+            position: 0,
+            header_read: false,
+            failed: false,
+            prev_tag_size_policy: PrevTagSizePolicy::default(),
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn with_prev_tag_size_policy(mut self, policy: PrevTagSizePolicy) -> FlvDemuxer {
+        self.prev_tag_size_policy = policy;
+        self
+    }
+
+    /// warnings collected so far under `PrevTagSizePolicy::Warn`, with the offset of the tag
+    pub fn take_warnings(&mut self) -> Vec<(u64, FlvError)> {
+        ::std::mem::take(&mut self.warnings)
+    }
+
+    /// offset of the next event in the stream
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    /// bytes pushed but not returned as events yet
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        if self.start > 0 && self.start >= self.buffer.len() / 2 {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.start += len;
+        self.position += len as u64;
+    }
+
+    pub fn next_event(&mut self) -> Result<Option<DemuxEvent>, FlvError> {
+        if self.failed {
+            return Ok(None);
+        }
+        match self.parse() {
+            Ok(event) => Ok(event),
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }
+    }
+
+    fn parse(&mut self) -> Result<Option<DemuxEvent>, FlvError> {
+        if !self.header_read {
+            let header_len = (MIN_FILE_HEADER_BYTE_COUNT + PREV_TAG_BYTE_COUNT) as usize;
+            if self.buffered_len() < header_len {
+                return Ok(None);
+            }
+            let header = FLVHeader::try_read(&mut &self.buffer[self.start..(self.start + header_len)])?;
+            self.consume(header_len);
+            self.header_read = true;
+            return Ok(Some(DemuxEvent::Header(header)));
+        }
+        if self.buffered_len() < TAG_HEADER_BYTE_COUNT as usize {
+            return Ok(None);
+        }
+        let data_size = {
+            let h = &self.buffer[self.start..];
+            (h[1] as u32) << 16 | (h[2] as u32) << 8 | h[3] as u32
+        };
+        let total = (TAG_HEADER_BYTE_COUNT + data_size + PREV_TAG_BYTE_COUNT) as usize;
+        if self.buffered_len() < total {
+            return Ok(None);
+        }
+        let position = self.position;
+        let (tag, prev_tag_size) = FLVTag::try_read_with_prev_tag_size(&mut &self.buffer[self.start..(self.start + total)])?.unwrap();
+        self.prev_tag_size_policy.check(tag.get_tag_size(), prev_tag_size, position, &mut self.warnings)?;
+        self.consume(total);
+        Ok(Some(DemuxEvent::Tag(position, tag)))
+    }
+
+    /// call at the end of the stream, fails if a partial header or tag is left over
+    pub fn finish(&self) -> Result<(), FlvError> {
+        if self.failed || self.buffered_len() == 0 {
+            return Ok(());
+        }
+        if !self.header_read || self.buffered_len() < TAG_HEADER_BYTE_COUNT as usize {
+            return Err(FlvError::UnexpectedEof);
+        }
+        let h = &self.buffer[self.start..];
+        let tag_size = TAG_HEADER_BYTE_COUNT + ((h[1] as u32) << 16 | (h[2] as u32) << 8 | h[3] as u32);
+        let actual = if h.len() < tag_size as usize { h.len() as u32 } else { tag_size };
+        Err(FlvError::TruncatedTag { expected: tag_size, actual: actual })
+    }
+}
+
+impl Default for FlvDemuxer {
+    fn default() -> FlvDemuxer {
+        FlvDemuxer::new()
+    }
+}
+
+#[test]
+fn test_demuxer() {
+    let mut data: Vec<u8> = b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+    for &timestamp in [0u8, 40].iter() {
+        data.extend_from_slice(&[8, 0, 0, 2, 0, 0, timestamp, 0, 0, 0, 0, 0xaf, 0x01, 0, 0, 0, 13]);
+    }
+    let mut demuxer = FlvDemuxer::new();
+    let mut positions: Vec<u64> = Vec::new();
+    for chunk in data.chunks(3) {
+        demuxer.push(chunk);
+        while let Some(event) = demuxer.next_event().unwrap() {
+            match event {
+                DemuxEvent::Header(header) => {
+                    assert!(header.hasAudioTags && header.hasVideoTags);
+                    positions.push(0);
+                }
+                DemuxEvent::Tag(position, tag) => {
+                    assert_eq!(tag.get_timestamp(), if position == 13 { 0 } else { 40 });
+                    positions.push(position);
+                }
+            }
+        }
+    }
+    assert_eq!(positions, vec![0, 13, 30]);
+    assert!(demuxer.finish().is_ok());
+
+    demuxer.push(&data[13..25]);
+    assert!(demuxer.next_event().unwrap().is_none());
+    match demuxer.finish() {
+        Err(FlvError::TruncatedTag { expected: 13, actual: 12 }) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+
+    // a wrong PreviousTagSize after the first tag
+    data[29] = 12;
+    let mut demuxer = FlvDemuxer::new().with_prev_tag_size_policy(PrevTagSizePolicy::Warn);
+    demuxer.push(&data);
+    let mut count = 0;
+    while let Some(_) = demuxer.next_event().unwrap() {
+        count += 1;
+    }
+    assert_eq!(count, 3);
+    let warnings = demuxer.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    match warnings[0] {
+        (13, FlvError::PrevTagSizeMismatch { expected: 13, actual: 12 }) => {},
+        ref w => panic!("unexpected {:?}", w),
+    }
+    let mut demuxer = FlvDemuxer::new().with_prev_tag_size_policy(PrevTagSizePolicy::Error);
+    demuxer.push(&data);
+    assert!(demuxer.next_event().unwrap().is_some());
+    match demuxer.next_event() {
+        Err(FlvError::PrevTagSizeMismatch { expected: 13, actual: 12 }) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    assert!(demuxer.next_event().unwrap().is_none());
+}
@@ -0,0 +1,627 @@
+use std::fmt;
+use std::io::Write;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use lib::{FLVTag, FLVTagType, FlvError, CODEC_ID_AVC, CODEC_ID_HEVC, read_u24_be, write_u24_be};
+
+/// codec of an Enhanced FLV video or audio tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FourCC(pub [u8; 4]);
+
+impl FourCC {
+    pub const AVC1: FourCC = FourCC(*b"avc1");
+    pub const HVC1: FourCC = FourCC(*b"hvc1");
+    pub const AV01: FourCC = FourCC(*b"av01");
+    pub const VP08: FourCC = FourCC(*b"vp08");
+    pub const VP09: FourCC = FourCC(*b"vp09");
+    pub const AC3: FourCC = FourCC(*b"ac-3");
+    pub const EC3: FourCC = FourCC(*b"ec-3");
+    pub const OPUS: FourCC = FourCC(*b"Opus");
+    pub const MP3: FourCC = FourCC(*b".mp3");
+    pub const FLAC: FourCC = FourCC(*b"fLaC");
+    pub const AAC: FourCC = FourCC(*b"mp4a");
+
+    /// the legacy CodecID of the same codec, if it has one
+    pub fn get_codec_id(&self) -> Option<u8> {
+        match *self {
+            FourCC::AVC1 => Some(CODEC_ID_AVC),
+            FourCC::HVC1 => Some(CODEC_ID_HEVC),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FourCC {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &b in self.0.iter() {
+            let c = if b >= 0x20 && b < 0x7f { b as char } else { '?' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// `videocodecid` or `audiocodecid` of the metadata: a legacy CodecID or SoundFormat,
+/// or for Enhanced FLV the FourCC read as a big endian number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CodecId {
+    Legacy(u8),
+    FourCC(FourCC),
+}
+
+impl CodecId {
+    /// `None` for a number that is no id
+    pub fn from_f64(f: f64) -> Option<CodecId> {
+        if f < 0.0 || f > u32::MAX as f64 || f.fract() != 0.0 {
+            return None;
+        }
+        Some(CodecId::from_u32(f as u32))
+    }
+
+    fn from_u32(n: u32) -> CodecId {
+        if n <= 0xff {
+            return CodecId::Legacy(n as u8);
+        }
+        CodecId::FourCC(FourCC([(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]))
+    }
+
+    fn to_u32(&self) -> u32 {
+        match *self {
+            CodecId::Legacy(id) => id as u32,
+            CodecId::FourCC(FourCC(b)) => (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32,
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.to_u32() as f64
+    }
+
+    /// the legacy CodecID, also of a FourCC that has one
+    pub fn get_codec_id(&self) -> Option<u8> {
+        match *self {
+            CodecId::Legacy(id) => Some(id),
+            CodecId::FourCC(ref fourcc) => fourcc.get_codec_id(),
+        }
+    }
+}
+
+impl From<u8> for CodecId {
+    fn from(id: u8) -> CodecId {
+        CodecId::Legacy(id)
+    }
+}
+
+impl From<FourCC> for CodecId {
+    fn from(fourcc: FourCC) -> CodecId {
+        CodecId::FourCC(fourcc)
+    }
+}
+
+impl fmt::Display for CodecId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecId::Legacy(id) => write!(f, "{}", id),
+            CodecId::FourCC(ref fourcc) => write!(f, "{}", fourcc),
+        }
+    }
+}
+
+/// the number as in the metadata
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use serde::ser::{Serialize, Serializer};
+    use serde::de::{Deserialize, Deserializer};
+    use super::CodecId;
+
+    impl Serialize for CodecId {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_u32(self.to_u32())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for CodecId {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<CodecId, D::Error> {
+            Ok(CodecId::from_u32(u32::deserialize(d)?))
+        }
+    }
+}
+
+/// set in the first byte of a video tag that carries an extended header
+pub const VIDEO_EX_HEADER_FLAG: u8 = 0x80;
+
+pub const VIDEO_FRAME_TYPE_KEYFRAME: u8 = 1;
+pub const VIDEO_FRAME_TYPE_COMMAND: u8 = 5;
+
+pub const VIDEO_PACKET_TYPE_SEQUENCE_START: u8 = 0;
+pub const VIDEO_PACKET_TYPE_CODED_FRAMES: u8 = 1;
+pub const VIDEO_PACKET_TYPE_SEQUENCE_END: u8 = 2;
+/// coded frames without the composition time, which is 0
+pub const VIDEO_PACKET_TYPE_CODED_FRAMES_X: u8 = 3;
+/// AMF0 encoded `colorInfo` and the like
+pub const VIDEO_PACKET_TYPE_METADATA: u8 = 4;
+pub const VIDEO_PACKET_TYPE_MPEG2TS_SEQUENCE_START: u8 = 5;
+pub const VIDEO_PACKET_TYPE_MULTITRACK: u8 = 6;
+pub const VIDEO_PACKET_TYPE_MOD_EX: u8 = 7;
+
+pub const AUDIO_PACKET_TYPE_SEQUENCE_START: u8 = 0;
+pub const AUDIO_PACKET_TYPE_CODED_FRAMES: u8 = 1;
+pub const AUDIO_PACKET_TYPE_SEQUENCE_END: u8 = 2;
+pub const AUDIO_PACKET_TYPE_MULTICHANNEL_CONFIG: u8 = 4;
+pub const AUDIO_PACKET_TYPE_MULTITRACK: u8 = 5;
+pub const AUDIO_PACKET_TYPE_MOD_EX: u8 = 7;
+
+pub const MULTITRACK_TYPE_ONE_TRACK: u8 = 0;
+pub const MULTITRACK_TYPE_MANY_TRACKS: u8 = 1;
+pub const MULTITRACK_TYPE_MANY_TRACKS_MANY_CODECS: u8 = 2;
+
+pub const MOD_EX_TYPE_TIMESTAMP_OFFSET_NANO: u8 = 0;
+
+/// One ModEx entry of an extended header. A nanosecond timestamp offset is kept in `data` as it
+/// was sent, 3 bytes big endian.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModEx<'a> {
+    pub mod_ex_type: u8,
+    pub data: &'a [u8],
+}
+
+/// The payload of one track
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExTrack<'a> {
+    pub fourcc: FourCC,
+    /// 0 for a packet that is not multitrack
+    pub track_id: u8,
+    /// only read from coded frames of avc1 and hvc1, 0 otherwise
+    pub composition_time: i32,
+    pub data: &'a [u8],
+}
+
+/// Enhanced FLV video tag body
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExVideoPacket<'a> {
+    pub frame_type: u8,
+    /// never Multitrack nor ModEx, those are read into the fields below
+    pub packet_type: u8,
+    pub mod_ex: Vec<ModEx<'a>>,
+    pub multitrack_type: Option<u8>,
+    /// set for the command frame type, which has no multitrack header nor tracks
+    pub video_command: Option<u8>,
+    pub tracks: Vec<ExTrack<'a>>,
+}
+
+/// Enhanced FLV audio tag body, sound format 9
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExAudioPacket<'a> {
+    pub packet_type: u8,
+    pub mod_ex: Vec<ModEx<'a>>,
+    pub multitrack_type: Option<u8>,
+    pub tracks: Vec<ExTrack<'a>>,
+}
+
+fn read_slice<'a>(r: &mut &'a [u8], len: usize) -> Result<&'a [u8], FlvError> {
+    if r.len() < len {
+        return Err(FlvError::UnexpectedEof);
+    }
+    let (head, tail) = r.split_at(len);
+    *r = tail;
+    Ok(head)
+}
+
+fn read_fourcc(r: &mut &[u8]) -> Result<FourCC, FlvError> {
+    let s = read_slice(r, 4)?;
+    Ok(FourCC([s[0], s[1], s[2], s[3]]))
+}
+
+/// ModEx entries, then the packet type that follows them
+fn read_mod_ex<'a>(r: &mut &'a [u8], mod_ex: &mut Vec<ModEx<'a>>) -> Result<u8, FlvError> {
+    loop {
+        let mut size = r.read_u8()? as usize + 1;
+        if size == 256 {
+            size = r.read_u16::<BigEndian>()? as usize + 1;
+        }
+        let data = read_slice(r, size)?;
+        let b = r.read_u8()?;
+        mod_ex.push(ModEx { mod_ex_type: b >> 4, data });
+        if b & 0x0f != VIDEO_PACKET_TYPE_MOD_EX {
+            return Ok(b & 0x0f);
+        }
+    }
+}
+
+fn write_mod_ex<W: Write + ?Sized>(w: &mut W, mod_ex: &[ModEx<'_>], packet_type: u8) -> Result<(), FlvError> {
+    for (i, m) in mod_ex.iter().enumerate() {
+        if m.data.is_empty() || m.data.len() > 0x10000 {
+            return Err(FlvError::BadBitstream("ModEx data size out of range"));
+        }
+        if m.data.len() < 256 {
+            w.write_u8((m.data.len() - 1) as u8)?;
+        } else {
+            w.write_u8(0xff)?;
+            w.write_u16::<BigEndian>((m.data.len() - 1) as u16)?;
+        }
+        w.write_all(m.data)?;
+        let next = if i + 1 < mod_ex.len() { VIDEO_PACKET_TYPE_MOD_EX } else { packet_type };
+        w.write_u8(m.mod_ex_type << 4 | next)?;
+    }
+    Ok(())
+}
+
+/// The track loop shared by video and audio. `body` reads the payload of a single track, its size
+/// is only known for many tracks.
+fn read_tracks<'a, F>(r: &mut &'a [u8], multitrack_type: Option<u8>, mut body: F) -> Result<Vec<ExTrack<'a>>, FlvError>
+    where F: FnMut(&mut &'a [u8], FourCC) -> Result<ExTrack<'a>, FlvError>
+{
+    let shared = match multitrack_type {
+        Some(MULTITRACK_TYPE_MANY_TRACKS_MANY_CODECS) => None,
+        _ => Some(read_fourcc(r)?),
+    };
+    let mut tracks: Vec<ExTrack<'_>> = Vec::new();
+    loop {
+        let fourcc = match shared {
+            Some(fourcc) => fourcc,
+            None => read_fourcc(r)?,
+        };
+        let track_id = if multitrack_type.is_some() { r.read_u8()? } else { 0 };
+        let mut track = match multitrack_type {
+            Some(t) if t != MULTITRACK_TYPE_ONE_TRACK => {
+                let size = read_u24_be(r)? as usize;
+                let mut data = read_slice(r, size)?;
+                body(&mut data, fourcc)?
+            }
+            _ => body(r, fourcc)?,
+        };
+        track.track_id = track_id;
+        tracks.push(track);
+        match multitrack_type {
+            Some(t) if t != MULTITRACK_TYPE_ONE_TRACK && !r.is_empty() => {},
+            _ => return Ok(tracks),
+        }
+    }
+}
+
+fn write_tracks<W: Write + ?Sized, F>(w: &mut W, multitrack_type: Option<u8>, tracks: &[ExTrack<'_>], mut body: F) -> Result<(), FlvError>
+    where F: FnMut(&mut Vec<u8>, &ExTrack<'_>) -> Result<(), FlvError>
+{
+    if tracks.is_empty() {
+        return Err(FlvError::BadBitstream("no track to write"));
+    }
+    if (multitrack_type.is_none() || multitrack_type == Some(MULTITRACK_TYPE_ONE_TRACK)) && tracks.len() > 1 {
+        return Err(FlvError::BadBitstream("many tracks in a single track packet"));
+    }
+    if multitrack_type != Some(MULTITRACK_TYPE_MANY_TRACKS_MANY_CODECS) {
+        if tracks.iter().any(|t| t.fourcc != tracks[0].fourcc) {
+            return Err(FlvError::BadBitstream("tracks of different codecs without ManyTracksManyCodecs"));
+        }
+        w.write_all(&tracks[0].fourcc.0)?;
+    }
+    for track in tracks.iter() {
+        if multitrack_type == Some(MULTITRACK_TYPE_MANY_TRACKS_MANY_CODECS) {
+            w.write_all(&track.fourcc.0)?;
+        }
+        if multitrack_type.is_some() {
+            w.write_u8(track.track_id)?;
+        }
+        let mut buf: Vec<u8> = Vec::new();
+        body(&mut buf, track)?;
+        match multitrack_type {
+            Some(t) if t != MULTITRACK_TYPE_ONE_TRACK => {
+                if buf.len() > 0xffffff {
+                    return Err(FlvError::BadBitstream("track data larger than its 24-bit size"));
+                }
+                w.write_all(&[(buf.len() >> 16) as u8, (buf.len() >> 8) as u8, buf.len() as u8])?;
+            }
+            _ => {},
+        }
+        w.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+fn has_composition_time(packet_type: u8, fourcc: FourCC) -> bool {
+    packet_type == VIDEO_PACKET_TYPE_CODED_FRAMES && (fourcc == FourCC::AVC1 || fourcc == FourCC::HVC1)
+}
+
+impl<'a> ExVideoPacket<'a> {
+    /// `data` is the tag body, starting at the byte with the IsExHeader flag
+    pub fn parse(mut data: &'a [u8]) -> Result<ExVideoPacket<'a>, FlvError> {
+        let r = &mut data;
+        let b = r.read_u8()?;
+        if b & VIDEO_EX_HEADER_FLAG == 0 {
+            return Err(FlvError::BadBitstream("not an extended video header"));
+        }
+        let frame_type = (b >> 4) & 0x07;
+        let mut packet_type = b & 0x0f;
+        let mut mod_ex: Vec<ModEx<'_>> = Vec::new();
+        if packet_type == VIDEO_PACKET_TYPE_MOD_EX {
+            packet_type = read_mod_ex(r, &mut mod_ex)?;
+        }
+        if frame_type == VIDEO_FRAME_TYPE_COMMAND && packet_type != VIDEO_PACKET_TYPE_METADATA {
+            let command = r.read_u8()?;
+            return Ok(ExVideoPacket {
+                frame_type,
+                packet_type,
+                mod_ex,
+                multitrack_type: None,
+                video_command: Some(command),
+                tracks: Vec::new(),
+            });
+        }
+        let mut multitrack_type = None;
+        if packet_type == VIDEO_PACKET_TYPE_MULTITRACK {
+            let b = r.read_u8()?;
+            multitrack_type = Some(b >> 4);
+            packet_type = b & 0x0f;
+            if packet_type == VIDEO_PACKET_TYPE_MULTITRACK {
+                return Err(FlvError::BadBitstream("nested multitrack video packet"));
+            }
+        }
+        let tracks = read_tracks(r, multitrack_type, |r, fourcc| {
+            let composition_time = if has_composition_time(packet_type, fourcc) {
+                let value = read_u24_be(r)? as i32;
+                (value << 8) >> 8 // sign-extend the 24-bit value
+            } else {
+                0
+            };
+            let data = *r;
+            *r = &[];
+            Ok(ExTrack { fourcc, track_id: 0, composition_time, data })
+        })?;
+        Ok(ExVideoPacket {
+            frame_type,
+            packet_type,
+            mod_ex,
+            multitrack_type,
+            video_command: None,
+            tracks,
+        })
+    }
+
+    /// fails on data the packet has no room for, like a ModEx of more than 65536 bytes or tracks of
+    /// different codecs without `MULTITRACK_TYPE_MANY_TRACKS_MANY_CODECS`
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) -> Result<(), FlvError> {
+        let mut first = self.packet_type;
+        if self.multitrack_type.is_some() {
+            first = VIDEO_PACKET_TYPE_MULTITRACK;
+        }
+        if !self.mod_ex.is_empty() {
+            w.write_u8(VIDEO_EX_HEADER_FLAG | self.frame_type << 4 | VIDEO_PACKET_TYPE_MOD_EX)?;
+            write_mod_ex(w, &self.mod_ex, first)?;
+        } else {
+            w.write_u8(VIDEO_EX_HEADER_FLAG | self.frame_type << 4 | first)?;
+        }
+        if let Some(t) = self.multitrack_type {
+            w.write_u8(t << 4 | self.packet_type)?;
+        }
+        if let Some(command) = self.video_command {
+            w.write_u8(command)?;
+            return Ok(());
+        }
+        let packet_type = self.packet_type;
+        write_tracks(w, self.multitrack_type, &self.tracks, |buf, track| {
+            if has_composition_time(packet_type, track.fourcc) {
+                write_u24_be(buf, (track.composition_time & 0xffffff) as u32);
+            }
+            buf.write_all(track.data)?;
+            Ok(())
+        })
+    }
+
+    /// the nanoseconds to add to the tag timestamp, from the TimestampOffsetNano ModEx
+    pub fn get_timestamp_offset_nano(&self) -> u32 {
+        self.mod_ex.iter()
+            .find(|m| m.mod_ex_type == MOD_EX_TYPE_TIMESTAMP_OFFSET_NANO && m.data.len() >= 3)
+            .map_or(0, |m| (m.data[0] as u32) << 16 | (m.data[1] as u32) << 8 | m.data[2] as u32)
+    }
+}
+
+impl<'a> ExAudioPacket<'a> {
+    /// `data` is the tag body, starting at the sound format byte
+    pub fn parse(mut data: &'a [u8]) -> Result<ExAudioPacket<'a>, FlvError> {
+        let r = &mut data;
+        let b = r.read_u8()?;
+        if b >> 4 != SOUND_FORMAT_EX_HEADER {
+            return Err(FlvError::BadBitstream("not an extended audio header"));
+        }
+        let mut packet_type = b & 0x0f;
+        let mut mod_ex: Vec<ModEx<'_>> = Vec::new();
+        if packet_type == AUDIO_PACKET_TYPE_MOD_EX {
+            packet_type = read_mod_ex(r, &mut mod_ex)?;
+        }
+        let mut multitrack_type = None;
+        if packet_type == AUDIO_PACKET_TYPE_MULTITRACK {
+            let b = r.read_u8()?;
+            multitrack_type = Some(b >> 4);
+            packet_type = b & 0x0f;
+            if packet_type == AUDIO_PACKET_TYPE_MULTITRACK {
+                return Err(FlvError::BadBitstream("nested multitrack audio packet"));
+            }
+        }
+        let tracks = read_tracks(r, multitrack_type, |r, fourcc| {
+            let data = *r;
+            *r = &[];
+            Ok(ExTrack { fourcc, track_id: 0, composition_time: 0, data })
+        })?;
+        Ok(ExAudioPacket {
+            packet_type,
+            mod_ex,
+            multitrack_type,
+            tracks,
+        })
+    }
+
+    /// fails like `ExVideoPacket::write`
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) -> Result<(), FlvError> {
+        let first = if self.multitrack_type.is_some() { AUDIO_PACKET_TYPE_MULTITRACK } else { self.packet_type };
+        if !self.mod_ex.is_empty() {
+            w.write_u8(SOUND_FORMAT_EX_HEADER << 4 | AUDIO_PACKET_TYPE_MOD_EX)?;
+            write_mod_ex(w, &self.mod_ex, first)?;
+        } else {
+            w.write_u8(SOUND_FORMAT_EX_HEADER << 4 | first)?;
+        }
+        if let Some(t) = self.multitrack_type {
+            w.write_u8(t << 4 | self.packet_type)?;
+        }
+        write_tracks(w, self.multitrack_type, &self.tracks, |buf, track| {
+            buf.write_all(track.data)?;
+            Ok(())
+        })
+    }
+}
+
+/// SoundFormat of an Enhanced FLV audio tag
+pub const SOUND_FORMAT_EX_HEADER: u8 = 9;
+
+pub const AUDIO_CHANNEL_ORDER_UNSPECIFIED: u8 = 0;
+pub const AUDIO_CHANNEL_ORDER_NATIVE: u8 = 1;
+pub const AUDIO_CHANNEL_ORDER_CUSTOM: u8 = 2;
+
+/// payload of a MultichannelConfig audio packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioMultichannelConfig {
+    pub channel_order: u8,
+    pub channel_count: u8,
+    /// one speaker per channel, for the custom order
+    pub channel_mapping: Vec<u8>,
+    /// speaker bit flags, for the native order
+    pub channel_flags: u32,
+}
+
+impl AudioMultichannelConfig {
+    pub fn parse(mut data: &[u8]) -> Result<AudioMultichannelConfig, FlvError> {
+        let r = &mut data;
+        let channel_order = r.read_u8()?;
+        let channel_count = r.read_u8()?;
+        let mut channel_mapping: Vec<u8> = Vec::new();
+        let mut channel_flags = 0;
+        if channel_order == AUDIO_CHANNEL_ORDER_CUSTOM {
+            channel_mapping = read_slice(r, channel_count as usize)?.to_vec();
+        } else if channel_order == AUDIO_CHANNEL_ORDER_NATIVE {
+            channel_flags = r.read_u32::<BigEndian>()?;
+        }
+        Ok(AudioMultichannelConfig {
+            channel_order,
+            channel_count,
+            channel_mapping,
+            channel_flags,
+        })
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) {
+        w.write_u8(self.channel_order).unwrap();
+        w.write_u8(self.channel_count).unwrap();
+        if self.channel_order == AUDIO_CHANNEL_ORDER_CUSTOM {
+            w.write_all(&self.channel_mapping).unwrap();
+        } else if self.channel_order == AUDIO_CHANNEL_ORDER_NATIVE {
+            w.write_u32::<BigEndian>(self.channel_flags).unwrap();
+        }
+    }
+}
+
+impl<D: AsRef<[u8]>> FLVTag<D> {
+    /// video tag with the IsExHeader bit set
+    pub fn is_ex_video(&self) -> bool {
+        self.get_tag_type() == FLVTagType::TAG_TYPE_VIDEO && self.get_data_size() > 0
+            && self.get_body()[0] & VIDEO_EX_HEADER_FLAG != 0
+    }
+
+    /// audio tag with sound format 9
+    pub fn is_ex_audio(&self) -> bool {
+        self.get_tag_type() == FLVTagType::TAG_TYPE_AUDIO && self.get_data_size() > 0
+            && self.get_body()[0] >> 4 == SOUND_FORMAT_EX_HEADER
+    }
+
+    pub fn get_ex_video_packet(&self) -> Result<ExVideoPacket<'_>, FlvError> {
+        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_VIDEO);
+        ExVideoPacket::parse(self.get_body())
+    }
+
+    pub fn get_ex_audio_packet(&self) -> Result<ExAudioPacket<'_>, FlvError> {
+        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_AUDIO);
+        ExAudioPacket::parse(self.get_body())
+    }
+
+    /// FourCC of the first track of an extended video tag
+    pub fn get_video_fourcc(&self) -> Option<FourCC> {
+        if !self.is_ex_video() {
+            return None;
+        }
+        self.get_ex_video_packet().ok().and_then(|p| p.tracks.first().map(|t| t.fourcc))
+    }
+
+    /// FourCC of the first track of an extended audio tag
+    pub fn get_audio_fourcc(&self) -> Option<FourCC> {
+        if !self.is_ex_audio() {
+            return None;
+        }
+        self.get_ex_audio_packet().ok().and_then(|p| p.tracks.first().map(|t| t.fourcc))
+    }
+}
+
+#[test]
+fn test_ex_packets() {
+    // keyframe, coded frames of hvc1 with a composition time of -2
+    let data: Vec<u8> = vec![0x91, b'h', b'v', b'c', b'1', 0xff, 0xff, 0xfe, 0, 0, 0, 1, 0x26];
+    let packet = ExVideoPacket::parse(&data).unwrap();
+    assert_eq!(packet.frame_type, VIDEO_FRAME_TYPE_KEYFRAME);
+    assert_eq!(packet.packet_type, VIDEO_PACKET_TYPE_CODED_FRAMES);
+    assert_eq!(packet.tracks[0].fourcc, FourCC::HVC1);
+    assert_eq!(packet.tracks[0].composition_time, -2);
+    assert_eq!(packet.tracks[0].data, &[0, 0, 0, 1, 0x26]);
+    let mut out: Vec<u8> = Vec::new();
+    packet.write(&mut out).unwrap();
+    assert_eq!(out, data);
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 0, &data);
+    let header = tag.video_header();
+    assert_eq!((header.frame_type, header.codec_id, header.packet_type, header.composition_time), (1, 12, 1, -2));
+    assert_eq!((header.fourcc, header.data), (Some(FourCC::HVC1), &[0, 0, 0, 1, 0x26][..]));
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 0, &[0x27, 1, 0xff, 0xff, 0xfe, 0, 0, 0, 1, 0x41]);
+    let header = tag.video_header();
+    assert_eq!((header.frame_type, header.codec_id, header.packet_type, header.composition_time), (2, 7, 1, -2));
+    assert_eq!((header.fourcc, header.data), (None, &[0, 0, 0, 1, 0x41][..]));
+    // av01 coded frames carry no composition time
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 0, &[0x91, b'a', b'v', b'0', b'1', 0x12, 0]);
+    assert!(!tag.is_avc_or_hevc());
+    assert_eq!((tag.get_codec_id(), tag.get_avc_packet_type()), (0, 1));
+    assert_eq!(tag.get_avc_composition_time_offset(), 0);
+
+    // ModEx nanosecond offset, then two av01 tracks of CodedFramesX
+    let data: Vec<u8> = vec![0x97, 2, 0, 0x01, 0xf4, 0x06, 0x13, b'a', b'v', b'0', b'1',
+                             0, 0, 0, 2, 0xaa, 0xbb, 1, 0, 0, 1, 0xcc];
+    let packet = ExVideoPacket::parse(&data).unwrap();
+    assert_eq!(packet.get_timestamp_offset_nano(), 500);
+    assert_eq!(packet.packet_type, VIDEO_PACKET_TYPE_CODED_FRAMES_X);
+    assert_eq!(packet.multitrack_type, Some(MULTITRACK_TYPE_MANY_TRACKS));
+    assert_eq!(packet.tracks.iter().map(|t| (t.track_id, t.data)).collect::<Vec<_>>(), vec![(0, &[0xaa, 0xbb][..]), (1, &[0xcc][..])]);
+    let mut out: Vec<u8> = Vec::new();
+    packet.write(&mut out).unwrap();
+    assert_eq!(out, data);
+
+    // Opus and fLaC tracks in one audio tag
+    let data: Vec<u8> = vec![0x95, 0x21, b'O', b'p', b'u', b's', 0, 0, 0, 1, 0x11, b'f', b'L', b'a', b'C', 1, 0, 0, 1, 0x22];
+    let packet = ExAudioPacket::parse(&data).unwrap();
+    assert_eq!(packet.packet_type, AUDIO_PACKET_TYPE_CODED_FRAMES);
+    assert_eq!(packet.tracks.iter().map(|t| t.fourcc).collect::<Vec<_>>(), vec![FourCC::OPUS, FourCC::FLAC]);
+    let mut out: Vec<u8> = Vec::new();
+    packet.write(&mut out).unwrap();
+    assert_eq!(out, data);
+
+    let config = AudioMultichannelConfig::parse(&[AUDIO_CHANNEL_ORDER_CUSTOM, 2, 0, 1]).unwrap();
+    assert_eq!(config.channel_mapping, vec![0, 1]);
+
+    assert!(ExVideoPacket::parse(&[0x91, b'h', b'v']).is_err());
+
+    // what the header has no room for is refused instead of written wrong
+    let big = vec![0; 0x10001];
+    let mut packet = ExAudioPacket::parse(&data).unwrap();
+    packet.multitrack_type = Some(MULTITRACK_TYPE_MANY_TRACKS);
+    match packet.write(&mut Vec::new()) {
+        Err(FlvError::BadBitstream("tracks of different codecs without ManyTracksManyCodecs")) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    packet.multitrack_type = Some(MULTITRACK_TYPE_MANY_TRACKS_MANY_CODECS);
+    packet.mod_ex.push(ModEx { mod_ex_type: 0, data: &big });
+    match packet.write(&mut Vec::new()) {
+        Err(FlvError::BadBitstream("ModEx data size out of range")) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+
+use lib::{FLVTag, FLVTagType, FlvError, CODEC_ID_HEVC};
+use enhanced::VIDEO_FRAME_TYPE_KEYFRAME;
+use h264;
+use hevc;
+use sei::SeiMessage;
+
+/// video frame type of a tag that is not a keyframe
+pub const VIDEO_FRAME_TYPE_INTER_FRAME: u8 = 2;
+
+/// what the first slice of a NALU packet says about the frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameSliceInfo {
+    pub codec_id: u8,
+    pub nalu_type: u8,
+    /// P, B, I, SP or SI
+    pub slice_type: &'static str,
+    /// IDR for AVC, IRAP for HEVC, where decoding can start
+    pub random_access: bool,
+    /// AVC only
+    pub frame_num: Option<u32>,
+    /// pic_order_cnt_lsb, none for IDR pictures of HEVC and the AVC pic_order_cnt_type 1 and 2
+    pub pic_order_cnt_lsb: Option<u32>,
+    /// a recovery point SEI comes before the slice in the packet
+    pub recovery_point: bool,
+}
+
+impl FrameSliceInfo {
+    /// the frame type the tag should have
+    pub fn get_frame_type(&self) -> u8 {
+        if self.random_access { VIDEO_FRAME_TYPE_KEYFRAME } else { VIDEO_FRAME_TYPE_INTER_FRAME }
+    }
+
+    /// a keyframe flag on a frame decoding cannot start at, or a random access frame without it
+    pub fn is_mislabeled(&self, frame_type: u8) -> bool {
+        (frame_type == VIDEO_FRAME_TYPE_KEYFRAME) != self.random_access
+    }
+}
+
+impl fmt::Display for FrameSliceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.codec_id == CODEC_ID_HEVC {
+            hevc::get_nal_unit_type_name(self.nalu_type)
+        } else if self.random_access {
+            "IDR"
+        } else {
+            "non-IDR"
+        };
+        write!(f, "{} {}", name, self.slice_type)?;
+        if let Some(frame_num) = self.frame_num {
+            write!(f, " frame_num {}", frame_num)?;
+        }
+        if let Some(poc) = self.pic_order_cnt_lsb {
+            write!(f, " poc lsb {}", poc)?;
+        }
+        Ok(())
+    }
+}
+
+/// a broken SEI does not keep the slice from being parsed, it just has no recovery point
+fn has_recovery_point(nalu: &[u8], codec_id: u8) -> bool {
+    SeiMessage::parse_nal_unit(nalu, codec_id)
+        .map(|messages| messages.iter().any(|m| m.get_recovery_point().map_or(false, |rp| rp.is_some())))
+        .unwrap_or(false)
+}
+
+/// Parses the first slice header of each AVC/HEVC NALU packet, with the parameter sets of the
+/// sequence headers and the in-band ones before it.
+pub struct FrameChecker {
+    length_size: usize,
+    avc_sps: Vec<h264::Sps>,
+    avc_pps: Vec<h264::Pps>,
+    hevc_sps: Vec<hevc::Sps>,
+    hevc_pps: Vec<hevc::Pps>,
+}
+
+impl FrameChecker {
+    pub fn new() -> FrameChecker {
+        FrameChecker {
+            length_size: 4,
+            avc_sps: Vec::new(),
+            avc_pps: Vec::new(),
+            hevc_sps: Vec::new(),
+            hevc_pps: Vec::new(),
+        }
+    }
+
+    fn add_avc_sps(&mut self, nalu: &[u8]) -> Result<(), FlvError> {
+        let sps = h264::Sps::parse(nalu)?;
+        self.avc_sps.retain(|s| s.seq_parameter_set_id != sps.seq_parameter_set_id);
+        self.avc_sps.push(sps);
+        Ok(())
+    }
+
+    fn add_avc_pps(&mut self, nalu: &[u8]) -> Result<(), FlvError> {
+        if self.avc_sps.is_empty() {
+            return Err(FlvError::BadBitstream("pps before any sps"));
+        }
+        // the sps only sizes the scaling lists, parse again when the pps refers to another one
+        let mut pps = h264::Pps::parse(nalu, &self.avc_sps[0])?;
+        if let Some(sps) = self.avc_sps.iter().find(|s| s.seq_parameter_set_id == pps.seq_parameter_set_id) {
+            pps = h264::Pps::parse(nalu, sps)?;
+        }
+        self.avc_pps.retain(|p| p.pic_parameter_set_id != pps.pic_parameter_set_id);
+        self.avc_pps.push(pps);
+        Ok(())
+    }
+
+    fn add_hevc_sps(&mut self, nalu: &[u8]) -> Result<(), FlvError> {
+        let sps = hevc::Sps::parse(nalu)?;
+        self.hevc_sps.retain(|s| s.sps_seq_parameter_set_id != sps.sps_seq_parameter_set_id);
+        self.hevc_sps.push(sps);
+        Ok(())
+    }
+
+    fn add_hevc_pps(&mut self, nalu: &[u8]) -> Result<(), FlvError> {
+        let pps = hevc::Pps::parse(nalu)?;
+        self.hevc_pps.retain(|p| p.pps_pic_parameter_set_id != pps.pps_pic_parameter_set_id);
+        self.hevc_pps.push(pps);
+        Ok(())
+    }
+
+    /// Takes the parameter sets of a sequence header, or gives the first slice of a NALU packet.
+    /// `Ok(None)` for the other tags and packets without a slice.
+    pub fn push_tag<D: AsRef<[u8]>>(&mut self, tag: &FLVTag<D>) -> Result<Option<FrameSliceInfo>, FlvError> {
+        if tag.get_tag_type() != FLVTagType::TAG_TYPE_VIDEO || tag.get_data_size() == 0 || !tag.is_avc_or_hevc() {
+            return Ok(None);
+        }
+        let hevc = tag.get_codec_id() == CODEC_ID_HEVC;
+        if tag.is_video_sequence_header() {
+            self.length_size = tag.get_nal_length_size()?;
+            if hevc {
+                let hvcc = tag.get_hvcc()?;
+                self.hevc_sps.clear();
+                self.hevc_pps.clear();
+                for nalu in hvcc.get_sps() {
+                    self.add_hevc_sps(nalu)?;
+                }
+                for nalu in hvcc.get_pps() {
+                    self.add_hevc_pps(nalu)?;
+                }
+            } else {
+                let avcc = tag.try_get_avcc()?;
+                self.avc_sps.clear();
+                self.avc_pps.clear();
+                for nalu in avcc.sps_array.iter() {
+                    self.add_avc_sps(nalu)?;
+                }
+                for nalu in avcc.pps_array.iter() {
+                    self.add_avc_pps(nalu)?;
+                }
+            }
+            return Ok(None);
+        }
+        if tag.get_avc_packet_type() != 1 {
+            return Ok(None);
+        }
+        let mut recovery_point = false;
+        for nalu in tag.nal_units_with_length_size(self.length_size) {
+            if nalu.is_empty() {
+                continue;
+            }
+            if hevc {
+                match hevc::get_nal_unit_type(nalu[0]) {
+                    hevc::NAL_UNIT_TYPE_SPS => self.add_hevc_sps(nalu)?,
+                    hevc::NAL_UNIT_TYPE_PPS => self.add_hevc_pps(nalu)?,
+                    hevc::NAL_UNIT_TYPE_PREFIX_SEI => recovery_point |= has_recovery_point(nalu, CODEC_ID_HEVC),
+                    nalu_type @ 0..=31 => {
+                        let header = hevc::SliceHeader::parse(nalu, &self.hevc_sps, &self.hevc_pps)?;
+                        return Ok(Some(FrameSliceInfo {
+                            codec_id: CODEC_ID_HEVC,
+                            nalu_type,
+                            slice_type: header.get_slice_type_name(),
+                            random_access: hevc::is_irap(nalu_type),
+                            frame_num: None,
+                            pic_order_cnt_lsb: header.slice_pic_order_cnt_lsb,
+                            recovery_point,
+                        }));
+                    }
+                    _ => {}
+                }
+            } else {
+                match nalu[0] & 0x1f {
+                    h264::NAL_UNIT_TYPE_SPS => self.add_avc_sps(nalu)?,
+                    h264::NAL_UNIT_TYPE_PPS => self.add_avc_pps(nalu)?,
+                    h264::NAL_UNIT_TYPE_SEI => recovery_point |= has_recovery_point(nalu, tag.get_codec_id()),
+                    h264::NAL_UNIT_TYPE_SLICE | h264::NAL_UNIT_TYPE_IDR => {
+                        let header = h264::SliceHeader::parse(nalu, &self.avc_sps, &self.avc_pps)?;
+                        return Ok(Some(FrameSliceInfo {
+                            codec_id: tag.get_codec_id(),
+                            nalu_type: header.nal_unit_type,
+                            slice_type: header.get_slice_type_name(),
+                            random_access: header.is_idr(),
+                            frame_num: Some(header.frame_num),
+                            pic_order_cnt_lsb: header.pic_order_cnt_lsb,
+                            recovery_point,
+                        }));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Default for FrameChecker {
+    fn default() -> FrameChecker {
+        FrameChecker::new()
+    }
+}
+
+#[test]
+fn test_frame_check() {
+    // High profile SPS and PPS of the h264 test, frame_num 4 bits, poc type 0 with 6 bit lsb
+    let sps: Vec<u8> = vec![0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00,
+                            0x04, 0x00, 0x00, 0x03, 0x00, 0xf2, 0x3c, 0x22, 0x11, 0x65, 0x80];
+    let mut avcc: Vec<u8> = vec![0x17, 0, 0, 0, 0, 1, 0x64, 0x00, 0x28, 0xff, 0xe1, 0, sps.len() as u8];
+    avcc.extend_from_slice(&sps);
+    avcc.extend_from_slice(&[1, 0, 6, 0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0]);
+    let mut checker = FrameChecker::new();
+    assert_eq!(checker.push_tag(&FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 0, &avcc)).unwrap(), None);
+
+    // flagged as a keyframe, but a P slice: first_mb 0, slice_type 5, pps 0, frame_num 1, poc lsb 2
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 40, &[0x17, 1, 0, 0, 0, 0, 0, 0, 4, 0x41, 0x9a, 0x21, 0x08]);
+    let info = checker.push_tag(&tag).unwrap().unwrap();
+    assert_eq!((info.slice_type, info.random_access, info.frame_num, info.pic_order_cnt_lsb), ("P", false, Some(1), Some(2)));
+    assert!(info.is_mislabeled(tag.get_frame_type()));
+    let mut tag = tag;
+    tag.set_frame_type(info.get_frame_type());
+    assert_eq!(tag.get_frame_type(), 2);
+    assert_eq!(format!("{}", info), "non-IDR P frame_num 1 poc lsb 2");
+
+    // an IDR I slice: slice_type 7, frame_num 0, idr_pic_id 0, poc lsb 0
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 80, &[0x17, 1, 0, 0, 0, 0, 0, 0, 4, 0x65, 0x88, 0x84, 0x00]);
+    let info = checker.push_tag(&tag).unwrap().unwrap();
+    assert_eq!((info.slice_type, info.random_access, info.frame_num), ("I", true, Some(0)));
+    assert!(!info.is_mislabeled(tag.get_frame_type()));
+}
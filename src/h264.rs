@@ -0,0 +1,866 @@
+use std::cmp;
+
+use lib::FlvError;
+
+pub const NAL_UNIT_TYPE_SLICE: u8 = 1;
+pub const NAL_UNIT_TYPE_IDR: u8 = 5;
+pub const NAL_UNIT_TYPE_SEI: u8 = 6;
+pub const NAL_UNIT_TYPE_SPS: u8 = 7;
+pub const NAL_UNIT_TYPE_PPS: u8 = 8;
+pub const NAL_UNIT_TYPE_AUD: u8 = 9;
+
+/// slice_type % 5
+pub const SLICE_TYPE_P: u32 = 0;
+pub const SLICE_TYPE_B: u32 = 1;
+pub const SLICE_TYPE_I: u32 = 2;
+pub const SLICE_TYPE_SP: u32 = 3;
+pub const SLICE_TYPE_SI: u32 = 4;
+
+/// NAL unit payload with the emulation prevention bytes (00 00 03) taken out
+pub fn remove_emulation_prevention(ebsp: &[u8]) -> Vec<u8> {
+    let mut rbsp: Vec<u8> = Vec::with_capacity(ebsp.len());
+    let mut zeros = 0;
+    for &b in ebsp.iter() {
+        if zeros >= 2 && b == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+    rbsp
+}
+
+/// MSB first bit reader with exp-Golomb codes, over RBSP data
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize, // in bits
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data: data,
+            position: 0,
+        }
+    }
+
+    pub fn get_position(&self) -> usize {
+        self.position
+    }
+
+    pub fn bits_left(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, FlvError> {
+        if self.position >= self.data.len() * 8 {
+            return Err(FlvError::UnexpectedEof);
+        }
+        let bit = (self.data[self.position / 8] >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Ok(bit == 1)
+    }
+
+    /// up to 32 bits
+    pub fn read_bits(&mut self, n: u32) -> Result<u32, FlvError> {
+        assert!(n <= 32);
+        let mut value: u32 = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u32;
+        }
+        Ok(value)
+    }
+
+    pub fn skip_bits(&mut self, n: usize) -> Result<(), FlvError> {
+        if self.bits_left() < n {
+            self.position = self.data.len() * 8;
+            return Err(FlvError::UnexpectedEof);
+        }
+        self.position += n;
+        Ok(())
+    }
+
+    /// ue(v)
+    pub fn read_ue(&mut self) -> Result<u32, FlvError> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(FlvError::BadBitstream("exp-golomb code longer than 32 bits"));
+            }
+        }
+        let value = ((1u64 << leading_zeros) - 1 + self.read_bits(leading_zeros)? as u64) as u32;
+        Ok(value)
+    }
+
+    /// se(v)
+    pub fn read_se(&mut self) -> Result<i32, FlvError> {
+        let k = self.read_ue()? as i64;
+        Ok(if k % 2 == 1 { (k + 1) / 2 } else { -(k / 2) } as i32)
+    }
+
+    /// false when only the rbsp stop bit and the alignment zeros are left
+    pub fn more_rbsp_data(&self) -> bool {
+        let mut end = self.data.len();
+        while end > 0 && self.data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end == 0 {
+            return false;
+        }
+        let last = self.data[end - 1];
+        let stop_bit = (end - 1) * 8 + 7 - last.trailing_zeros() as usize;
+        self.position < stop_bit
+    }
+}
+
+fn check_nal_unit_type(nalu: &[u8], nalu_type: u8) -> Result<Vec<u8>, FlvError> {
+    if nalu.is_empty() {
+        return Err(FlvError::UnexpectedEof);
+    }
+    if nalu[0] & 0x1f != nalu_type {
+        return Err(FlvError::BadBitstream("unexpected nal unit type"));
+    }
+    Ok(remove_emulation_prevention(&nalu[1..]))
+}
+
+fn skip_scaling_list(r: &mut BitReader<'_>, size: usize) -> Result<(), FlvError> {
+    let mut last_scale: i32 = 8;
+    let mut next_scale: i32 = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = r.read_se()?;
+            if delta_scale < -128 || delta_scale > 127 {
+                return Err(FlvError::BadBitstream("delta_scale out of range"));
+            }
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+fn skip_scaling_lists(r: &mut BitReader<'_>, count: usize) -> Result<(), FlvError> {
+    for i in 0..count {
+        if r.read_bit()? { // scaling_list_present_flag
+            skip_scaling_list(r, if i < 6 { 16 } else { 64 })?;
+        }
+    }
+    Ok(())
+}
+
+/// bit_depth_*_minus8 of an SPS, up to 14 bits
+fn read_bit_depth(r: &mut BitReader<'_>) -> Result<u8, FlvError> {
+    let minus8 = r.read_ue()?;
+    if minus8 > 6 {
+        return Err(FlvError::BadBitstream("bit_depth_minus8 out of range"));
+    }
+    Ok(minus8 as u8 + 8)
+}
+
+/// log2_max_frame_num_minus4 or log2_max_pic_order_cnt_lsb_minus4, the slice header reads up to 16 bits of them
+fn read_log2_max(r: &mut BitReader<'_>, error: &'static str) -> Result<u32, FlvError> {
+    let minus4 = r.read_ue()?;
+    if minus4 > 12 {
+        return Err(FlvError::BadBitstream(error));
+    }
+    Ok(minus4 + 4)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HrdCpb {
+    pub bit_rate_value_minus1: u32,
+    pub cpb_size_value_minus1: u32,
+    pub cbr_flag: bool,
+}
+
+/// hrd_parameters() of the VUI
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hrd {
+    pub bit_rate_scale: u8,
+    pub cpb_size_scale: u8,
+    pub cpbs: Vec<HrdCpb>,
+    pub initial_cpb_removal_delay_length: u8,
+    pub cpb_removal_delay_length: u8,
+    pub dpb_output_delay_length: u8,
+    pub time_offset_length: u8,
+}
+
+impl Hrd {
+    fn read(r: &mut BitReader<'_>) -> Result<Hrd, FlvError> {
+        let cpb_cnt = r.read_ue()? as usize + 1;
+        if cpb_cnt > 32 {
+            return Err(FlvError::BadBitstream("cpb_cnt_minus1 out of range"));
+        }
+        let bit_rate_scale = r.read_bits(4)? as u8;
+        let cpb_size_scale = r.read_bits(4)? as u8;
+        let mut cpbs: Vec<HrdCpb> = Vec::with_capacity(cpb_cnt);
+        for _ in 0..cpb_cnt {
+            cpbs.push(HrdCpb {
+                bit_rate_value_minus1: r.read_ue()?,
+                cpb_size_value_minus1: r.read_ue()?,
+                cbr_flag: r.read_bit()?,
+            });
+        }
+        Ok(Hrd {
+            bit_rate_scale,
+            cpb_size_scale,
+            cpbs,
+            initial_cpb_removal_delay_length: r.read_bits(5)? as u8 + 1,
+            cpb_removal_delay_length: r.read_bits(5)? as u8 + 1,
+            dpb_output_delay_length: r.read_bits(5)? as u8 + 1,
+            time_offset_length: r.read_bits(5)? as u8,
+        })
+    }
+
+    /// bits per second of the first CPB
+    pub fn get_bit_rate(&self) -> u64 {
+        self.cpbs.first().map_or(0, |c| (c.bit_rate_value_minus1 as u64 + 1) << (6 + self.bit_rate_scale))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VuiTiming {
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate_flag: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vui {
+    pub aspect_ratio_idc: Option<u8>,
+    /// sample aspect ratio, set for aspect_ratio_idc 255 (Extended_SAR)
+    pub sar: Option<(u16, u16)>,
+    pub overscan_appropriate: Option<bool>,
+    pub video_format: Option<u8>,
+    pub video_full_range_flag: bool,
+    /// colour_primaries, transfer_characteristics, matrix_coefficients
+    pub colour_description: Option<(u8, u8, u8)>,
+    pub chroma_sample_loc: Option<(u32, u32)>,
+    pub timing: Option<VuiTiming>,
+    pub nal_hrd: Option<Hrd>,
+    pub vcl_hrd: Option<Hrd>,
+    pub low_delay_hrd_flag: bool,
+    pub pic_struct_present_flag: bool,
+    pub max_num_reorder_frames: Option<u32>,
+    pub max_dec_frame_buffering: Option<u32>,
+}
+
+impl Vui {
+    fn read(r: &mut BitReader<'_>) -> Result<Vui, FlvError> {
+        let mut aspect_ratio_idc = None;
+        let mut sar = None;
+        if r.read_bit()? {
+            let idc = r.read_bits(8)? as u8;
+            if idc == 255 {
+                sar = Some((r.read_bits(16)? as u16, r.read_bits(16)? as u16));
+            }
+            aspect_ratio_idc = Some(idc);
+        }
+        let overscan_appropriate = if r.read_bit()? { Some(r.read_bit()?) } else { None };
+        let mut video_format = None;
+        let mut video_full_range_flag = false;
+        let mut colour_description = None;
+        if r.read_bit()? { // video_signal_type_present_flag
+            video_format = Some(r.read_bits(3)? as u8);
+            video_full_range_flag = r.read_bit()?;
+            if r.read_bit()? {
+                colour_description = Some((r.read_bits(8)? as u8, r.read_bits(8)? as u8, r.read_bits(8)? as u8));
+            }
+        }
+        let chroma_sample_loc = if r.read_bit()? { Some((r.read_ue()?, r.read_ue()?)) } else { None };
+        let timing = if r.read_bit()? {
+            Some(VuiTiming {
+                num_units_in_tick: r.read_bits(32)?,
+                time_scale: r.read_bits(32)?,
+                fixed_frame_rate_flag: r.read_bit()?,
+            })
+        } else {
+            None
+        };
+        let nal_hrd = if r.read_bit()? { Some(Hrd::read(r)?) } else { None };
+        let vcl_hrd = if r.read_bit()? { Some(Hrd::read(r)?) } else { None };
+        let low_delay_hrd_flag = if nal_hrd.is_some() || vcl_hrd.is_some() { r.read_bit()? } else { false };
+        let pic_struct_present_flag = r.read_bit()?;
+        let mut max_num_reorder_frames = None;
+        let mut max_dec_frame_buffering = None;
+        if r.read_bit()? { // bitstream_restriction_flag
+            r.read_bit()?; // motion_vectors_over_pic_boundaries_flag
+            r.read_ue()?; // max_bytes_per_pic_denom
+            r.read_ue()?; // max_bits_per_mb_denom
+            r.read_ue()?; // log2_max_mv_length_horizontal
+            r.read_ue()?; // log2_max_mv_length_vertical
+            max_num_reorder_frames = Some(r.read_ue()?);
+            max_dec_frame_buffering = Some(r.read_ue()?);
+        }
+        Ok(Vui {
+            aspect_ratio_idc,
+            sar,
+            overscan_appropriate,
+            video_format,
+            video_full_range_flag,
+            colour_description,
+            chroma_sample_loc,
+            timing,
+            nal_hrd,
+            vcl_hrd,
+            low_delay_hrd_flag,
+            pic_struct_present_flag,
+            max_num_reorder_frames,
+            max_dec_frame_buffering,
+        })
+    }
+}
+
+/// seq_parameter_set_rbsp()
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane_flag: bool,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub log2_max_frame_num: u32,
+    pub pic_order_cnt_type: u32,
+    pub log2_max_pic_order_cnt_lsb: u32,
+    pub delta_pic_order_always_zero_flag: bool,
+    pub offset_for_non_ref_pic: i32,
+    pub offset_for_top_to_bottom_field: i32,
+    pub offset_for_ref_frame: Vec<i32>,
+    pub max_num_ref_frames: u32,
+    pub gaps_in_frame_num_value_allowed_flag: bool,
+    pub pic_width_in_mbs: u32,
+    pub pic_height_in_map_units: u32,
+    pub frame_mbs_only_flag: bool,
+    pub mb_adaptive_frame_field_flag: bool,
+    pub direct_8x8_inference_flag: bool,
+    /// left, right, top, bottom, in crop units
+    pub frame_cropping: Option<(u32, u32, u32, u32)>,
+    pub vui: Option<Vui>,
+}
+
+impl Sps {
+    /// `nalu` is a whole SPS NAL unit, header byte included
+    pub fn parse(nalu: &[u8]) -> Result<Sps, FlvError> {
+        let rbsp = check_nal_unit_type(nalu, NAL_UNIT_TYPE_SPS)?;
+        let mut r = BitReader::new(&rbsp);
+        let profile_idc = r.read_bits(8)? as u8;
+        let constraint_flags = r.read_bits(8)? as u8;
+        let level_idc = r.read_bits(8)? as u8;
+        let seq_parameter_set_id = r.read_ue()?;
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane_flag = false;
+        let mut bit_depth_luma = 8;
+        let mut bit_depth_chroma = 8;
+        match profile_idc {
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135 => {
+                chroma_format_idc = r.read_ue()?;
+                if chroma_format_idc > 3 {
+                    return Err(FlvError::BadBitstream("chroma_format_idc out of range"));
+                }
+                if chroma_format_idc == 3 {
+                    separate_colour_plane_flag = r.read_bit()?;
+                }
+                bit_depth_luma = read_bit_depth(&mut r)?;
+                bit_depth_chroma = read_bit_depth(&mut r)?;
+                r.read_bit()?; // qpprime_y_zero_transform_bypass_flag
+                if r.read_bit()? { // seq_scaling_matrix_present_flag
+                    skip_scaling_lists(&mut r, if chroma_format_idc != 3 { 8 } else { 12 })?;
+                }
+            }
+            _ => {}
+        }
+        let log2_max_frame_num = read_log2_max(&mut r, "log2_max_frame_num_minus4 out of range")?;
+        let pic_order_cnt_type = r.read_ue()?;
+        let mut log2_max_pic_order_cnt_lsb = 0;
+        let mut delta_pic_order_always_zero_flag = false;
+        let mut offset_for_non_ref_pic = 0;
+        let mut offset_for_top_to_bottom_field = 0;
+        let mut offset_for_ref_frame = Vec::new();
+        match pic_order_cnt_type {
+            0 => log2_max_pic_order_cnt_lsb = read_log2_max(&mut r, "log2_max_pic_order_cnt_lsb_minus4 out of range")?,
+            1 => {
+                delta_pic_order_always_zero_flag = r.read_bit()?;
+                offset_for_non_ref_pic = r.read_se()?;
+                offset_for_top_to_bottom_field = r.read_se()?;
+                let cycle = r.read_ue()?;
+                if cycle > 255 {
+                    return Err(FlvError::BadBitstream("num_ref_frames_in_pic_order_cnt_cycle out of range"));
+                }
+                for _ in 0..cycle {
+                    offset_for_ref_frame.push(r.read_se()?);
+                }
+            }
+            2 => {}
+            _ => return Err(FlvError::BadBitstream("pic_order_cnt_type out of range")),
+        }
+        let max_num_ref_frames = r.read_ue()?;
+        let gaps_in_frame_num_value_allowed_flag = r.read_bit()?;
+        let pic_width_in_mbs = r.read_ue()? + 1;
+        let pic_height_in_map_units = r.read_ue()? + 1;
+        let frame_mbs_only_flag = r.read_bit()?;
+        let mb_adaptive_frame_field_flag = if !frame_mbs_only_flag { r.read_bit()? } else { false };
+        let direct_8x8_inference_flag = r.read_bit()?;
+        let frame_cropping = if r.read_bit()? {
+            Some((r.read_ue()?, r.read_ue()?, r.read_ue()?, r.read_ue()?))
+        } else {
+            None
+        };
+        let vui = if r.read_bit()? { Some(Vui::read(&mut r)?) } else { None };
+        Ok(Sps {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            seq_parameter_set_id,
+            chroma_format_idc,
+            separate_colour_plane_flag,
+            bit_depth_luma,
+            bit_depth_chroma,
+            log2_max_frame_num,
+            pic_order_cnt_type,
+            log2_max_pic_order_cnt_lsb,
+            delta_pic_order_always_zero_flag,
+            offset_for_non_ref_pic,
+            offset_for_top_to_bottom_field,
+            offset_for_ref_frame,
+            max_num_ref_frames,
+            gaps_in_frame_num_value_allowed_flag,
+            pic_width_in_mbs,
+            pic_height_in_map_units,
+            frame_mbs_only_flag,
+            mb_adaptive_frame_field_flag,
+            direct_8x8_inference_flag,
+            frame_cropping,
+            vui,
+        })
+    }
+
+    /// ChromaArrayType
+    pub fn chroma_array_type(&self) -> u32 {
+        if self.separate_colour_plane_flag { 0 } else { self.chroma_format_idc }
+    }
+
+    // CropUnitX, CropUnitY
+    fn crop_units(&self) -> (u32, u32) {
+        let frame_factor = if self.frame_mbs_only_flag { 1 } else { 2 };
+        match self.chroma_array_type() {
+            0 => (1, frame_factor),
+            1 => (2, 2 * frame_factor),
+            2 => (2, frame_factor),
+            _ => (1, frame_factor),
+        }
+    }
+
+    /// luma width after cropping
+    pub fn get_width(&self) -> u32 {
+        let width = self.pic_width_in_mbs.saturating_mul(16);
+        match self.frame_cropping {
+            Some((left, right, _, _)) => width.saturating_sub(left.saturating_add(right).saturating_mul(self.crop_units().0)),
+            None => width,
+        }
+    }
+
+    /// luma height after cropping, a frame of two fields when `frame_mbs_only_flag` is not set
+    pub fn get_height(&self) -> u32 {
+        let height = (2 - self.frame_mbs_only_flag as u32).saturating_mul(self.pic_height_in_map_units).saturating_mul(16);
+        match self.frame_cropping {
+            Some((_, _, top, bottom)) => height.saturating_sub(top.saturating_add(bottom).saturating_mul(self.crop_units().1)),
+            None => height,
+        }
+    }
+
+    /// frames per second from the VUI timing info, a frame being two ticks
+    pub fn get_frame_rate(&self) -> Option<f64> {
+        match self.vui.as_ref().and_then(|v| v.timing.as_ref()) {
+            Some(t) if t.num_units_in_tick > 0 && t.time_scale > 0 => Some(t.time_scale as f64 / (2.0 * t.num_units_in_tick as f64)),
+            _ => None,
+        }
+    }
+
+    pub fn get_max_num_reorder_frames(&self) -> Option<u32> {
+        self.vui.as_ref().and_then(|v| v.max_num_reorder_frames)
+    }
+
+    pub fn get_profile_name(&self) -> &'static str {
+        match self.profile_idc {
+            66 => if self.constraint_flags & 0x40 != 0 { "Constrained Baseline" } else { "Baseline" },
+            77 => "Main",
+            88 => "Extended",
+            100 => "High",
+            110 => "High 10",
+            122 => "High 4:2:2",
+            244 => "High 4:4:4 Predictive",
+            44 => "CAVLC 4:4:4 Intra",
+            83 | 86 => "Scalable",
+            118 | 128 => "Multiview",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// pic_parameter_set_rbsp()
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pps {
+    pub pic_parameter_set_id: u32,
+    pub seq_parameter_set_id: u32,
+    pub entropy_coding_mode_flag: bool,
+    pub bottom_field_pic_order_in_frame_present_flag: bool,
+    pub num_slice_groups: u32,
+    pub num_ref_idx_l0_default_active: u32,
+    pub num_ref_idx_l1_default_active: u32,
+    pub weighted_pred_flag: bool,
+    pub weighted_bipred_idc: u8,
+    pub pic_init_qp: i32,
+    pub pic_init_qs: i32,
+    pub chroma_qp_index_offset: i32,
+    pub deblocking_filter_control_present_flag: bool,
+    pub constrained_intra_pred_flag: bool,
+    pub redundant_pic_cnt_present_flag: bool,
+    pub transform_8x8_mode_flag: bool,
+    pub second_chroma_qp_index_offset: i32,
+}
+
+impl Pps {
+    /// `nalu` is a whole PPS NAL unit, `sps` the one it refers to, its chroma format sizes the scaling lists
+    pub fn parse(nalu: &[u8], sps: &Sps) -> Result<Pps, FlvError> {
+        let rbsp = check_nal_unit_type(nalu, NAL_UNIT_TYPE_PPS)?;
+        let mut r = BitReader::new(&rbsp);
+        let pic_parameter_set_id = r.read_ue()?;
+        let seq_parameter_set_id = r.read_ue()?;
+        let entropy_coding_mode_flag = r.read_bit()?;
+        let bottom_field_pic_order_in_frame_present_flag = r.read_bit()?;
+        let num_slice_groups = r.read_ue()? + 1;
+        if num_slice_groups > 8 {
+            return Err(FlvError::BadBitstream("num_slice_groups_minus1 out of range"));
+        }
+        if num_slice_groups > 1 {
+            match r.read_ue()? { // slice_group_map_type
+                0 => for _ in 0..num_slice_groups {
+                    r.read_ue()?; // run_length_minus1
+                },
+                2 => for _ in 0..(num_slice_groups - 1) {
+                    r.read_ue()?; // top_left
+                    r.read_ue()?; // bottom_right
+                },
+                3 | 4 | 5 => {
+                    r.read_bit()?; // slice_group_change_direction_flag
+                    r.read_ue()?; // slice_group_change_rate_minus1
+                }
+                6 => {
+                    let pic_size_in_map_units = r.read_ue()? as usize + 1;
+                    let bits = 32 - (num_slice_groups - 1).leading_zeros() as usize;
+                    r.skip_bits(pic_size_in_map_units * bits)?;
+                }
+                _ => {}
+            }
+        }
+        let num_ref_idx_l0_default_active = r.read_ue()? + 1;
+        let num_ref_idx_l1_default_active = r.read_ue()? + 1;
+        let weighted_pred_flag = r.read_bit()?;
+        let weighted_bipred_idc = r.read_bits(2)? as u8;
+        let qp_bd_offset = 6 * (sps.bit_depth_luma as i32 - 8);
+        let pic_init_qp_minus26 = r.read_se()?;
+        if pic_init_qp_minus26 < -(26 + qp_bd_offset) || pic_init_qp_minus26 > 25 {
+            return Err(FlvError::BadBitstream("pic_init_qp_minus26 out of range"));
+        }
+        let pic_init_qs_minus26 = r.read_se()?;
+        if pic_init_qs_minus26 < -26 || pic_init_qs_minus26 > 25 {
+            return Err(FlvError::BadBitstream("pic_init_qs_minus26 out of range"));
+        }
+        let pic_init_qp = pic_init_qp_minus26 + 26;
+        let pic_init_qs = pic_init_qs_minus26 + 26;
+        let chroma_qp_index_offset = r.read_se()?;
+        let deblocking_filter_control_present_flag = r.read_bit()?;
+        let constrained_intra_pred_flag = r.read_bit()?;
+        let redundant_pic_cnt_present_flag = r.read_bit()?;
+        let mut transform_8x8_mode_flag = false;
+        let mut second_chroma_qp_index_offset = chroma_qp_index_offset;
+        if r.more_rbsp_data() {
+            transform_8x8_mode_flag = r.read_bit()?;
+            if r.read_bit()? { // pic_scaling_matrix_present_flag
+                let count = 6 + if transform_8x8_mode_flag { if sps.chroma_format_idc != 3 { 2 } else { 6 } } else { 0 };
+                skip_scaling_lists(&mut r, count)?;
+            }
+            second_chroma_qp_index_offset = r.read_se()?;
+        }
+        Ok(Pps {
+            pic_parameter_set_id,
+            seq_parameter_set_id,
+            entropy_coding_mode_flag,
+            bottom_field_pic_order_in_frame_present_flag,
+            num_slice_groups,
+            num_ref_idx_l0_default_active,
+            num_ref_idx_l1_default_active,
+            weighted_pred_flag,
+            weighted_bipred_idc,
+            pic_init_qp,
+            pic_init_qs,
+            chroma_qp_index_offset,
+            deblocking_filter_control_present_flag,
+            constrained_intra_pred_flag,
+            redundant_pic_cnt_present_flag,
+            transform_8x8_mode_flag,
+            second_chroma_qp_index_offset,
+        })
+    }
+}
+
+/// slice header fields up to the picture order count
+#[derive(Debug, Clone, PartialEq)]
+pub struct SliceHeader {
+    pub nal_unit_type: u8,
+    pub nal_ref_idc: u8,
+    pub first_mb_in_slice: u32,
+    /// slice_type % 5, see `SLICE_TYPE_P`
+    pub slice_type: u32,
+    pub pic_parameter_set_id: u32,
+    pub frame_num: u32,
+    pub field_pic_flag: bool,
+    pub bottom_field_flag: bool,
+    pub idr_pic_id: Option<u32>,
+    /// pic_order_cnt_type 0
+    pub pic_order_cnt_lsb: Option<u32>,
+    pub delta_pic_order_cnt_bottom: i32,
+    /// pic_order_cnt_type 1
+    pub delta_pic_order_cnt: [i32; 2],
+}
+
+impl SliceHeader {
+    /// `nalu` is a whole slice NAL unit, `sps` and `pps` the parameter sets in effect, picked by id
+    pub fn parse(nalu: &[u8], sps: &[Sps], pps: &[Pps]) -> Result<SliceHeader, FlvError> {
+        if nalu.is_empty() {
+            return Err(FlvError::UnexpectedEof);
+        }
+        let nal_unit_type = nalu[0] & 0x1f;
+        if nal_unit_type != NAL_UNIT_TYPE_SLICE && nal_unit_type != NAL_UNIT_TYPE_IDR {
+            return Err(FlvError::BadBitstream("unexpected nal unit type"));
+        }
+        // the header is short, no need to unescape the whole slice
+        let rbsp = remove_emulation_prevention(&nalu[1..cmp::min(nalu.len(), 64)]);
+        let mut r = BitReader::new(&rbsp);
+        let first_mb_in_slice = r.read_ue()?;
+        let slice_type = r.read_ue()?;
+        if slice_type > 9 {
+            return Err(FlvError::BadBitstream("slice_type out of range"));
+        }
+        let pic_parameter_set_id = r.read_ue()?;
+        let pps = match pps.iter().rev().find(|p| p.pic_parameter_set_id == pic_parameter_set_id) {
+            Some(pps) => pps,
+            None => return Err(FlvError::BadBitstream("slice refers to a missing pps")),
+        };
+        let sps = match sps.iter().rev().find(|s| s.seq_parameter_set_id == pps.seq_parameter_set_id) {
+            Some(sps) => sps,
+            None => return Err(FlvError::BadBitstream("pps refers to a missing sps")),
+        };
+        if sps.separate_colour_plane_flag {
+            r.read_bits(2)?; // colour_plane_id
+        }
+        let frame_num = r.read_bits(sps.log2_max_frame_num)?;
+        let mut field_pic_flag = false;
+        let mut bottom_field_flag = false;
+        if !sps.frame_mbs_only_flag {
+            field_pic_flag = r.read_bit()?;
+            if field_pic_flag {
+                bottom_field_flag = r.read_bit()?;
+            }
+        }
+        let idr_pic_id = if nal_unit_type == NAL_UNIT_TYPE_IDR { Some(r.read_ue()?) } else { None };
+        let mut pic_order_cnt_lsb = None;
+        let mut delta_pic_order_cnt_bottom = 0;
+        let mut delta_pic_order_cnt = [0, 0];
+        if sps.pic_order_cnt_type == 0 {
+            pic_order_cnt_lsb = Some(r.read_bits(sps.log2_max_pic_order_cnt_lsb)?);
+            if pps.bottom_field_pic_order_in_frame_present_flag && !field_pic_flag {
+                delta_pic_order_cnt_bottom = r.read_se()?;
+            }
+        }
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero_flag {
+            delta_pic_order_cnt[0] = r.read_se()?;
+            if pps.bottom_field_pic_order_in_frame_present_flag && !field_pic_flag {
+                delta_pic_order_cnt[1] = r.read_se()?;
+            }
+        }
+        Ok(SliceHeader {
+            nal_unit_type,
+            nal_ref_idc: (nalu[0] >> 5) & 0x3,
+            first_mb_in_slice,
+            slice_type: slice_type % 5,
+            pic_parameter_set_id,
+            frame_num,
+            field_pic_flag,
+            bottom_field_flag,
+            idr_pic_id,
+            pic_order_cnt_lsb,
+            delta_pic_order_cnt_bottom,
+            delta_pic_order_cnt,
+        })
+    }
+
+    pub fn is_idr(&self) -> bool {
+        self.nal_unit_type == NAL_UNIT_TYPE_IDR
+    }
+
+    /// I or SI, no reference to other pictures
+    pub fn is_intra(&self) -> bool {
+        self.slice_type == SLICE_TYPE_I || self.slice_type == SLICE_TYPE_SI
+    }
+
+    pub fn get_slice_type_name(&self) -> &'static str {
+        match self.slice_type {
+            SLICE_TYPE_P => "P",
+            SLICE_TYPE_B => "B",
+            SLICE_TYPE_I => "I",
+            SLICE_TYPE_SP => "SP",
+            _ => "SI",
+        }
+    }
+}
+
+/// Picture order counts of the pictures of a stream, in decoding order (8.2.1).
+/// Memory management control operation 5 is not seen, the slice header is not read that far.
+#[derive(Debug, Clone, Default)]
+pub struct PicOrderCnt {
+    prev_pic_order_cnt_msb: i64,
+    prev_pic_order_cnt_lsb: i64,
+    prev_frame_num: i64,
+    prev_frame_num_offset: i64,
+}
+
+impl PicOrderCnt {
+    pub fn new() -> PicOrderCnt {
+        PicOrderCnt::default()
+    }
+
+    /// the order count of the picture of `slice`, the smaller of the two fields for a frame
+    pub fn next(&mut self, sps: &Sps, slice: &SliceHeader) -> i64 {
+        let is_ref = slice.nal_ref_idc != 0;
+        let (top, bottom) = match sps.pic_order_cnt_type {
+            0 => {
+                if slice.is_idr() {
+                    self.prev_pic_order_cnt_msb = 0;
+                    self.prev_pic_order_cnt_lsb = 0;
+                }
+                let max_lsb = 1i64 << sps.log2_max_pic_order_cnt_lsb;
+                let lsb = slice.pic_order_cnt_lsb.unwrap_or(0) as i64;
+                let msb = if lsb < self.prev_pic_order_cnt_lsb && self.prev_pic_order_cnt_lsb - lsb >= max_lsb / 2 {
+                    self.prev_pic_order_cnt_msb + max_lsb
+                } else if lsb > self.prev_pic_order_cnt_lsb && lsb - self.prev_pic_order_cnt_lsb > max_lsb / 2 {
+                    self.prev_pic_order_cnt_msb - max_lsb
+                } else {
+                    self.prev_pic_order_cnt_msb
+                };
+                if is_ref {
+                    self.prev_pic_order_cnt_msb = msb;
+                    self.prev_pic_order_cnt_lsb = lsb;
+                }
+                let top = msb + lsb;
+                (top, if slice.field_pic_flag { top } else { top + slice.delta_pic_order_cnt_bottom as i64 })
+            }
+            pic_order_cnt_type => {
+                let frame_num = slice.frame_num as i64;
+                let frame_num_offset = if slice.is_idr() {
+                    0
+                } else if self.prev_frame_num > frame_num {
+                    self.prev_frame_num_offset + (1i64 << sps.log2_max_frame_num)
+                } else {
+                    self.prev_frame_num_offset
+                };
+                self.prev_frame_num = frame_num;
+                self.prev_frame_num_offset = frame_num_offset;
+                if pic_order_cnt_type == 2 {
+                    let count = if slice.is_idr() { 0 } else if is_ref { 2 * (frame_num_offset + frame_num) } else { 2 * (frame_num_offset + frame_num) - 1 };
+                    (count, count)
+                } else {
+                    self.type_1(sps, slice, frame_num_offset + frame_num)
+                }
+            }
+        };
+        if !slice.field_pic_flag {
+            cmp::min(top, bottom)
+        } else if slice.bottom_field_flag {
+            bottom
+        } else {
+            top
+        }
+    }
+
+    /// top and bottom field order counts of pic_order_cnt_type 1, wrapping on hostile offsets
+    fn type_1(&self, sps: &Sps, slice: &SliceHeader, frame_num: i64) -> (i64, i64) {
+        let cycle = &sps.offset_for_ref_frame;
+        let mut abs_frame_num = if cycle.is_empty() { 0 } else { frame_num };
+        if slice.nal_ref_idc == 0 && abs_frame_num > 0 {
+            abs_frame_num -= 1;
+        }
+        let mut expected: i64 = 0;
+        if abs_frame_num > 0 {
+            let delta_per_cycle = cycle.iter().fold(0i64, |sum, &offset| sum.wrapping_add(offset as i64));
+            let cycle_count = (abs_frame_num - 1) / cycle.len() as i64;
+            let frame_num_in_cycle = ((abs_frame_num - 1) % cycle.len() as i64) as usize;
+            expected = cycle_count.wrapping_mul(delta_per_cycle);
+            for &offset in &cycle[..(frame_num_in_cycle + 1)] {
+                expected = expected.wrapping_add(offset as i64);
+            }
+        }
+        if slice.nal_ref_idc == 0 {
+            expected = expected.wrapping_add(sps.offset_for_non_ref_pic as i64);
+        }
+        let top = expected.wrapping_add(slice.delta_pic_order_cnt[0] as i64);
+        let bottom = if !slice.field_pic_flag {
+            top.wrapping_add(sps.offset_for_top_to_bottom_field as i64).wrapping_add(slice.delta_pic_order_cnt[1] as i64)
+        } else {
+            expected.wrapping_add(sps.offset_for_top_to_bottom_field as i64).wrapping_add(slice.delta_pic_order_cnt[0] as i64)
+        };
+        (top, bottom)
+    }
+}
+
+#[test]
+fn test_sps_pps() {
+    // High profile 1920x1080 (68 mbs high, 8 lines cropped), 30 fps, two emulation prevention bytes
+    let sps: Vec<u8> = vec![0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00,
+                            0x04, 0x00, 0x00, 0x03, 0x00, 0xf2, 0x3c, 0x22, 0x11, 0x65, 0x80];
+    let sps = Sps::parse(&sps).unwrap();
+    assert_eq!(sps.profile_idc, 100);
+    assert_eq!(sps.level_idc, 40);
+    assert_eq!(sps.chroma_format_idc, 1);
+    assert_eq!(sps.bit_depth_luma, 8);
+    assert_eq!(sps.max_num_ref_frames, 4);
+    assert!(sps.frame_mbs_only_flag);
+    assert_eq!(sps.frame_cropping, Some((0, 0, 0, 4)));
+    assert_eq!((sps.get_width(), sps.get_height()), (1920, 1080));
+    assert_eq!(sps.get_frame_rate(), Some(30.0));
+    assert_eq!(sps.get_max_num_reorder_frames(), Some(2));
+    assert_eq!(sps.get_profile_name(), "High");
+
+    let pps = Pps::parse(&[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0], &sps).unwrap();
+    assert!(pps.entropy_coding_mode_flag);
+    assert_eq!(pps.num_ref_idx_l0_default_active, 3);
+    assert_eq!(pps.weighted_bipred_idc, 2);
+    assert_eq!(pps.pic_init_qp, 23);
+    assert!(pps.transform_8x8_mode_flag);
+    assert_eq!(pps.second_chroma_qp_index_offset, -2);
+    // pic_init_qp_minus26 of 2^31 - 1
+    match Pps::parse(&[0x68, 0xce, 0, 0, 0, 0, 0x7f, 0xff, 0xff, 0xff, 0x40], &sps) {
+        Err(FlvError::BadBitstream("pic_init_qp_minus26 out of range")) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+
+    match Sps::parse(&[0x68, 0x00]) {
+        Err(FlvError::BadBitstream(..)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    // Baseline, log2_max_frame_num_minus4 13 would make slice headers read 17 bit frame_num
+    match Sps::parse(&[0x67, 0x42, 0x00, 0x1e, 0x8e, 0x80]) {
+        Err(FlvError::BadBitstream("log2_max_frame_num_minus4 out of range")) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    assert_eq!(remove_emulation_prevention(&[0, 0, 3, 1, 0, 0, 3]), vec![0, 0, 1, 0, 0]);
+    let mut r = BitReader::new(&[0b1010_0110, 0b0100_0000]);
+    assert_eq!((r.read_ue().unwrap(), r.read_ue().unwrap(), r.read_se().unwrap(), r.read_se().unwrap()), (0, 1, -1, 2));
+}
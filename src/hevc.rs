@@ -0,0 +1,465 @@
+use std::cmp;
+use std::io::Write;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use lib::FlvError;
+use h264::{remove_emulation_prevention, BitReader};
+
+pub const NAL_UNIT_TYPE_BLA_W_LP: u8 = 16;
+pub const NAL_UNIT_TYPE_IDR_W_RADL: u8 = 19;
+pub const NAL_UNIT_TYPE_IDR_N_LP: u8 = 20;
+pub const NAL_UNIT_TYPE_CRA: u8 = 21;
+pub const NAL_UNIT_TYPE_VPS: u8 = 32;
+pub const NAL_UNIT_TYPE_SPS: u8 = 33;
+pub const NAL_UNIT_TYPE_PPS: u8 = 34;
+pub const NAL_UNIT_TYPE_AUD: u8 = 35;
+pub const NAL_UNIT_TYPE_PREFIX_SEI: u8 = 39;
+pub const NAL_UNIT_TYPE_SUFFIX_SEI: u8 = 40;
+
+pub const SLICE_TYPE_B: u32 = 0;
+pub const SLICE_TYPE_P: u32 = 1;
+pub const SLICE_TYPE_I: u32 = 2;
+
+/// nal_unit_type from the first byte of the two byte NAL unit header
+pub fn get_nal_unit_type(header: u8) -> u8 {
+    (header >> 1) & 0x3f
+}
+
+/// BLA, IDR and CRA pictures, where decoding can start
+pub fn is_irap(nalu_type: u8) -> bool {
+    nalu_type >= NAL_UNIT_TYPE_BLA_W_LP && nalu_type <= 23
+}
+
+pub fn get_nal_unit_type_name(nalu_type: u8) -> &'static str {
+    match nalu_type {
+        0 | 1 => "TRAIL",
+        2 | 3 => "TSA",
+        4 | 5 => "STSA",
+        6 | 7 => "RADL",
+        8 | 9 => "RASL",
+        16..=18 => "BLA",
+        19 | 20 => "IDR",
+        21 => "CRA",
+        32 => "VPS",
+        33 => "SPS",
+        34 => "PPS",
+        35 => "AUD",
+        36 => "EOS",
+        37 => "EOB",
+        38 => "FD",
+        39 | 40 => "SEI",
+        _ => "Unknown Nalu Type",
+    }
+}
+
+/// NAL units of one type in the record
+#[derive(Debug, Clone, PartialEq)]
+pub struct HvcCArray {
+    pub array_completeness: bool,
+    pub nal_unit_type: u8,
+    pub nal_units: Vec<Vec<u8>>,
+}
+
+/// HEVCDecoderConfigurationRecord, the sequence header of codec id 12
+#[derive(Debug, Clone, PartialEq)]
+pub struct HvcC {
+    pub configuration_version: u8,
+    pub general_profile_space: u8,
+    pub general_tier_flag: bool,
+    pub general_profile_idc: u8,
+    pub general_profile_compatibility_flags: u32,
+    pub general_constraint_indicator_flags: u64, // 48 bits
+    pub general_level_idc: u8,
+    pub min_spatial_segmentation_idc: u16,
+    pub parallelism_type: u8,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub avg_frame_rate: u16, // frames per 256 seconds
+    pub constant_frame_rate: u8,
+    pub num_temporal_layers: u8,
+    pub temporal_id_nested: bool,
+    pub length_size_minus_one: u8,
+    pub arrays: Vec<HvcCArray>,
+}
+
+impl HvcC {
+    pub fn parse(mut data: &[u8]) -> Result<HvcC, FlvError> {
+        let r = &mut data;
+        let configuration_version = r.read_u8()?;
+        let b = r.read_u8()?;
+        let general_profile_compatibility_flags = r.read_u32::<BigEndian>()?;
+        let general_constraint_indicator_flags = (r.read_u32::<BigEndian>()? as u64) << 16 | r.read_u16::<BigEndian>()? as u64;
+        let general_level_idc = r.read_u8()?;
+        let min_spatial_segmentation_idc = r.read_u16::<BigEndian>()? & 0x0fff;
+        let parallelism_type = r.read_u8()? & 0b11;
+        let chroma_format_idc = r.read_u8()? & 0b11;
+        let bit_depth_luma = (r.read_u8()? & 0b111) + 8;
+        let bit_depth_chroma = (r.read_u8()? & 0b111) + 8;
+        let avg_frame_rate = r.read_u16::<BigEndian>()?;
+        let c = r.read_u8()?;
+        let num_of_arrays = r.read_u8()?;
+        let mut arrays: Vec<HvcCArray> = Vec::with_capacity(num_of_arrays as usize);
+        for _ in 0..num_of_arrays {
+            let t = r.read_u8()?;
+            let num_nalus = r.read_u16::<BigEndian>()?;
+            let mut nal_units: Vec<Vec<u8>> = Vec::new();
+            for _ in 0..num_nalus {
+                let len = r.read_u16::<BigEndian>()? as usize;
+                if r.len() < len {
+                    return Err(FlvError::UnexpectedEof);
+                }
+                nal_units.push(r[..len].to_vec());
+                *r = &r[len..];
+            }
+            arrays.push(HvcCArray {
+                array_completeness: t & 0x80 != 0,
+                nal_unit_type: t & 0x3f,
+                nal_units,
+            });
+        }
+        Ok(HvcC {
+            configuration_version,
+            general_profile_space: b >> 6,
+            general_tier_flag: b & 0x20 != 0,
+            general_profile_idc: b & 0x1f,
+            general_profile_compatibility_flags,
+            general_constraint_indicator_flags,
+            general_level_idc,
+            min_spatial_segmentation_idc,
+            parallelism_type,
+            chroma_format_idc,
+            bit_depth_luma,
+            bit_depth_chroma,
+            avg_frame_rate,
+            constant_frame_rate: c >> 6,
+            num_temporal_layers: (c >> 3) & 0b111,
+            temporal_id_nested: c & 0b100 != 0,
+            length_size_minus_one: c & 0b11,
+            arrays,
+        })
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) {
+        w.write_u8(self.configuration_version).unwrap();
+        w.write_u8(self.general_profile_space << 6 | (self.general_tier_flag as u8) << 5 | self.general_profile_idc & 0x1f).unwrap();
+        w.write_u32::<BigEndian>(self.general_profile_compatibility_flags).unwrap();
+        w.write_u32::<BigEndian>((self.general_constraint_indicator_flags >> 16) as u32).unwrap();
+        w.write_u16::<BigEndian>(self.general_constraint_indicator_flags as u16).unwrap();
+        w.write_u8(self.general_level_idc).unwrap();
+        w.write_u16::<BigEndian>(0xf000 | self.min_spatial_segmentation_idc).unwrap();
+        w.write_u8(0xfc | self.parallelism_type).unwrap();
+        w.write_u8(0xfc | self.chroma_format_idc).unwrap();
+        w.write_u8(0xf8 | self.bit_depth_luma.saturating_sub(8) & 0b111).unwrap();
+        w.write_u8(0xf8 | self.bit_depth_chroma.saturating_sub(8) & 0b111).unwrap();
+        w.write_u16::<BigEndian>(self.avg_frame_rate).unwrap();
+        w.write_u8(self.constant_frame_rate << 6 | self.num_temporal_layers << 3 | (self.temporal_id_nested as u8) << 2 | self.length_size_minus_one).unwrap();
+        w.write_u8(self.arrays.len() as u8).unwrap();
+        for array in self.arrays.iter() {
+            w.write_u8((array.array_completeness as u8) << 7 | array.nal_unit_type).unwrap();
+            w.write_u16::<BigEndian>(array.nal_units.len() as u16).unwrap();
+            for nalu in array.nal_units.iter() {
+                w.write_u16::<BigEndian>(nalu.len() as u16).unwrap();
+                w.write_all(nalu).unwrap();
+            }
+        }
+    }
+
+    /// parameter sets of one NAL unit type, in record order
+    pub fn get_nal_units(&self, nal_unit_type: u8) -> Vec<&[u8]> {
+        self.arrays.iter()
+            .filter(|a| a.nal_unit_type == nal_unit_type)
+            .flat_map(|a| a.nal_units.iter().map(|n| &n[..]))
+            .collect()
+    }
+
+    pub fn get_vps(&self) -> Vec<&[u8]> {
+        self.get_nal_units(NAL_UNIT_TYPE_VPS)
+    }
+
+    pub fn get_sps(&self) -> Vec<&[u8]> {
+        self.get_nal_units(NAL_UNIT_TYPE_SPS)
+    }
+
+    pub fn get_pps(&self) -> Vec<&[u8]> {
+        self.get_nal_units(NAL_UNIT_TYPE_PPS)
+    }
+
+    pub fn get_profile_name(&self) -> &'static str {
+        match self.general_profile_idc {
+            1 => "Main",
+            2 => "Main 10",
+            3 => "Main Still Picture",
+            4 => "Format Range Extensions",
+            5 => "High Throughput",
+            9 => "Screen Content Coding",
+            _ => "Unknown",
+        }
+    }
+
+    /// general_level_idc is 30 times the level number
+    pub fn get_level(&self) -> f64 {
+        self.general_level_idc as f64 / 30.0
+    }
+
+    pub fn get_tier_name(&self) -> &'static str {
+        if self.general_tier_flag { "High" } else { "Main" }
+    }
+}
+
+fn check_nal_unit_type(nalu: &[u8], nalu_type: u8) -> Result<Vec<u8>, FlvError> {
+    if nalu.len() < 2 {
+        return Err(FlvError::UnexpectedEof);
+    }
+    if get_nal_unit_type(nalu[0]) != nalu_type {
+        return Err(FlvError::BadBitstream("unexpected nal unit type"));
+    }
+    Ok(remove_emulation_prevention(&nalu[2..]))
+}
+
+fn skip_profile_tier_level(r: &mut BitReader<'_>, max_sub_layers_minus1: u32) -> Result<(), FlvError> {
+    r.skip_bits(88 + 8)?; // general profile and level
+    let mut sub_layers: Vec<(bool, bool)> = Vec::new();
+    for _ in 0..max_sub_layers_minus1 {
+        sub_layers.push((r.read_bit()?, r.read_bit()?));
+    }
+    if max_sub_layers_minus1 > 0 {
+        r.skip_bits(2 * (8 - max_sub_layers_minus1 as usize))?; // reserved_zero_2bits
+    }
+    for &(profile_present, level_present) in sub_layers.iter() {
+        r.skip_bits(if profile_present { 88 } else { 0 } + if level_present { 8 } else { 0 })?;
+    }
+    Ok(())
+}
+
+/// bit_depth_luma_minus8 or bit_depth_chroma_minus8, up to 16 bits with the range extensions
+fn read_bit_depth(r: &mut BitReader<'_>) -> Result<u8, FlvError> {
+    let minus8 = r.read_ue()?;
+    if minus8 > 8 {
+        return Err(FlvError::BadBitstream("bit_depth_minus8 out of range"));
+    }
+    Ok(minus8 as u8 + 8)
+}
+
+/// the head of an HEVC SPS, up to what a slice header needs and the reorder limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub sps_video_parameter_set_id: u8,
+    pub sps_max_sub_layers: u8,
+    pub sps_seq_parameter_set_id: u32,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane_flag: bool,
+    pub pic_width_in_luma_samples: u32,
+    pub pic_height_in_luma_samples: u32,
+    /// left, right, top, bottom offsets
+    pub conformance_window: Option<(u32, u32, u32, u32)>,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub log2_max_pic_order_cnt_lsb: u32,
+    /// of the highest sub-layer
+    pub sps_max_num_reorder_pics: u32,
+}
+
+impl Sps {
+    pub fn parse(nalu: &[u8]) -> Result<Sps, FlvError> {
+        let rbsp = check_nal_unit_type(nalu, NAL_UNIT_TYPE_SPS)?;
+        let mut r = BitReader::new(&rbsp);
+        let sps_video_parameter_set_id = r.read_bits(4)? as u8;
+        let max_sub_layers_minus1 = r.read_bits(3)?;
+        r.read_bit()?; // sps_temporal_id_nesting_flag
+        skip_profile_tier_level(&mut r, max_sub_layers_minus1)?;
+        let sps_seq_parameter_set_id = r.read_ue()?;
+        let chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc > 3 {
+            return Err(FlvError::BadBitstream("chroma_format_idc out of range"));
+        }
+        let separate_colour_plane_flag = if chroma_format_idc == 3 { r.read_bit()? } else { false };
+        let pic_width_in_luma_samples = r.read_ue()?;
+        let pic_height_in_luma_samples = r.read_ue()?;
+        let conformance_window = if r.read_bit()? {
+            Some((r.read_ue()?, r.read_ue()?, r.read_ue()?, r.read_ue()?))
+        } else {
+            None
+        };
+        let bit_depth_luma = read_bit_depth(&mut r)?;
+        let bit_depth_chroma = read_bit_depth(&mut r)?;
+        let log2_max_pic_order_cnt_lsb_minus4 = r.read_ue()?;
+        if log2_max_pic_order_cnt_lsb_minus4 > 12 {
+            return Err(FlvError::BadBitstream("log2_max_pic_order_cnt_lsb_minus4 out of range"));
+        }
+        let log2_max_pic_order_cnt_lsb = log2_max_pic_order_cnt_lsb_minus4 + 4;
+        let mut sps_max_num_reorder_pics = 0;
+        let first_sub_layer = if r.read_bit()? { 0 } else { max_sub_layers_minus1 }; // sps_sub_layer_ordering_info_present_flag
+        for _ in first_sub_layer..(max_sub_layers_minus1 + 1) {
+            r.read_ue()?; // sps_max_dec_pic_buffering_minus1
+            sps_max_num_reorder_pics = r.read_ue()?;
+            r.read_ue()?; // sps_max_latency_increase_plus1
+        }
+        Ok(Sps {
+            sps_video_parameter_set_id,
+            sps_max_sub_layers: max_sub_layers_minus1 as u8 + 1,
+            sps_seq_parameter_set_id,
+            chroma_format_idc,
+            separate_colour_plane_flag,
+            pic_width_in_luma_samples,
+            pic_height_in_luma_samples,
+            conformance_window,
+            bit_depth_luma,
+            bit_depth_chroma,
+            log2_max_pic_order_cnt_lsb,
+            sps_max_num_reorder_pics,
+        })
+    }
+}
+
+/// the head of an HEVC PPS, up to what a slice header needs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pps {
+    pub pps_pic_parameter_set_id: u32,
+    pub pps_seq_parameter_set_id: u32,
+    pub dependent_slice_segments_enabled_flag: bool,
+    pub output_flag_present_flag: bool,
+    pub num_extra_slice_header_bits: u8,
+}
+
+impl Pps {
+    pub fn parse(nalu: &[u8]) -> Result<Pps, FlvError> {
+        let rbsp = check_nal_unit_type(nalu, NAL_UNIT_TYPE_PPS)?;
+        let mut r = BitReader::new(&rbsp);
+        Ok(Pps {
+            pps_pic_parameter_set_id: r.read_ue()?,
+            pps_seq_parameter_set_id: r.read_ue()?,
+            dependent_slice_segments_enabled_flag: r.read_bit()?,
+            output_flag_present_flag: r.read_bit()?,
+            num_extra_slice_header_bits: r.read_bits(3)? as u8,
+        })
+    }
+}
+
+/// header of the first slice segment of a picture, up to the picture order count
+#[derive(Debug, Clone, PartialEq)]
+pub struct SliceHeader {
+    pub nal_unit_type: u8,
+    pub no_output_of_prior_pics_flag: bool,
+    pub slice_pic_parameter_set_id: u32,
+    /// see `SLICE_TYPE_B`
+    pub slice_type: u32,
+    pub pic_output_flag: bool,
+    /// none for IDR pictures
+    pub slice_pic_order_cnt_lsb: Option<u32>,
+}
+
+impl SliceHeader {
+    /// `nalu` is the first slice segment of a picture, `sps` and `pps` the parameter sets in effect, picked by id
+    pub fn parse(nalu: &[u8], sps: &[Sps], pps: &[Pps]) -> Result<SliceHeader, FlvError> {
+        if nalu.len() < 2 {
+            return Err(FlvError::UnexpectedEof);
+        }
+        let nal_unit_type = get_nal_unit_type(nalu[0]);
+        if nal_unit_type > 31 {
+            return Err(FlvError::BadBitstream("unexpected nal unit type"));
+        }
+        let rbsp = remove_emulation_prevention(&nalu[2..cmp::min(nalu.len(), 64)]);
+        let mut r = BitReader::new(&rbsp);
+        if !r.read_bit()? {
+            return Err(FlvError::BadBitstream("not the first slice segment of a picture"));
+        }
+        let no_output_of_prior_pics_flag = if is_irap(nal_unit_type) { r.read_bit()? } else { false };
+        let slice_pic_parameter_set_id = r.read_ue()?;
+        let pps = match pps.iter().rev().find(|p| p.pps_pic_parameter_set_id == slice_pic_parameter_set_id) {
+            Some(pps) => pps,
+            None => return Err(FlvError::BadBitstream("slice refers to a missing pps")),
+        };
+        let sps = match sps.iter().rev().find(|s| s.sps_seq_parameter_set_id == pps.pps_seq_parameter_set_id) {
+            Some(sps) => sps,
+            None => return Err(FlvError::BadBitstream("pps refers to a missing sps")),
+        };
+        r.skip_bits(pps.num_extra_slice_header_bits as usize)?; // slice_reserved_flag
+        let slice_type = r.read_ue()?;
+        if slice_type > 2 {
+            return Err(FlvError::BadBitstream("slice_type out of range"));
+        }
+        let pic_output_flag = if pps.output_flag_present_flag { r.read_bit()? } else { true };
+        if sps.separate_colour_plane_flag {
+            r.read_bits(2)?; // colour_plane_id
+        }
+        let slice_pic_order_cnt_lsb = if nal_unit_type != NAL_UNIT_TYPE_IDR_W_RADL && nal_unit_type != NAL_UNIT_TYPE_IDR_N_LP {
+            Some(r.read_bits(sps.log2_max_pic_order_cnt_lsb)?)
+        } else {
+            None
+        };
+        Ok(SliceHeader {
+            nal_unit_type,
+            no_output_of_prior_pics_flag,
+            slice_pic_parameter_set_id,
+            slice_type,
+            pic_output_flag,
+            slice_pic_order_cnt_lsb,
+        })
+    }
+
+    pub fn get_slice_type_name(&self) -> &'static str {
+        match self.slice_type {
+            SLICE_TYPE_B => "B",
+            SLICE_TYPE_P => "P",
+            _ => "I",
+        }
+    }
+}
+
+#[test]
+fn test_hvcc() {
+    // Main profile level 4.1, 4 byte lengths, one VPS, SPS and PPS
+    let mut data: Vec<u8> = vec![0x01, 0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 123,
+                                 0xf0, 0x00, 0xfc, 0xfd, 0xf8, 0xf8, 0x00, 0x00, 0x0f, 0x03];
+    data.extend_from_slice(&[0xa0, 0, 1, 0, 3, 0x40, 0x01, 0x0c]);
+    data.extend_from_slice(&[0xa1, 0, 1, 0, 3, 0x42, 0x01, 0x01]);
+    data.extend_from_slice(&[0xa2, 0, 1, 0, 2, 0x44, 0x01]);
+    let hvcc = HvcC::parse(&data).unwrap();
+    assert_eq!(hvcc.get_profile_name(), "Main");
+    assert_eq!(hvcc.get_tier_name(), "Main");
+    assert!((hvcc.get_level() - 4.1).abs() < 1e-9);
+    assert_eq!(hvcc.chroma_format_idc, 1);
+    assert_eq!(hvcc.length_size_minus_one, 3);
+    assert_eq!(hvcc.num_temporal_layers, 1);
+    assert_eq!(hvcc.get_vps(), vec![&[0x40, 0x01, 0x0c][..]]);
+    assert_eq!(get_nal_unit_type(hvcc.get_sps()[0][0]), NAL_UNIT_TYPE_SPS);
+    assert_eq!(get_nal_unit_type(hvcc.get_pps()[0][0]), NAL_UNIT_TYPE_PPS);
+
+    let mut out: Vec<u8> = Vec::new();
+    hvcc.write(&mut out);
+    assert_eq!(out, data);
+
+    match HvcC::parse(&data[..(data.len() - 1)]) {
+        Err(FlvError::UnexpectedEof) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn test_slice_header() {
+    // Main profile 1920x1080, 8 bit lsb of picture order count, 2 reorder pictures
+    let sps = Sps::parse(&[0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03,
+                           0x00, 0x5d, 0xa0, 0x03, 0xc0, 0x80, 0x10, 0xe5, 0x94, 0x57, 0x80]).unwrap();
+    assert_eq!((sps.pic_width_in_luma_samples, sps.pic_height_in_luma_samples), (1920, 1080));
+    assert_eq!((sps.chroma_format_idc, sps.bit_depth_luma, sps.log2_max_pic_order_cnt_lsb), (1, 8, 8));
+    assert_eq!(sps.sps_max_num_reorder_pics, 2);
+    // the same with bit_depth_luma_minus8 9
+    match Sps::parse(&[0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03,
+                       0x00, 0x5d, 0xa0, 0x03, 0xc0, 0x80, 0x10, 0xe4, 0x2a, 0x51, 0x5e, 0x00]) {
+        Err(FlvError::BadBitstream("bit_depth_minus8 out of range")) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    let pps = Pps::parse(&[0x44, 0x01, 0xc1]).unwrap();
+    assert_eq!(pps.num_extra_slice_header_bits, 0);
+
+    let cra = SliceHeader::parse(&[0x2a, 0x01, 0xac, 0x16], &[sps.clone()], &[pps.clone()]).unwrap();
+    assert_eq!((cra.nal_unit_type, cra.get_slice_type_name(), cra.slice_pic_order_cnt_lsb), (NAL_UNIT_TYPE_CRA, "I", Some(5)));
+    let trail = SliceHeader::parse(&[0x02, 0x01, 0xe0, 0xf0], &[sps.clone()], &[pps.clone()]).unwrap();
+    assert_eq!((trail.get_slice_type_name(), trail.slice_pic_order_cnt_lsb), ("B", Some(7)));
+    match SliceHeader::parse(&[0x02, 0x01, 0xe0, 0xf0], &[sps], &[]) {
+        Err(FlvError::BadBitstream(..)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
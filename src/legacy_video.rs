@@ -0,0 +1,426 @@
+use std::fmt;
+
+use h264::BitReader;
+use lib::{FLVTag, FLVTagType, FlvError, read_u24_be};
+use lib::{CODEC_ID_SORENSON_H263, CODEC_ID_SCREEN_VIDEO, CODEC_ID_VP6, CODEC_ID_VP6_ALPHA, CODEC_ID_SCREEN_VIDEO_V2};
+use enhanced::{VIDEO_FRAME_TYPE_KEYFRAME, VIDEO_FRAME_TYPE_COMMAND};
+use frame_check::VIDEO_FRAME_TYPE_INTER_FRAME;
+
+/// video frame type of an H.263 disposable inter frame
+pub const VIDEO_FRAME_TYPE_DISPOSABLE_INTER_FRAME: u8 = 3;
+
+pub const H263_PICTURE_TYPE_INTRA: u8 = 0;
+pub const H263_PICTURE_TYPE_INTER: u8 = 1;
+pub const H263_PICTURE_TYPE_DISPOSABLE_INTER: u8 = 2;
+
+/// picture header of Sorenson H.263, codec id 2
+#[derive(Debug, Clone, PartialEq)]
+pub struct H263PictureHeader {
+    /// 0 or 1, version 1 has escape codes for larger coefficients
+    pub version: u8,
+    pub temporal_reference: u8,
+    pub width: u16,
+    pub height: u16,
+    pub picture_type: u8,
+    pub deblocking: bool,
+    pub quantizer: u8,
+}
+
+impl H263PictureHeader {
+    pub fn parse(data: &[u8]) -> Result<H263PictureHeader, FlvError> {
+        let mut r = BitReader::new(data);
+        if r.read_bits(17)? != 1 {
+            return Err(FlvError::BadBitstream("h263 picture start code not found"));
+        }
+        let version = r.read_bits(5)? as u8;
+        if version > 1 {
+            return Err(FlvError::BadBitstream("h263 version is not 0 or 1"));
+        }
+        let temporal_reference = r.read_bits(8)? as u8;
+        let (width, height) = match r.read_bits(3)? {
+            0 => (r.read_bits(8)? as u16, r.read_bits(8)? as u16),
+            1 => (r.read_bits(16)? as u16, r.read_bits(16)? as u16),
+            2 => (352, 288),
+            3 => (176, 144),
+            4 => (128, 96),
+            5 => (320, 240),
+            6 => (160, 120),
+            _ => return Err(FlvError::BadBitstream("h263 picture size is reserved")),
+        };
+        let picture_type = r.read_bits(2)? as u8;
+        if picture_type > H263_PICTURE_TYPE_DISPOSABLE_INTER {
+            return Err(FlvError::BadBitstream("h263 picture type is reserved"));
+        }
+        Ok(H263PictureHeader {
+            version,
+            temporal_reference,
+            width,
+            height,
+            picture_type,
+            deblocking: r.read_bit()?,
+            quantizer: r.read_bits(5)? as u8,
+        })
+    }
+
+    pub fn get_picture_type_name(&self) -> &'static str {
+        match self.picture_type {
+            H263_PICTURE_TYPE_INTRA => "I",
+            H263_PICTURE_TYPE_INTER => "P",
+            _ => "disposable P",
+        }
+    }
+}
+
+/// Screen Video packet of codec id 3, or 6 for version 2
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScreenVideoHeader {
+    pub version: u8,
+    pub block_width: u16,
+    pub image_width: u16,
+    pub block_height: u16,
+    pub image_height: u16,
+    /// blocks per row and column, from the bottom left
+    pub columns: u16,
+    pub rows: u16,
+    /// blocks with data, the others did not change since the previous frame
+    pub changed_blocks: usize,
+    /// version 2 blocks coded against the previous frame, with a diff region or a zlib prime
+    pub dependent_blocks: usize,
+    /// version 2 frame the later ones may prime their zlib streams with
+    pub iframe_image: bool,
+    /// version 2 palette block before the image blocks
+    pub has_palette: bool,
+}
+
+impl ScreenVideoHeader {
+    pub fn parse(data: &[u8], version: u8) -> Result<ScreenVideoHeader, FlvError> {
+        if data.len() < 4 {
+            return Err(FlvError::UnexpectedEof);
+        }
+        let block_width = ((data[0] >> 4) as u16 + 1) * 16;
+        let image_width = (data[0] as u16 & 0x0f) << 8 | data[1] as u16;
+        let block_height = ((data[2] >> 4) as u16 + 1) * 16;
+        let image_height = (data[2] as u16 & 0x0f) << 8 | data[3] as u16;
+        let mut r = &data[4..];
+        let (mut iframe_image, mut has_palette) = (false, false);
+        if version == 2 {
+            // reserved 6, has iframe image, has palette info
+            let flags = *r.first().ok_or(FlvError::UnexpectedEof)?;
+            iframe_image = flags & 0x02 != 0;
+            has_palette = flags & 0x01 != 0;
+            r = &r[1..];
+            if has_palette {
+                // an image block of its own, only skipped
+                if r.len() < 2 {
+                    return Err(FlvError::UnexpectedEof);
+                }
+                let size = (r[0] as usize) << 8 | r[1] as usize;
+                if r.len() < 2 + size {
+                    return Err(FlvError::UnexpectedEof);
+                }
+                r = &r[(2 + size)..];
+            }
+        }
+        let columns = (image_width + block_width - 1) / block_width;
+        let rows = (image_height + block_height - 1) / block_height;
+        let (mut changed_blocks, mut dependent_blocks) = (0, 0);
+        for _ in 0..(columns as usize * rows as usize) {
+            if r.len() < 2 {
+                return Err(FlvError::UnexpectedEof);
+            }
+            let size = (r[0] as usize) << 8 | r[1] as usize;
+            r = &r[2..];
+            if size == 0 {
+                continue;
+            }
+            if r.len() < size {
+                return Err(FlvError::UnexpectedEof);
+            }
+            changed_blocks += 1;
+            // reserved 3, color depth 2, has diff blocks, zlib prime current, zlib prime previous
+            if version == 2 && r[0] & 0x05 != 0 {
+                dependent_blocks += 1;
+            }
+            r = &r[size..];
+        }
+        Ok(ScreenVideoHeader {
+            version,
+            block_width,
+            image_width,
+            block_height,
+            image_height,
+            columns,
+            rows,
+            changed_blocks,
+            dependent_blocks,
+            iframe_image,
+            has_palette,
+        })
+    }
+
+    /// every block is there and none refers to the previous frame
+    pub fn is_keyframe(&self) -> bool {
+        self.changed_blocks > 0 && self.changed_blocks == self.columns as usize * self.rows as usize && self.dependent_blocks == 0
+    }
+}
+
+/// VP6 frame header, the sizes are only in keyframes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vp6FrameHeader {
+    pub keyframe: bool,
+    pub quantizer: u8,
+    /// coefficients in a second partition
+    pub separated_coeff: bool,
+    pub version: Option<u8>,
+    /// 0 simple, 3 advanced
+    pub profile: Option<u8>,
+    pub interlaced: bool,
+    /// stored macroblock rows and columns
+    pub mb_rows: Option<u8>,
+    pub mb_cols: Option<u8>,
+    /// displayed macroblock rows and columns
+    pub display_mb_rows: Option<u8>,
+    pub display_mb_cols: Option<u8>,
+}
+
+impl Vp6FrameHeader {
+    pub fn parse(data: &[u8]) -> Result<Vp6FrameHeader, FlvError> {
+        let b0 = *data.first().ok_or(FlvError::UnexpectedEof)?;
+        let mut header = Vp6FrameHeader {
+            keyframe: b0 & 0x80 == 0,
+            quantizer: (b0 >> 1) & 0x3f,
+            separated_coeff: b0 & 0x01 != 0,
+            version: None,
+            profile: None,
+            interlaced: false,
+            mb_rows: None,
+            mb_cols: None,
+            display_mb_rows: None,
+            display_mb_cols: None,
+        };
+        if !header.keyframe {
+            return Ok(header);
+        }
+        let b1 = *data.get(1).ok_or(FlvError::UnexpectedEof)?;
+        let profile = (b1 >> 1) & 0x03;
+        // the offset of the second partition comes first
+        let sizes = if header.separated_coeff || profile == 0 { 4 } else { 2 };
+        if data.len() < sizes + 4 {
+            return Err(FlvError::UnexpectedEof);
+        }
+        if data[sizes] == 0 || data[sizes + 1] == 0 {
+            return Err(FlvError::BadBitstream("vp6 keyframe without macroblocks"));
+        }
+        header.version = Some(b1 >> 3);
+        header.profile = Some(profile);
+        header.interlaced = b1 & 0x01 != 0;
+        header.mb_rows = Some(data[sizes]);
+        header.mb_cols = Some(data[sizes + 1]);
+        header.display_mb_rows = Some(data[sizes + 2]);
+        header.display_mb_cols = Some(data[sizes + 3]);
+        Ok(header)
+    }
+}
+
+/// VP6 packet of codec id 4, or 5 with an alpha channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vp6Header {
+    /// pixels cropped from the right and the bottom of the macroblocks
+    pub horizontal_adjustment: u8,
+    pub vertical_adjustment: u8,
+    /// VP6A only, the size of the color frame
+    pub alpha_offset: Option<u32>,
+    pub frame: Vp6FrameHeader,
+    pub alpha_frame: Option<Vp6FrameHeader>,
+}
+
+impl Vp6Header {
+    pub fn parse(data: &[u8], alpha: bool) -> Result<Vp6Header, FlvError> {
+        let adjustment = *data.first().ok_or(FlvError::UnexpectedEof)?;
+        let mut r = &data[1..];
+        let (alpha_offset, frame, alpha_frame) = if alpha {
+            let offset = read_u24_be(&mut r)?;
+            if r.len() < offset as usize {
+                return Err(FlvError::UnexpectedEof);
+            }
+            let (color, alpha) = r.split_at(offset as usize);
+            let alpha_frame = if alpha.is_empty() { None } else { Some(Vp6FrameHeader::parse(alpha)?) };
+            (Some(offset), Vp6FrameHeader::parse(color)?, alpha_frame)
+        } else {
+            (None, Vp6FrameHeader::parse(r)?, None)
+        };
+        Ok(Vp6Header {
+            horizontal_adjustment: adjustment >> 4,
+            vertical_adjustment: adjustment & 0x0f,
+            alpha_offset,
+            frame,
+            alpha_frame,
+        })
+    }
+
+    pub fn get_width(&self) -> Option<u32> {
+        self.frame.display_mb_cols.map(|cols| (cols as u32 * 16).saturating_sub(self.horizontal_adjustment as u32))
+    }
+
+    pub fn get_height(&self) -> Option<u32> {
+        self.frame.display_mb_rows.map(|rows| (rows as u32 * 16).saturating_sub(self.vertical_adjustment as u32))
+    }
+}
+
+/// picture header of the video codecs before AVC
+#[derive(Debug, Clone, PartialEq)]
+pub enum LegacyVideoHeader {
+    SorensonH263(H263PictureHeader),
+    ScreenVideo(ScreenVideoHeader),
+    Vp6(Vp6Header),
+}
+
+impl LegacyVideoHeader {
+    /// `None` for VP6 inter frames
+    pub fn get_width(&self) -> Option<u32> {
+        match *self {
+            LegacyVideoHeader::SorensonH263(ref h) => Some(h.width as u32),
+            LegacyVideoHeader::ScreenVideo(ref h) => Some(h.image_width as u32),
+            LegacyVideoHeader::Vp6(ref h) => h.get_width(),
+        }
+    }
+
+    pub fn get_height(&self) -> Option<u32> {
+        match *self {
+            LegacyVideoHeader::SorensonH263(ref h) => Some(h.height as u32),
+            LegacyVideoHeader::ScreenVideo(ref h) => Some(h.image_height as u32),
+            LegacyVideoHeader::Vp6(ref h) => h.get_height(),
+        }
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        match *self {
+            LegacyVideoHeader::SorensonH263(ref h) => h.picture_type == H263_PICTURE_TYPE_INTRA,
+            LegacyVideoHeader::ScreenVideo(ref h) => h.is_keyframe(),
+            LegacyVideoHeader::Vp6(ref h) => h.frame.keyframe,
+        }
+    }
+
+    /// the frame type the tag should have
+    pub fn get_frame_type(&self) -> u8 {
+        match *self {
+            _ if self.is_keyframe() => VIDEO_FRAME_TYPE_KEYFRAME,
+            LegacyVideoHeader::SorensonH263(ref h) if h.picture_type == H263_PICTURE_TYPE_DISPOSABLE_INTER => VIDEO_FRAME_TYPE_DISPOSABLE_INTER_FRAME,
+            _ => VIDEO_FRAME_TYPE_INTER_FRAME,
+        }
+    }
+
+    /// a keyframe flag on a frame that needs the ones before, or a keyframe without it
+    pub fn is_mislabeled(&self, frame_type: u8) -> bool {
+        (frame_type == VIDEO_FRAME_TYPE_KEYFRAME) != self.is_keyframe()
+    }
+}
+
+impl fmt::Display for LegacyVideoHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LegacyVideoHeader::SorensonH263(ref h) => write!(f, "h263 v{} {} {}x{} tr {} q {}", h.version, h.get_picture_type_name(), h.width, h.height, h.temporal_reference, h.quantizer),
+            LegacyVideoHeader::ScreenVideo(ref h) => write!(f, "screen video v{} {}x{} blocks {}x{} of {}x{}, {} changed", h.version, h.image_width, h.image_height,
+                h.columns, h.rows, h.block_width, h.block_height, h.changed_blocks),
+            LegacyVideoHeader::Vp6(ref h) => {
+                write!(f, "{} {}", if h.alpha_offset.is_some() { "vp6a" } else { "vp6" }, if h.frame.keyframe { "I" } else { "P" })?;
+                if let (Some(width), Some(height)) = (h.get_width(), h.get_height()) {
+                    write!(f, " {}x{} profile {}", width, height, h.frame.profile.unwrap())?;
+                }
+                write!(f, " q {}", h.frame.quantizer)?;
+                if let Some(offset) = h.alpha_offset {
+                    write!(f, ", alpha at {}", offset)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<D: AsRef<[u8]>> FLVTag<D> {
+    /// Sorenson H.263, Screen Video or VP6
+    pub fn is_legacy_video(&self) -> bool {
+        if self.get_tag_type() != FLVTagType::TAG_TYPE_VIDEO || self.get_data_size() == 0 || self.is_ex_video() {
+            return false;
+        }
+        let codec_id = self.get_codec_id();
+        codec_id >= CODEC_ID_SORENSON_H263 && codec_id <= CODEC_ID_SCREEN_VIDEO_V2
+    }
+
+    /// picture header of a legacy video tag, `None` for the other tags and command frames
+    pub fn get_legacy_video_header(&self) -> Result<Option<LegacyVideoHeader>, FlvError> {
+        if !self.is_legacy_video() || self.get_frame_type() == VIDEO_FRAME_TYPE_COMMAND {
+            return Ok(None);
+        }
+        let data = &self.get_body()[1..];
+        let header = match self.get_codec_id() {
+            CODEC_ID_SORENSON_H263 => LegacyVideoHeader::SorensonH263(H263PictureHeader::parse(data)?),
+            CODEC_ID_SCREEN_VIDEO => LegacyVideoHeader::ScreenVideo(ScreenVideoHeader::parse(data, 1)?),
+            CODEC_ID_SCREEN_VIDEO_V2 => LegacyVideoHeader::ScreenVideo(ScreenVideoHeader::parse(data, 2)?),
+            CODEC_ID_VP6 => LegacyVideoHeader::Vp6(Vp6Header::parse(data, false)?),
+            CODEC_ID_VP6_ALPHA => LegacyVideoHeader::Vp6(Vp6Header::parse(data, true)?),
+            _ => unreachable!(),
+        };
+        Ok(Some(header))
+    }
+}
+
+#[test]
+fn test_legacy_video_headers() {
+    fn pack(fields: &[(u32, u32)]) -> Vec<u8> {
+        let mut bits: Vec<bool> = Vec::new();
+        for &(value, n) in fields.iter() {
+            bits.extend((0..n).rev().map(|i| (value >> i) & 1 == 1));
+        }
+        bits.chunks(8).map(|c| c.iter().enumerate().fold(0u8, |b, (i, &bit)| b | (bit as u8) << (7 - i))).collect()
+    }
+
+    // psc, version 0, tr 3, custom 8 bit size 200x150, disposable inter, deblocking, q 10
+    let mut body = vec![0x32];
+    body.extend(pack(&[(1, 17), (0, 5), (3, 8), (0, 3), (200, 8), (150, 8), (2, 2), (1, 1), (10, 5)]));
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 0, &body);
+    let header = tag.get_legacy_video_header().unwrap().unwrap();
+    assert_eq!((header.get_width(), header.get_height(), header.is_keyframe()), (Some(200), Some(150), false));
+    assert_eq!(header.get_frame_type(), VIDEO_FRAME_TYPE_DISPOSABLE_INTER_FRAME);
+    assert!(!header.is_mislabeled(tag.get_frame_type()));
+    assert_eq!(format!("{}", header), "h263 v0 disposable P 200x150 tr 3 q 10");
+
+    // 64x40 in 32x32 blocks, 2x2 of them, the top right one unchanged
+    let mut body = vec![0x13, 0x10, 0x40, 0x10, 0x28];
+    body.extend_from_slice(&[0, 2, 0xaa, 0xbb, 0, 1, 0xcc, 0, 1, 0xdd, 0, 0]);
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 40, &body);
+    let header = tag.get_legacy_video_header().unwrap().unwrap();
+    assert_eq!((header.get_width(), header.get_height()), (Some(64), Some(40)));
+    assert!(header.is_mislabeled(tag.get_frame_type()));
+    assert_eq!(header.get_frame_type(), VIDEO_FRAME_TYPE_INTER_FRAME);
+    assert_eq!(format!("{}", header), "screen video v1 64x40 blocks 2x2 of 32x32, 3 changed");
+
+    // version 2 iframe image with a palette, one 16x16 block
+    let body = vec![0x16, 0x00, 0x10, 0x00, 0x10, 0x03, 0, 3, 1, 2, 3, 0, 2, 0x00, 0xee];
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 80, &body);
+    match tag.get_legacy_video_header().unwrap().unwrap() {
+        LegacyVideoHeader::ScreenVideo(ref screen) => {
+            assert_eq!((screen.iframe_image, screen.has_palette, screen.changed_blocks), (true, true, 1));
+            assert!(screen.is_keyframe());
+        }
+        ref header => panic!("unexpected {:?}", header),
+    }
+
+    // vp6a keyframe, adjustment 8 and 4, simple profile: 20x15 macroblocks shown as 312x236
+    let color = [0x14, 0x40, 0x00, 0x10, 15, 20, 15, 20, 0xff];
+    let mut body = vec![0x15, 0x84, 0, 0, color.len() as u8];
+    body.extend_from_slice(&color);
+    body.extend_from_slice(&[0x14, 0x40, 0x00, 0x10, 15, 20, 15, 20]);
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 80, &body);
+    let header = tag.get_legacy_video_header().unwrap().unwrap();
+    assert_eq!((header.get_width(), header.get_height(), header.is_keyframe()), (Some(312), Some(236), true));
+    assert_eq!(format!("{}", header), "vp6a I 312x236 profile 0 q 10, alpha at 9");
+
+    // an inter frame has no size
+    let tag = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 120, &[0x24, 0x00, 0x94, 0x00]);
+    let header = tag.get_legacy_video_header().unwrap().unwrap();
+    assert_eq!((header.get_width(), header.is_keyframe()), (None, false));
+    // and no composition time, the bytes after the codec id are the frame
+    assert!(!tag.is_avc_or_hevc());
+    assert_eq!(tag.get_avc_composition_time_offset(), 0);
+}
@@ -3,81 +3,325 @@ extern crate byteorder;
 extern crate xml;
 
 use std::collections::BTreeMap;
-use std::io::{Read, Write, Cursor, Seek, SeekFrom};
+use std::io::{self, Read, Write, Cursor, Seek, SeekFrom};
+use std::error;
+use std::cmp;
 use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use self::rustc_serialize::json::Json;
+use amf3::Amf3Decoder;
+use amf0::Amf0Value;
+use metadata::META_EVENT_NAME;
+use h264::{self, Sps};
+use hevc::{self, HvcC};
+use enhanced::{self, ExVideoPacket, FourCC};
 use std::fmt;
 
+#[derive(Debug)]
+pub enum FlvError {
+    /// first 4 bytes of the file are not "FLV" followed by version 1
+    BadSignature([u8; 4]),
+    /// header DataOffset is not 9
+    BadHeaderSize(u32),
+    /// stream ended in the middle of a tag body
+    TruncatedTag { expected: u32, actual: u32 },
+    /// stream ended in the middle of a fixed size field
+    UnexpectedEof,
+    UnknownTagType(u8),
+    /// tag header fields are out of the sane range
+    BadTagHeader(&'static str),
+    BadAmfMarker(u8),
+    /// AMF0 reference to a value that was not read, or is not complete yet
+    BadAmfReference(u16),
+    /// malformed AMF3 data
+    BadAmf3(&'static str),
+    /// script data tag that does not start with a handler name
+    BadScriptData(&'static str),
+    /// malformed codec bitstream, like an SPS
+    BadBitstream(&'static str),
+    /// trailing PreviousTagSize does not match the tag just read
+    PrevTagSizeMismatch { expected: u32, actual: u32 },
+    /// rewritten metadata tag does not fit in the room left for it, sizes are tag sizes
+    MetaTagOverflow { reserved: u32, needed: u32 },
+    /// AMF values nested deeper than `AMF_MAX_DEPTH`
+    AmfTooDeep,
+    /// AMF key, class name or XML document that is not UTF-8, or a key too long for its length field
+    BadAmfString(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for FlvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FlvError::BadSignature(ref sig) => write!(f, "bad flv signature: {:?}", sig),
+            FlvError::BadHeaderSize(size) => write!(f, "bad flv header size: {}", size),
+            FlvError::TruncatedTag { expected, actual } => write!(f, "truncated tag: expect {} bytes, got {}", expected, actual),
+            FlvError::UnexpectedEof => write!(f, "unexpected end of stream"),
+            FlvError::UnknownTagType(t) => write!(f, "unknown tagType: {}", t),
+            FlvError::BadTagHeader(reason) => write!(f, "bad tag header: {}", reason),
+            FlvError::BadAmfMarker(m) => write!(f, "unsupported amf0 mark {}", m),
+            FlvError::BadAmfReference(index) => write!(f, "bad amf0 reference {}", index),
+            FlvError::BadAmf3(reason) => write!(f, "bad amf3 data: {}", reason),
+            FlvError::BadScriptData(reason) => write!(f, "bad script data: {}", reason),
+            FlvError::BadBitstream(reason) => write!(f, "bad bitstream: {}", reason),
+            FlvError::PrevTagSizeMismatch { expected, actual } => write!(f, "previous tag size mismatch: expect {}, got {}", expected, actual),
+            FlvError::MetaTagOverflow { reserved, needed } => write!(f, "metadata tag does not fit: {} bytes reserved, {} needed", reserved, needed),
+            FlvError::AmfTooDeep => write!(f, "amf values nested deeper than {}", AMF_MAX_DEPTH),
+            FlvError::BadAmfString(reason) => write!(f, "bad amf string: {}", reason),
+            FlvError::Io(ref e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl error::Error for FlvError {
+    fn description(&self) -> &str {
+        match *self {
+            FlvError::BadSignature(..) => "bad flv signature",
+            FlvError::BadHeaderSize(..) => "bad flv header size",
+            FlvError::TruncatedTag { .. } => "truncated tag",
+            FlvError::UnexpectedEof => "unexpected end of stream",
+            FlvError::UnknownTagType(..) => "unknown tag type",
+            FlvError::BadTagHeader(..) => "bad tag header",
+            FlvError::BadAmfMarker(..) => "unsupported amf0 mark",
+            FlvError::BadAmfReference(..) => "bad amf0 reference",
+            FlvError::BadAmf3(..) => "bad amf3 data",
+            FlvError::BadScriptData(..) => "bad script data",
+            FlvError::BadBitstream(..) => "bad bitstream",
+            FlvError::PrevTagSizeMismatch { .. } => "previous tag size mismatch",
+            FlvError::MetaTagOverflow { .. } => "metadata tag does not fit",
+            FlvError::AmfTooDeep => "amf values nested too deep",
+            FlvError::BadAmfString(..) => "bad amf string",
+            FlvError::Io(ref e) => e.description(),
+        }
+    }
+}
+
+impl From<io::Error> for FlvError {
+    fn from(e: io::Error) -> FlvError {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            FlvError::UnexpectedEof
+        } else {
+            FlvError::Io(e)
+        }
+    }
+}
+
+impl From<byteorder::Error> for FlvError {
+    fn from(e: byteorder::Error) -> FlvError {
+        match e {
+            byteorder::Error::UnexpectedEOF => FlvError::UnexpectedEof,
+            byteorder::Error::Io(e) => FlvError::from(e),
+        }
+    }
+}
+
+/// AMF0 values are decoded into `Json`; the types json has no room for are kept in objects with these keys:
+/// a date is `{"__date__": ms, "__timezone__": minutes}`, an XML document is `{"__xml__": text}`
+/// and a typed object is an object with a `"__class__"` member. `write_amf0_value` writes them back as such.
+/// The mapping is lossy: an object that happens to have just these members is written back as a date or
+/// an XML document, use `Amf0Value` to keep the types.
+pub const AMF0_DATE_KEY: &'static str = "__date__";
+pub const AMF0_TIMEZONE_KEY: &'static str = "__timezone__";
+pub const AMF0_XML_KEY: &'static str = "__xml__";
+pub const AMF0_CLASS_KEY: &'static str = "__class__";
+
+/// values nested deeper than this are refused, the decoders recurse once per level
+pub const AMF_MAX_DEPTH: usize = 64;
+
+pub(crate) fn check_amf_depth(depth: usize) -> Result<(), FlvError> {
+    if depth >= AMF_MAX_DEPTH {
+        return Err(FlvError::AmfTooDeep);
+    }
+    Ok(())
+}
+
 pub trait ReadAMF0Ext : ReadBytesExt {
+    fn try_read_amf0_number(&mut self) -> Result<Json, FlvError> {
+        Ok(Json::F64(self.read_f64::<BigEndian>()?))
+    }
+
+    fn try_read_amf0_boolean(&mut self) -> Result<Json, FlvError> {
+        Ok(Json::Boolean(self.read_u8()? != 0))
+    }
+
+    fn try_read_amf0_string(&mut self) -> Result<Json, FlvError> {
+        let len = self.read_u16::<BigEndian>()?;
+        Ok(Json::String(self.try_read_amf0_raw_string(len as usize)?))
+    }
+
+    fn try_read_amf0_long_string(&mut self) -> Result<Json, FlvError> {
+        let len = self.read_u32::<BigEndian>()?;
+        Ok(Json::String(self.try_read_amf0_raw_string(len as usize)?))
+    }
+
+    fn try_read_amf0_raw_string(&mut self, len: usize) -> Result<String, FlvError> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(cmp::min(len, 64 * 1024));
+        let mut handle = self.take(len as u64);
+        let read_len = handle.read_to_end(&mut buffer)?;
+        if len != read_len {
+            return Err(FlvError::UnexpectedEof);
+        }
+        Ok(String::from_utf8_lossy(&buffer).to_string())
+    }
+
+    fn try_read_amf0_date(&mut self) -> Result<Json, FlvError> {
+        let ms = self.read_f64::<BigEndian>()?;
+        let timezone = self.read_i16::<BigEndian>()?;
+        let mut obj = BTreeMap::new();
+        obj.insert(AMF0_DATE_KEY.to_string(), Json::F64(ms));
+        obj.insert(AMF0_TIMEZONE_KEY.to_string(), Json::I64(timezone as i64));
+        Ok(Json::Object(obj))
+    }
+
+    fn try_read_amf0_xml_document(&mut self) -> Result<Json, FlvError> {
+        let len = self.read_u32::<BigEndian>()?;
+        let mut obj = BTreeMap::new();
+        obj.insert(AMF0_XML_KEY.to_string(), Json::String(self.try_read_amf0_raw_string(len as usize)?));
+        Ok(Json::Object(obj))
+    }
+
+    fn try_read_amf0_ecma_array(&mut self) -> Result<Json, FlvError> {
+        let _count = self.read_u32::<BigEndian>()?;
+        read_amf0_object(self, &mut Vec::new(), None, 0)
+    }
+
+    fn try_read_amf0_strict_array(&mut self) -> Result<Json, FlvError> {
+        read_amf0_strict_array(self, &mut Vec::new(), 0)
+    }
+
+    fn try_read_amf0_object(&mut self) -> Result<Json, FlvError> {
+        read_amf0_object(self, &mut Vec::new(), None, 0)
+    }
+
+    fn try_read_amf0_typed_object(&mut self) -> Result<Json, FlvError> {
+        let len = self.read_u16::<BigEndian>()?;
+        let class = self.try_read_amf0_raw_string(len as usize)?;
+        read_amf0_object(self, &mut Vec::new(), Some(class), 0)
+    }
+
+    /// reads one value with its marker, references in it may only point into the value itself
+    fn try_read_amf0_value(&mut self) -> Result<Json, FlvError> {
+        read_amf0_value(self, &mut Vec::new(), 0)
+    }
+
     fn read_amf0_number(&mut self) -> Json {
-        Json::F64(self.read_f64::<BigEndian>().unwrap())
+        self.try_read_amf0_number().unwrap_or_else(|e| panic!("{}", e))
     }
 
     fn read_amf0_boolean(&mut self) -> Json {
-        Json::Boolean(self.read_u8().unwrap() != 0)
+        self.try_read_amf0_boolean().unwrap_or_else(|e| panic!("{}", e))
     }
 
     fn read_amf0_string(&mut self) -> Json {
-        let len = self.read_u16::<BigEndian>().unwrap();
-        Json::String(self.read_amf0_raw_string(len as usize))
+        self.try_read_amf0_string().unwrap_or_else(|e| panic!("{}", e))
     }
 
     fn read_amf0_raw_string(&mut self, len: usize) -> String {
-        let mut buffer: Vec<u8> = Vec::with_capacity(len);
-        let mut handle = self.take(len as u64);
-        let read_len = handle.read_to_end(&mut buffer).unwrap();
-        assert_eq!(len, read_len);
-        String::from_utf8_lossy(&buffer).to_string()
+        self.try_read_amf0_raw_string(len).unwrap_or_else(|e| panic!("{}", e))
     }
 
     fn read_amf0_ecma_array(&mut self) -> Json {
-        let _count = self.read_u32::<BigEndian>().unwrap();
-        self.read_amf0_object()
+        self.try_read_amf0_ecma_array().unwrap_or_else(|e| panic!("{}", e))
     }
 
     fn read_amf0_strict_array(&mut self) -> Json {
-        let count = self.read_u32::<BigEndian>().unwrap();
-        let mut v: Vec<Json> = Vec::with_capacity(count as usize);
-        for _ in 0..count {
-            v.push(self.read_amf0_value());
-        }
-        Json::Array(v)
+        self.try_read_amf0_strict_array().unwrap_or_else(|e| panic!("{}", e))
     }
 
     fn read_amf0_object(&mut self) -> Json {
-        let mut obj = BTreeMap::new();
-        loop {
-            let len = self.read_u16::<BigEndian>().unwrap() as usize;
-            if len == 0 {
-                let end_mark = self.read_u8().unwrap();
-                assert_eq!(0x09, end_mark);
-                break;
-            }
-            else {
-                let key = self.read_amf0_raw_string(len);
-                let val = self.read_amf0_value();
-                obj.insert(key, val);
+        self.try_read_amf0_object().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn read_amf0_value(&mut self) -> Json {
+        self.try_read_amf0_value().unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+impl<R: Read + ?Sized> ReadAMF0Ext for R {}
+
+// `refs` is the AMF0 reference table: objects, typed objects, ECMA arrays and strict arrays
+// in the order they start, `None` until the value is complete. `depth` is 0 for a value not in another one.
+fn read_amf0_value<R: Read + ?Sized>(r: &mut R, refs: &mut Vec<Option<Json>>, depth: usize) -> Result<Json, FlvError> {
+    let marker = r.read_u8()?;
+    read_amf0_value_with_marker(r, marker, refs, depth)
+}
+
+fn read_amf0_value_with_marker<R: Read + ?Sized>(r: &mut R, marker: u8, refs: &mut Vec<Option<Json>>, depth: usize) -> Result<Json, FlvError> {
+    check_amf_depth(depth)?;
+    match marker {
+        0x00 => r.try_read_amf0_number(),
+        0x01 => r.try_read_amf0_boolean(),
+        0x02 => r.try_read_amf0_string(),
+        0x03 => read_amf0_object(r, refs, None, depth),
+        0x04 => Ok(Json::Null), // movieclip, reserved and without a body
+        0x05 => Ok(Json::Null),
+        0x06 => Ok(Json::Null), // undefined
+        0x07 => {
+            let index = r.read_u16::<BigEndian>()?;
+            match refs.get(index as usize) {
+                Some(&Some(ref v)) => Ok(v.clone()),
+                _ => Err(FlvError::BadAmfReference(index)),
             }
         }
-        Json::Object(obj)
+        0x08 => {
+            let _count = r.read_u32::<BigEndian>()?;
+            read_amf0_object(r, refs, None, depth)
+        }
+        0x09 => Ok(Json::Null), // object end out of an object, written by some encoders after ECMA arrays
+        0x0A => read_amf0_strict_array(r, refs, depth),
+        0x0B => r.try_read_amf0_date(),
+        0x0C => r.try_read_amf0_long_string(),
+        0x0D => Ok(Json::Null), // unsupported
+        0x0F => r.try_read_amf0_xml_document(),
+        0x10 => {
+            let len = r.read_u16::<BigEndian>()?;
+            let class = r.try_read_amf0_raw_string(len as usize)?;
+            read_amf0_object(r, refs, Some(class), depth)
+        }
+        0x11 => Ok(Amf3Decoder::new().read_value(r)?.to_json()), // avmplus, one AMF3 value follows
+        n => Err(FlvError::BadAmfMarker(n))
     }
+}
 
-    fn read_amf0_value(&mut self) -> Json {
-        match self.read_u8().unwrap() {
-            0x00 => self.read_amf0_number(),
-            0x01 => self.read_amf0_boolean(),
-            0x02 => self.read_amf0_string(),
-            0x03 => self.read_amf0_object(),
-            0x05 => Json::Null,
-            0x06 => Json::Null,
-            0x08 => self.read_amf0_ecma_array(),
-            0x0A => self.read_amf0_strict_array(),
-            n => panic!(format!("unsupported mark {}", n))
+fn read_amf0_object<R: Read + ?Sized>(r: &mut R, refs: &mut Vec<Option<Json>>, class: Option<String>, depth: usize) -> Result<Json, FlvError> {
+    let index = refs.len();
+    refs.push(None);
+    let mut obj = BTreeMap::new();
+    if let Some(class) = class {
+        obj.insert(AMF0_CLASS_KEY.to_string(), Json::String(class));
+    }
+    loop {
+        let len = r.read_u16::<BigEndian>()? as usize;
+        if len == 0 {
+            let end_mark = r.read_u8()?;
+            if end_mark != 0x09 {
+                return Err(FlvError::BadAmfMarker(end_mark));
+            }
+            break;
+        }
+        else {
+            let key = r.try_read_amf0_raw_string(len)?;
+            let val = read_amf0_value(r, refs, depth + 1)?;
+            obj.insert(key, val);
         }
     }
+    let obj = Json::Object(obj);
+    refs[index] = Some(obj.clone());
+    Ok(obj)
 }
 
-impl<R: Read + ?Sized> ReadAMF0Ext for R {}
+fn read_amf0_strict_array<R: Read + ?Sized>(r: &mut R, refs: &mut Vec<Option<Json>>, depth: usize) -> Result<Json, FlvError> {
+    let index = refs.len();
+    refs.push(None);
+    let count = r.read_u32::<BigEndian>()?;
+    // count comes from the stream, don't trust it for the allocation
+    let mut v: Vec<Json> = Vec::with_capacity(cmp::min(count, 1024) as usize);
+    for _ in 0..count {
+        v.push(read_amf0_value(r, refs, depth + 1)?);
+    }
+    let v = Json::Array(v);
+    refs[index] = Some(v.clone());
+    Ok(v)
+}
 
 pub trait WriteAMF0Ext : WriteBytesExt {
     fn write_amf0_number(&mut self, f: f64) {
@@ -92,11 +336,34 @@ pub trait WriteAMF0Ext : WriteBytesExt {
         assert_eq!(len, write_len);
     }
 
+    /// strings longer than 65535 bytes are written as long strings
     fn write_amf0_string(&mut self, s: &String) {
+        if s.len() > 0xffff {
+            self.write_amf0_long_string(s);
+            return;
+        }
         self.write_u8(0x02).unwrap();
         self.write_amf0_raw_string(s);
     }
 
+    fn write_amf0_long_string(&mut self, s: &String) {
+        self.write_u8(0x0C).unwrap();
+        self.write_u32::<BigEndian>(s.len() as u32).unwrap();
+        self.write_all(s.as_bytes()).unwrap();
+    }
+
+    fn write_amf0_date(&mut self, ms: f64, timezone: i16) {
+        self.write_u8(0x0B).unwrap();
+        self.write_f64::<BigEndian>(ms).unwrap();
+        self.write_i16::<BigEndian>(timezone).unwrap();
+    }
+
+    fn write_amf0_xml_document(&mut self, s: &String) {
+        self.write_u8(0x0F).unwrap();
+        self.write_u32::<BigEndian>(s.len() as u32).unwrap();
+        self.write_all(s.as_bytes()).unwrap();
+    }
+
     fn write_amf0_boolean(&mut self, b: bool) {
         self.write_u8(0x01).unwrap();
         self.write_u8(if b { 1 } else { 0 }).unwrap();
@@ -121,6 +388,22 @@ pub trait WriteAMF0Ext : WriteBytesExt {
         self.write_u8(0x09).unwrap();
     }
 
+    /// the `"__class__"` member is the class name, the others are the members
+    fn write_amf0_typed_object(&mut self, o: &BTreeMap<String, Json>) {
+        self.write_u8(0x10).unwrap();
+        let class = o.get(AMF0_CLASS_KEY).and_then(|c| c.as_string()).unwrap_or("").to_string();
+        self.write_amf0_raw_string(&class);
+        for (ref key, ref v) in o.iter() {
+            if key.as_str() == AMF0_CLASS_KEY {
+                continue;
+            }
+            self.write_amf0_raw_string(key);
+            self.write_amf0_value(v);
+        }
+        self.write_u16::<BigEndian>(0).unwrap();
+        self.write_u8(0x09).unwrap();
+    }
+
     fn write_amf0_null(&mut self) {
         self.write_u8(0x05).unwrap();
     }
@@ -134,7 +417,18 @@ pub trait WriteAMF0Ext : WriteBytesExt {
             &String(ref s) => self.write_amf0_string(s),
             &Boolean(ref b) => self.write_amf0_boolean(*b),
             &Array(ref a) => self.write_amf0_strict_array(a),
-            &Object(ref o) => self.write_amf0_object(o),
+            &Object(ref o) => {
+                let date = (o.get(AMF0_DATE_KEY).and_then(|d| d.as_f64()), o.get(AMF0_TIMEZONE_KEY).and_then(|t| t.as_i64()));
+                if let (Some(ms), Some(timezone), 2) = (date.0, date.1, o.len()) {
+                    self.write_amf0_date(ms, timezone as i16);
+                } else if let (Some(xml), 1) = (o.get(AMF0_XML_KEY).and_then(|x| x.as_string()), o.len()) {
+                    self.write_amf0_xml_document(&xml.to_string());
+                } else if o.get(AMF0_CLASS_KEY).map(|c| c.is_string()).unwrap_or(false) {
+                    self.write_amf0_typed_object(o);
+                } else {
+                    self.write_amf0_object(o);
+                }
+            }
             &Null => self.write_amf0_null(),
         }
     }
@@ -165,12 +459,81 @@ fn write_and_read() {
     test_amf(Json::Object(obj));
 }
 
-fn read_u24_be(r: &mut Read) -> u32 {
-    let (b1, b2, b3) = (r.read_u8().unwrap() as u32, r.read_u8().unwrap() as u32, r.read_u8().unwrap() as u32);
-    b1 << 16 | b2 << 8 | b3
+#[test]
+fn test_amf0_types() {
+    // strict array of: date, xml document, typed object, reference to the typed object, undefined
+    let mut data: Vec<u8> = vec![0x0A, 0, 0, 0, 5];
+    data.extend_from_slice(&[0x0B, 0x42, 0x77, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0, 0]);
+    data.extend_from_slice(&[0x0F, 0, 0, 0, 4]);
+    data.extend_from_slice(b"<a/>");
+    data.extend_from_slice(&[0x10, 0, 1, b'C', 0, 1, b'x', 0x00, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0x09]);
+    data.extend_from_slice(&[0x07, 0, 1]);
+    data.extend_from_slice(&[0x06]);
+    let v = (&mut &data[..]).try_read_amf0_value().unwrap();
+    {
+        let a = v.as_array().unwrap();
+        assert_eq!(a[0].find(AMF0_DATE_KEY), Some(&Json::F64(1580547964928.0)));
+        assert_eq!(a[1].find(AMF0_XML_KEY), Some(&Json::String("<a/>".to_string())));
+        assert_eq!(a[2].find(AMF0_CLASS_KEY), Some(&Json::String("C".to_string())));
+        assert_eq!(a[2].find("x"), Some(&Json::F64(1.0)));
+        assert_eq!(a[3], a[2]);
+        assert_eq!(a[4], Json::Null);
+    }
+    let mut out: Vec<u8> = Vec::new();
+    out.write_amf0_value(&v);
+    assert_eq!((&mut &out[..]).try_read_amf0_value().unwrap(), v);
+    // the reference is written inline, undefined as null
+    assert_eq!(&out[..(data.len() - 4)], &data[..(data.len() - 4)]);
+
+    let long = Json::String("a".repeat(0x10000));
+    let mut out: Vec<u8> = Vec::new();
+    out.write_amf0_value(&long);
+    assert_eq!(out[0], 0x0C);
+    assert_eq!((&mut &out[..]).try_read_amf0_value().unwrap(), long);
+
+    match (&mut &[0x0A, 0, 0, 0, 1, 0x07, 0, 0][..]).try_read_amf0_value() {
+        Err(FlvError::BadAmfReference(0)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn test_script_data() {
+    let mut tag = FLVTagRef::from_bytes(&[18, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap().to_tag();
+    let cue = FLVScriptData::new("onCuePoint", vec![Amf0Value::Number(1.0), Amf0Value::String("x".to_string()), Amf0Value::Null]);
+    tag.set_script_data(&cue);
+    assert_eq!(tag.try_get_script_data().unwrap(), cue);
+    assert_eq!(tag.try_get_objects().unwrap().len(), 4);
+    assert!(!cue.is_metadata());
+    assert_eq!(tag.try_get_metadata().unwrap(), None);
+
+    let mut entries = ::amf0::Amf0Object::new();
+    entries.insert("duration", Amf0Value::Number(3.0));
+    let frame = FLVScriptData::new(SET_DATA_FRAME, vec![Amf0Value::String(META_EVENT_NAME.to_string()), Amf0Value::EcmaArray { count: None, entries: entries }]);
+    assert_eq!(frame.event_name(), META_EVENT_NAME);
+    assert_eq!(frame.args().len(), 1);
+    tag.set_script_data(&frame);
+    let mut meta = tag.try_get_metadata().unwrap().unwrap();
+    assert_eq!(meta.duration, Some(3.0));
+    meta.duration = Some(4.0);
+    tag.set_metadata(&meta);
+    let frame = tag.try_get_script_data().unwrap();
+    assert_eq!(frame.name, SET_DATA_FRAME);
+    assert_eq!(frame.args()[0].as_object().unwrap().get("duration"), Some(&Amf0Value::Number(4.0)));
+
+    tag.set_amf0_values(&[Amf0Value::Number(0.0)]);
+    match tag.try_get_script_data() {
+        Err(FlvError::BadScriptData(..)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+pub(crate) fn read_u24_be(r: &mut Read) -> byteorder::Result<u32> {
+    let (b1, b2, b3) = (r.read_u8()? as u32, r.read_u8()? as u32, r.read_u8()? as u32);
+    Ok(b1 << 16 | b2 << 8 | b3)
 }
 
-fn write_u24_be(w: &mut Write, n: u32) {
+pub(crate) fn write_u24_be(w: &mut Write, n: u32) {
     w.write_u8(((n >> 16) & 0xff) as u8).unwrap();
     w.write_u8(((n >> 8 ) & 0xff) as u8).unwrap();
     w.write_u8(((n      ) & 0xff) as u8).unwrap();
@@ -194,31 +557,94 @@ pub fn format_seconds_ms(ms: u64) -> String {
     }
 }
 
-const TAG_HEADER_BYTE_COUNT: u32 = 11;
-const PREV_TAG_BYTE_COUNT: u32 = 4;
-const MIN_FILE_HEADER_BYTE_COUNT: u32 = 9;
+pub const TAG_HEADER_BYTE_COUNT: u32 = 11;
+pub const PREV_TAG_BYTE_COUNT: u32 = 4;
+pub const MIN_FILE_HEADER_BYTE_COUNT: u32 = 9;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum FLVTagType {
-    TAG_TYPE_AUDIO = 8,
-    TAG_TYPE_VIDEO = 9,
-    TAG_TYPE_SCRIPTDATAOBJECT = 18,
+    TAG_TYPE_AUDIO,
+    TAG_TYPE_VIDEO,
+    TAG_TYPE_SCRIPTDATAOBJECT,
+    /// any other value of the 5-bit TagType field, kept so the tag can be passed through
+    Unknown(u8),
 }
 
+impl FLVTagType {
+    /// strict version of `from`, rejects unknown tag types
+    pub fn try_from(t: u8) -> Result<FLVTagType, FlvError> {
+        match FLVTagType::from(t) {
+            FLVTagType::Unknown(t) => Err(FlvError::UnknownTagType(t)),
+            tag_type => Ok(tag_type),
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match *self {
+            FLVTagType::TAG_TYPE_AUDIO => 8,
+            FLVTagType::TAG_TYPE_VIDEO => 9,
+            FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT => 18,
+            FLVTagType::Unknown(t) => t,
+        }
+    }
+}
+
+/// `t` is the first byte of the tag, the Reserved and Filter bits are ignored
 impl From<u8> for FLVTagType {
     fn from(t: u8) -> FLVTagType {
-        use std;
+        match t & 0x1f {
+            8 => FLVTagType::TAG_TYPE_AUDIO,
+            9 => FLVTagType::TAG_TYPE_VIDEO,
+            18 => FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT,
+            t => FLVTagType::Unknown(t),
+        }
+    }
+}
+
+/// what the tag readers do when the PreviousTagSize after a tag is wrong, `Warn` by default
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PrevTagSizePolicy {
+    /// don't check it
+    Ignore,
+    /// keep reading, remember a `FlvError::PrevTagSizeMismatch` warning
+    Warn,
+    /// stop with `FlvError::PrevTagSizeMismatch`
+    Error,
+}
 
-        let v: Vec<u8> = vec![8, 9, 18];
-        if !v.contains(&t) {
-            panic!(format!("unknown tagType: {}", t));
+impl Default for PrevTagSizePolicy {
+    fn default() -> PrevTagSizePolicy {
+        PrevTagSizePolicy::Warn
+    }
+}
+
+impl PrevTagSizePolicy {
+    /// checks the PreviousTagSize after a tag at `position`, a warning goes to `warnings`
+    pub(crate) fn check(self, tag_size: u32, prev_tag_size: u32, position: u64, warnings: &mut Vec<(u64, FlvError)>) -> Result<(), FlvError> {
+        if prev_tag_size == tag_size || self == PrevTagSizePolicy::Ignore {
+            return Ok(());
         }
-        unsafe {
-            std::mem::transmute(t)
+        let e = FlvError::PrevTagSizeMismatch { expected: tag_size, actual: prev_tag_size };
+        if self == PrevTagSizePolicy::Warn {
+            warnings.push((position, e));
+            return Ok(());
         }
+        Err(e)
     }
 }
 
+/// what `FLVTagRead` does with tags of `FLVTagType::Unknown`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnknownTagPolicy {
+    /// yield them like any other tag
+    Keep,
+    /// read and drop them
+    Skip,
+    /// stop with `FlvError::UnknownTagType`
+    Error,
+}
+
+#[derive(Debug, Clone)]
 pub struct FLVHeader {
     pub hasAudioTags: bool,
     pub hasVideoTags: bool,
@@ -226,18 +652,26 @@ pub struct FLVHeader {
 
 impl FLVHeader {
     pub fn read(r: &mut Read) -> FLVHeader {
-        assert_eq!(r.read_u8().unwrap(), 'F' as u8);
-        assert_eq!(r.read_u8().unwrap(), 'L' as u8);
-        assert_eq!(r.read_u8().unwrap(), 'V' as u8);
-        assert_eq!(r.read_u8().unwrap(), 1);
-        let flags = r.read_u8().unwrap();
-        assert_eq!(r.read_u32::<BigEndian>().unwrap(), 9);
-        r.read_u32::<BigEndian>().unwrap();
-
-        FLVHeader {
+        FLVHeader::try_read(r).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_read(r: &mut Read) -> Result<FLVHeader, FlvError> {
+        let mut signature = [0u8; 4];
+        r.read_exact(&mut signature)?;
+        if &signature != b"FLV\x01" {
+            return Err(FlvError::BadSignature(signature));
+        }
+        let flags = r.read_u8()?;
+        let header_size = r.read_u32::<BigEndian>()?;
+        if header_size != MIN_FILE_HEADER_BYTE_COUNT {
+            return Err(FlvError::BadHeaderSize(header_size));
+        }
+        r.read_u32::<BigEndian>()?;
+
+        Ok(FLVHeader {
             hasAudioTags: (flags & 0x04) > 0,
             hasVideoTags: (flags & 0x01) > 0,
-        }
+        })
     }
 
     pub fn write(&self, w: &mut Write) {
@@ -258,14 +692,46 @@ impl FLVHeader {
     }
 }
 
+/// A tag, without the PreviousTagSize after it.
+/// `D` is the storage, `FLVTag` owns its bytes and `FLVTagRef` borrows them,
+/// both have the same accessors.
 #[derive(Debug, Clone)]
-pub struct FLVTag {
-    data: Vec<u8>//tag without last 4 bytes
+pub struct FLVTag<D = Vec<u8>> {
+    data: D//tag without last 4 bytes
 }
 
-impl FLVTag {
+pub type FLVTagRef<'a> = FLVTag<&'a [u8]>;
+
+impl<D: AsRef<[u8]>> FLVTag<D> {
+    #[inline]
+    fn bytes(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
+    pub fn to_tag(&self) -> FLVTag {
+        FLVTag {
+            data: self.bytes().to_vec()
+        }
+    }
+
+    pub fn as_tag_ref(&self) -> FLVTagRef<'_> {
+        FLVTag {
+            data: self.bytes()
+        }
+    }
+
     pub fn get_tag_type(&self) -> FLVTagType {
-        FLVTagType::from(self.data[0])
+        FLVTagType::from(self.bytes()[0])
+    }
+
+    /// Filter bit, set when the packet is encrypted/filtered
+    pub fn get_filter(&self) -> bool {
+        (self.bytes()[0] & 0x20) != 0
+    }
+
+    /// the two Reserved bits in front of the Filter bit, should be 0
+    pub fn get_reserved(&self) -> u8 {
+        self.bytes()[0] >> 6
     }
 
     pub fn get_tag_size(&self) -> u32 {
@@ -273,7 +739,35 @@ impl FLVTag {
     }
 
     pub fn get_data_size(&self) -> u32 {
-        ((self.data[1] as u32) << 16) | ((self.data[2] as u32) << 8) | (self.data[3] as u32)
+        ((self.bytes()[1] as u32) << 16) | ((self.bytes()[2] as u32) << 8) | (self.bytes()[3] as u32)
+    }
+
+    /// the tag data, without the tag header
+    pub fn get_body(&self) -> &[u8] {
+        let start = TAG_HEADER_BYTE_COUNT as usize;
+        &self.bytes()[start..(start + self.get_data_size() as usize)]
+    }
+
+    pub fn get_timestamp(&self) -> u64 {
+        ((self.bytes()[7] as u64) << 24) | ((self.bytes()[4] as u64) << 16) | ((self.bytes()[5] as u64) << 8) | (self.bytes()[6] as u64)
+    }
+
+    pub fn write(&self, w: &mut Write) {
+        w.write_all(&self.bytes()[..(self.get_tag_size() as usize)]).unwrap();
+        w.write_u32::<BigEndian>(self.get_tag_size()).unwrap();
+    }
+}
+
+impl FLVTag {
+    /// a tag of `tag_type` holding `body`
+    pub fn new(tag_type: FLVTagType, timestamp: u64, body: &[u8]) -> FLVTag {
+        let mut data: Vec<u8> = vec![0; TAG_HEADER_BYTE_COUNT as usize];
+        data[0] = tag_type.as_u8();
+        data.extend_from_slice(body);
+        let mut tag = FLVTag { data: data };
+        tag.set_data_size(body.len() as u32);
+        tag.set_timestamp(timestamp);
+        tag
     }
 
     pub fn set_data_size(&mut self, value: u32) {
@@ -283,10 +777,6 @@ impl FLVTag {
 		//bytes.length = TAG_HEADER_BYTE_COUNT + value;
     }
 
-    pub fn get_timestamp(&self) -> u64 {
-        ((self.data[7] as u64) << 24) | ((self.data[4] as u64) << 16) | ((self.data[5] as u64) << 8) | (self.data[6] as u64)
-    }
-
     pub fn set_timestamp(&mut self, value: u64) {
         self.data[7] = ((value >> 24) & 0xff) as u8; // extended byte in unusual location
     	self.data[4] = ((value >> 16) & 0xff) as u8;
@@ -294,12 +784,50 @@ impl FLVTag {
     	self.data[6] = ((value      ) & 0xff) as u8;
     }
 
+    /// rewrites the frame type nibble of a video tag, the 3 bits of it for an extended header
+    pub fn set_frame_type(&mut self, frame_type: u8) {
+        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_VIDEO);
+        let b = self.data[TAG_HEADER_BYTE_COUNT as usize];
+        self.data[TAG_HEADER_BYTE_COUNT as usize] = if b & enhanced::VIDEO_EX_HEADER_FLAG != 0 {
+            enhanced::VIDEO_EX_HEADER_FLAG | (frame_type & 0x07) << 4 | (b & 0x0f)
+        } else {
+            (frame_type & 0x0f) << 4 | (b & 0x0f)
+        };
+    }
+
     pub fn read(r: &mut Read) -> Option<FLVTag>{
+        FLVTag::try_read(r).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// `Ok(None)` means the stream ended cleanly on a tag boundary.
+    /// A single tag has no one to take a warning, the PreviousTagSize is not checked here,
+    /// see `try_read_with_policy` or the readers, they warn by default
+    pub fn try_read(r: &mut Read) -> Result<Option<FLVTag>, FlvError> {
+        FLVTag::try_read_with_policy(r, PrevTagSizePolicy::Ignore)
+    }
+
+    /// like `try_read`, a wrong PreviousTagSize is an error under `PrevTagSizePolicy::Error`.
+    /// there is no one to take a warning, `Warn` does not check either
+    pub fn try_read_with_policy(r: &mut Read, policy: PrevTagSizePolicy) -> Result<Option<FLVTag>, FlvError> {
+        match FLVTag::try_read_with_prev_tag_size(r)? {
+            Some((tag, prev_tag_size)) => {
+                if policy == PrevTagSizePolicy::Error {
+                    policy.check(tag.get_tag_size(), prev_tag_size, 0, &mut Vec::new())?;
+                }
+                Ok(Some(tag))
+            }
+            None => Ok(None)
+        }
+    }
+
+    /// reads a tag and the PreviousTagSize after it, without checking one against the other
+    pub fn try_read_with_prev_tag_size(r: &mut Read) -> Result<Option<(FLVTag, u32)>, FlvError> {
         let tag_type = match r.read_u8() {
             Ok(n) => n,
-            Err(..) => return None
+            Err(byteorder::Error::UnexpectedEOF) => return Ok(None),
+            Err(e) => return Err(FlvError::from(e)),
         };
-        let data_size = read_u24_be(r);
+        let data_size = read_u24_be(r)?;
         let tag_size = TAG_HEADER_BYTE_COUNT + data_size;
         let mut payload: Vec<u8> = Vec::with_capacity(tag_size as usize);
 
@@ -308,231 +836,634 @@ impl FLVTag {
 
         {
             let mut handle = r.take((tag_size - 1 - 3) as u64);
-            let read_len = handle.read_to_end(&mut payload).unwrap();
-            assert_eq!((tag_size - 4) as usize, read_len);
+            let read_len = handle.read_to_end(&mut payload)?;
+            if (tag_size - 4) as usize != read_len {
+                return Err(FlvError::TruncatedTag { expected: tag_size, actual: 4 + read_len as u32 });
+            }
         }
-        r.read_u32::<BigEndian>().unwrap();
+        let prev_tag_size = r.read_u32::<BigEndian>()?;
 
-        Some(FLVTag {
+        Ok(Some((FLVTag {
             data: payload
-        })
+        }, prev_tag_size)))
     }
+}
 
-    pub fn write(&self, w: &mut Write) {
-        w.write(&self.data[..(self.get_tag_size() as usize)]).unwrap();
-        w.write_u32::<BigEndian>(self.get_tag_size()).unwrap();
+impl<'a> FLVTagRef<'a> {
+    /// `data` is one whole tag without the PreviousTagSize after it
+    pub fn from_bytes(data: &'a [u8]) -> Result<FLVTagRef<'a>, FlvError> {
+        if data.len() < TAG_HEADER_BYTE_COUNT as usize {
+            return Err(FlvError::UnexpectedEof);
+        }
+        let tag = FLVTag {
+            data: data
+        };
+        if tag.get_tag_size() as usize != data.len() {
+            return Err(FlvError::TruncatedTag { expected: tag.get_tag_size(), actual: data.len() as u32 });
+        }
+        Ok(tag)
     }
 }
 
-impl FLVTag {
+impl<D: AsRef<[u8]>> FLVTag<D> {
     pub fn get_objects(&self) -> Vec<Json> {
-        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT);
+        self.try_get_objects().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// every value in the tag, the handler name first
+    pub fn try_get_objects(&self) -> Result<Vec<Json>, FlvError> {
+        if self.get_tag_type() != FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT {
+            return Err(FlvError::BadTagHeader("not a script data tag"));
+        }
+        let data = &self.bytes()[(TAG_HEADER_BYTE_COUNT as usize)..];
         let mut v: Vec<Json> = Vec::with_capacity(2);
-        let mut handle = Cursor::new(&self.data[(TAG_HEADER_BYTE_COUNT as usize)..]);
-        v.push(handle.read_amf0_value());
-        v.push(handle.read_amf0_value());
-        v
+        let mut handle = Cursor::new(data);
+        let mut refs: Vec<Option<Json>> = Vec::new(); // shared, a later value may refer into an earlier one
+        while !is_script_data_filler(&data[(handle.position() as usize)..]) {
+            v.push(read_amf0_value(&mut handle, &mut refs, 0)?);
+        }
+        Ok(v)
+    }
+
+    /// every script data value in the tag, in order and as written, without the filler after them
+    pub fn try_get_amf0_values(&self) -> Result<Vec<Amf0Value>, FlvError> {
+        if self.get_tag_type() != FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT {
+            return Err(FlvError::BadTagHeader("not a script data tag"));
+        }
+        let mut data = &self.bytes()[(TAG_HEADER_BYTE_COUNT as usize)..];
+        let mut v: Vec<Amf0Value> = Vec::new();
+        while !is_script_data_filler(data) {
+            v.push(Amf0Value::try_read(&mut data)?);
+        }
+        Ok(v)
+    }
+
+    pub fn try_get_script_data(&self) -> Result<FLVScriptData, FlvError> {
+        let mut values = self.try_get_amf0_values()?;
+        let name = match values.first() {
+            Some(&Amf0Value::String(ref name)) | Some(&Amf0Value::LongString(ref name)) => name.clone(),
+            Some(..) => return Err(FlvError::BadScriptData("handler name is not a string")),
+            None => return Err(FlvError::BadScriptData("no handler name")),
+        };
+        values.remove(0);
+        Ok(FLVScriptData { name: name, values: values })
     }
+}
 
+impl FLVTag {
     pub fn set_objects(&mut self, vec: &Vec<Json>) {
         assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT);
         
         let mut buf: Vec<u8> = Vec::new();
-        buf.write(&self.data[0..(TAG_HEADER_BYTE_COUNT as usize)]);
-        buf.write_amf0_value(&vec[0]);
-        buf.write_amf0_value(&vec[1]);
+        buf.write_all(&self.data[0..(TAG_HEADER_BYTE_COUNT as usize)]).unwrap();
+        for v in vec.iter() {
+            buf.write_amf0_value(v);
+        }
         let data_size = buf.len() as u32 - TAG_HEADER_BYTE_COUNT;
         self.data = buf;
 
         self.set_data_size(data_size);
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct AvcC {
-    pub version: u8,
-    pub profile: u8,
-    pub compatibility: u8,
-    pub level: u8,
-    pub nalu_length_size_minus_1: u8,
-    pub num_of_sps: u8,
-    pub sps: Vec<u8>,
-    pub pps_array: Vec<Vec<u8>>,
+    pub fn set_amf0_values(&mut self, values: &[Amf0Value]) {
+        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT);
+
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_all(&self.data[0..(TAG_HEADER_BYTE_COUNT as usize)]).unwrap();
+        for v in values.iter() {
+            v.write(&mut buf);
+        }
+        let data_size = buf.len() as u32 - TAG_HEADER_BYTE_COUNT;
+        self.data = buf;
+
+        self.set_data_size(data_size);
+    }
+
+    pub fn set_script_data(&mut self, script: &FLVScriptData) {
+        let mut values: Vec<Amf0Value> = Vec::with_capacity(script.values.len() + 1);
+        values.push(Amf0Value::String(script.name.clone()));
+        values.extend(script.values.iter().cloned());
+        self.set_amf0_values(&values);
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct NalUnitInfo {
-    pub nalu_type: u8,
-    pub nalu_tag: u8,
-    pub nalu_size: u32,
+/// First byte of the room `FLVTagWrite` leaves after the script data values for the metadata to grow,
+/// zero bytes follow it up to the end of the tag. It is the reserved RecordSet marker, no value starts with it.
+pub const SCRIPT_DATA_FILLER_MARKER: u8 = 0x0E;
+
+/// the end of the values, the rest of the tag is skipped by its size
+fn is_script_data_filler(rest: &[u8]) -> bool {
+    rest.first().map_or(true, |&b| b == SCRIPT_DATA_FILLER_MARKER)
 }
 
-impl fmt::Display for NalUnitInfo {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let tp  = match self.nalu_type {
-            1 => "   ", // IBP
-            5 => "IDR", // IDR
-            6 => "SEI", // SEI
-            7 => "SPS", // SPS
-            8 => "PPS", // PPS
-            _ => "Unknown Nalu Type",
-        };
-        let tag = match self.nalu_tag {
-            0b11 => "I",
-            0b10 => "P",
-            0b00 => "B",
-            _    => "X", // panic!("unknow nalu type"),
-        };
-        write!(f, "[{} {} {:>5}]", tp, tag, self.nalu_size)
-    }
+/// handler that stores its first argument as the data frame named by it, used to set `onMetaData`
+pub const SET_DATA_FRAME: &'static str = "@setDataFrame";
+
+/// A script data tag, a handler name like `onMetaData`, `onCuePoint` or `onTextData` and its values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FLVScriptData {
+    pub name: String,
+    pub values: Vec<Amf0Value>,
 }
 
-pub struct NalUnitInfos(pub Vec<NalUnitInfo>);
+impl FLVScriptData {
+    pub fn new(name: &str, values: Vec<Amf0Value>) -> FLVScriptData {
+        FLVScriptData { name: name.to_string(), values: values }
+    }
 
-impl fmt::Display for NalUnitInfos {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for info in self.0.iter() {
-            try!(write!(f, "{} ", info));
+    /// `@setDataFrame` is looked through, the event is the name it carries
+    pub fn event_name(&self) -> &str {
+        if self.name == SET_DATA_FRAME {
+            if let Some(name) = self.values.first().and_then(|v| v.as_str()) {
+                return name;
+            }
         }
-        Ok(())
+        &self.name
     }
-}
 
-impl FLVTag {
-    pub fn get_frame_type(&self) -> u8 {
-        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_VIDEO);
-        (self.data[TAG_HEADER_BYTE_COUNT as usize + 0] >> 4) & 0x0f
+    /// values of the event, without the name `@setDataFrame` carries
+    pub fn args(&self) -> &[Amf0Value] {
+        &self.values[self.args_start()..]
     }
 
-    pub fn get_codec_id(&self) -> u8 {
-        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_VIDEO);
-        (self.data[TAG_HEADER_BYTE_COUNT as usize + 0] & 0x0f)
+    pub fn args_mut(&mut self) -> &mut [Amf0Value] {
+        let start = self.args_start();
+        &mut self.values[start..]
     }
 
-    pub fn get_avc_packet_type(&self) -> u8 {
-        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_VIDEO);
-        self.data[TAG_HEADER_BYTE_COUNT as usize + 1]
+    fn args_start(&self) -> usize {
+        if self.name == SET_DATA_FRAME && self.values.first().and_then(|v| v.as_str()).is_some() { 1 } else { 0 }
     }
 
-    pub fn get_avc_composition_time_offset(&self) -> i32 {
-        assert!(self.get_codec_id() == 7 || self.get_codec_id() == 12); // CODEC_ID_AVC
-        // assert_eq!(self.get_avc_packet_type(), 1); 
-        // AVC_PACKET_TYPE_SEQUENCE_HEADER 0
-        // AVC_PACKET_TYPE_NALU            1
-        // AVC_PACKET_TYPE_END_OF_SEQUENCE 2
-        if self.get_avc_packet_type() != 1 {
-            return 0;
+    pub fn is_metadata(&self) -> bool {
+        self.event_name() == META_EVENT_NAME
+    }
+}
+
+/// Length prefixed NAL units, stops at the first unit that does not fit
+pub struct NalUnits<'a> {
+    data: &'a [u8],
+    length_size: usize,
+}
+
+impl<'a> Iterator for NalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.data.len() < self.length_size {
+            return None;
         }
-        let mut value: i32 = (self.data[TAG_HEADER_BYTE_COUNT as usize + 2] as i32) << 16;
-        value |= (self.data[TAG_HEADER_BYTE_COUNT as usize + 3] as i32) << 8;
-        value |= self.data[TAG_HEADER_BYTE_COUNT as usize + 4] as i32;
-        if (value & 0x00800000) != 0 {
-            value |= 0xff000000;	// sign-extend the 24-bit read for a 32-bit int
+        let mut nalu_size: usize = 0;
+        for i in 0..self.length_size {
+            nalu_size = (nalu_size << 8) | self.data[i] as usize;
         }
-        return value;
+        if self.data.len() - self.length_size < nalu_size {
+            self.data = &[];
+            return None;
+        }
+        let nalu = &self.data[self.length_size..(self.length_size + nalu_size)];
+        self.data = &self.data[(self.length_size + nalu_size)..];
+        Some(nalu)
     }
+}
 
-    pub fn get_nal_uints_info(&self) -> NalUnitInfos { // see see what in the data
-        
-        use std::fmt::Write;
-        
-        assert!(self.get_codec_id() == 7 || self.get_codec_id() == 12); // CODEC_ID_AVC
-        let mut handle: Cursor<&[u8]> = Cursor::new(&self.data[(TAG_HEADER_BYTE_COUNT as usize)..]);
-        let mut ret: Vec<NalUnitInfo> = Vec::new();
+/// AVCDecoderConfigurationRecord, the sequence header of codec id 7
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcC {
+    pub version: u8,
+    pub profile: u8,
+    pub compatibility: u8,
+    pub level: u8,
+    pub nalu_length_size_minus_1: u8,
+    pub sps_array: Vec<Vec<u8>>,
+    pub pps_array: Vec<Vec<u8>>,
+    /// trailer of the high profiles, `None` when the record stops after the PPS like many encoders write it
+    pub ext: Option<AvcCExt>,
+}
 
-        handle.seek(SeekFrom::Start(5)); // seek to nalus
-        let data_size = self.get_data_size() as u64;
-        loop {
-            let bytes_avaliable = data_size - handle.seek(SeekFrom::Current(0)).unwrap();
-            if bytes_avaliable < 4 {
-                break;
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcCExt {
+    pub chroma_format: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    pub sps_ext_array: Vec<Vec<u8>>,
+}
+
+fn read_parameter_sets(r: &mut &[u8], count: usize) -> Result<Vec<Vec<u8>>, FlvError> {
+    let mut sets: Vec<Vec<u8>> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = r.read_u16::<BigEndian>()? as usize;
+        if r.len() < len {
+            return Err(FlvError::UnexpectedEof);
+        }
+        sets.push(r[..len].to_vec());
+        *r = &r[len..];
+    }
+    Ok(sets)
+}
+
+fn write_parameter_sets<W: Write + ?Sized>(w: &mut W, sets: &[Vec<u8>]) {
+    for set in sets.iter() {
+        w.write_u16::<BigEndian>(set.len() as u16).unwrap();
+        w.write_all(set).unwrap();
+    }
+}
+
+impl AvcC {
+    pub fn parse(mut data: &[u8]) -> Result<AvcC, FlvError> {
+        let r = &mut data;
+        let version = r.read_u8()?;
+        let profile = r.read_u8()?;
+        let compatibility = r.read_u8()?;
+        let level = r.read_u8()?;
+        let nalu_length_size_minus_1 = r.read_u8()? & 0b00000011;
+        if nalu_length_size_minus_1 == 2 {
+            return Err(FlvError::BadBitstream("avcC nal length size of 3"));
+        }
+        let num_of_sps = r.read_u8()? & 0b00011111;
+        let sps_array = read_parameter_sets(r, num_of_sps as usize)?;
+        let num_of_pps = r.read_u8()?;
+        let pps_array = read_parameter_sets(r, num_of_pps as usize)?;
+        let mut ext = None;
+        if (profile == 100 || profile == 110 || profile == 122 || profile == 144) && r.len() >= 4 {
+            let chroma_format = r.read_u8()? & 0b11;
+            let bit_depth_luma_minus8 = r.read_u8()? & 0b111;
+            let bit_depth_chroma_minus8 = r.read_u8()? & 0b111;
+            let num_of_sps_ext = r.read_u8()?;
+            ext = Some(AvcCExt {
+                chroma_format,
+                bit_depth_luma_minus8,
+                bit_depth_chroma_minus8,
+                sps_ext_array: read_parameter_sets(r, num_of_sps_ext as usize)?,
+            });
+        }
+        Ok(AvcC {
+            version,
+            profile,
+            compatibility,
+            level,
+            nalu_length_size_minus_1,
+            sps_array,
+            pps_array,
+            ext,
+        })
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) {
+        w.write_u8(self.version).unwrap();
+        w.write_u8(self.profile).unwrap();
+        w.write_u8(self.compatibility).unwrap();
+        w.write_u8(self.level).unwrap();
+        w.write_u8(0b11111100 | self.nalu_length_size_minus_1).unwrap();
+        w.write_u8(0b11100000 | self.sps_array.len() as u8).unwrap();
+        write_parameter_sets(w, &self.sps_array);
+        w.write_u8(self.pps_array.len() as u8).unwrap();
+        write_parameter_sets(w, &self.pps_array);
+        if let Some(ref ext) = self.ext {
+            w.write_u8(0b11111100 | ext.chroma_format).unwrap();
+            w.write_u8(0b11111000 | ext.bit_depth_luma_minus8).unwrap();
+            w.write_u8(0b11111000 | ext.bit_depth_chroma_minus8).unwrap();
+            w.write_u8(ext.sps_ext_array.len() as u8).unwrap();
+            write_parameter_sets(w, &ext.sps_ext_array);
+        }
+    }
+
+    /// a record with 4 byte NAL lengths for in-band parameter sets, the profile fields come from the first SPS
+    pub fn from_parameter_sets(sps_array: Vec<Vec<u8>>, pps_array: Vec<Vec<u8>>) -> Result<AvcC, FlvError> {
+        let sps = match sps_array.first() {
+            Some(sps) if sps.len() >= 4 => Sps::parse(sps)?,
+            _ => return Err(FlvError::BadBitstream("avcC without sps")),
+        };
+        let ext = match sps.profile_idc {
+            100 | 110 | 122 | 144 => Some(AvcCExt {
+                chroma_format: sps.chroma_format_idc as u8,
+                bit_depth_luma_minus8: sps.bit_depth_luma - 8,
+                bit_depth_chroma_minus8: sps.bit_depth_chroma - 8,
+                sps_ext_array: Vec::new(),
+            }),
+            _ => None,
+        };
+        Ok(AvcC {
+            version: 1,
+            profile: sps.profile_idc,
+            compatibility: sps_array[0][2],
+            level: sps.level_idc,
+            nalu_length_size_minus_1: 3,
+            sps_array,
+            pps_array,
+            ext,
+        })
+    }
+
+    /// bytes of the length before each NAL unit in the NALU packets, 1, 2 or 4
+    pub fn get_nal_length_size(&self) -> usize {
+        self.nalu_length_size_minus_1 as usize + 1
+    }
+
+    /// the first SPS
+    #[deprecated(note = "use `sps_array`, a record may hold more than one SPS")]
+    pub fn sps(&self) -> &[u8] {
+        self.sps_array.first().map_or(&[], |sps| &sps[..])
+    }
+
+    #[deprecated(note = "use `sps_array.len()`")]
+    pub fn num_of_sps(&self) -> u8 {
+        self.sps_array.len() as u8
+    }
+
+    /// the first SPS, the one the stream starts with
+    pub fn parse_sps(&self) -> Result<Sps, FlvError> {
+        match self.sps_array.first() {
+            Some(sps) => Sps::parse(sps),
+            None => Err(FlvError::BadBitstream("avcC without sps")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NalUnitInfo {
+    pub codec_id: u8,
+    pub nalu_type: u8,
+    /// nal_ref_idc for AVC, TemporalId for HEVC
+    pub nalu_tag: u8,
+    pub nalu_size: u32,
+}
+
+impl fmt::Display for NalUnitInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.codec_id == CODEC_ID_HEVC {
+            let tp = match hevc::get_nal_unit_type_name(self.nalu_type) {
+                "TRAIL" => "   ",
+                name => name,
+            };
+            return write!(f, "[{} {} {:>5}]", tp, self.nalu_tag, self.nalu_size);
+        }
+        let tp  = match self.nalu_type {
+            1 => "   ", // IBP
+            5 => "IDR", // IDR
+            6 => "SEI", // SEI
+            7 => "SPS", // SPS
+            8 => "PPS", // PPS
+            _ => "Unknown Nalu Type",
+        };
+        let tag = match self.nalu_tag {
+            0b11 => "I",
+            0b10 => "P",
+            0b00 => "B",
+            _    => "X", // panic!("unknow nalu type"),
+        };
+        write!(f, "[{} {} {:>5}]", tp, tag, self.nalu_size)
+    }
+}
+
+pub struct NalUnitInfos(pub Vec<NalUnitInfo>);
+
+impl fmt::Display for NalUnitInfos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for info in self.0.iter() {
+            try!(write!(f, "{} ", info));
+        }
+        Ok(())
+    }
+}
+
+pub const CODEC_ID_SORENSON_H263: u8 = 2;
+pub const CODEC_ID_SCREEN_VIDEO: u8 = 3;
+pub const CODEC_ID_VP6: u8 = 4;
+pub const CODEC_ID_VP6_ALPHA: u8 = 5;
+pub const CODEC_ID_SCREEN_VIDEO_V2: u8 = 6;
+pub const CODEC_ID_AVC: u8 = 7;
+pub const CODEC_ID_HEVC: u8 = 12;
+
+/// the head of a video tag body, the legacy one or the first track of an extended one
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoTagHeader<'a> {
+    pub frame_type: u8,
+    /// for an extended header, the CodecID of its FourCC, 0 when the codec has none
+    pub codec_id: u8,
+    /// see `get_avc_packet_type`
+    pub packet_type: u8,
+    /// of AVC/HEVC NALU packets, 0 otherwise
+    pub composition_time: i32,
+    pub fourcc: Option<FourCC>,
+    /// NAL units or decoder configuration record of AVC/HEVC and extended headers, the frame of other codecs
+    pub data: &'a [u8],
+}
+
+impl<D: AsRef<[u8]>> FLVTag<D> {
+    /// Reads the head of the video tag once, an extended header is parsed up to its first track.
+    /// A broken extended header reads as a Metadata packet without codec and data.
+    pub fn video_header(&self) -> VideoTagHeader<'_> {
+        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_VIDEO);
+        let body = self.get_body();
+        let b = body.first().cloned().unwrap_or(0);
+        if b & enhanced::VIDEO_EX_HEADER_FLAG != 0 {
+            let mut header = VideoTagHeader {
+                frame_type: (b >> 4) & 0x07,
+                codec_id: 0,
+                packet_type: enhanced::VIDEO_PACKET_TYPE_METADATA, // no frame data to look at
+                composition_time: 0,
+                fourcc: None,
+                data: &[],
+            };
+            if let Ok(packet) = ExVideoPacket::parse(body) {
+                header.packet_type = match packet.packet_type {
+                    enhanced::VIDEO_PACKET_TYPE_CODED_FRAMES_X => 1,
+                    t => t,
+                };
+                if let Some(track) = packet.tracks.into_iter().next() {
+                    header.codec_id = track.fourcc.get_codec_id().unwrap_or(0);
+                    header.composition_time = track.composition_time;
+                    header.fourcc = Some(track.fourcc);
+                    header.data = track.data;
+                }
             }
-            let nalu_size = handle.read_u32::<BigEndian>().unwrap();
-            if bytes_avaliable < 4 + nalu_size as u64 {
-                break;
+            return header;
+        }
+        let codec_id = b & 0x0f;
+        let packet_type = body.get(1).cloned().unwrap_or(0);
+        if codec_id != CODEC_ID_AVC && codec_id != CODEC_ID_HEVC {
+            return VideoTagHeader {
+                frame_type: (b >> 4) & 0x0f,
+                codec_id: codec_id,
+                packet_type: packet_type,
+                composition_time: 0,
+                fourcc: None,
+                data: body.get(1..).unwrap_or(&[]),
+            };
+        }
+        let mut composition_time: i32 = 0;
+        if packet_type == 1 && body.len() >= 5 {
+            composition_time = (body[2] as i32) << 16 | (body[3] as i32) << 8 | body[4] as i32;
+            if (composition_time & 0x00800000) != 0 {
+                composition_time |= 0xff000000;	// sign-extend the 24-bit read for a 32-bit int
             }
-            let nalu_type = handle.read_u8().unwrap();
-            let tp  = nalu_type & 0x1F;
-            let tag = (nalu_type >> 5) & 0x3;
-            ret.push(NalUnitInfo {
-                        nalu_type: tp,
-                        nalu_tag: tag,
-                        nalu_size,
-                    });
-            handle.seek(SeekFrom::Current((nalu_size as i64) - 1));
         }
+        VideoTagHeader {
+            frame_type: (b >> 4) & 0x0f,
+            codec_id: codec_id,
+            packet_type: packet_type,
+            composition_time: composition_time,
+            fourcc: None,
+            data: body.get(5..).unwrap_or(&[]),
+        }
+    }
+
+    pub fn get_frame_type(&self) -> u8 {
+        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_VIDEO);
+        let b = self.bytes()[TAG_HEADER_BYTE_COUNT as usize + 0];
+        if b & enhanced::VIDEO_EX_HEADER_FLAG != 0 {
+            return (b >> 4) & 0x07;
+        }
+        (b >> 4) & 0x0f
+    }
+
+    /// for an extended header, the CodecID of its FourCC, 0 when the codec has none
+    pub fn get_codec_id(&self) -> u8 {
+        self.video_header().codec_id
+    }
+
+    /// For an extended header the PacketType is mapped on the AVC ones: SequenceStart is 0, CodedFrames
+    /// and CodedFramesX are 1, SequenceEnd is 2. Metadata and MPEG2TSSequenceStart keep their value.
+    pub fn get_avc_packet_type(&self) -> u8 {
+        self.video_header().packet_type
+    }
+
+    /// AVC or HEVC, both carry length prefixed NAL units behind the same packet header
+    pub fn is_avc_or_hevc(&self) -> bool {
+        let codec_id = self.get_codec_id();
+        codec_id == CODEC_ID_AVC || codec_id == CODEC_ID_HEVC
+    }
+
+    /// avcC or hvcC tag, or the SequenceStart of any extended header codec
+    pub fn is_video_sequence_header(&self) -> bool {
+        let header = self.video_header();
+        (header.codec_id == CODEC_ID_AVC || header.codec_id == CODEC_ID_HEVC || self.is_ex_video())
+            && self.get_data_size() > 1 && header.packet_type == 0
+    }
+
+    /// a NALU packet holding an IDR picture, or an IRAP picture for HEVC, whatever the frame type says
+    pub fn has_random_access_nal_unit(&self, length_size: usize) -> bool {
+        let header = self.video_header();
+        if (header.codec_id != CODEC_ID_AVC && header.codec_id != CODEC_ID_HEVC) || self.get_data_size() < 5 || header.packet_type != 1 {
+            return false;
+        }
+        let hevc = header.codec_id == CODEC_ID_HEVC;
+        NalUnits { data: header.data, length_size: length_size }.any(|nalu| match nalu.first() {
+            Some(&b) if hevc => hevc::is_irap(hevc::get_nal_unit_type(b)),
+            Some(&b) => b & 0x1f == h264::NAL_UNIT_TYPE_IDR,
+            None => false,
+        })
+    }
+
+    /// CompositionTime of an AVC/HEVC NALU packet, 0 for the other packets and codecs, they have none
+    pub fn get_avc_composition_time_offset(&self) -> i32 {
+        self.video_header().composition_time
+    }
+
+    pub fn get_nal_uints_info(&self) -> NalUnitInfos { // see see what in the data
+        self.get_nal_uints_info_with_length_size(4)
+    }
+
+    pub fn get_nal_uints_info_with_length_size(&self, length_size: usize) -> NalUnitInfos {
+        let codec_id = self.get_codec_id();
+        assert!(codec_id == CODEC_ID_AVC || codec_id == CODEC_ID_HEVC);
+        let ret: Vec<NalUnitInfo> = self.nal_units_with_length_size(length_size)
+            .filter(|nalu| !nalu.is_empty())
+            .map(|nalu| {
+                let (nalu_type, nalu_tag) = if codec_id == CODEC_ID_HEVC {
+                    // temporal id for hevc, the second header byte may be missing in a broken unit
+                    (hevc::get_nal_unit_type(nalu[0]), nalu.get(1).map_or(0, |b| (b & 0x7).saturating_sub(1)))
+                } else {
+                    (nalu[0] & 0x1F, (nalu[0] >> 5) & 0x3)
+                };
+                NalUnitInfo {
+                    codec_id,
+                    nalu_type,
+                    nalu_tag,
+                    nalu_size: nalu.len() as u32,
+                }
+            })
+            .collect();
         return NalUnitInfos(ret);
     }
 
+    /// NAL units of an AVC/HEVC NALU packet with 4 byte lengths, borrowed from the tag
+    pub fn nal_units(&self) -> NalUnits<'_> {
+        self.nal_units_with_length_size(4)
+    }
+
+    /// `length_size` comes from the sequence header in effect, see `get_nal_length_size`
+    pub fn nal_units_with_length_size(&self, length_size: usize) -> NalUnits<'_> {
+        assert!(length_size == 1 || length_size == 2 || length_size == 4);
+        if self.is_ex_video() {
+            let header = self.video_header();
+            let data = if header.packet_type == 1 { header.data } else { &[] };
+            return NalUnits { data: data, length_size: length_size };
+        }
+        NalUnits {
+            data: self.get_body().get(5..).unwrap_or(&[]),
+            length_size: length_size,
+        }
+    }
+
     // for ffmpeg avpacket
     pub fn get_nal_units(&self) -> Vec<Vec<u8>> {
-        
-        let mut units: Vec<Vec<u8>> = Vec::new();
-        let mut handle: Cursor<&[u8]> = Cursor::new(&self.data[(TAG_HEADER_BYTE_COUNT as usize)..]);
+        self.nal_units().map(|nalu| nalu.to_vec()).collect()
+    }
 
-        handle.seek(SeekFrom::Start(5)); // seek to nalus
-        let data_size = self.get_data_size() as u64;
-        loop {
-            let bytes_avaliable = data_size - handle.seek(SeekFrom::Current(0)).unwrap();
-            if bytes_avaliable < 4 {
-                break;
-            }
-            let nalu_size = handle.read_u32::<BigEndian>().unwrap() as u64;
-            if bytes_avaliable < 4 + nalu_size {
-                break;
+    pub fn get_nal_units_with_length_size(&self, length_size: usize) -> Vec<Vec<u8>> {
+        self.nal_units_with_length_size(length_size).map(|nalu| nalu.to_vec()).collect()
+    }
+
+    /// the NAL length size an AVC or HEVC sequence header sets for the NALU packets after it
+    pub fn get_nal_length_size(&self) -> Result<usize, FlvError> {
+        if self.get_codec_id() == CODEC_ID_HEVC {
+            let length_size = self.get_hvcc()?.length_size_minus_one as usize + 1;
+            if length_size == 3 {
+                return Err(FlvError::BadBitstream("hvcC nal length size of 3"));
             }
-            let mut nalu_data: Vec<u8> = vec![0; nalu_size as usize];
-            handle.read_exact(&mut nalu_data).unwrap();
-            units.push(nalu_data);
+            return Ok(length_size);
         }
-        return units;
+        Ok(self.try_get_avcc()?.get_nal_length_size())
     }
 
+    /// the decoder configuration record of a sequence header
     pub fn get_avcc_data(&self) -> &[u8] {
-        &self.data[(TAG_HEADER_BYTE_COUNT as usize + 5)..]
+        if self.is_ex_video() {
+            return self.video_header().data;
+        }
+        self.get_body().get(5..).unwrap_or(&[])
+    }
+
+    pub fn get_hvcc(&self) -> Result<HvcC, FlvError> {
+        if self.get_tag_type() != FLVTagType::TAG_TYPE_VIDEO || self.get_codec_id() != CODEC_ID_HEVC {
+            return Err(FlvError::BadTagHeader("not an HEVC video tag"));
+        }
+        if self.get_data_size() < 5 {
+            return Err(FlvError::UnexpectedEof);
+        }
+        if self.get_avc_packet_type() != 0 { // AVC_PACKET_TYPE_SEQUENCE_HEADER
+            return Err(FlvError::BadTagHeader("not a sequence header"));
+        }
+        HvcC::parse(self.get_avcc_data())
     }
 
     pub fn get_avcc(&self) -> AvcC {
-        
-        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_VIDEO);
-        assert_eq!(self.get_codec_id(), 7); // CODEC_ID_AVC
-        assert_eq!(self.get_avc_packet_type(), 0); // AVC_PACKET_TYPE_SEQUENCE_HEADER
-        
-        let mut data: Cursor<&[u8]> = Cursor::new(&self.data);
-        data.seek(SeekFrom::Start((TAG_HEADER_BYTE_COUNT + 5) as u64));
-        // parse start
-        let version: u8 = data.read_u8().unwrap();
-        let profile: u8 = data.read_u8().unwrap();
-        let compatibility: u8 = data.read_u8().unwrap();
-        let level: u8 = data.read_u8().unwrap();
-        
-        let nalu_length_size_minus_1: u8 = data.read_u8().unwrap() & 0b00000011;
-        let num_of_sps: u8 = data.read_u8().unwrap() & 0b00011111;
-        assert_eq!(num_of_sps, 1);
-        
-        let sps_len = data.read_u16::<BigEndian>().unwrap();
-        let mut sps: Vec<u8> = vec![0; sps_len as usize];
-        data.read_exact(&mut sps).unwrap();
+        self.try_get_avcc().unwrap()
+    }
 
-        let num_of_pps = data.read_u8().unwrap();
-        let mut pps_array: Vec<Vec<u8>> = Vec::with_capacity(num_of_pps as usize);
-        for _ in 0..(num_of_pps as usize) {
-            let pps_len = data.read_u16::<BigEndian>().unwrap();
-            let mut pps: Vec<u8> = vec![0; pps_len as usize];
-            data.read_exact(&mut pps).unwrap();
-            pps_array.push(pps);
+    pub fn try_get_avcc(&self) -> Result<AvcC, FlvError> {
+        if self.get_tag_type() != FLVTagType::TAG_TYPE_VIDEO || self.get_codec_id() != CODEC_ID_AVC {
+            return Err(FlvError::BadTagHeader("not an AVC video tag"));
         }
-
-        AvcC {
-            version,
-            profile,
-            compatibility,
-            level,
-            nalu_length_size_minus_1,
-            num_of_sps,
-            sps,
-            pps_array,
+        if self.get_data_size() < 5 {
+            return Err(FlvError::UnexpectedEof);
+        }
+        if self.get_avc_packet_type() != 0 { // AVC_PACKET_TYPE_SEQUENCE_HEADER
+            return Err(FlvError::BadTagHeader("not a sequence header"));
         }
+        AvcC::parse(self.get_avcc_data())
     }
 }
 
@@ -576,15 +1507,15 @@ pub const SOUND_SIZE_16BITS: u8 = 16;
 pub const SOUND_CHANNELS_MONO: u8 = 1;
 pub const SOUND_CHANNELS_STEREO: u8 = 2;
 
-impl FLVTag {
+impl<D: AsRef<[u8]>> FLVTag<D> {
     pub fn get_sound_format(&self) -> u8 {
         assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_AUDIO);
-        (self.data[TAG_HEADER_BYTE_COUNT as usize + 0] >> 4) & 0x0f
+        (self.bytes()[TAG_HEADER_BYTE_COUNT as usize + 0] >> 4) & 0x0f
     }
 
     pub fn get_sound_rate(&self) -> f64 {
         assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_AUDIO);
-        match (self.data[TAG_HEADER_BYTE_COUNT as usize + 0] >> 2) & 0b11 {
+        match (self.bytes()[TAG_HEADER_BYTE_COUNT as usize + 0] >> 2) & 0b11 {
             0 => SOUND_RATE_5K,
             1 => SOUND_RATE_11K,
             2 => SOUND_RATE_22K,
@@ -607,7 +1538,7 @@ impl FLVTag {
 
     pub fn get_sound_size(&self) -> u8 {
         assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_AUDIO);
-        if ((self.data[TAG_HEADER_BYTE_COUNT as usize + 0] >> 1) & 1) == 1 {
+        if ((self.bytes()[TAG_HEADER_BYTE_COUNT as usize + 0] >> 1) & 1) == 1 {
             SOUND_SIZE_16BITS
         } else {
             SOUND_SIZE_8BITS
@@ -616,7 +1547,7 @@ impl FLVTag {
 
     pub fn get_sound_channels(&self) -> u8 {
         assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_AUDIO);
-        if (self.data[TAG_HEADER_BYTE_COUNT as usize + 0] & 1) == 1 {
+        if (self.bytes()[TAG_HEADER_BYTE_COUNT as usize + 0] & 1) == 1 {
             SOUND_CHANNELS_STEREO
         } else {
             SOUND_CHANNELS_MONO
@@ -639,6 +1570,22 @@ impl FLVTag {
         }
     }
 
+    pub fn get_sound_data(&self) -> &[u8] {
+        self.bytes()[(TAG_HEADER_BYTE_COUNT as usize + 2)..].as_ref()
+    }
+
+    pub fn get_sound_data_size(&self) -> u32 {
+        self.get_data_size() - 2
+    }
+
+    pub fn is_acc_sequence_header(&self) -> bool {
+        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_AUDIO);
+        assert_eq!(self.get_sound_format(), 10);
+        self.bytes()[TAG_HEADER_BYTE_COUNT as usize + 1] == 0
+    }
+}
+
+impl FLVTag {
     pub fn get_sound_adts_header_data(asc: &AudioSpecificConfig, frame_len: u32) -> [u8; 7] {
 
         let &AudioSpecificConfig { original_audio_object_type, audio_object_type, sample_index, channel_config } = asc;
@@ -668,20 +1615,6 @@ impl FLVTag {
         header[6] = 0xfc;
         return header;
     }
-
-    pub fn get_sound_data(&self) -> &[u8] {
-        self.data[(TAG_HEADER_BYTE_COUNT as usize + 2)..].as_ref()
-    }
-
-    pub fn get_sound_data_size(&self) -> u32 {
-        self.get_data_size() - 2
-    }
-
-    pub fn is_acc_sequence_header(&self) -> bool {
-        assert_eq!(self.get_tag_type(), FLVTagType::TAG_TYPE_AUDIO);
-        assert_eq!(self.get_sound_format(), 10);
-        self.data[TAG_HEADER_BYTE_COUNT as usize + 1] == 0
-    }
 }
 
 pub struct FLVTagRead<'a, R: Read + 'a> {
@@ -689,63 +1622,450 @@ pub struct FLVTagRead<'a, R: Read + 'a> {
     pub header: FLVHeader,
     position: u64,
     finished: bool,
+    unknown_tag_policy: UnknownTagPolicy,
+    prev_tag_size_policy: PrevTagSizePolicy,
+    warnings: Vec<(u64, FlvError)>,
 }
 
 impl<'a, R: Read> FLVTagRead<'a, R> {
     pub fn new(r: &'a mut R) -> FLVTagRead<'a, R> {
-        let header = FLVHeader::read(r);
-        FLVTagRead::<'a, R> {
+        FLVTagRead::try_new(r).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(r: &'a mut R) -> Result<FLVTagRead<'a, R>, FlvError> {
+        let header = FLVHeader::try_read(r)?;
+        Ok(FLVTagRead::<'a, R> {
             source: r,
             header: header,
             finished: false,
-            position: MIN_FILE_HEADER_BYTE_COUNT as u64 + 4
-        }
+            position: MIN_FILE_HEADER_BYTE_COUNT as u64 + 4,
+            unknown_tag_policy: UnknownTagPolicy::Keep,
+            prev_tag_size_policy: PrevTagSizePolicy::default(),
+            warnings: Vec::new(),
+        })
+    }
+
+    pub fn with_prev_tag_size_policy(mut self, policy: PrevTagSizePolicy) -> Self {
+        self.prev_tag_size_policy = policy;
+        self
+    }
+
+    /// warnings collected so far under `PrevTagSizePolicy::Warn`, with the offset of the tag
+    pub fn take_warnings(&mut self) -> Vec<(u64, FlvError)> {
+        ::std::mem::take(&mut self.warnings)
+    }
+
+    pub fn with_unknown_tag_policy(mut self, policy: UnknownTagPolicy) -> Self {
+        self.unknown_tag_policy = policy;
+        self
     }
 
     pub fn get_position(&self) -> u64 {
         self.position
     }
+
+    /// like `next`, but hands the error back instead of panicking.
+    /// the reader is finished after the first error.
+    pub fn try_next(&mut self) -> Result<Option<FLVTag>, FlvError> {
+        loop {
+            if self.finished {
+                return Ok(None);
+            }
+            match FLVTag::try_read_with_prev_tag_size(self.source) {
+                Ok(Some((tag, prev_tag_size))) => {
+                    if let Err(e) = self.prev_tag_size_policy.check(tag.get_tag_size(), prev_tag_size, self.position, &mut self.warnings) {
+                        self.finished = true;
+                        return Err(e);
+                    }
+                    if let FLVTagType::Unknown(t) = tag.get_tag_type() {
+                        match self.unknown_tag_policy {
+                            UnknownTagPolicy::Keep => {},
+                            UnknownTagPolicy::Skip => {
+                                self.position += tag.get_tag_size() as u64 + 4;
+                                continue;
+                            }
+                            UnknownTagPolicy::Error => {
+                                self.finished = true;
+                                return Err(FlvError::UnknownTagType(t));
+                            }
+                        }
+                    }
+                    self.position += tag.get_tag_size() as u64 + 4;
+                    return Ok(Some(tag));
+                }
+                Ok(None) => {
+                    self.finished = true;
+                    return Ok(None);
+                }
+                Err(e) => {
+                    self.finished = true;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    pub fn try_iter<'b>(&'b mut self) -> FLVTagTryIter<'b, 'a, R> {
+        FLVTagTryIter {
+            parser: self
+        }
+    }
 }
 
 impl<'a, R: Read + 'a> Iterator for FLVTagRead<'a, R> {
     type Item = FLVTag;
 
     fn next(&mut self) -> Option<FLVTag> {
-        if self.finished {
-            None
+        self.try_next().unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+pub struct FLVTagTryIter<'b, 'a: 'b, R: Read + 'a> {
+    parser: &'b mut FLVTagRead<'a, R>,
+}
+
+impl<'b, 'a: 'b, R: Read + 'a> Iterator for FLVTagTryIter<'b, 'a, R> {
+    type Item = Result<FLVTag, FlvError>;
+
+    fn next(&mut self) -> Option<Result<FLVTag, FlvError>> {
+        match self.parser.try_next() {
+            Ok(Some(tag)) => Some(Ok(tag)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
         }
-        else {
-            let tag = FLVTag::read(self.source);
-            if tag.is_none() {
-                self.finished = true;
-            }
-            else {
-                self.position += tag.as_ref().unwrap().get_tag_size() as u64 + 4
+    }
+}
+
+/// Walks a seekable flv file from the end to the front by following the PreviousTagSize fields,
+/// so the tail of a huge file can be read without scanning it.
+pub struct FLVTagReverseRead<'a, R: Read + Seek + 'a> {
+    source: &'a mut R,
+    pub header: FLVHeader,
+    position: u64, // offset of the last returned tag, or the file size
+    finished: bool,
+}
+
+impl<'a, R: Read + Seek> FLVTagReverseRead<'a, R> {
+    pub fn try_new(r: &'a mut R) -> Result<FLVTagReverseRead<'a, R>, FlvError> {
+        r.seek(SeekFrom::Start(0))?;
+        let header = FLVHeader::try_read(r)?;
+        let size = r.seek(SeekFrom::End(0))?;
+        Ok(FLVTagReverseRead::<'a, R> {
+            source: r,
+            header: header,
+            position: size,
+            finished: false,
+        })
+    }
+
+    /// offset of the tag returned by the last `try_next`
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<FLVTag>, FlvError> {
+        let first_tag_position = MIN_FILE_HEADER_BYTE_COUNT as u64 + PREV_TAG_BYTE_COUNT as u64;
+        if self.finished || self.position <= first_tag_position {
+            self.finished = true;
+            return Ok(None);
+        }
+        self.finished = true; // until this tag is read
+        self.source.seek(SeekFrom::Start(self.position - PREV_TAG_BYTE_COUNT as u64))?;
+        let prev_tag_size = self.source.read_u32::<BigEndian>()? as u64;
+        if prev_tag_size < TAG_HEADER_BYTE_COUNT as u64 || prev_tag_size + (PREV_TAG_BYTE_COUNT as u64) + first_tag_position > self.position {
+            return Err(FlvError::BadTagHeader("PreviousTagSize out of range"));
+        }
+        let tag_position = self.position - PREV_TAG_BYTE_COUNT as u64 - prev_tag_size;
+        self.source.seek(SeekFrom::Start(tag_position))?;
+        let (tag, _) = FLVTag::try_read_with_prev_tag_size(self.source)?.ok_or(FlvError::UnexpectedEof)?;
+        // the tag was found with the PreviousTagSize, a wrong one leaves every tag before it out of reach
+        if tag.get_tag_size() as u64 != prev_tag_size {
+            return Err(FlvError::PrevTagSizeMismatch { expected: tag.get_tag_size(), actual: prev_tag_size as u32 });
+        }
+        self.position = tag_position;
+        self.finished = false;
+        Ok(Some(tag))
+    }
+}
+
+impl<'a, R: Read + Seek + 'a> Iterator for FLVTagReverseRead<'a, R> {
+    type Item = Result<FLVTag, FlvError>;
+
+    fn next(&mut self) -> Option<Result<FLVTag, FlvError>> {
+        match self.try_next() {
+            Ok(Some(tag)) => Some(Ok(tag)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Reads tags straight out of a byte slice (a whole file in memory or a memory map),
+/// the tags borrow the slice instead of copying every tag body.
+pub struct FLVSliceRead<'a> {
+    data: &'a [u8],
+    pub header: FLVHeader,
+    position: usize,
+    finished: bool,
+    prev_tag_size_policy: PrevTagSizePolicy,
+    warnings: Vec<(u64, FlvError)>,
+}
+
+impl<'a> FLVSliceRead<'a> {
+    pub fn try_new(data: &'a [u8]) -> Result<FLVSliceRead<'a>, FlvError> {
+        let mut handle = Cursor::new(data);
+        let header = FLVHeader::try_read(&mut handle)?;
+        Ok(FLVSliceRead {
+            data: data,
+            header: header,
+            position: (MIN_FILE_HEADER_BYTE_COUNT + PREV_TAG_BYTE_COUNT) as usize,
+            finished: false,
+            prev_tag_size_policy: PrevTagSizePolicy::default(),
+            warnings: Vec::new(),
+        })
+    }
+
+    pub fn with_prev_tag_size_policy(mut self, policy: PrevTagSizePolicy) -> Self {
+        self.prev_tag_size_policy = policy;
+        self
+    }
+
+    /// warnings collected so far under `PrevTagSizePolicy::Warn`, with the offset of the tag
+    pub fn take_warnings(&mut self) -> Vec<(u64, FlvError)> {
+        ::std::mem::take(&mut self.warnings)
+    }
+
+    /// offset of the next tag
+    pub fn get_position(&self) -> u64 {
+        self.position as u64
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<FLVTagRef<'a>>, FlvError> {
+        if self.finished || self.position == self.data.len() {
+            self.finished = true;
+            return Ok(None);
+        }
+        self.finished = true; // until this tag is read
+        let rest = &self.data[self.position..];
+        if rest.len() < TAG_HEADER_BYTE_COUNT as usize {
+            return Err(FlvError::UnexpectedEof);
+        }
+        let data_size = read_u24_be(&mut &rest[1..4])?;
+        let tag_size = data_size + TAG_HEADER_BYTE_COUNT;
+        if rest.len() < (tag_size + PREV_TAG_BYTE_COUNT) as usize {
+            let actual = if rest.len() < tag_size as usize { rest.len() as u32 } else { tag_size };
+            return Err(FlvError::TruncatedTag { expected: tag_size, actual: actual });
+        }
+        let prev_tag_size = (&rest[(tag_size as usize)..]).read_u32::<BigEndian>()?;
+        self.prev_tag_size_policy.check(tag_size, prev_tag_size, self.position as u64, &mut self.warnings)?;
+        self.position += (tag_size + PREV_TAG_BYTE_COUNT) as usize;
+        self.finished = false;
+        Ok(Some(FLVTag {
+            data: &rest[..(tag_size as usize)]
+        }))
+    }
+}
+
+impl<'a> Iterator for FLVSliceRead<'a> {
+    type Item = Result<FLVTagRef<'a>, FlvError>;
+
+    fn next(&mut self) -> Option<Result<FLVTagRef<'a>, FlvError>> {
+        match self.try_next() {
+            Ok(Some(tag)) => Some(Ok(tag)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// What `FLVTagScan` keeps of a tag: the header fields and the first bytes of the payload.
+#[derive(Debug, Clone)]
+pub struct FLVTagSummary {
+    pub position: u64,
+    pub tag_type: FLVTagType,
+    pub data_size: u32,
+    pub timestamp: u64,
+    pub prefix: Vec<u8>, // first `prefix_size` bytes of the payload, or less for short tags
+}
+
+impl FLVTagSummary {
+    pub fn get_tag_size(&self) -> u32 {
+        TAG_HEADER_BYTE_COUNT + self.data_size
+    }
+
+    /// video frame type, 1 for keyframes
+    pub fn get_frame_type(&self) -> Option<u8> {
+        match self.tag_type {
+            FLVTagType::TAG_TYPE_VIDEO if self.prefix.len() > 0 && self.prefix[0] & enhanced::VIDEO_EX_HEADER_FLAG != 0 => Some((self.prefix[0] >> 4) & 0x07),
+            FLVTagType::TAG_TYPE_VIDEO if self.prefix.len() > 0 => Some((self.prefix[0] >> 4) & 0x0f),
+            _ => None
+        }
+    }
+
+    /// for an extended header, the CodecID of a FourCC right after the first byte, 0 otherwise
+    pub fn get_codec_id(&self) -> Option<u8> {
+        match self.tag_type {
+            FLVTagType::TAG_TYPE_VIDEO if self.prefix.len() > 0 && self.prefix[0] & enhanced::VIDEO_EX_HEADER_FLAG != 0 => {
+                let b = self.prefix[0];
+                let fourcc_follows = self.prefix.len() >= 5 && (b >> 4) & 0x07 != enhanced::VIDEO_FRAME_TYPE_COMMAND
+                    && b & 0x0f != enhanced::VIDEO_PACKET_TYPE_MULTITRACK && b & 0x0f != enhanced::VIDEO_PACKET_TYPE_MOD_EX;
+                if !fourcc_follows {
+                    return Some(0);
+                }
+                let fourcc = FourCC([self.prefix[1], self.prefix[2], self.prefix[3], self.prefix[4]]);
+                Some(fourcc.get_codec_id().unwrap_or(0))
             }
-            tag
+            FLVTagType::TAG_TYPE_VIDEO if self.prefix.len() > 0 => Some(self.prefix[0] & 0x0f),
+            _ => None
         }
     }
+
+    pub fn get_sound_format(&self) -> Option<u8> {
+        match self.tag_type {
+            FLVTagType::TAG_TYPE_AUDIO if self.prefix.len() > 0 => Some((self.prefix[0] >> 4) & 0x0f),
+            _ => None
+        }
+    }
+}
+
+/// Reads only the tag headers and a few payload bytes, seeking over the rest of each payload.
+pub struct FLVTagScan<'a, R: Read + Seek + 'a> {
+    source: &'a mut R,
+    pub header: FLVHeader,
+    prefix_size: usize,
+    position: u64,
+    finished: bool,
+    prev_tag_size_policy: PrevTagSizePolicy,
+    warnings: Vec<(u64, FlvError)>,
 }
 
-pub struct FLVTagWrite<W: Write + Seek> {
+impl<'a, R: Read + Seek> FLVTagScan<'a, R> {
+    pub fn try_new(r: &'a mut R) -> Result<FLVTagScan<'a, R>, FlvError> {
+        r.seek(SeekFrom::Start(0))?;
+        let header = FLVHeader::try_read(r)?;
+        Ok(FLVTagScan::<'a, R> {
+            source: r,
+            header: header,
+            prefix_size: 5, // enough for the AVC packet type and composition time
+            position: (MIN_FILE_HEADER_BYTE_COUNT + PREV_TAG_BYTE_COUNT) as u64,
+            finished: false,
+            prev_tag_size_policy: PrevTagSizePolicy::default(),
+            warnings: Vec::new(),
+        })
+    }
+
+    pub fn with_prev_tag_size_policy(mut self, policy: PrevTagSizePolicy) -> Self {
+        self.prev_tag_size_policy = policy;
+        self
+    }
+
+    /// warnings collected so far under `PrevTagSizePolicy::Warn`, with the offset of the tag
+    pub fn take_warnings(&mut self) -> Vec<(u64, FlvError)> {
+        ::std::mem::take(&mut self.warnings)
+    }
+
+    pub fn with_prefix_size(mut self, prefix_size: usize) -> FLVTagScan<'a, R> {
+        self.prefix_size = prefix_size;
+        self
+    }
+
+    /// offset of the next tag
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    /// continue scanning from a tag at `position`, e.g. one from the keyframes table
+    pub fn seek(&mut self, position: u64) -> Result<(), FlvError> {
+        self.source.seek(SeekFrom::Start(position))?;
+        self.position = position;
+        self.finished = false;
+        Ok(())
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<FLVTagSummary>, FlvError> {
+        if self.finished {
+            return Ok(None);
+        }
+        self.finished = true; // until this tag is read
+        let mut tag_header = [0u8; 11];
+        let read_len = self.source.read(&mut tag_header)?;
+        if read_len == 0 {
+            return Ok(None);
+        }
+        self.source.read_exact(&mut tag_header[read_len..])?;
+        let tag = FLVTag {
+            data: &tag_header[..]
+        };
+        let data_size = tag.get_data_size();
+
+        let prefix_len = cmp::min(self.prefix_size, data_size as usize);
+        let mut prefix: Vec<u8> = vec![0; prefix_len];
+        self.source.read_exact(&mut prefix)?;
+        self.source.seek(SeekFrom::Current((data_size as usize - prefix_len) as i64))?;
+        let prev_tag_size = self.source.read_u32::<BigEndian>()?;
+        self.prev_tag_size_policy.check(tag.get_tag_size(), prev_tag_size, self.position, &mut self.warnings)?;
+
+        let summary = FLVTagSummary {
+            position: self.position,
+            tag_type: tag.get_tag_type(),
+            data_size: data_size,
+            timestamp: tag.get_timestamp(),
+            prefix: prefix,
+        };
+        self.position += (tag.get_tag_size() + PREV_TAG_BYTE_COUNT) as u64;
+        self.finished = false;
+        Ok(Some(summary))
+    }
+}
+
+impl<'a, R: Read + Seek + 'a> Iterator for FLVTagScan<'a, R> {
+    type Item = Result<FLVTagSummary, FlvError>;
+
+    fn next(&mut self) -> Option<Result<FLVTagSummary, FlvError>> {
+        match self.try_next() {
+            Ok(Some(tag)) => Some(Ok(tag)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Writes tags in order and counts the bytes written.
+/// Any `Write` works, pipes and sockets included, only `write_meta_tag` needs `Seek`;
+/// without it write the final metadata first with `write_tag`.
+pub struct FLVTagWrite<W: Write> {
     stream: W,
     position: u64,
+    meta_padding: u32,
+    meta_reserved: Option<u32>, // tag size of the room after the file header
 }
 
-impl<W: Write + Seek> FLVTagWrite<W> {
+/// room left for the metadata to grow by default, enough for the keyframes of about an hour
+pub const DEFAULT_META_PADDING: u32 = 8 * 1024;
+
+impl<W: Write> FLVTagWrite<W> {
     pub fn new(w: W) -> FLVTagWrite<W> {
         FLVTagWrite::<W> {
             stream: w,
-            position: 0
+            position: 0,
+            meta_padding: DEFAULT_META_PADDING,
+            meta_reserved: None,
         }
     }
 
+    /// extra bytes kept in the first metadata tag, so a later `write_meta_tag` can grow into them,
+    /// `DEFAULT_META_PADDING` when not set
+    pub fn with_meta_padding(mut self, padding: u32) -> FLVTagWrite<W> {
+        self.meta_padding = padding;
+        self
+    }
+
     pub fn write_header(&mut self, header: &FLVHeader) {
         header.write(&mut self.stream);
         self.position += MIN_FILE_HEADER_BYTE_COUNT as u64 + 4;
     }
 
-    pub fn write_tag(&mut self, tag: &FLVTag) {
+    pub fn write_tag<D: AsRef<[u8]>>(&mut self, tag: &FLVTag<D>) {
         tag.write(&mut self.stream);
         // match tag.get_tag_type() {
         //     FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT => {
@@ -756,25 +2076,418 @@ impl<W: Write + Seek> FLVTagWrite<W> {
         self.position += tag.get_tag_size() as u64 + 4
     }
 
-    pub fn write_meta_tag(&mut self, tag: &FLVTag) {
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> W {
+        self.stream
+    }
+}
+
+impl<W: Write + Seek> FLVTagWrite<W> {
+    /// Writes the metadata tag right after the file header.
+    ///
+    /// The first call reserves the room, `meta_padding` bytes bigger than the tag.
+    /// Later calls pad the tag to exactly that room with filler after its values, a tag that does
+    /// not fit is not written and `FlvError::MetaTagOverflow` tells how big the room must be,
+    /// see `write_meta_tag_relocating`.
+    pub fn write_meta_tag<D: AsRef<[u8]>>(&mut self, tag: &FLVTag<D>) -> Result<(), FlvError> {
         use std::io::SeekFrom::{Current, Start};
 
-        let current_pos = self.stream.seek(Current(0)).unwrap();
-        self.stream.seek(Start(MIN_FILE_HEADER_BYTE_COUNT as u64 + 4)).unwrap();
-        self.write_tag(tag);
+        let meta_position = MIN_FILE_HEADER_BYTE_COUNT as u64 + PREV_TAG_BYTE_COUNT as u64;
+        let tag = meta_room_tag(tag, self.meta_reserved, self.meta_padding, self.position)?;
+
+        let current_pos = self.stream.seek(Current(0))?;
+        self.stream.seek(Start(meta_position))?;
+        self.write_tag(&tag);
+        self.meta_reserved = Some(tag.get_tag_size());
         // position fix
-        let new_pos = self.stream.seek(Current(0)).unwrap();
+        let new_pos = self.stream.seek(Current(0))?;
         if current_pos > new_pos {
-            self.stream.seek(Start(current_pos)).unwrap();
+            self.stream.seek(Start(current_pos))?;
             self.position = current_pos;
         } else {
             self.position = new_pos;
         }
+        Ok(())
     }
+}
 
-    pub fn get_position(&self) -> u64 {
-        self.position
+impl<W: Read + Write + Seek> FLVTagWrite<W> {
+    /// Moves every tag after the metadata room `grow` bytes back, so the room gets bigger.
+    /// File positions kept by the caller, like the keyframes table, move by `grow` too.
+    pub fn grow_meta_room(&mut self, grow: u32) -> Result<(), FlvError> {
+        let reserved = match self.meta_reserved {
+            Some(reserved) => reserved,
+            None => return Err(FlvError::MetaTagOverflow { reserved: 0, needed: grow }),
+        };
+        let start = (MIN_FILE_HEADER_BYTE_COUNT + PREV_TAG_BYTE_COUNT + reserved + PREV_TAG_BYTE_COUNT) as u64;
+        let mut buf: Vec<u8> = vec![0; 64 * 1024];
+        let mut end = self.position;
+        // copy from the end, the ranges overlap
+        while end > start {
+            let len = cmp::min(buf.len() as u64, end - start) as usize;
+            self.stream.seek(SeekFrom::Start(end - len as u64))?;
+            self.stream.read_exact(&mut buf[..len])?;
+            self.stream.seek(SeekFrom::Start(end - len as u64 + grow as u64))?;
+            self.stream.write_all(&buf[..len])?;
+            end -= len as u64;
+        }
+        self.position += grow as u64;
+        // the room gets a bigger padded copy of the tag in it, so the file stays readable
+        let tag = {
+            let mut data: Vec<u8> = vec![0; reserved as usize];
+            self.stream.seek(SeekFrom::Start(start - (reserved + PREV_TAG_BYTE_COUNT) as u64))?;
+            self.stream.read_exact(&mut data)?;
+            FLVTag { data: data }
+        };
+        self.stream.seek(SeekFrom::Start(self.position))?;
+        self.meta_reserved = Some(reserved + grow);
+        let tag = pad_meta_tag(&tag, reserved + grow).unwrap_or(tag);
+        self.write_meta_tag(&tag)
+    }
+
+    /// Writes the metadata tag, making room for it when it does not fit.
+    /// `build` gets how many bytes the tags after the metadata were moved and returns the metadata
+    /// with its file positions moved as much; returns the total move.
+    pub fn write_meta_tag_relocating<F>(&mut self, mut build: F) -> Result<u64, FlvError>
+        where F: FnMut(u64) -> FLVTag {
+        let mut shift: u64 = 0;
+        loop {
+            let tag = build(shift);
+            match self.write_meta_tag(&tag) {
+                Err(FlvError::MetaTagOverflow { reserved, needed }) if self.meta_reserved.is_some() && needed > reserved => {
+                    self.grow_meta_room(needed - reserved)?;
+                    shift += (needed - reserved) as u64;
+                }
+                r => return r.map(|_| shift),
+            }
+        }
+    }
+}
+
+/// The metadata tag padded to the room after the file header. `reserved` is the tag size of the room,
+/// `None` until the first metadata tag is written at `position`, which gets a room `padding` bytes bigger than it.
+pub(crate) fn meta_room_tag<D: AsRef<[u8]>>(tag: &FLVTag<D>, reserved: Option<u32>, padding: u32, position: u64) -> Result<FLVTag, FlvError> {
+    let meta_position = MIN_FILE_HEADER_BYTE_COUNT as u64 + PREV_TAG_BYTE_COUNT as u64;
+    match reserved {
+        Some(reserved) => match pad_meta_tag(tag, reserved) {
+            Some(tag) => Ok(tag),
+            None => Err(FlvError::MetaTagOverflow { reserved: reserved, needed: unpadded_meta_tag(tag).get_tag_size() }),
+        },
+        None if position <= meta_position => {
+            let size = unpadded_meta_tag(tag).get_tag_size().saturating_add(padding);
+            Ok(pad_meta_tag(tag, size).unwrap_or(tag.to_tag()))
+        }
+        None => Err(FlvError::MetaTagOverflow { reserved: 0, needed: tag.get_tag_size() }),
+    }
+}
+
+/// the script tag without the filler after its values, other tags as they are
+fn unpadded_meta_tag<D: AsRef<[u8]>>(tag: &FLVTag<D>) -> FLVTag {
+    let mut unpadded = tag.to_tag();
+    if tag.get_tag_type() == FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT {
+        if let Ok(script) = tag.try_get_script_data() {
+            unpadded.set_script_data(&script);
+        }
+    }
+    unpadded
+}
+
+/// fills the script tag after its values with `SCRIPT_DATA_FILLER_MARKER` and zero bytes so the tag
+/// is `tag_size` bytes, `None` when it is bigger
+fn pad_meta_tag<D: AsRef<[u8]>>(tag: &FLVTag<D>, tag_size: u32) -> Option<FLVTag> {
+    let mut tag = unpadded_meta_tag(tag);
+    let filler = tag_size.checked_sub(tag.get_tag_size())?;
+    if filler > 0 {
+        let data_size = tag.get_data_size() + filler;
+        tag.data.push(SCRIPT_DATA_FILLER_MARKER);
+        tag.data.resize(tag_size as usize, 0);
+        tag.set_data_size(data_size);
+    }
+    Some(tag)
+}
+
+#[test]
+fn test_try_read() {
+    match FLVHeader::try_read(&mut &b"FLX\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00"[..]) {
+        Err(FlvError::BadSignature(..)) => {},
+        r => panic!("unexpected {:?}", r.map(|_| ())),
+    }
+
+    // audio tag, 2 bytes of data
+    let mut tag: Vec<u8> = vec![8, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0xaf, 0x01, 0, 0, 0, 13];
+    assert!(FLVTag::try_read(&mut &tag[..]).unwrap().is_some());
+    assert!(FLVTag::try_read(&mut &b""[..]).unwrap().is_none());
+    match FLVTag::try_read(&mut &tag[..12]) {
+        Err(FlvError::TruncatedTag { expected: 13, actual: 12 }) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    tag[16] = 12;
+    assert!(FLVTag::try_read(&mut &tag[..]).unwrap().is_some());
+    match FLVTag::try_read_with_policy(&mut &tag[..], PrevTagSizePolicy::Error) {
+        Err(FlvError::PrevTagSizeMismatch { expected: 13, actual: 12 }) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    tag[16] = 13;
+    tag[0] = 0x27; // filter bit set, type 7
+    let unknown = FLVTag::try_read(&mut &tag[..]).unwrap().unwrap();
+    assert_eq!(unknown.get_tag_type(), FLVTagType::Unknown(7));
+    assert!(unknown.get_filter());
+    let mut bytes: Vec<u8> = Vec::new();
+    unknown.write(&mut bytes);
+    assert_eq!(bytes, tag);
+    match FLVTagType::try_from(tag[0]) {
+        Err(FlvError::UnknownTagType(7)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    match Cursor::new(vec![0x12u8]).try_read_amf0_value() {
+        Err(FlvError::BadAmfMarker(0x12)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn test_reverse_read() {
+    let mut data: Vec<u8> = b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+    for &timestamp in [0u8, 40, 80].iter() {
+        data.extend_from_slice(&[8, 0, 0, 2, 0, 0, timestamp, 0, 0, 0, 0, 0xaf, 0x01, 0, 0, 0, 13]);
+    }
+    let mut c = Cursor::new(data);
+    let timestamps: Vec<u64> = FLVTagReverseRead::try_new(&mut c).unwrap().map(|t| t.unwrap().get_timestamp()).collect();
+    assert_eq!(timestamps, vec![80, 40, 0]);
+
+    let mut data = c.into_inner();
+    data[13 + 16] = 12;
+    // walking back from a wrong PreviousTagSize lands in the middle of a tag
+    let mut c = Cursor::new(data.clone());
+    let tags: Vec<Result<FLVTag, FlvError>> = FLVTagReverseRead::try_new(&mut c).unwrap().collect();
+    assert_eq!(tags.len(), 3);
+    assert!(tags[2].is_err());
+    let mut data = c.into_inner();
+    data[13 + 3] = 1; // the tag says it is one byte shorter than it is
+    data[13 + 16] = 13;
+    let mut c = Cursor::new(data.clone());
+    match FLVTagReverseRead::try_new(&mut c).unwrap().nth(2) {
+        Some(Err(FlvError::PrevTagSizeMismatch { expected: 12, actual: 13 })) => {},
+        t => panic!("unexpected {:?}", t),
+    }
+    data[13 + 3] = 2;
+    data[13 + 16] = 12;
+    // the default policy warns and reads it like any other file
+    let mut c = Cursor::new(data.clone());
+    assert_eq!(FLVTagRead::new(&mut c).map(|tag| tag.get_timestamp()).collect::<Vec<u64>>(), vec![0, 40, 80]);
+    let mut c = Cursor::new(data.clone());
+    match FLVTagScan::try_new(&mut c).unwrap().with_prev_tag_size_policy(PrevTagSizePolicy::Error).nth(0) {
+        Some(Err(FlvError::PrevTagSizeMismatch { expected: 13, actual: 12 })) => {},
+        t => panic!("unexpected {:?}", t.map(|t| t.map(|t| t.position))),
+    }
+    assert_eq!(FLVSliceRead::try_new(&data).unwrap().count(), 3);
+    let mut c = Cursor::new(data);
+    let mut parser = FLVTagRead::try_new(&mut c).unwrap().with_prev_tag_size_policy(PrevTagSizePolicy::Warn);
+    assert_eq!(parser.try_iter().count(), 3);
+    let warnings = parser.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    match warnings[0] {
+        (13, FlvError::PrevTagSizeMismatch { expected: 13, actual: 12 }) => {},
+        ref w => panic!("unexpected {:?}", w),
+    }
+}
+
+#[test]
+fn test_slice_read() {
+    let mut data: Vec<u8> = b"FLV\x01\x01\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+    data.extend_from_slice(&[9, 0, 0, 16, 0, 0, 40, 0, 0, 0, 0, 0x17, 0x01, 0, 0, 0,
+                             0, 0, 0, 2, 0x65, 0x88, 0, 0, 0, 1, 0x06, 0, 0, 0, 27]);
+    let tags: Vec<FLVTagRef> = FLVSliceRead::try_new(&data).unwrap().map(|t| t.unwrap()).collect();
+    assert_eq!(tags.len(), 1);
+    assert_eq!(tags[0].get_timestamp(), 40);
+    assert_eq!(tags[0].nal_units().collect::<Vec<&[u8]>>(), vec![&[0x65u8, 0x88][..], &[0x06u8][..]]);
+    assert_eq!(tags[0].to_tag().get_nal_units(), vec![vec![0x65, 0x88], vec![0x06]]);
+
+    let len = data.len();
+    match FLVSliceRead::try_new(&data[..(len - 2)]).unwrap().next() {
+        Some(Err(FlvError::TruncatedTag { expected: 27, actual: 27 })) => {},
+        t => panic!("unexpected {:?}", t),
+    }
+}
+
+#[test]
+fn test_avcc() {
+    // High profile, 2 byte NAL lengths, two SPS, one PPS and the chroma/bit depth trailer
+    let data: Vec<u8> = vec![1, 100, 0, 40, 0xfd, 0xe2, 0, 2, 0x67, 0x64, 0, 3, 0x67, 0x64, 0x01, 1, 0, 2, 0x68, 0xeb,
+                             0xfd, 0xf8, 0xf8, 0];
+    let avcc = AvcC::parse(&data).unwrap();
+    assert_eq!(avcc.get_nal_length_size(), 2);
+    assert_eq!(avcc.sps_array, vec![vec![0x67, 0x64], vec![0x67, 0x64, 0x01]]);
+    assert_eq!(avcc.pps_array, vec![vec![0x68, 0xeb]]);
+    #[allow(deprecated)]
+    {
+        assert_eq!((avcc.num_of_sps(), avcc.sps()), (2, &[0x67, 0x64][..]));
+    }
+    assert_eq!(avcc.ext.as_ref().map(|ext| (ext.chroma_format, ext.bit_depth_luma_minus8, ext.sps_ext_array.len())), Some((1, 0, 0)));
+    let mut out: Vec<u8> = Vec::new();
+    avcc.write(&mut out);
+    assert_eq!(out, data);
+
+    // the same record without the trailer, as most encoders write it
+    let avcc = AvcC::parse(&data[..20]).unwrap();
+    assert_eq!(avcc.ext, None);
+    match AvcC::parse(&data[..11]) {
+        Err(FlvError::UnexpectedEof) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+
+    let mut tag_data: Vec<u8> = vec![9, 0, 0, 13, 0, 0, 0, 0, 0, 0, 0, 0x17, 1, 0, 0, 0, 0, 2, 0x65, 0x88, 0, 1, 0x06, 0];
+    let tag = FLVTagRef::from_bytes(&tag_data).unwrap();
+    assert_eq!(tag.nal_units_with_length_size(2).collect::<Vec<&[u8]>>(), vec![&[0x65u8, 0x88][..], &[0x06u8][..]]);
+    assert!(tag.has_random_access_nal_unit(2));
+    assert_eq!(tag.nal_units().count(), 0);
+
+    tag_data = vec![9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x17, 0, 0, 0, 0];
+    tag_data.extend_from_slice(&data);
+    tag_data[3] = (5 + data.len()) as u8;
+    assert_eq!(FLVTagRef::from_bytes(&tag_data).unwrap().get_nal_length_size().unwrap(), 2);
+
+    // a record of the other codec or a truncated tag is an error, not a panic
+    match FLVTagRef::from_bytes(&tag_data).unwrap().get_hvcc() {
+        Err(FlvError::BadTagHeader(_)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    match FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 0, &[0x1c, 0]).get_hvcc() {
+        Err(FlvError::UnexpectedEof) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    // a NALU packet or a tag of another type is not a sequence header
+    let nalu = FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 0, &[0x17, 1, 0, 0, 0, 0, 2, 0x65, 0x88]);
+    match nalu.try_get_avcc() {
+        Err(FlvError::BadTagHeader(_)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    match FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 0, &[0x1c, 1, 0, 0, 0, 0, 0, 0, 2, 0x26, 0x01]).get_hvcc() {
+        Err(FlvError::BadTagHeader(_)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    let audio = FLVTag::new(FLVTagType::TAG_TYPE_AUDIO, 0, &[0xaf, 0, 0x12, 0x10, 0, 0]);
+    assert!(audio.try_get_avcc().is_err());
+    assert!(audio.get_hvcc().is_err());
+    match nalu.try_get_objects() {
+        Err(FlvError::BadTagHeader(_)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    match audio.try_get_amf0_values() {
+        Err(FlvError::BadTagHeader(_)) => {},
+        r => panic!("unexpected {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_tag_scan() {
+    let mut data: Vec<u8> = b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+    data.extend_from_slice(&[9, 0, 0, 7, 0, 0, 40, 0, 0, 0, 0, 0x17, 0x01, 0, 0, 0, 0xaa, 0xbb, 0, 0, 0, 18]);
+    data.extend_from_slice(&[8, 0, 0, 1, 0, 0, 80, 0, 0, 0, 0, 0xaf, 0, 0, 0, 12]);
+    let mut c = Cursor::new(data);
+    let tags: Vec<FLVTagSummary> = FLVTagScan::try_new(&mut c).unwrap().with_prefix_size(2).map(|t| t.unwrap()).collect();
+    assert_eq!(tags.len(), 2);
+    assert_eq!((tags[0].position, tags[0].timestamp, tags[0].data_size), (13, 40, 7));
+    assert_eq!(tags[0].prefix, vec![0x17, 0x01]);
+    assert_eq!(tags[0].get_frame_type(), Some(1));
+    assert_eq!((tags[1].position, tags[1].get_sound_format()), (35, Some(10)));
+    assert_eq!(tags[1].prefix, vec![0xaf]);
+}
+
+#[test]
+fn test_stream_write() {
+    let mut data: Vec<u8> = b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+    data.extend_from_slice(&[8, 0, 0, 2, 0, 0, 40, 0, 0, 0, 0, 0xaf, 0x01, 0, 0, 0, 13]);
+    let mut c = Cursor::new(data.clone());
+    let mut parser = FLVTagRead::try_new(&mut c).unwrap();
+    let mut tag_write = FLVTagWrite::new(Vec::new()); // Vec<u8> is not Seek
+    tag_write.write_header(&parser.header);
+    for tag in parser.try_iter() {
+        tag_write.write_tag(&tag.unwrap());
+    }
+    assert_eq!(tag_write.get_position(), data.len() as u64);
+    assert_eq!(tag_write.into_inner(), data);
+}
+
+#[test]
+fn test_meta_tag_rewrite() {
+    fn meta_tag(filepositions: &Vec<u64>) -> FLVTag {
+        let mut tag = FLVTag::try_read(&mut &[18, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 12][..]).unwrap().unwrap();
+        let mut keyframes = BTreeMap::new();
+        keyframes.insert("filepositions".to_string(), Json::Array(filepositions.iter().map(|&p| Json::F64(p as f64)).collect()));
+        let mut root = BTreeMap::new();
+        root.insert("keyframes".to_string(), Json::Object(keyframes));
+        tag.set_objects(&vec![Json::String("onMetaData".to_string()), Json::Object(root)]);
+        tag
+    }
+    let header = FLVHeader { hasAudioTags: true, hasVideoTags: false };
+    let audio = FLVTag::try_read(&mut &[8, 0, 0, 2, 0, 0, 40, 0, 0, 0, 0, 0xaf, 0x01, 0, 0, 0, 13][..]).unwrap().unwrap();
+
+    // padding is big enough
+    let mut tag_write = FLVTagWrite::new(Cursor::new(Vec::new())).with_meta_padding(100);
+    tag_write.write_header(&header);
+    tag_write.write_meta_tag(&meta_tag(&vec![])).unwrap();
+    let position = tag_write.get_position();
+    tag_write.write_tag(&audio);
+    tag_write.write_meta_tag(&meta_tag(&vec![position, position])).unwrap();
+    assert_eq!(tag_write.get_position(), position + 17);
+
+    // the metadata shrinks, the room keeps its size and the filler is not read as values
+    let mut tag_write = FLVTagWrite::new(Cursor::new(Vec::new())).with_meta_padding(100);
+    tag_write.write_header(&header);
+    tag_write.write_meta_tag(&meta_tag(&vec![0, 0, 0])).unwrap();
+    let position = tag_write.get_position();
+    tag_write.write_tag(&audio);
+    assert_eq!(tag_write.write_meta_tag_relocating(|_| meta_tag(&vec![])).unwrap(), 0);
+    let mut c = tag_write.into_inner();
+    c.set_position(0);
+    let tags: Vec<FLVTag> = FLVTagRead::try_new(&mut c).unwrap().try_iter().map(|t| t.unwrap()).collect();
+    assert_eq!(tags[0].get_tag_size() as u64 + 4, position - 13);
+    assert_eq!(tags[0].try_get_amf0_values().unwrap().len(), 2);
+    assert_eq!(tags[0].try_get_objects().unwrap().len(), 2);
+    assert_eq!(tags[1].get_timestamp(), 40);
+
+    // a last Number 0 is a value, not filler, padded or not
+    let mut cue = audio.clone();
+    cue.data[0] = FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT.as_u8();
+    let values = vec![Amf0Value::String("onMetaData".to_string()), Amf0Value::Object(::amf0::Amf0Object::new()), Amf0Value::Number(0.0)];
+    cue.set_amf0_values(&values);
+    assert_eq!(cue.try_get_amf0_values().unwrap(), values);
+    let mut tag_write = FLVTagWrite::new(Cursor::new(Vec::new())).with_meta_padding(100);
+    tag_write.write_header(&header);
+    tag_write.write_meta_tag(&cue).unwrap();
+    tag_write.write_tag(&audio);
+    tag_write.write_meta_tag(&cue).unwrap();
+    let mut c = tag_write.into_inner();
+    c.set_position(0);
+    let tags: Vec<FLVTag> = FLVTagRead::try_new(&mut c).unwrap().try_iter().map(|t| t.unwrap()).collect();
+    assert_eq!(tags[0].get_data_size(), cue.get_data_size() + 100);
+    assert_eq!(tags[0].try_get_amf0_values().unwrap(), values);
+    assert_eq!(tags[0].try_get_objects().unwrap().len(), 3);
+
+    // no padding, the audio tag is moved
+    let mut tag_write = FLVTagWrite::new(Cursor::new(Vec::new())).with_meta_padding(0);
+    tag_write.write_header(&header);
+    tag_write.write_meta_tag(&meta_tag(&vec![])).unwrap();
+    let position = tag_write.get_position();
+    tag_write.write_tag(&audio);
+    match tag_write.write_meta_tag(&meta_tag(&vec![position])) {
+        Err(FlvError::MetaTagOverflow { .. }) => {},
+        r => panic!("unexpected {:?}", r),
     }
+    let shift = tag_write.write_meta_tag_relocating(|shift| meta_tag(&vec![position + shift])).unwrap();
+    assert!(shift > 0);
+    let mut c = tag_write.into_inner();
+    c.set_position(0);
+    let tags: Vec<FLVTag> = FLVTagRead::try_new(&mut c).unwrap().try_iter().map(|t| t.unwrap()).collect();
+    assert_eq!(tags.len(), 2);
+    let objects = tags[0].get_objects();
+    assert_eq!(objects[1].find_path(&["keyframes", "filepositions"]), Some(&Json::Array(vec![Json::F64((position + shift) as f64)])));
+    assert_eq!(tags[1].get_timestamp(), 40);
+    assert_eq!(c.get_ref()[(position + shift) as usize], 8);
 }
 
 //按6分钟切割,计算分割点
@@ -0,0 +1,280 @@
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use lib::{FLVTag, FLVTagRef, FLVTagType, FLVScriptData, FlvError};
+use amf0::{Amf0Value, Amf0Object};
+use h264::Sps;
+use legacy_video::LegacyVideoHeader;
+use enhanced::CodecId;
+
+pub const META_EVENT_NAME: &'static str = "onMetaData";
+
+/// `keyframes` index, seconds and file offsets of the video keyframes
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FlvKeyframes {
+    pub times: Vec<f64>,
+    pub filepositions: Vec<u64>,
+}
+
+/// The `onMetaData` object. A known key holding a value of an unexpected type stays in `extra`.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FlvMetadata {
+    pub duration: Option<f64>,
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    pub framerate: Option<f64>,
+    pub videodatarate: Option<f64>,
+    pub videocodecid: Option<CodecId>,
+    pub audiodatarate: Option<f64>,
+    pub audiosamplerate: Option<f64>,
+    pub stereo: Option<bool>,
+    pub audiocodecid: Option<CodecId>,
+    pub keyframes: Option<FlvKeyframes>,
+    /// every other entry, in file order
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub extra: Amf0Object,
+}
+
+const F64_KEYS: [&'static str; 7] = ["duration", "width", "height", "framerate", "videodatarate", "audiodatarate", "audiosamplerate"];
+
+fn as_codec_id(v: &Amf0Value) -> Option<CodecId> {
+    v.as_f64().and_then(CodecId::from_f64)
+}
+
+fn as_number_vec(v: &Amf0Value) -> Option<Vec<f64>> {
+    match *v {
+        Amf0Value::StrictArray(ref a) => a.iter().map(|item| item.as_f64()).collect(),
+        _ => None,
+    }
+}
+
+fn as_keyframes(v: &Amf0Value) -> Option<FlvKeyframes> {
+    let o = v.as_object()?;
+    if o.len() != 2 {
+        return None;
+    }
+    let times = as_number_vec(o.get("times")?)?;
+    let filepositions = as_number_vec(o.get("filepositions")?)?;
+    if filepositions.iter().any(|&p| p < 0.0 || p.fract() != 0.0) {
+        return None;
+    }
+    Some(FlvKeyframes {
+        times: times,
+        filepositions: filepositions.iter().map(|&p| p as u64).collect(),
+    })
+}
+
+fn number_array<T: Copy + Into<f64>>(v: &[T]) -> Amf0Value {
+    Amf0Value::StrictArray(v.iter().map(|&n| Amf0Value::Number(n.into())).collect())
+}
+
+impl FlvMetadata {
+    /// reads the members of an object or ECMA array, `None` for any other value
+    pub fn from_amf0(value: &Amf0Value) -> Option<FlvMetadata> {
+        let object = value.as_object()?;
+        let mut meta = FlvMetadata::default();
+        for &(ref k, ref v) in object.iter() {
+            let known = match k.as_str() {
+                "duration" => v.as_f64().map(|f| meta.duration = Some(f)),
+                "width" => v.as_f64().map(|f| meta.width = Some(f)),
+                "height" => v.as_f64().map(|f| meta.height = Some(f)),
+                "framerate" => v.as_f64().map(|f| meta.framerate = Some(f)),
+                "videodatarate" => v.as_f64().map(|f| meta.videodatarate = Some(f)),
+                "videocodecid" => as_codec_id(v).map(|id| meta.videocodecid = Some(id)),
+                "audiodatarate" => v.as_f64().map(|f| meta.audiodatarate = Some(f)),
+                "audiosamplerate" => v.as_f64().map(|f| meta.audiosamplerate = Some(f)),
+                "stereo" => match *v {
+                    Amf0Value::Boolean(b) => Some(meta.stereo = Some(b)),
+                    _ => None,
+                },
+                "audiocodecid" => as_codec_id(v).map(|id| meta.audiocodecid = Some(id)),
+                "keyframes" => as_keyframes(v).map(|k| meta.keyframes = Some(k)),
+                _ => None,
+            };
+            if known.is_none() {
+                meta.extra.insert(k, v.clone());
+            }
+        }
+        Some(meta)
+    }
+
+    fn known_values(&self) -> Vec<(&'static str, Option<Amf0Value>)> {
+        let numbers = [self.duration, self.width, self.height, self.framerate, self.videodatarate, self.audiodatarate, self.audiosamplerate];
+        let mut v: Vec<(&'static str, Option<Amf0Value>)> = F64_KEYS.iter().zip(numbers.iter())
+            .map(|(&k, n)| (k, n.map(Amf0Value::Number)))
+            .collect();
+        v.push(("videocodecid", self.videocodecid.map(|id| Amf0Value::Number(id.to_f64()))));
+        v.push(("stereo", self.stereo.map(Amf0Value::Boolean)));
+        v.push(("audiocodecid", self.audiocodecid.map(|id| Amf0Value::Number(id.to_f64()))));
+        v.push(("keyframes", self.keyframes.as_ref().map(|k| {
+            let mut o = Amf0Object::new();
+            o.insert("times", number_array(&k.times));
+            o.insert("filepositions", Amf0Value::StrictArray(k.filepositions.iter().map(|&p| Amf0Value::Number(p as f64)).collect()));
+            Amf0Value::Object(o)
+        })));
+        v
+    }
+
+    /// Rewrites `object` to hold this metadata. Entries keep their place and unchanged values their
+    /// bytes, missing fields are removed and new ones appended.
+    pub fn update_amf0_object(&self, object: &mut Amf0Object) {
+        let known = self.known_values();
+        let mut entries: Vec<(String, Amf0Value)> = Vec::with_capacity(object.len());
+        for (k, _) in object.0.drain(..) {
+            let value = match known.iter().find(|&&(key, _)| key == k) {
+                // a typed field that only failed to parse is still in extra
+                Some(&(_, ref value)) => value.clone().or_else(|| self.extra.get(&k).cloned()),
+                None => self.extra.get(&k).cloned(),
+            };
+            if let Some(value) = value {
+                entries.push((k, value));
+            }
+        }
+        object.0 = entries;
+        for (k, v) in known.into_iter() {
+            if let Some(v) = v {
+                if object.get(k).is_none() {
+                    object.insert(k, v);
+                }
+            }
+        }
+        for &(ref k, ref v) in self.extra.iter() {
+            if object.get(k).is_none() {
+                object.insert(k, v.clone());
+            }
+        }
+    }
+
+    /// fills the missing width, height and framerate from the video bitstream
+    pub fn fill_from_sps(&mut self, sps: &Sps) {
+        if self.width.is_none() {
+            self.width = Some(sps.get_width() as f64);
+        }
+        if self.height.is_none() {
+            self.height = Some(sps.get_height() as f64);
+        }
+        if self.framerate.is_none() {
+            self.framerate = sps.get_frame_rate();
+        }
+    }
+
+    /// fills the missing width and height from a Sorenson H.263, Screen Video or VP6 keyframe
+    pub fn fill_from_video_header(&mut self, header: &LegacyVideoHeader) {
+        if self.width.is_none() {
+            self.width = header.get_width().map(|w| w as f64);
+        }
+        if self.height.is_none() {
+            self.height = header.get_height().map(|h| h as f64);
+        }
+    }
+
+    /// a fresh ECMA array, the typed fields first
+    pub fn to_amf0(&self) -> Amf0Value {
+        let mut entries = Amf0Object::new();
+        self.update_amf0_object(&mut entries);
+        Amf0Value::EcmaArray { count: None, entries: entries }
+    }
+}
+
+impl<D: AsRef<[u8]>> FLVTag<D> {
+    /// the metadata of an `onMetaData` script tag, also behind `@setDataFrame`, `None` for other script tags
+    pub fn try_get_metadata(&self) -> Result<Option<FlvMetadata>, FlvError> {
+        let script = match self.try_get_script_data() {
+            Ok(script) => script,
+            Err(FlvError::BadScriptData(..)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if !script.is_metadata() {
+            return Ok(None);
+        }
+        Ok(script.args().first().and_then(FlvMetadata::from_amf0))
+    }
+}
+
+impl FLVTag {
+    /// Writes `meta` into the tag, updating the existing `onMetaData` object in place when there is one.
+    pub fn set_metadata(&mut self, meta: &FlvMetadata) {
+        let mut script = match self.try_get_script_data() {
+            Ok(ref script) if script.is_metadata() && script.args().first().and_then(|v| v.as_object()).is_some() => script.clone(),
+            _ => FLVScriptData::new(META_EVENT_NAME, vec![Amf0Value::EcmaArray { count: None, entries: Amf0Object::new() }]),
+        };
+        meta.update_amf0_object(script.args_mut()[0].as_object_mut().unwrap());
+        self.set_script_data(&script);
+    }
+
+    /// an empty `onMetaData` script tag holding `meta`
+    pub fn new_metadata_tag(meta: &FlvMetadata) -> FLVTag {
+        // bare script tag header, no data yet
+        let mut tag = FLVTagRef::from_bytes(&[FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT.as_u8(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap().to_tag();
+        tag.set_metadata(meta);
+        tag
+    }
+}
+
+#[test]
+fn test_metadata() {
+    let mut o = Amf0Object::new();
+    o.insert("creator", Amf0Value::String("x".to_string()));
+    o.insert("duration", Amf0Value::Number(1.5));
+    o.insert("width", Amf0Value::String("wide".to_string()));
+    o.insert("videocodecid", Amf0Value::Number(7.0));
+    let mut keyframes = Amf0Object::new();
+    keyframes.insert("times", number_array(&[0.0, 1.0]));
+    keyframes.insert("filepositions", number_array(&[13.0, 400.0]));
+    o.insert("keyframes", Amf0Value::Object(keyframes));
+    let value = Amf0Value::EcmaArray { count: Some(0), entries: o.clone() };
+
+    let mut meta = FlvMetadata::from_amf0(&value).unwrap();
+    assert_eq!(meta.duration, Some(1.5));
+    assert_eq!(meta.width, None);
+    assert_eq!(meta.videocodecid, Some(CodecId::Legacy(7)));
+    assert_eq!(meta.keyframes.as_ref().unwrap().filepositions, vec![13, 400]);
+    assert_eq!(meta.extra.iter().map(|&(ref k, _)| k.as_str()).collect::<Vec<&str>>(), vec!["creator", "width"]);
+
+    // unchanged metadata leaves the object as it was
+    let mut same = o.clone();
+    meta.update_amf0_object(&mut same);
+    assert_eq!(same, o);
+
+    meta.duration = Some(2.0);
+    meta.videocodecid = None;
+    meta.stereo = Some(true);
+    let mut updated = o.clone();
+    meta.update_amf0_object(&mut updated);
+    assert_eq!(updated.iter().map(|&(ref k, _)| k.as_str()).collect::<Vec<&str>>(), vec!["creator", "duration", "width", "keyframes", "stereo"]);
+    assert_eq!(updated.get("duration"), Some(&Amf0Value::Number(2.0)));
+
+    let tag = FLVTag::new_metadata_tag(&meta);
+    assert_eq!(tag.try_get_metadata().unwrap(), Some(meta));
+
+    // Enhanced FLV writes the FourCC as a number
+    let mut o = Amf0Object::new();
+    o.insert("videocodecid", Amf0Value::Number(0x68766331 as f64));
+    let meta = FlvMetadata::from_amf0(&Amf0Value::Object(o.clone())).unwrap();
+    assert_eq!(meta.videocodecid, Some(CodecId::FourCC(::enhanced::FourCC::HVC1)));
+    assert_eq!(meta.videocodecid.and_then(|id| id.get_codec_id()), Some(12));
+    assert!(meta.extra.is_empty());
+    let mut same = o.clone();
+    meta.update_amf0_object(&mut same);
+    assert_eq!(same, o);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_metadata_serde() {
+    let mut meta = FlvMetadata::default();
+    meta.duration = Some(2.5);
+    meta.videocodecid = Some(CodecId::Legacy(7));
+    meta.audiocodecid = Some(CodecId::FourCC(::enhanced::FourCC::OPUS));
+    meta.keyframes = Some(FlvKeyframes { times: vec![0.0], filepositions: vec![13] });
+    meta.extra.insert("zz", Amf0Value::String("last".to_string()));
+    meta.extra.insert("aa", Amf0Value::Boolean(true));
+    let json = ::serde_json::to_string(&meta).unwrap();
+    assert!(json.contains(r#""keyframes":{"times":[0.0],"filepositions":[13]}"#));
+    assert!(json.contains(r#""extra":{"zz":"last","aa":true}"#));
+    assert!(json.contains(r#""videocodecid":7,"#));
+    let back: FlvMetadata = ::serde_json::from_str(&json).unwrap();
+    assert_eq!(back, meta);
+}
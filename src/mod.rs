@@ -1,7 +1,32 @@
 extern crate rustc_serialize;
 extern crate byteorder;
 extern crate xml;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 pub mod lib;
 pub mod utils;
-pub mod crc32;
\ No newline at end of file
+pub mod crc32;
+pub mod resync;
+pub mod demux;
+pub mod amf0;
+pub mod amf3;
+pub mod metadata;
+pub mod h264;
+pub mod hevc;
+pub mod enhanced;
+pub mod codec_config;
+pub mod annexb;
+pub mod sei;
+pub mod captions;
+pub mod frame_check;
+pub mod timing;
+pub mod legacy_video;
+#[cfg(feature = "async")]
+pub mod async_io;
\ No newline at end of file
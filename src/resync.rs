@@ -0,0 +1,222 @@
+use std::io::Read;
+use std::cmp;
+
+use lib::{FLVHeader, FLVTag, FLVTagRef, FLVTagType, FlvError, TAG_HEADER_BYTE_COUNT, PREV_TAG_BYTE_COUNT, MIN_FILE_HEADER_BYTE_COUNT};
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum ResyncEvent {
+    /// a good tag and its offset in the file
+    Tag(u64, FLVTag),
+    /// bytes in `[start, end)` were dropped, `error` is why the tag at `start` was rejected
+    Skipped { start: u64, end: u64, error: FlvError },
+}
+
+/// Tag reader that survives corrupted or truncated regions.
+///
+/// The tag at the current offset is read as long as it is whole and its PreviousTagSize matches,
+/// whatever its type and size. When it is bad, the reader scans forward byte by byte
+/// for the next plausible tag header: a known tag type with zero reserved bits,
+/// stream id 0, a data size not above `max_data_size`, a matching PreviousTagSize
+/// and a timestamp no more than `timestamp_tolerance` ms before the last good tag
+/// and no more than `max_timestamp_jump` ms after it.
+/// The dropped range is reported as `ResyncEvent::Skipped` and reading resumes there.
+pub struct FLVTagResyncRead<'a, R: Read + 'a> {
+    source: &'a mut R,
+    pub header: FLVHeader,
+    pub max_data_size: u32,
+    pub timestamp_tolerance: u64,
+    pub max_timestamp_jump: u64,
+    buffer: Vec<u8>,
+    start: usize, // buffer[start] is at file offset `position`
+    position: u64,
+    eof: bool,
+    last_timestamp: Option<u64>,
+}
+
+impl<'a, R: Read> FLVTagResyncRead<'a, R> {
+    pub fn try_new(r: &'a mut R) -> Result<FLVTagResyncRead<'a, R>, FlvError> {
+        let header = FLVHeader::try_read(r)?;
+        Ok(FLVTagResyncRead::<'a, R> {
+            source: r,
+            header: header,
+            max_data_size: 4 * 1024 * 1024,
+            timestamp_tolerance: 1000,
+            max_timestamp_jump: 5 * 60 * 1000,
+            buffer: Vec::with_capacity(READ_CHUNK_SIZE),
+            start: 0,
+            position: MIN_FILE_HEADER_BYTE_COUNT as u64 + 4,
+            eof: false,
+            last_timestamp: None,
+        })
+    }
+
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    fn available(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    /// make sure `len` bytes are buffered, returns false if the stream ends first
+    fn fill(&mut self, len: usize) -> Result<bool, FlvError> {
+        while self.available() < len && !self.eof {
+            if self.start > 0 && self.start >= self.buffer.len() / 2 {
+                self.buffer.drain(..self.start);
+                self.start = 0;
+            }
+            let want = cmp::max(len - self.available(), READ_CHUNK_SIZE);
+            let read_len = (&mut *self.source).take(want as u64).read_to_end(&mut self.buffer)?;
+            if read_len == 0 {
+                self.eof = true;
+            }
+        }
+        Ok(self.available() >= len)
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.start += len;
+        self.position += len as u64;
+    }
+
+    /// Checks the tag at the current offset, returns its tag size and how many bytes it takes
+    /// with the PreviousTagSize. The tag at the current offset only has to be whole and followed by
+    /// its PreviousTagSize or the end of the stream, while `scanning` forward the heuristics apply too.
+    fn check_tag(&mut self, scanning: bool) -> Result<(usize, usize), FlvError> {
+        if !self.fill(TAG_HEADER_BYTE_COUNT as usize)? {
+            return Err(FlvError::UnexpectedEof);
+        }
+        let (tag_type, data_size, timestamp) = {
+            let h = &self.buffer[self.start..];
+            if scanning && (h[0] >> 6) != 0 {
+                return Err(FlvError::BadTagHeader("reserved bits are set"));
+            }
+            let tag_type = FLVTagType::from(h[0]);
+            if scanning && (h[8] != 0 || h[9] != 0 || h[10] != 0) {
+                return Err(FlvError::BadTagHeader("stream id is not 0"));
+            }
+            let data_size = (h[1] as u32) << 16 | (h[2] as u32) << 8 | h[3] as u32;
+            let timestamp = (h[7] as u64) << 24 | (h[4] as u64) << 16 | (h[5] as u64) << 8 | h[6] as u64;
+            (tag_type, data_size, timestamp)
+        };
+        if scanning {
+            if let FLVTagType::Unknown(t) = tag_type {
+                return Err(FlvError::UnknownTagType(t));
+            }
+            if data_size > self.max_data_size || (tag_type != FLVTagType::TAG_TYPE_SCRIPTDATAOBJECT && data_size == 0) {
+                return Err(FlvError::BadTagHeader("data size out of range"));
+            }
+            if let Some(last) = self.last_timestamp {
+                if timestamp + self.timestamp_tolerance < last || timestamp > last + self.max_timestamp_jump {
+                    return Err(FlvError::BadTagHeader("timestamp out of range"));
+                }
+            }
+        }
+        let tag_size = TAG_HEADER_BYTE_COUNT + data_size;
+        let total = (tag_size + PREV_TAG_BYTE_COUNT) as usize;
+        if !self.fill(total)? {
+            let available = self.available();
+            if !scanning && available >= tag_size as usize {
+                // the last tag of a file cut right after it
+                return Ok((tag_size as usize, available));
+            }
+            let actual = cmp::min(available, tag_size as usize) as u32;
+            return Err(FlvError::TruncatedTag { expected: tag_size, actual: actual });
+        }
+        let p = self.start + tag_size as usize;
+        let prev_tag_size = (self.buffer[p] as u32) << 24 | (self.buffer[p + 1] as u32) << 16 | (self.buffer[p + 2] as u32) << 8 | self.buffer[p + 3] as u32;
+        if prev_tag_size != tag_size {
+            return Err(FlvError::PrevTagSizeMismatch { expected: tag_size, actual: prev_tag_size });
+        }
+        Ok((tag_size as usize, total))
+    }
+
+    pub fn next_event(&mut self) -> Result<Option<ResyncEvent>, FlvError> {
+        if !self.fill(1)? {
+            return Ok(None);
+        }
+        let error = match self.check_tag(false) {
+            Ok((tag_size, len)) => {
+                let position = self.position;
+                let tag = FLVTagRef::from_bytes(&self.buffer[self.start..(self.start + tag_size)])?.to_tag();
+                self.consume(len);
+                self.last_timestamp = Some(tag.get_timestamp());
+                return Ok(Some(ResyncEvent::Tag(position, tag)));
+            }
+            Err(FlvError::Io(e)) => return Err(FlvError::Io(e)),
+            Err(e) => e,
+        };
+        // resync, the bytes are dropped as the scan goes so a long bad region is not kept in the buffer
+        let start = self.position;
+        self.consume(1);
+        loop {
+            if !self.fill(1)? {
+                break;
+            }
+            match self.check_tag(true) {
+                Ok(_) => break,
+                Err(FlvError::Io(e)) => return Err(FlvError::Io(e)),
+                Err(_) => self.consume(1),
+            }
+        }
+        Ok(Some(ResyncEvent::Skipped { start: start, end: self.position, error: error }))
+    }
+}
+
+impl<'a, R: Read + 'a> Iterator for FLVTagResyncRead<'a, R> {
+    type Item = Result<ResyncEvent, FlvError>;
+
+    fn next(&mut self) -> Option<Result<ResyncEvent, FlvError>> {
+        match self.next_event() {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[test]
+fn test_resync() {
+    use std::io::Write;
+
+    fn tag(timestamp: u8) -> Vec<u8> {
+        vec![8, 0, 0, 2, 0, 0, timestamp, 0, 0, 0, 0, 0xaf, 0x01, 0, 0, 0, 13]
+    }
+
+    let mut data: Vec<u8> = Vec::new();
+    data.write(b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00").unwrap();
+    data.write(&tag(0)).unwrap();
+    data.write(&[0xde, 0xad, 0xbe, 0xef, 8, 0, 0]).unwrap(); // garbage
+    data.write(&tag(40)).unwrap();
+    data.write(&tag(80)[..10]).unwrap(); // truncated
+
+    let mut source = &data[..];
+    let events: Vec<ResyncEvent> = FLVTagResyncRead::try_new(&mut source).unwrap().map(|e| e.unwrap()).collect();
+    assert_eq!(events.len(), 4);
+    match events[0] { ResyncEvent::Tag(13, ref t) => assert_eq!(t.get_timestamp(), 0), ref e => panic!("unexpected {:?}", e) }
+    match events[1] { ResyncEvent::Skipped { start: 30, end: 37, .. } => {}, ref e => panic!("unexpected {:?}", e) }
+    match events[2] { ResyncEvent::Tag(37, ref t) => assert_eq!(t.get_timestamp(), 40), ref e => panic!("unexpected {:?}", e) }
+    match events[3] { ResyncEvent::Skipped { start: 54, end: 64, error: FlvError::UnexpectedEof } => {}, ref e => panic!("unexpected {:?}", e) }
+
+    // a tag over max_data_size, an unknown tag type and an empty audio tag in place are not corruption,
+    // neither is a last tag without its PreviousTagSize
+    let big_size: u32 = 4 * 1024 * 1024 + 10;
+    let mut data: Vec<u8> = Vec::new();
+    data.write(b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00").unwrap();
+    data.write(&[9, (big_size >> 16) as u8, (big_size >> 8) as u8, big_size as u8, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    data.resize(13 + 11 + big_size as usize, 0x17);
+    data.write(&[0, 0x40, 0, 21]).unwrap();
+    data.write(&[7, 0, 0, 1, 0, 0, 40, 0, 0, 0, 0, 0xaa, 0, 0, 0, 12]).unwrap();
+    data.write(&[8, 0, 0, 0, 0, 0, 40, 0, 0, 0, 0, 0, 0, 0, 11]).unwrap();
+    data.write(&tag(80)[..13]).unwrap();
+    let mut source = &data[..];
+    let events: Vec<ResyncEvent> = FLVTagResyncRead::try_new(&mut source).unwrap().map(|e| e.unwrap()).collect();
+    let tags: Vec<(FLVTagType, u32)> = events.iter().map(|e| match *e {
+        ResyncEvent::Tag(_, ref t) => (t.get_tag_type(), t.get_data_size()),
+        ref e => panic!("unexpected {:?}", e),
+    }).collect();
+    assert_eq!(tags, vec![(FLVTagType::TAG_TYPE_VIDEO, big_size), (FLVTagType::Unknown(7), 1),
+                          (FLVTagType::TAG_TYPE_AUDIO, 0), (FLVTagType::TAG_TYPE_AUDIO, 2)]);
+}
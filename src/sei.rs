@@ -0,0 +1,326 @@
+use h264::{self, BitReader, Sps};
+use hevc;
+use lib::{FLVTag, FlvError, CODEC_ID_HEVC};
+
+pub const SEI_PAYLOAD_TYPE_BUFFERING_PERIOD: u32 = 0;
+pub const SEI_PAYLOAD_TYPE_PIC_TIMING: u32 = 1;
+pub const SEI_PAYLOAD_TYPE_USER_DATA_REGISTERED_ITU_T_T35: u32 = 4;
+pub const SEI_PAYLOAD_TYPE_USER_DATA_UNREGISTERED: u32 = 5;
+pub const SEI_PAYLOAD_TYPE_RECOVERY_POINT: u32 = 6;
+
+/// ITU-T T.35 country code of the United States, ATSC A/53 captions use it
+pub const T35_COUNTRY_CODE_US: u8 = 0xb5;
+pub const T35_PROVIDER_CODE_ATSC: u16 = 0x0031;
+
+/// cc_type of the A/53 cc_data triplets
+pub const CC_TYPE_NTSC_FIELD_1: u8 = 0;
+pub const CC_TYPE_NTSC_FIELD_2: u8 = 1;
+pub const CC_TYPE_DTVCC_PACKET_DATA: u8 = 2;
+pub const CC_TYPE_DTVCC_PACKET_START: u8 = 3;
+
+pub fn get_payload_type_name(payload_type: u32) -> &'static str {
+    match payload_type {
+        0 => "buffering_period",
+        1 => "pic_timing",
+        4 => "user_data_registered_itu_t_t35",
+        5 => "user_data_unregistered",
+        6 => "recovery_point",
+        129 => "active_parameter_sets",
+        137 => "mastering_display_colour_volume",
+        144 => "content_light_level_info",
+        _ => "unknown",
+    }
+}
+
+/// an SEI NAL unit of the codec, AVC type 6 or HEVC prefix/suffix SEI
+pub fn is_sei_nal_unit(header: u8, codec_id: u8) -> bool {
+    if codec_id == CODEC_ID_HEVC {
+        let nalu_type = hevc::get_nal_unit_type(header);
+        return nalu_type == hevc::NAL_UNIT_TYPE_PREFIX_SEI || nalu_type == hevc::NAL_UNIT_TYPE_SUFFIX_SEI;
+    }
+    header & 0x1f == h264::NAL_UNIT_TYPE_SEI
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeiMessage {
+    pub codec_id: u8,
+    pub payload_type: u32,
+    /// without the emulation prevention bytes
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CcData {
+    pub cc_valid: bool,
+    pub cc_type: u8,
+    pub data: [u8; 2],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveryPoint {
+    /// recovery_frame_cnt for AVC, recovery_poc_cnt for HEVC
+    pub recovery_cnt: i32,
+    pub exact_match_flag: bool,
+    pub broken_link_flag: bool,
+    /// AVC only
+    pub changing_slice_group_idc: u8,
+}
+
+/// clock_timestamp of an AVC pic_timing, the time fields not sent are 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockTimestamp {
+    pub ct_type: u8,
+    pub nuit_field_based_flag: bool,
+    pub counting_type: u8,
+    pub full_timestamp_flag: bool,
+    pub discontinuity_flag: bool,
+    pub cnt_dropped_flag: bool,
+    pub n_frames: u8,
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub time_offset: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PicTiming {
+    /// present when the SPS has HRD parameters
+    pub cpb_removal_delay: Option<u32>,
+    pub dpb_output_delay: Option<u32>,
+    /// present when the SPS says pic_struct_present_flag
+    pub pic_struct: Option<u8>,
+    pub clock_timestamps: Vec<Option<ClockTimestamp>>,
+}
+
+fn read_ff_coded(data: &mut &[u8]) -> Result<u32, FlvError> {
+    let mut value: u32 = 0;
+    loop {
+        let b = match data.first() {
+            Some(&b) => b,
+            None => return Err(FlvError::UnexpectedEof),
+        };
+        *data = &data[1..];
+        value += b as u32;
+        if b != 0xff {
+            return Ok(value);
+        }
+    }
+}
+
+impl SeiMessage {
+    /// SEI messages of an SEI NAL unit, none for the other NAL units
+    pub fn parse_nal_unit(nalu: &[u8], codec_id: u8) -> Result<Vec<SeiMessage>, FlvError> {
+        let header_size = if codec_id == CODEC_ID_HEVC { 2 } else { 1 };
+        if nalu.len() < header_size {
+            return Err(FlvError::UnexpectedEof);
+        }
+        if !is_sei_nal_unit(nalu[0], codec_id) {
+            return Ok(Vec::new());
+        }
+        let mut rbsp = h264::remove_emulation_prevention(&nalu[header_size..]);
+        while rbsp.last() == Some(&0) {
+            rbsp.pop();
+        }
+        let mut messages: Vec<SeiMessage> = Vec::new();
+        let mut data = &rbsp[..];
+        // up to rbsp_trailing_bits
+        while !data.is_empty() && data != [0x80] {
+            let payload_type = read_ff_coded(&mut data)?;
+            let payload_size = read_ff_coded(&mut data)? as usize;
+            if data.len() < payload_size {
+                return Err(FlvError::BadBitstream("sei payload past the end of the nal unit"));
+            }
+            messages.push(SeiMessage {
+                codec_id: codec_id,
+                payload_type: payload_type,
+                payload: data[..payload_size].to_vec(),
+            });
+            data = &data[payload_size..];
+        }
+        Ok(messages)
+    }
+
+    /// uuid_iso_iec_11578 and the data after it
+    pub fn get_user_data_unregistered(&self) -> Option<([u8; 16], &[u8])> {
+        if self.payload_type != SEI_PAYLOAD_TYPE_USER_DATA_UNREGISTERED || self.payload.len() < 16 {
+            return None;
+        }
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&self.payload[..16]);
+        Some((uuid, &self.payload[16..]))
+    }
+
+    /// itu_t_t35_country_code and the data after it, after the extension byte for country code 0xff
+    pub fn get_itu_t_t35(&self) -> Option<(u8, &[u8])> {
+        if self.payload_type != SEI_PAYLOAD_TYPE_USER_DATA_REGISTERED_ITU_T_T35 || self.payload.is_empty() {
+            return None;
+        }
+        match self.payload[0] {
+            0xff if self.payload.len() < 2 => None,
+            0xff => Some((0xff, &self.payload[2..])),
+            country_code => Some((country_code, &self.payload[1..])),
+        }
+    }
+
+    /// the ATSC A/53 cc_data of a "GA94" T.35 message
+    pub fn get_cc_data(&self) -> Option<Vec<CcData>> {
+        let data = match self.get_itu_t_t35() {
+            Some((T35_COUNTRY_CODE_US, data)) => data,
+            _ => return None,
+        };
+        // provider code, user identifier, user_data_type_code 3, flags and cc_count, em_data
+        if data.len() < 9 || ((data[0] as u16) << 8 | data[1] as u16) != T35_PROVIDER_CODE_ATSC || &data[2..6] != b"GA94" || data[6] != 0x03 {
+            return None;
+        }
+        if data[7] & 0x40 == 0 { // process_cc_data_flag
+            return Some(Vec::new());
+        }
+        let cc_count = (data[7] & 0x1f) as usize;
+        let triplets = &data[9..];
+        Some(triplets.chunks(3).take(cc_count).filter(|t| t.len() == 3).map(|t| CcData {
+            cc_valid: t[0] & 0x04 != 0,
+            cc_type: t[0] & 0x03,
+            data: [t[1], t[2]],
+        }).collect())
+    }
+
+    pub fn get_recovery_point(&self) -> Result<Option<RecoveryPoint>, FlvError> {
+        if self.payload_type != SEI_PAYLOAD_TYPE_RECOVERY_POINT {
+            return Ok(None);
+        }
+        let mut r = BitReader::new(&self.payload);
+        if self.codec_id == CODEC_ID_HEVC {
+            return Ok(Some(RecoveryPoint {
+                recovery_cnt: r.read_se()?,
+                exact_match_flag: r.read_bit()?,
+                broken_link_flag: r.read_bit()?,
+                changing_slice_group_idc: 0,
+            }));
+        }
+        Ok(Some(RecoveryPoint {
+            recovery_cnt: r.read_ue()? as i32,
+            exact_match_flag: r.read_bit()?,
+            broken_link_flag: r.read_bit()?,
+            changing_slice_group_idc: r.read_bits(2)? as u8,
+        }))
+    }
+
+    /// AVC pic_timing, its syntax depends on the HRD and VUI of the active SPS
+    pub fn get_pic_timing(&self, sps: &Sps) -> Result<Option<PicTiming>, FlvError> {
+        if self.payload_type != SEI_PAYLOAD_TYPE_PIC_TIMING || self.codec_id == CODEC_ID_HEVC {
+            return Ok(None);
+        }
+        let (hrd, pic_struct_present_flag) = match sps.vui {
+            Some(ref vui) => (vui.nal_hrd.as_ref().or(vui.vcl_hrd.as_ref()), vui.pic_struct_present_flag),
+            None => (None, false),
+        };
+        let mut r = BitReader::new(&self.payload);
+        let mut timing = PicTiming {
+            cpb_removal_delay: None,
+            dpb_output_delay: None,
+            pic_struct: None,
+            clock_timestamps: Vec::new(),
+        };
+        if let Some(hrd) = hrd {
+            timing.cpb_removal_delay = Some(r.read_bits(hrd.cpb_removal_delay_length as u32)?);
+            timing.dpb_output_delay = Some(r.read_bits(hrd.dpb_output_delay_length as u32)?);
+        }
+        if !pic_struct_present_flag {
+            return Ok(Some(timing));
+        }
+        let pic_struct = r.read_bits(4)? as u8;
+        let num_clock_ts = match pic_struct {
+            0..=2 => 1,
+            3 | 4 | 7 => 2,
+            5 | 6 | 8 => 3,
+            _ => return Err(FlvError::BadBitstream("reserved pic_struct")),
+        };
+        // inferred as 24 without HRD parameters
+        let time_offset_length = hrd.map_or(24, |hrd| hrd.time_offset_length as u32);
+        timing.pic_struct = Some(pic_struct);
+        for _ in 0..num_clock_ts {
+            if !r.read_bit()? {
+                timing.clock_timestamps.push(None);
+                continue;
+            }
+            let mut ts = ClockTimestamp {
+                ct_type: r.read_bits(2)? as u8,
+                nuit_field_based_flag: r.read_bit()?,
+                counting_type: r.read_bits(5)? as u8,
+                full_timestamp_flag: r.read_bit()?,
+                discontinuity_flag: r.read_bit()?,
+                cnt_dropped_flag: r.read_bit()?,
+                n_frames: r.read_bits(8)? as u8,
+                seconds: 0,
+                minutes: 0,
+                hours: 0,
+                time_offset: 0,
+            };
+            if ts.full_timestamp_flag {
+                ts.seconds = r.read_bits(6)? as u8;
+                ts.minutes = r.read_bits(6)? as u8;
+                ts.hours = r.read_bits(5)? as u8;
+            } else if r.read_bit()? {
+                ts.seconds = r.read_bits(6)? as u8;
+                if r.read_bit()? {
+                    ts.minutes = r.read_bits(6)? as u8;
+                    if r.read_bit()? {
+                        ts.hours = r.read_bits(5)? as u8;
+                    }
+                }
+            }
+            if time_offset_length > 0 {
+                let value = r.read_bits(time_offset_length)? as i64;
+                ts.time_offset = (value - ((value >> (time_offset_length - 1)) << time_offset_length)) as i32;
+            }
+            timing.clock_timestamps.push(Some(ts));
+        }
+        Ok(Some(timing))
+    }
+}
+
+impl<D: AsRef<[u8]>> FLVTag<D> {
+    /// SEI messages of an AVC/HEVC NALU packet, `length_size` as in `nal_units_with_length_size`
+    pub fn get_sei_messages(&self, length_size: usize) -> Result<Vec<SeiMessage>, FlvError> {
+        let codec_id = self.get_codec_id();
+        let mut messages: Vec<SeiMessage> = Vec::new();
+        for nalu in self.nal_units_with_length_size(length_size) {
+            if !nalu.is_empty() && is_sei_nal_unit(nalu[0], codec_id) {
+                messages.extend(SeiMessage::parse_nal_unit(nalu, codec_id)?);
+            }
+        }
+        Ok(messages)
+    }
+}
+
+#[test]
+fn test_sei() {
+    use lib::CODEC_ID_AVC;
+
+    // user data unregistered with an emulation prevention byte, then a recovery point
+    let mut nalu: Vec<u8> = vec![0x06, 0x05, 0x14];
+    nalu.extend_from_slice(&[0xdc, 0x45, 0xe9, 0xbd, 0xe6, 0xd9, 0x48, 0xb7, 0x96, 0x2c, 0xd8, 0x20, 0xd9, 0x23, 0xee, 0xef]);
+    nalu.extend_from_slice(&[b'x', 0, 0, 3, 1]);
+    nalu.extend_from_slice(&[0x06, 0x01, 0b1010_0000, 0x80]);
+    let messages = SeiMessage::parse_nal_unit(&nalu, CODEC_ID_AVC).unwrap();
+    assert_eq!(messages.len(), 2);
+    let (uuid, data) = messages[0].get_user_data_unregistered().unwrap();
+    assert_eq!(uuid[0], 0xdc);
+    assert_eq!(data, &[b'x', 0, 0, 1][..]);
+    let rp = messages[1].get_recovery_point().unwrap().unwrap();
+    assert_eq!((rp.recovery_cnt, rp.exact_match_flag, rp.broken_link_flag), (0, false, true));
+
+    // A/53 captions, one field 1 pair and one DTVCC start
+    let nalu: Vec<u8> = vec![0x06, 0x04, 0x11, 0xb5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03, 0x42, 0xff,
+                             0xfc, 0x94, 0x2c, 0xff, 0x02, 0x21, 0xff, 0x80];
+    let messages = SeiMessage::parse_nal_unit(&nalu, CODEC_ID_AVC).unwrap();
+    assert_eq!(messages[0].get_cc_data().unwrap(), vec![
+        CcData { cc_valid: true, cc_type: CC_TYPE_NTSC_FIELD_1, data: [0x94, 0x2c] },
+        CcData { cc_valid: true, cc_type: CC_TYPE_DTVCC_PACKET_START, data: [0x02, 0x21] },
+    ]);
+
+    match SeiMessage::parse_nal_unit(&[0x06, 0x05, 0x20, 0x00], CODEC_ID_AVC) {
+        Err(FlvError::BadBitstream(..)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
@@ -0,0 +1,292 @@
+use std::fmt;
+
+use lib::{FLVTag, FLVTagType, FlvError, CODEC_ID_HEVC};
+use frame_check::FrameChecker;
+use hevc;
+
+/// frames looked back at for pts collisions and the reorder depth, more than any DPB holds
+const REORDER_WINDOW: usize = 64;
+
+/// a video frame in decoding order, times in milliseconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameTiming {
+    pub dts: i64,
+    pub cts: i32,
+    pub pts: i64,
+    /// IDR/IRAP, or flagged as a keyframe with an I slice or a recovery point SEI,
+    /// the FLV frame type when the slice cannot be parsed
+    pub keyframe: bool,
+    /// I, P or B, from the first slice or guessed from the reordering
+    pub frame_type: char,
+    /// frames before this one in decoding order and after it in presentation order
+    pub reorder_depth: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimingIssue {
+    NegativeCts { frame: usize, cts: i32 },
+    /// cts over the limit of the analyzer
+    ImplausibleCts { frame: usize, cts: i32 },
+    /// two frames shown at the same time
+    PtsCollision { frame: usize, other: usize, pts: i64 },
+    /// dts not after the one of the frame before
+    NonMonotonicDts { frame: usize, dts: i64, previous: i64 },
+    /// more reordering than the SPS allows
+    ReorderDepthExceeded { frame: usize, depth: u32, max: u32 },
+}
+
+impl fmt::Display for TimingIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimingIssue::NegativeCts { frame, cts } => write!(f, "frame {}: negative cts {}", frame, cts),
+            TimingIssue::ImplausibleCts { frame, cts } => write!(f, "frame {}: implausible cts {}", frame, cts),
+            TimingIssue::PtsCollision { frame, other, pts } => write!(f, "frame {}: pts {} of frame {} too", frame, pts, other),
+            TimingIssue::NonMonotonicDts { frame, dts, previous } => write!(f, "frame {}: dts {} after {}", frame, dts, previous),
+            TimingIssue::ReorderDepthExceeded { frame, depth, max } => write!(f, "frame {}: reorder depth {} over max_num_reorder_frames {}", frame, depth, max),
+        }
+    }
+}
+
+/// frames from a keyframe up to the next one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gop {
+    pub first_frame: usize,
+    pub frame_count: usize,
+    pub dts: i64,
+    /// to the dts of the next GOP, the last one gets the frame duration before its end
+    pub duration: i64,
+    /// frame types in presentation order, like IBBPBBP
+    pub pattern: String,
+    /// some frame is shown before the keyframe, it refers to the GOP before
+    pub open: bool,
+    pub max_reorder_depth: u32,
+}
+
+pub struct TimingReport {
+    pub frames: Vec<FrameTiming>,
+    pub issues: Vec<TimingIssue>,
+    pub gops: Vec<Gop>,
+    pub max_reorder_depth: u32,
+    /// of the last sequence header, when its SPS tells
+    pub max_num_reorder_frames: Option<u32>,
+}
+
+impl TimingReport {
+    /// frame indexes in presentation order, frames with the same pts in decoding order
+    pub fn get_presentation_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.frames.len()).collect();
+        order.sort_by_key(|&i| self.frames[i].pts);
+        order
+    }
+}
+
+/// Reasons about the dts and cts of AVC/HEVC NALU packets, see `TimingReport`.
+pub struct TimingAnalyzer {
+    checker: FrameChecker,
+    implausible_cts: i32,
+    max_num_reorder_frames: Option<u32>,
+    frames: Vec<FrameTiming>,
+    issues: Vec<TimingIssue>,
+    gops: Vec<Gop>,
+    gop_start: Option<usize>,
+}
+
+impl TimingAnalyzer {
+    pub fn new() -> TimingAnalyzer {
+        TimingAnalyzer {
+            checker: FrameChecker::new(),
+            implausible_cts: 1000,
+            max_num_reorder_frames: None,
+            frames: Vec::new(),
+            issues: Vec::new(),
+            gops: Vec::new(),
+            gop_start: None,
+        }
+    }
+
+    /// cts over `ms` is reported, 1 second by default
+    pub fn with_implausible_cts(mut self, ms: i32) -> TimingAnalyzer {
+        self.implausible_cts = ms;
+        self
+    }
+
+    pub fn push_tag<D: AsRef<[u8]>>(&mut self, tag: &FLVTag<D>) -> Result<(), FlvError> {
+        if tag.get_tag_type() != FLVTagType::TAG_TYPE_VIDEO || tag.get_data_size() == 0 || !tag.is_avc_or_hevc() {
+            return Ok(());
+        }
+        if tag.is_video_sequence_header() {
+            self.checker.push_tag(tag)?;
+            self.max_num_reorder_frames = if tag.get_codec_id() == CODEC_ID_HEVC {
+                let hvcc = tag.get_hvcc()?;
+                match hvcc.get_sps().first() {
+                    Some(nalu) => Some(hevc::Sps::parse(nalu)?.sps_max_num_reorder_pics),
+                    None => None,
+                }
+            } else {
+                tag.try_get_avcc()?.parse_sps()?.get_max_num_reorder_frames()
+            };
+            return Ok(());
+        }
+        if tag.get_avc_packet_type() != 1 {
+            return Ok(());
+        }
+        // a broken slice only loses the slice type
+        let slice = self.checker.push_tag(tag).unwrap_or(None);
+
+        let index = self.frames.len();
+        let dts = tag.get_timestamp() as i64;
+        let cts = tag.get_avc_composition_time_offset();
+        let pts = dts + cts as i64;
+        if cts < 0 {
+            self.issues.push(TimingIssue::NegativeCts { frame: index, cts });
+        } else if cts > self.implausible_cts {
+            self.issues.push(TimingIssue::ImplausibleCts { frame: index, cts });
+        }
+        if let Some(previous) = self.frames.last().map(|f| f.dts) {
+            if dts <= previous {
+                self.issues.push(TimingIssue::NonMonotonicDts { frame: index, dts, previous });
+            }
+        }
+        let window = &self.frames[index.saturating_sub(REORDER_WINDOW)..];
+        if let Some(other) = window.iter().rposition(|f| f.pts == pts) {
+            self.issues.push(TimingIssue::PtsCollision { frame: index, other: index - window.len() + other, pts });
+        }
+        let reorder_depth = window.iter().filter(|f| f.pts > pts).count() as u32;
+        if let Some(max) = self.max_num_reorder_frames {
+            if reorder_depth > max {
+                self.issues.push(TimingIssue::ReorderDepthExceeded { frame: index, depth: reorder_depth, max });
+            }
+        }
+
+        // open GOPs of AVC start with a non-IDR I slice or a recovery point, trust the flag for those
+        let flagged = tag.get_frame_type() == 1;
+        let keyframe = slice.as_ref().map_or(flagged, |s| s.random_access || flagged && (s.slice_type == "I" || s.recovery_point));
+        let frame_type = match slice {
+            Some(ref s) if s.slice_type.starts_with("S") => if s.slice_type == "SI" { 'I' } else { 'P' },
+            Some(ref s) => s.slice_type.chars().next().unwrap(),
+            None if keyframe => 'I',
+            None if reorder_depth > 0 => 'B',
+            None => 'P',
+        };
+        if keyframe || self.gop_start.is_none() {
+            self.close_gop(dts);
+            self.gop_start = Some(index);
+        }
+        self.frames.push(FrameTiming {
+            dts,
+            cts,
+            pts,
+            keyframe,
+            frame_type,
+            reorder_depth,
+        });
+        Ok(())
+    }
+
+    fn close_gop(&mut self, next_dts: i64) {
+        let start = match self.gop_start {
+            Some(start) => start,
+            None => return,
+        };
+        let frames = &self.frames[start..];
+        let mut order: Vec<&FrameTiming> = frames.iter().collect();
+        order.sort_by_key(|f| f.pts);
+        self.gops.push(Gop {
+            first_frame: start,
+            frame_count: frames.len(),
+            dts: frames[0].dts,
+            duration: next_dts - frames[0].dts,
+            pattern: order.iter().map(|f| f.frame_type).collect(),
+            open: frames.iter().any(|f| f.pts < frames[0].pts),
+            max_reorder_depth: frames.iter().map(|f| f.reorder_depth).max().unwrap_or(0),
+        });
+    }
+
+    pub fn finish(mut self) -> TimingReport {
+        let end = match (self.frames.len(), self.frames.last()) {
+            (n, Some(last)) if n > 1 => last.dts + (last.dts - self.frames[n - 2].dts),
+            (_, Some(last)) => last.dts,
+            _ => 0,
+        };
+        self.close_gop(end);
+        TimingReport {
+            max_reorder_depth: self.frames.iter().map(|f| f.reorder_depth).max().unwrap_or(0),
+            frames: self.frames,
+            issues: self.issues,
+            gops: self.gops,
+            max_num_reorder_frames: self.max_num_reorder_frames,
+        }
+    }
+}
+
+impl Default for TimingAnalyzer {
+    fn default() -> TimingAnalyzer {
+        TimingAnalyzer::new()
+    }
+}
+
+#[test]
+fn test_timing() {
+    fn frame(frame_type: u8, dts: u64, cts: i32) -> FLVTag {
+        let c = cts as u32;
+        FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, dts, &[frame_type << 4 | 7, 1, (c >> 16) as u8, (c >> 8) as u8, c as u8])
+    }
+    let mut analyzer = TimingAnalyzer::new();
+    // closed I P B B, then an open GOP with a B before its I, a pts collision and a negative cts
+    let tags = vec![frame(1, 0, 40), frame(2, 40, 120), frame(2, 80, 0), frame(2, 120, 0),
+                    frame(1, 160, 80), frame(2, 200, 0), frame(2, 240, 40), frame(2, 280, -40)];
+    for tag in tags.iter() {
+        analyzer.push_tag(tag).unwrap();
+    }
+    let report = analyzer.finish();
+    assert_eq!(report.max_reorder_depth, 1);
+    assert_eq!(report.get_presentation_order(), vec![0, 2, 3, 1, 5, 4, 7, 6]);
+    assert_eq!(report.gops.len(), 2);
+    assert_eq!((report.gops[0].pattern.as_str(), report.gops[0].open, report.gops[0].duration), ("IBBP", false, 160));
+    assert_eq!((report.gops[1].pattern.as_str(), report.gops[1].open, report.gops[1].duration), ("BIBP", true, 160));
+    assert_eq!(report.issues, vec![
+        TimingIssue::NegativeCts { frame: 7, cts: -40 },
+        TimingIssue::PtsCollision { frame: 7, other: 4, pts: 240 },
+    ]);
+}
+
+#[test]
+fn test_timing_slices() {
+    // the SPS and PPS of the frame check test, frame_num 4 bits and poc lsb 6 bits
+    let sps: Vec<u8> = vec![0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00,
+                            0x04, 0x00, 0x00, 0x03, 0x00, 0xf2, 0x3c, 0x22, 0x11, 0x65, 0x80];
+    let mut avcc: Vec<u8> = vec![0x17, 0, 0, 0, 0, 1, 0x64, 0x00, 0x28, 0xff, 0xe1, 0, sps.len() as u8];
+    avcc.extend_from_slice(&sps);
+    avcc.extend_from_slice(&[1, 0, 6, 0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0]);
+    let idr: &[u8] = &[0x65, 0x88, 0x84, 0x00];
+    let p: &[u8] = &[0x41, 0x9a, 0x21, 0x08];
+    // non-IDR I slice, frame_num 1, poc lsb 4
+    let i: &[u8] = &[0x41, 0x88, 0x88, 0x90];
+    // recovery point, recovery_frame_cnt 0, broken link
+    let recovery: &[u8] = &[0x06, 0x06, 0x01, 0b1010_0000, 0x80];
+    fn frame(frame_type: u8, dts: u64, cts: i32, nal_units: &[&[u8]]) -> FLVTag {
+        let c = cts as u32;
+        let mut data: Vec<u8> = vec![frame_type << 4 | 7, 1, (c >> 16) as u8, (c >> 8) as u8, c as u8];
+        for nalu in nal_units {
+            data.extend_from_slice(&[0, 0, 0, nalu.len() as u8]);
+            data.extend_from_slice(nalu);
+        }
+        FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, dts, &data)
+    }
+    let mut analyzer = TimingAnalyzer::new();
+    // IDR P, an open GOP from a flagged I slice with a P shown before it, a flagged P that is no keyframe,
+    // then a flagged P after a recovery point
+    let tags = vec![FLVTag::new(FLVTagType::TAG_TYPE_VIDEO, 0, &avcc),
+                    frame(1, 0, 0, &[idr]), frame(2, 40, 0, &[p]),
+                    frame(1, 80, 80, &[i]), frame(2, 120, 0, &[p]),
+                    frame(1, 160, 40, &[p]),
+                    frame(1, 200, 40, &[recovery, p])];
+    for tag in tags.iter() {
+        analyzer.push_tag(tag).unwrap();
+    }
+    let report = analyzer.finish();
+    assert_eq!(report.frames.iter().map(|f| f.keyframe).collect::<Vec<bool>>(), vec![true, false, true, false, false, true]);
+    assert_eq!(report.gops.iter().map(|g| (g.first_frame, g.frame_count, g.open)).collect::<Vec<_>>(),
+               vec![(0, 2, false), (2, 3, true), (5, 1, false)]);
+    assert_eq!(report.gops[1].pattern, "PIP");
+}